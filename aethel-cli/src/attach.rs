@@ -0,0 +1,247 @@
+use std::error::Error;
+use std::io::{self, IsTerminal};
+use std::process;
+
+use aethel_common::proto::aethel::{AttachRequest, ExecRequest, InputEvent};
+use nix::sys::termios::{self, SetArg, Termios};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::copy::Client;
+
+/// Ctrl-P Ctrl-Q, as docker: leaves the process running and returns.
+const DETACH_KEYS: [u8; 2] = [0x10, 0x11];
+
+nix::ioctl_read_bad!(tiocgwinsz, nix::libc::TIOCGWINSZ, nix::libc::winsize);
+
+/// Puts the local terminal in raw mode, so keys reach the process as typed,
+/// and restores it when dropped.
+pub struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    /// `None` when stdin is not a terminal.
+    pub fn enable() -> nix::Result<Option<Self>> {
+        let stdin = io::stdin();
+        if !stdin.is_terminal() {
+            return Ok(None);
+        }
+        let original = termios::tcgetattr(&stdin)?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &raw)?;
+        Ok(Some(RawMode { original }))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(io::stdin(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// The local terminal's size as an input event, if stdout is one.
+pub fn terminal_size() -> Option<InputEvent> {
+    let mut size: nix::libc::winsize = unsafe { std::mem::zeroed() };
+    unsafe { tiocgwinsz(nix::libc::STDOUT_FILENO, &mut size) }.ok()?;
+    Some(InputEvent {
+        rows: size.ws_row as u32,
+        cols: size.ws_col as u32,
+        ..Default::default()
+    })
+}
+
+/// Splits `input` at the detach keys, which may straddle reads: `matched`
+/// is how many of them the reads so far ended with. Returns what to send
+/// on, and whether the keys were typed.
+fn scan_detach(matched: &mut usize, input: &[u8]) -> (Vec<u8>, bool) {
+    let mut data = Vec::with_capacity(input.len());
+    for &byte in input {
+        if byte == DETACH_KEYS[*matched] {
+            *matched += 1;
+            if *matched == DETACH_KEYS.len() {
+                return (data, true);
+            }
+            continue;
+        }
+        data.extend_from_slice(&DETACH_KEYS[..*matched]);
+        *matched = usize::from(byte == DETACH_KEYS[0]);
+        if *matched == 0 {
+            data.push(byte);
+        }
+    }
+    (data, false)
+}
+
+/// Sends stdin when `interactive`, and with `tty` the terminal's size
+/// whenever it changes, as requests `wrap` makes. Returns true once the
+/// detach keys are typed on a tty; false when there is nothing left to send.
+pub async fn forward_input<T>(tx: mpsc::Sender<T>, interactive: bool, tty: bool, wrap: fn(InputEvent) -> T) -> bool {
+    let mut resized = if tty { signal(SignalKind::window_change()).ok() } else { None };
+    if resized.is_none() && !interactive {
+        return false;
+    }
+    let mut stdin = interactive.then(tokio::io::stdin);
+    let mut matched = 0;
+    let mut buf = [0; 4096];
+
+    loop {
+        tokio::select! {
+            read = async { stdin.as_mut().unwrap().read(&mut buf).await }, if stdin.is_some() => {
+                let n = match read {
+                    Ok(0) | Err(_) => {
+                        stdin = None;
+                        let close = InputEvent { close: true, ..Default::default() };
+                        if tx.send(wrap(close)).await.is_err() || resized.is_none() {
+                            return false;
+                        }
+                        continue;
+                    }
+                    Ok(n) => n,
+                };
+                let (data, detached) = if tty { scan_detach(&mut matched, &buf[..n]) } else { (buf[..n].to_vec(), false) };
+                if !data.is_empty() && tx.send(wrap(InputEvent { data, ..Default::default() })).await.is_err() {
+                    return false;
+                }
+                if detached {
+                    return true;
+                }
+            }
+            Some(()) = async { resized.as_mut().unwrap().recv().await }, if resized.is_some() => {
+                if let Some(size) = terminal_size() {
+                    if tx.send(wrap(size)).await.is_err() {
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Completes once the user detaches; never when there is just no more input.
+async fn detached(input: JoinHandle<bool>) {
+    if !input.await.unwrap_or(false) {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Attaches to a running container until its output ends or the user
+/// detaches. Stdin is only forwarded when `interactive`.
+pub async fn attach(client: &mut Client, container_id: &str, interactive: bool) -> Result<(), Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel(16);
+    tx.send(AttachRequest {
+        container_id: container_id.to_string(),
+        input: terminal_size(),
+    })
+    .await?;
+    let mut stream = client.attach(ReceiverStream::new(rx)).await?.into_inner();
+
+    // The first message says whether the container has a terminal.
+    let tty = stream.message().await?.is_some_and(|first| first.tty);
+    let _raw = if tty && interactive { RawMode::enable()? } else { None };
+    let wrap = |input| AttachRequest {
+        input: Some(input),
+        ..Default::default()
+    };
+    let detached = detached(tokio::spawn(forward_input(tx, interactive, tty, wrap)));
+    tokio::pin!(detached);
+    let mut stdout = tokio::io::stdout();
+
+    loop {
+        tokio::select! {
+            output = stream.message() => match output? {
+                Some(output) => {
+                    stdout.write_all(&output.data).await?;
+                    stdout.flush().await?;
+                }
+                None => break,
+            },
+            () = &mut detached => break,
+        }
+    }
+    Ok(())
+}
+
+/// Runs a command in a running container, as `request` says, until it exits
+/// or the user detaches. Returns its exit code; 0 when detached.
+pub async fn exec(client: &mut Client, request: ExecRequest) -> Result<i32, Box<dyn Error>> {
+    let (interactive, tty) = (request.stdin, request.tty);
+    let (tx, rx) = mpsc::channel(16);
+    tx.send(ExecRequest {
+        input: if tty { terminal_size() } else { None },
+        ..request
+    })
+    .await?;
+    let mut stream = client.exec(ReceiverStream::new(rx)).await?.into_inner();
+
+    let _raw = if tty && interactive { RawMode::enable()? } else { None };
+    let wrap = |input| ExecRequest {
+        input: Some(input),
+        ..Default::default()
+    };
+    let detached = detached(tokio::spawn(forward_input(tx, interactive, tty, wrap)));
+    tokio::pin!(detached);
+    let (mut stdout, mut stderr) = (tokio::io::stdout(), tokio::io::stderr());
+
+    loop {
+        tokio::select! {
+            output = stream.message() => {
+                let Some(output) = output? else {
+                    return Err("exec stream ended without an exit code".into());
+                };
+                if output.exited {
+                    stdout.flush().await?;
+                    stderr.flush().await?;
+                    return Ok(output.exit_code);
+                }
+                match output.stream.as_str() {
+                    "stderr" => stderr.write_all(&output.data).await?,
+                    _ => stdout.write_all(&output.data).await?,
+                }
+                stdout.flush().await?;
+            }
+            () = &mut detached => return Ok(0),
+        }
+    }
+}
+
+/// Ends the CLI after a session: the read of stdin may still be blocked on
+/// a thread the runtime would wait for on return.
+pub fn exit(result: Result<i32, Box<dyn Error>>) -> ! {
+    match result {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scans_for_detach_keys() {
+        // Split across two reads.
+        let mut matched = 0;
+        assert_eq!(scan_detach(&mut matched, b"ls\x10"), (b"ls".to_vec(), false));
+        assert_eq!(matched, 1);
+        assert_eq!(scan_detach(&mut matched, b"\x11after"), (vec![], true));
+
+        // A repeated Ctrl-P sends the first one on and still detaches.
+        let mut matched = 0;
+        assert_eq!(scan_detach(&mut matched, b"\x10\x10\x11"), (vec![0x10], true));
+
+        // A Ctrl-P the keys do not follow goes through, in one read or two.
+        let mut matched = 0;
+        assert_eq!(scan_detach(&mut matched, b"\x10x"), (b"\x10x".to_vec(), false));
+        assert_eq!(matched, 0);
+        assert_eq!(scan_detach(&mut matched, b"\x10"), (vec![], false));
+        assert_eq!(scan_detach(&mut matched, b"x"), (b"\x10x".to_vec(), false));
+    }
+}
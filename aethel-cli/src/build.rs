@@ -0,0 +1,64 @@
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::Path;
+
+use aethel_common::proto::aethel::BuildImageRequest;
+use tar::Builder;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::copy::{Client, RequestWriter};
+use crate::short_id;
+
+/// Sends the directory `context` as a tar, after a first message carrying
+/// the build settings, and prints the daemon's progress as it builds.
+pub async fn build(
+    client: &mut Client,
+    context: &Path,
+    tag: Option<&str>,
+    file: Option<&str>,
+    no_cache: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !context.is_dir() {
+        return Err(format!("build context {} is not a directory", context.display()).into());
+    }
+
+    let (tx, rx) = mpsc::channel(4);
+    tx.send(BuildImageRequest {
+        tag: tag.unwrap_or_default().to_string(),
+        containerfile: file.unwrap_or_default().to_string(),
+        no_cache,
+        data: Vec::new(),
+    })
+    .await?;
+
+    let context = context.to_path_buf();
+    let writer = tokio::task::spawn_blocking(move || -> io::Result<()> {
+        let mut builder = Builder::new(RequestWriter::new(tx, |data| BuildImageRequest {
+            data,
+            ..Default::default()
+        }));
+        builder.follow_symlinks(false);
+        builder.append_dir_all(".", &context)?;
+        builder.into_inner()?.flush()
+    });
+
+    // A rejected request stops the upload; report why rather than the
+    // broken pipe.
+    let response = client.build_image(ReceiverStream::new(rx)).await;
+    let written = writer.await?;
+    let mut stream = response?.into_inner();
+    written?;
+
+    while let Some(progress) = stream.message().await? {
+        match progress.status.as_str() {
+            "Step" => println!("STEP {}/{}: {}", progress.step, progress.total, progress.message),
+            "Cached" => println!(" ---> Using cache {}", short_id(&progress.digest)),
+            "Output" => println!("{}", progress.message),
+            "Built" => println!("Successfully built {}", short_id(&progress.digest)),
+            "Tagged" => println!("Successfully tagged {}", progress.message),
+            status => println!("{}", status),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,324 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use aethel_common::proto::aethel::aethel_service_client::AethelServiceClient;
+use aethel_common::proto::aethel::{ArchiveChunk, CopyFromContainerRequest, CopyToContainerRequest};
+use tar::{Archive, Builder, EntryType};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Channel;
+use tonic::Streaming;
+
+use crate::CHUNK_SIZE;
+
+pub type Client = AethelServiceClient<Channel>;
+
+/// Splits `CONTAINER:PATH`. Arguments starting with `/` or `.` are always
+/// host paths, so a host path with a colon can be written `./a:b`.
+pub fn split_container_path(arg: &str) -> Option<(&str, &str)> {
+    if arg.starts_with('/') || arg.starts_with('.') {
+        return None;
+    }
+    arg.split_once(':').filter(|(container, _)| !container.is_empty())
+}
+
+/// Sends the host path `source` to `path` in the container as a tar.
+pub async fn copy_to(client: &mut Client, container_id: &str, path: &str, source: &Path) -> Result<(), Box<dyn Error>> {
+    let meta = fs::symlink_metadata(source)?;
+    let name = match source.file_name() {
+        Some(name) => name.to_owned(),
+        None => fs::canonicalize(source)?
+            .file_name()
+            .ok_or_else(|| format!("cannot copy {}", source.display()))?
+            .to_owned(),
+    };
+
+    let (tx, rx) = mpsc::channel(4);
+    tx.send(CopyToContainerRequest {
+        container_id: container_id.to_string(),
+        path: path.to_string(),
+        name: name.to_string_lossy().into_owned(),
+        data: Vec::new(),
+    })
+    .await?;
+
+    let source = source.to_path_buf();
+    let writer = tokio::task::spawn_blocking(move || -> io::Result<()> {
+        let mut builder = Builder::new(RequestWriter::new(tx, |data| CopyToContainerRequest {
+            data,
+            ..Default::default()
+        }));
+        builder.follow_symlinks(false);
+        if meta.is_dir() {
+            builder.append_dir_all(&name, &source)?;
+        } else {
+            builder.append_path_with_name(&source, &name)?;
+        }
+        builder.into_inner()?.flush()
+    });
+
+    let result = client.copy_to_container(ReceiverStream::new(rx)).await;
+    writer.await??;
+    result?;
+    Ok(())
+}
+
+/// Fetches `path` from the container and writes it to the host path
+/// `destination`: inside it when it is a directory, otherwise as it.
+pub async fn copy_from(
+    client: &mut Client,
+    container_id: &str,
+    path: &str,
+    destination: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let request = tonic::Request::new(CopyFromContainerRequest {
+        container_id: container_id.to_string(),
+        path: path.to_string(),
+    });
+    let mut stream = client.copy_from_container(request).await?.into_inner();
+    // Fail on a missing path before touching the destination.
+    let first = stream.message().await?.map(|chunk| chunk.data).unwrap_or_default();
+
+    // The archive is named after the last component of the path, with `..`
+    // stopping at the container's root as the daemon resolves it.
+    let mut clean = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::ParentDir => {
+                clean.pop();
+            }
+            _ => {}
+        }
+    }
+    let name = clean.file_name().map(PathBuf::from).unwrap_or_default();
+    let target = if destination.is_dir() {
+        destination.join(&name)
+    } else {
+        destination.to_path_buf()
+    };
+    let reader = StreamReader::new(stream, first);
+    tokio::task::spawn_blocking(move || extract(reader, &name, &target)).await??;
+    Ok(())
+}
+
+/// Writes every entry, all of which sit under `name`, to `target` instead.
+/// Entries are unpacked inside the directory that will hold `target`, never
+/// through a symlink an earlier entry made: the archive comes from inside the
+/// container, and its symlinks mean something else on the host.
+fn extract<R: Read>(reader: R, name: &Path, target: &Path) -> io::Result<()> {
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // The root comes unnamed, straight into `target`; anything else lands
+    // next to it under its own name and is renamed if that is not `target`.
+    let (dst, scratch) = if name.as_os_str().is_empty() {
+        (target.to_path_buf(), None)
+    } else if target.file_name() == Some(name.as_os_str()) {
+        (parent.to_path_buf(), None)
+    } else {
+        let scratch = parent.join(format!(".aethel-cp-{}", std::process::id()));
+        (scratch.clone(), Some(scratch))
+    };
+    fs::create_dir_all(&dst)?;
+
+    let result = unpack(reader, name, &dst).and_then(|()| match &scratch {
+        Some(scratch) => fs::rename(scratch.join(name), target),
+        None => Ok(()),
+    });
+    if let Some(scratch) = scratch {
+        let _ = fs::remove_dir_all(scratch);
+    }
+    result
+}
+
+/// Unpacks the archive in `reader` inside `dst`, checking every entry sits
+/// under `name` and not under a symlink it holds.
+fn unpack<R: Read>(reader: R, name: &Path, dst: &Path) -> io::Result<()> {
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(nix::unistd::geteuid().is_root());
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+
+    let mut symlinks = HashSet::new();
+    let check = |path: &Path, symlinks: &HashSet<PathBuf>| -> io::Result<()> {
+        let invalid = |why: &str| io::Error::new(io::ErrorKind::InvalidData, format!("archive entry {} {}", path.display(), why));
+        if !path.components().all(|component| matches!(component, Component::Normal(_))) || !path.starts_with(name) {
+            return Err(invalid("is outside the copied path"));
+        }
+        if path.ancestors().skip(1).any(|ancestor| symlinks.contains(ancestor)) {
+            return Err(invalid("is under a symlink"));
+        }
+        Ok(())
+    };
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.components().collect::<PathBuf>();
+        check(&path, &symlinks)?;
+        match entry.header().entry_type() {
+            EntryType::Symlink => {
+                symlinks.insert(path.clone());
+            }
+            EntryType::Link => {
+                let link = entry.link_name()?.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "hard link without a target"))?;
+                check(&link.components().collect::<PathBuf>(), &symlinks)?;
+            }
+            _ => {}
+        }
+        if !entry.unpack_in(dst)? {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("archive entry {} was skipped", path.display())));
+        }
+    }
+    Ok(())
+}
+
+/// Sends what blocking code writes as a stream of requests, each chunk
+/// wrapped by `wrap`.
+pub struct RequestWriter<T> {
+    tx: mpsc::Sender<T>,
+    wrap: fn(Vec<u8>) -> T,
+    buf: Vec<u8>,
+}
+
+impl<T> RequestWriter<T> {
+    pub fn new(tx: mpsc::Sender<T>, wrap: fn(Vec<u8>) -> T) -> Self {
+        RequestWriter {
+            tx,
+            wrap,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+}
+
+impl<T> Write for RequestWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        self.tx
+            .blocking_send((self.wrap)(data))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "daemon went away"))
+    }
+}
+
+/// Lets blocking code read an archive the daemon streams.
+struct StreamReader {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl StreamReader {
+    fn new(mut stream: Streaming<ArchiveChunk>, first: Vec<u8>) -> Self {
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                let item = match stream.message().await {
+                    Ok(Some(chunk)) => Ok(chunk.data),
+                    Ok(None) => break,
+                    Err(status) => Err(io::Error::other(status.message().to_string())),
+                };
+                let failed = item.is_err();
+                if tx.send(item).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        StreamReader { rx, chunk: first, pos: 0 }
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.blocking_recv() {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::Header;
+
+    fn entry(builder: &mut Builder<Vec<u8>>, kind: EntryType, path: &str, link: &str, contents: &str) {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_mode(if kind == EntryType::Directory { 0o755 } else { 0o644 });
+        header.set_mtime(1);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_size(contents.len() as u64);
+        if kind == EntryType::Symlink {
+            builder.append_link(&mut header, path, link).unwrap();
+        } else {
+            builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn extracts_under_a_new_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = Builder::new(Vec::new());
+        entry(&mut builder, EntryType::Directory, "nginx", "", "");
+        entry(&mut builder, EntryType::Regular, "nginx/nginx.conf", "", "events {}");
+        let tar = builder.into_inner().unwrap();
+
+        let target = dir.path().join("conf");
+        extract(tar.as_slice(), Path::new("nginx"), &target).unwrap();
+
+        assert_eq!(fs::read_to_string(target.join("nginx.conf")).unwrap(), "events {}");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn refuses_entries_under_an_extracted_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let mut builder = Builder::new(Vec::new());
+        entry(&mut builder, EntryType::Directory, "app", "", "");
+        entry(&mut builder, EntryType::Symlink, "app/out", outside.to_str().unwrap(), "");
+        entry(&mut builder, EntryType::Symlink, "app/in", ".", "");
+        entry(&mut builder, EntryType::Regular, "app/in/x", "", "x");
+        let tar = builder.into_inner().unwrap();
+
+        let inside = extract(tar.as_slice(), Path::new("app"), &dir.path().join("app"));
+        let mut builder = Builder::new(Vec::new());
+        entry(&mut builder, EntryType::Directory, "app", "", "");
+        entry(&mut builder, EntryType::Symlink, "app/out", outside.to_str().unwrap(), "");
+        entry(&mut builder, EntryType::Regular, "app/out/x", "", "x");
+        let escaping = extract(builder.into_inner().unwrap().as_slice(), Path::new("app"), &dir.path().join("copy"));
+
+        assert_eq!(inside.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(escaping.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(!dir.path().join("app/x").exists());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+    }
+}
@@ -1,6 +1,18 @@
 use aethel_common::proto::aethel::aethel_service_client::AethelServiceClient;
-use aethel_common::proto::aethel::{CreateContainerRequest, StopRequest, LogsRequest};
+use aethel_common::time;
+use aethel_common::proto::aethel::{CreateContainerRequest, InspectContainerRequest, InspectContainerResponse, PauseContainerRequest, UnpauseContainerRequest, CheckNetworkRequest, StatsRequest, StatsSample, StopRequest, LogsRequest, PullImageRequest, PushImageRequest, InspectImageRequest, TagImageRequest, RemoveImageRequest, ArchiveChunk, SaveImagesRequest, PruneRequest, PruneResponse, CommitContainerRequest, DiffContainerRequest, ExportContainerRequest, CreateVolumeRequest, InspectVolumeRequest, RemoveVolumeRequest, VolumeInfo, ExecRequest};
 use clap::{Parser, Subcommand};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+mod attach;
+mod build;
+mod copy;
+mod template;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -12,20 +24,492 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Run { 
-        #[arg(short, long)]
+        #[arg(long)]
         image: String,
-        #[arg(default_value = "/bin/sh")]
-        command: String,
+        /// Keep stdin open and attach to the container
+        #[arg(short, long)]
+        interactive: bool,
+        /// Run the container on a pseudo-terminal
+        #[arg(short, long)]
+        tty: bool,
+        #[arg(long, default_value = "bridge")]
+        network: String,
+        #[arg(long)]
+        platform: Option<String>,
+        /// Mount a named volume or host path: SOURCE:DESTINATION[:ro|rw]
+        #[arg(short, long = "volume")]
+        volumes: Vec<String>,
+        /// Where the container's output is logged: json-file, syslog,
+        /// journald or none; the daemon's default if not given
+        #[arg(long)]
+        log_driver: Option<String>,
+        /// Log driver option, KEY=VALUE: max-size (e.g. 10m) or max-file for
+        /// json-file; syslog-address, syslog-facility or tag for syslog; tag
+        /// for journald
+        #[arg(long = "log-opt")]
+        log_opts: Vec<String>,
+        command: Option<String>,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
     },
     Ps {},
+    /// Show a container's configuration and state as JSON
+    Inspect {
+        container_id: String,
+        /// Print a template instead, e.g. '{{.State.Pid}}' or '{{json .Config}}'
+        #[arg(short, long)]
+        format: Option<String>,
+    },
+    /// Run a command in a running container
+    Exec {
+        container_id: String,
+        /// Forward stdin to the command
+        #[arg(short, long)]
+        interactive: bool,
+        /// Run the command on a pseudo-terminal
+        #[arg(short, long)]
+        tty: bool,
+        /// NAME=value, repeatable; added to the image's environment
+        #[arg(short, long)]
+        env: Vec<String>,
+        /// Directory to run the command in
+        #[arg(short, long)]
+        workdir: Option<String>,
+        /// user[:group] to run as instead of the container's user
+        #[arg(short, long)]
+        user: Option<String>,
+        command: String,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Attach to a running container's input and output; Ctrl-P Ctrl-Q detaches
+    Attach {
+        container_id: String,
+        /// Do not forward stdin
+        #[arg(long)]
+        no_stdin: bool,
+    },
     Stop { 
         #[arg(short, long)]
-        container_id: String 
+        container_id: String,
+        /// Stop the container even if it is paused
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Freeze every process in a container
+    Pause {
+        container_id: String,
+    },
+    /// Resume a paused container
+    Unpause {
+        container_id: String,
+    },
+    /// Show containers' CPU, memory, network and disk use, refreshed every second
+    Stats {
+        /// Every running container when none are given
+        container_ids: Vec<String>,
+        /// Print one sample as JSON and exit
+        #[arg(long)]
+        json: bool,
     },
     Logs { 
         #[arg(short, long)]
-        container_id: String 
+        container_id: String,
+        /// Keep printing new output until the container's output ends
+        #[arg(short, long)]
+        follow: bool,
+        /// Only the last N lines of the log so far
+        #[arg(short = 'n', long)]
+        tail: Option<u32>,
+        /// Only output since: an RFC 3339 time, Unix seconds, or a duration ago like 10m or 1h30m
+        #[arg(long, value_parser = parse_time_bound)]
+        since: Option<String>,
+        /// Only output before, in the same forms as --since
+        #[arg(long, value_parser = parse_time_bound)]
+        until: Option<String>,
+        /// Prefix each line with when it was written
+        #[arg(short, long)]
+        timestamps: bool,
+    },
+    Pull {
+        image: String,
+        #[arg(long)]
+        platform: Option<String>,
+    },
+    Push {
+        image: String,
+    },
+    Images {},
+    Tag {
+        source: String,
+        target: String,
+    },
+    Rmi {
+        image: String,
+        #[arg(short, long)]
+        force: bool,
+    },
+    Image {
+        #[command(subcommand)]
+        command: ImageCommands,
+    },
+    System {
+        #[command(subcommand)]
+        command: SystemCommands,
+    },
+    Volume {
+        #[command(subcommand)]
+        command: VolumeCommands,
+    },
+    Network {
+        #[command(subcommand)]
+        command: NetworkCommands,
+    },
+    Load {
+        /// Archive to read instead of stdin
+        #[arg(short, long)]
+        input: Option<PathBuf>,
+    },
+    Save {
+        #[arg(required = true)]
+        images: Vec<String>,
+        /// File to write instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// "docker" or "oci"
+        #[arg(long, default_value = "docker")]
+        format: String,
+    },
+    /// Create an image from a container's changes
+    Commit {
+        container_id: String,
+        /// Repository and optional tag for the new image
+        reference: String,
+        #[arg(short, long)]
+        author: Option<String>,
+        /// Commit message
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// List paths a container added (A), changed (C) or deleted (D)
+    Diff {
+        container_id: String,
+    },
+    /// Copy files between a container and the host; one side is CONTAINER:PATH
+    Cp {
+        source: String,
+        destination: String,
+    },
+    /// Build an image from a Containerfile
+    Build {
+        /// Directory sent to the daemon for COPY and ADD to read from
+        context: PathBuf,
+        /// Repository and optional tag for the built image
+        #[arg(short, long)]
+        tag: Option<String>,
+        /// Containerfile within the context (default: Containerfile, then Dockerfile)
+        #[arg(short, long)]
+        file: Option<String>,
+        /// Run every step instead of reusing cached results
+        #[arg(long)]
+        no_cache: bool,
     },
+    /// Write a container's filesystem as a tar archive
+    Export {
+        container_id: String,
+        /// File to write instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Size of the chunks archives are streamed in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Subcommand)]
+enum ImageCommands {
+    Inspect {
+        image: String,
+    },
+    /// Remove image content no reference or container uses
+    Prune {
+        /// Only report what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum SystemCommands {
+    /// Remove stopped containers, then prune images
+    Prune {
+        /// Only report what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum VolumeCommands {
+    /// Create a volume
+    Create {
+        /// Generated when omitted
+        name: Option<String>,
+        /// KEY=VALUE, repeatable
+        #[arg(long = "label")]
+        labels: Vec<String>,
+    },
+    Ls {},
+    Inspect {
+        name: String,
+    },
+    /// Remove a volume no container mounts
+    Rm {
+        name: String,
+    },
+    /// Remove every volume no container mounts
+    Prune {
+        /// Only report what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum NetworkCommands {
+    /// Ask a container's CNI plugins whether its network is still as they set it up
+    Check {
+        container_id: String,
+    },
+}
+
+/// Sizes as `docker images` prints them, in decimal units.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", bytes, UNITS[0])
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
+
+/// Writes an archive the daemon streams to `output`, or to stdout.
+async fn write_archive(
+    mut stream: tonic::Streaming<ArchiveChunk>,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer: Box<dyn AsyncWrite + Unpin> = match output {
+        Some(path) => Box::new(tokio::fs::File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+    while let Some(chunk) = stream.message().await? {
+        writer.write_all(&chunk.data).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Streams `input` to the daemon chunk by chunk.
+fn archive_chunks(mut input: Box<dyn AsyncRead + Send + Unpin>) -> ReceiverStream<ArchiveChunk> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        loop {
+            let mut data = vec![0; CHUNK_SIZE];
+            match input.read(&mut data).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    data.truncate(n);
+                    if tx.send(ArchiveChunk { data }).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error reading archive: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+fn print_prune(response: &PruneResponse, dry_run: bool) {
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    for id in &response.containers {
+        println!("{} container: {}", verb, id);
+    }
+    for digest in &response.blobs {
+        println!("{} blob: {}", verb, digest);
+    }
+    for digest in &response.layers {
+        println!("{} layer: {}", verb, digest);
+    }
+    let verb = if dry_run { "Would reclaim" } else { "Reclaimed" };
+    println!("{} {}", verb, human_size(response.reclaimed));
+}
+
+fn print_volume(volume: &VolumeInfo) -> Result<(), Box<dyn std::error::Error>> {
+    let inspect = serde_json::json!({
+        "Name": volume.name,
+        "Mountpoint": volume.mountpoint,
+        "CreatedAt": volume.created,
+        "Labels": volume.labels,
+        "RefCount": volume.ref_count,
+    });
+    println!("{}", serde_json::to_string_pretty(&inspect)?);
+    Ok(())
+}
+
+fn container_json(container: InspectContainerResponse) -> serde_json::Value {
+    let mounts: Vec<_> = container
+        .mounts
+        .into_iter()
+        .map(|mount| {
+            serde_json::json!({
+                "Type": if mount.name.is_empty() { "bind" } else { "volume" },
+                "Name": mount.name,
+                "Source": mount.source,
+                "Destination": mount.destination,
+                "RW": !mount.read_only,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "Id": container.id,
+        "Image": container.image,
+        "ImageDigest": container.image_digest,
+        "Platform": container.platform,
+        "Path": container.path,
+        "Args": container.args,
+        "State": {
+            "Status": container.status,
+            "Running": container.status == "Running",
+            "Pid": container.pid,
+            "StartedAt": container.started_at,
+            "FinishedAt": container.finished_at,
+            "ExitCode": container.exit_code,
+            "OOMKilled": container.oom_killed,
+        },
+        "HostConfig": {
+            "Memory": container.memory_limit,
+            "PidsLimit": container.pids_limit,
+            "CpuQuota": container.cpu_quota,
+            "CpuPeriod": container.cpu_period,
+        },
+        "Config": {
+            "Env": container.env,
+            "ExposedPorts": container.exposed_ports,
+            "Labels": container.labels,
+            "OpenStdin": container.open_stdin,
+            "Tty": container.tty,
+        },
+        "Mounts": mounts,
+        "NetworkSettings": {
+            "Network": container.network,
+            "IPAddress": container.ip_address,
+        },
+        "LogConfig": {
+            "Type": container.log_driver,
+            "Config": container.log_opts,
+        },
+        "RootfsPath": container.rootfs,
+        "LogPath": container.log_path,
+    })
+}
+
+fn print_stats(sample: &StatsSample) {
+    println!(
+        "{:<12}  {:>7}  {:>21}  {:>6}  {:>19}  {:>19}  {:>5}",
+        "CONTAINER ID", "CPU %", "MEM USAGE / LIMIT", "MEM %", "NET I/O", "BLOCK I/O", "PIDS"
+    );
+    for stats in &sample.containers {
+        let (limit, percent) = if stats.memory_limit == 0 {
+            ("unlimited".to_string(), "--".to_string())
+        } else {
+            let percent = stats.memory_usage as f64 / stats.memory_limit as f64 * 100.0;
+            (human_size(stats.memory_limit), format!("{:.2}%", percent))
+        };
+        println!(
+            "{:<12}  {:>6.2}%  {:>21}  {:>6}  {:>19}  {:>19}  {:>5}",
+            &stats.container_id[..stats.container_id.len().min(12)],
+            stats.cpu_percent,
+            format!("{} / {}", human_size(stats.memory_usage), limit),
+            percent,
+            format!("{} / {}", human_size(stats.net_rx_bytes), human_size(stats.net_tx_bytes)),
+            format!("{} / {}", human_size(stats.block_read_bytes), human_size(stats.block_write_bytes)),
+            stats.pids,
+        );
+    }
+}
+
+fn stats_json(sample: StatsSample) -> serde_json::Value {
+    let containers: Vec<_> = sample
+        .containers
+        .into_iter()
+        .map(|stats| {
+            serde_json::json!({
+                "Id": stats.container_id,
+                "Read": sample.timestamp,
+                "CPUPercent": stats.cpu_percent,
+                "CPUUsageUsec": stats.cpu_usage_usec,
+                "MemoryUsage": stats.memory_usage,
+                "MemoryLimit": stats.memory_limit,
+                "MemoryCache": stats.memory_cache,
+                "Pids": stats.pids,
+                "BlockRead": stats.block_read_bytes,
+                "BlockWrite": stats.block_write_bytes,
+                "NetRx": stats.net_rx_bytes,
+                "NetTx": stats.net_tx_bytes,
+            })
+        })
+        .collect();
+    serde_json::Value::Array(containers)
+}
+
+/// Reads `--since` and `--until` into the RFC 3339 form the daemon takes.
+fn parse_time_bound(value: &str) -> Result<String, String> {
+    if time::parse_rfc3339(value).is_some() {
+        return Ok(value.to_string());
+    }
+    if let Ok(secs) = value.parse::<u64>() {
+        return Ok(time::format_rfc3339(UNIX_EPOCH + Duration::from_secs(secs)));
+    }
+    parse_duration(value)
+        .and_then(|ago| SystemTime::now().checked_sub(ago))
+        .map(time::format_rfc3339)
+        .ok_or_else(|| format!("expected an RFC 3339 time, Unix seconds or a duration like 10m, got {:?}", value))
+}
+
+/// Durations like `90s`, `10m` or `1h30m`; `d` is days.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (mut secs, mut digits) = (0u64, String::new());
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            _ => return None,
+        };
+        secs = secs.checked_add(digits.parse::<u64>().ok()?.checked_mul(unit)?)?;
+        digits.clear();
+    }
+    (digits.is_empty() && !value.is_empty()).then(|| Duration::from_secs(secs))
+}
+
+fn short_id(digest: &str) -> &str {
+    let hex = digest.split(':').nth(1).unwrap_or(digest);
+    &hex[..hex.len().min(12)]
 }
 
 #[tokio::main]
@@ -35,13 +519,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut client = AethelServiceClient::connect("http://[::1]:50051").await?;
 
     match &cli.command {
-        Commands::Run { image, command } => {
+        Commands::Run { image, interactive, tty, network, platform, volumes, log_driver, log_opts, command, args } => {
+            let log_opts = log_opts
+                .iter()
+                .map(|opt| {
+                    opt.split_once('=')
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .ok_or_else(|| format!("invalid log option {:?}, expected KEY=VALUE", opt))
+                })
+                .collect::<Result<_, _>>()?;
             let request = tonic::Request::new(CreateContainerRequest {
                 image_name: image.clone(),
-                command: command.clone(),
-                args: vec![],
+                command: command.clone().unwrap_or_default(),
+                args: args.clone(),
+                network: network.clone(),
+                platform: platform.clone().unwrap_or_default(),
+                volumes: volumes.clone(),
+                stdin: *interactive,
+                tty: *tty,
+                log_opts,
+                log_driver: log_driver.clone().unwrap_or_default(),
             });
             let response = client.create_container(request).await?.into_inner();
+            if *interactive || *tty {
+                let result = attach::attach(&mut client, &response.container_id, *interactive).await;
+                attach::exit(result.map(|()| 0));
+            }
             println!("Container created with ID: {} and IP: {}", response.container_id, response.ip_address);
         }
         Commands::Ps {} => {
@@ -51,21 +554,321 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("{:<36} {:<20} {:<10} {:<15}", container.id, container.image, container.status, container.ip_address);
             }
         }
-        Commands::Stop { container_id } => {
+        Commands::Inspect { container_id, format } => {
+            let request = tonic::Request::new(InspectContainerRequest {
+                container_id: container_id.clone(),
+            });
+            let inspect = container_json(client.inspect_container(request).await?.into_inner());
+            match format {
+                Some(format) => println!("{}", template::render(format, &inspect)?),
+                None => println!("{}", serde_json::to_string_pretty(&inspect)?),
+            }
+        }
+        Commands::Exec { container_id, interactive, tty, env, workdir, user, command, args } => {
+            let request = ExecRequest {
+                container_id: container_id.clone(),
+                command: command.clone(),
+                args: args.clone(),
+                env: env.clone(),
+                working_dir: workdir.clone().unwrap_or_default(),
+                user: user.clone().unwrap_or_default(),
+                stdin: *interactive,
+                tty: *tty,
+                input: None,
+            };
+            attach::exit(attach::exec(&mut client, request).await);
+        }
+        Commands::Attach { container_id, no_stdin } => {
+            let result = attach::attach(&mut client, container_id, !no_stdin).await;
+            attach::exit(result.map(|()| 0));
+        }
+        Commands::Stop { container_id, force } => {
             let request = tonic::Request::new(StopRequest {
                 container_id: container_id.clone(),
+                force: *force,
             });
             let response = client.stop_container(request).await?;
             println!("Container stopped: {}", response.into_inner().success);
         }
-        Commands::Logs { container_id } => {
+        Commands::Pause { container_id } => {
+            let request = tonic::Request::new(PauseContainerRequest {
+                container_id: container_id.clone(),
+            });
+            client.pause_container(request).await?;
+            println!("{}", container_id);
+        }
+        Commands::Unpause { container_id } => {
+            let request = tonic::Request::new(UnpauseContainerRequest {
+                container_id: container_id.clone(),
+            });
+            client.unpause_container(request).await?;
+            println!("{}", container_id);
+        }
+        Commands::Stats { container_ids, json } => {
+            let request = tonic::Request::new(StatsRequest {
+                container_ids: container_ids.clone(),
+                stream: !*json,
+            });
+            let mut stream = client.stats(request).await?.into_inner();
+            let refresh = std::io::stdout().is_terminal();
+            while let Some(sample) = stream.message().await? {
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&stats_json(sample))?);
+                    break;
+                }
+                if refresh {
+                    // Clear the screen and start again at the top.
+                    print!("\x1b[2J\x1b[H");
+                }
+                print_stats(&sample);
+            }
+        }
+        Commands::Logs { container_id, follow, tail, since, until, timestamps } => {
             let request = tonic::Request::new(LogsRequest {
                 container_id: container_id.clone(),
+                follow: *follow,
+                tail: *tail,
+                since: since.clone().unwrap_or_default(),
+                until: until.clone().unwrap_or_default(),
+                streams: Vec::new(),
             });
             let mut stream = client.stream_logs(request).await?.into_inner();
 
-            while let Some(log_entry) = stream.message().await? {
-                println!("{}", log_entry.entry);
+            let (mut stdout, mut stderr) = (tokio::io::stdout(), tokio::io::stderr());
+            // Whether stdout and stderr are partway through a line, which
+            // gets no timestamp of its own.
+            let mut mid_line = [false; 2];
+            while let Some(entry) = stream.message().await? {
+                let is_stderr = entry.stream == "stderr";
+                let mid = &mut mid_line[usize::from(is_stderr)];
+                let mut data = Vec::with_capacity(entry.data.len());
+                if *timestamps && !*mid {
+                    data.extend_from_slice(format!("{} ", entry.timestamp).as_bytes());
+                }
+                data.extend_from_slice(&entry.data);
+                *mid = entry.partial;
+
+                if is_stderr {
+                    stderr.write_all(&data).await?;
+                } else {
+                    stdout.write_all(&data).await?;
+                    stdout.flush().await?;
+                }
+            }
+        }
+        Commands::Pull { image, platform } => {
+            let request = tonic::Request::new(PullImageRequest {
+                image: image.clone(),
+                platform: platform.clone().unwrap_or_default(),
+            });
+            let mut stream = client.pull_image(request).await?.into_inner();
+
+            while let Some(progress) = stream.message().await? {
+                let short = short_id(&progress.digest);
+                match progress.status.as_str() {
+                    "Downloading" => println!("{}: Downloading {}/{} bytes", short, progress.current, progress.total),
+                    "Pulled" => println!("Pulled {} ({})", image, progress.digest),
+                    status => println!("{}: {}", short, status),
+                }
+            }
+        }
+        Commands::Images {} => {
+            let mut stream = client.list_images(tonic::Request::new(aethel_common::proto::aethel::Empty {})).await?.into_inner();
+            println!("{:<40} {:<20} {:<12} {:<20} {:<10}", "REPOSITORY", "TAG", "IMAGE ID", "CREATED", "SIZE");
+            while let Some(image) = stream.message().await? {
+                let created = image.created.get(..19).map(|c| c.replace('T', " ")).unwrap_or_default();
+                println!(
+                    "{:<40} {:<20} {:<12} {:<20} {:<10}",
+                    image.repository,
+                    image.tag,
+                    short_id(&image.id),
+                    created,
+                    human_size(image.size)
+                );
+            }
+        }
+        Commands::Tag { source, target } => {
+            let request = tonic::Request::new(TagImageRequest {
+                source: source.clone(),
+                target: target.clone(),
+            });
+            let response = client.tag_image(request).await?.into_inner();
+            println!("Tagged {}", response.name);
+        }
+        Commands::Rmi { image, force } => {
+            let request = tonic::Request::new(RemoveImageRequest {
+                image: image.clone(),
+                force: *force,
+            });
+            let response = client.remove_image(request).await?.into_inner();
+            for name in response.untagged {
+                println!("Untagged: {}", name);
+            }
+            if !response.deleted.is_empty() {
+                println!("Deleted: {}", response.deleted);
+            }
+        }
+        Commands::Image { command: ImageCommands::Inspect { image } } => {
+            let request = tonic::Request::new(InspectImageRequest {
+                image: image.clone(),
+            });
+            let response = client.inspect_image(request).await?.into_inner();
+            let config: serde_json::Value = serde_json::from_str(&response.config)?;
+            let inspect = serde_json::json!({
+                "Id": response.id,
+                "RepoTags": response.repo_tags,
+                "RepoDigests": response.repo_digests,
+                "MediaType": response.media_type,
+                "Platform": response.platform,
+                "Created": response.created,
+                "Size": response.size,
+                "Config": config,
+            });
+            println!("{}", serde_json::to_string_pretty(&inspect)?);
+        }
+        Commands::Image { command: ImageCommands::Prune { dry_run } } => {
+            let request = tonic::Request::new(PruneRequest { dry_run: *dry_run });
+            let response = client.prune_images(request).await?.into_inner();
+            print_prune(&response, *dry_run);
+        }
+        Commands::System { command: SystemCommands::Prune { dry_run } } => {
+            let request = tonic::Request::new(PruneRequest { dry_run: *dry_run });
+            let response = client.prune_system(request).await?.into_inner();
+            print_prune(&response, *dry_run);
+        }
+        Commands::Volume { command: VolumeCommands::Create { name, labels } } => {
+            let labels = labels
+                .iter()
+                .map(|label| {
+                    label
+                        .split_once('=')
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .ok_or_else(|| format!("invalid label '{}': expected KEY=VALUE", label))
+                })
+                .collect::<Result<_, _>>()?;
+            let request = tonic::Request::new(CreateVolumeRequest {
+                name: name.clone().unwrap_or_default(),
+                labels,
+            });
+            let volume = client.create_volume(request).await?.into_inner();
+            println!("{}", volume.name);
+        }
+        Commands::Volume { command: VolumeCommands::Ls {} } => {
+            let mut stream = client.list_volumes(tonic::Request::new(aethel_common::proto::aethel::Empty {})).await?.into_inner();
+            println!("{:<64} {:<6} {:<20}", "VOLUME NAME", "USERS", "CREATED");
+            while let Some(volume) = stream.message().await? {
+                let created = volume.created.get(..19).map(|c| c.replace('T', " ")).unwrap_or_default();
+                println!("{:<64} {:<6} {:<20}", volume.name, volume.ref_count, created);
+            }
+        }
+        Commands::Volume { command: VolumeCommands::Inspect { name } } => {
+            let request = tonic::Request::new(InspectVolumeRequest { name: name.clone() });
+            let volume = client.inspect_volume(request).await?.into_inner();
+            print_volume(&volume)?;
+        }
+        Commands::Volume { command: VolumeCommands::Rm { name } } => {
+            let request = tonic::Request::new(RemoveVolumeRequest { name: name.clone() });
+            client.remove_volume(request).await?;
+            println!("{}", name);
+        }
+        Commands::Volume { command: VolumeCommands::Prune { dry_run } } => {
+            let request = tonic::Request::new(PruneRequest { dry_run: *dry_run });
+            let response = client.prune_volumes(request).await?.into_inner();
+            let verb = if *dry_run { "Would remove" } else { "Removed" };
+            for name in &response.volumes {
+                println!("{} volume: {}", verb, name);
+            }
+            let verb = if *dry_run { "Would reclaim" } else { "Reclaimed" };
+            println!("{} {}", verb, human_size(response.reclaimed));
+        }
+        Commands::Network { command: NetworkCommands::Check { container_id } } => {
+            let request = tonic::Request::new(CheckNetworkRequest {
+                container_id: container_id.clone(),
+            });
+            client.check_network(request).await?;
+            println!("{}", container_id);
+        }
+        Commands::Load { input } => {
+            let reader: Box<dyn AsyncRead + Send + Unpin> = match input {
+                Some(path) => Box::new(tokio::fs::File::open(path).await?),
+                None => Box::new(tokio::io::stdin()),
+            };
+            let mut stream = client.load_images(archive_chunks(reader)).await?.into_inner();
+            while let Some(loaded) = stream.message().await? {
+                if loaded.image.is_empty() {
+                    println!("Loaded image ID: {}", loaded.digest);
+                } else {
+                    println!("Loaded image: {} ({})", loaded.image, loaded.digest);
+                }
+            }
+        }
+        Commands::Save { images, output, format } => {
+            if output.is_none() && std::io::stdout().is_terminal() {
+                return Err("refusing to write an archive to a terminal; use -o or redirect stdout".into());
+            }
+            let request = tonic::Request::new(SaveImagesRequest {
+                images: images.clone(),
+                format: format.clone(),
+            });
+            let stream = client.save_images(request).await?.into_inner();
+            write_archive(stream, output.as_deref()).await?;
+        }
+        Commands::Commit { container_id, reference, author, message } => {
+            let request = tonic::Request::new(CommitContainerRequest {
+                container_id: container_id.clone(),
+                reference: reference.clone(),
+                author: author.clone().unwrap_or_default(),
+                message: message.clone().unwrap_or_default(),
+            });
+            let response = client.commit_container(request).await?.into_inner();
+            println!("Committed {} as {}", response.reference, response.id);
+        }
+        Commands::Diff { container_id } => {
+            let request = tonic::Request::new(DiffContainerRequest {
+                container_id: container_id.clone(),
+            });
+            let response = client.diff_container(request).await?.into_inner();
+            for change in response.changes {
+                println!("{} {}", change.kind, change.path);
+            }
+        }
+        Commands::Cp { source, destination } => {
+            match (copy::split_container_path(source), copy::split_container_path(destination)) {
+                (None, Some((container_id, path))) => {
+                    copy::copy_to(&mut client, container_id, path, Path::new(source)).await?;
+                }
+                (Some((container_id, path)), None) => {
+                    copy::copy_from(&mut client, container_id, path, Path::new(destination)).await?;
+                }
+                _ => return Err("exactly one of source and destination must be CONTAINER:PATH".into()),
+            }
+        }
+        Commands::Build { context, tag, file, no_cache } => {
+            build::build(&mut client, context, tag.as_deref(), file.as_deref(), *no_cache).await?;
+        }
+        Commands::Export { container_id, output } => {
+            if output.is_none() && std::io::stdout().is_terminal() {
+                return Err("refusing to write an archive to a terminal; use -o or redirect stdout".into());
+            }
+            let request = tonic::Request::new(ExportContainerRequest {
+                container_id: container_id.clone(),
+            });
+            let stream = client.export_container(request).await?.into_inner();
+            write_archive(stream, output.as_deref()).await?;
+        }
+        Commands::Push { image } => {
+            let request = tonic::Request::new(PushImageRequest {
+                image: image.clone(),
+            });
+            let mut stream = client.push_image(request).await?.into_inner();
+
+            while let Some(progress) = stream.message().await? {
+                let short = short_id(&progress.digest);
+                match progress.status.as_str() {
+                    "Uploading" => println!("{}: Uploading {}/{} bytes", short, progress.current, progress.total),
+                    "Pushed" => println!("Pushed {} ({})", image, progress.digest),
+                    status => println!("{}: {}", short, status),
+                }
             }
         }
     }
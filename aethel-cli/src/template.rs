@@ -0,0 +1,112 @@
+use serde_json::Value;
+
+/// Renders a `--format` template against `value`, in the subset of Go's
+/// template syntax docker users write: `{{.Field.Nested}}` prints a field,
+/// `{{json .Field}}` prints it as JSON, and `{{.}}` is the whole value.
+pub fn render(template: &str, value: &Value) -> Result<String, String> {
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| format!("unclosed action in template {:?}", template))?;
+        let action = rest[start + 2..start + end].trim();
+        let (json, path) = match action.strip_prefix("json ") {
+            Some(path) => (true, path.trim()),
+            None => (false, action),
+        };
+        let field = lookup(value, path)?;
+        if json {
+            output.push_str(&serde_json::to_string(field).map_err(|e| e.to_string())?);
+        } else {
+            write_plain(&mut output, field);
+        }
+        rest = &rest[start + end + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Follows a `.A.B` path into `value`.
+fn lookup<'a>(value: &'a Value, path: &str) -> Result<&'a Value, String> {
+    let fields = path
+        .strip_prefix('.')
+        .ok_or_else(|| format!("unsupported template action {:?}", path))?;
+    let mut field = value;
+    for name in fields.split('.').filter(|name| !name.is_empty()) {
+        field = field
+            .get(name)
+            .ok_or_else(|| format!("no field {:?} in {:?}", name, path))?;
+    }
+    Ok(field)
+}
+
+/// Prints as Go's templates do: strings bare, lists as `[a b]` and maps as
+/// `map[k:v]`.
+fn write_plain(output: &mut String, value: &Value) {
+    match value {
+        Value::Null => output.push_str("<no value>"),
+        Value::String(s) => output.push_str(s),
+        Value::Array(items) => {
+            output.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    output.push(' ');
+                }
+                write_plain(output, item);
+            }
+            output.push(']');
+        }
+        Value::Object(fields) => {
+            output.push_str("map[");
+            for (i, (key, item)) in fields.iter().enumerate() {
+                if i > 0 {
+                    output.push(' ');
+                }
+                output.push_str(key);
+                output.push(':');
+                write_plain(output, item);
+            }
+            output.push(']');
+        }
+        other => output.push_str(&other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn renders_fields_and_json() {
+        let value = json!({"State": {"Status": "running", "Pid": 42}, "Args": ["-c", "true"]});
+
+        assert_eq!(render("{{.State.Status}} {{ .State.Pid }}", &value).unwrap(), "running 42");
+        assert_eq!(render("{{json .Args}}", &value).unwrap(), r#"["-c","true"]"#);
+        assert_eq!(render("pid={{json .State.Pid}}\n", &value).unwrap(), "pid=42\n");
+        assert_eq!(render("{{.}}", &json!("whole")).unwrap(), "whole");
+    }
+
+    #[test]
+    fn prints_lists_and_maps_as_go_does() {
+        let value = json!({"Env": ["A=1", "B=2"], "Labels": {"b": "2", "a": ["x", null]}, "Empty": []});
+
+        assert_eq!(render("{{.Env}}", &value).unwrap(), "[A=1 B=2]");
+        assert_eq!(render("{{.Labels}}", &value).unwrap(), "map[a:[x <no value>] b:2]");
+        assert_eq!(render("{{.Empty}}", &value).unwrap(), "[]");
+    }
+
+    #[test]
+    fn rejects_malformed_templates() {
+        let value = json!({"Name": "web"});
+
+        let unclosed = render("{{.Name", &value).unwrap_err();
+        assert!(unclosed.contains("unclosed action"), "{}", unclosed);
+        let missing = render("{{.State.Status}}", &value).unwrap_err();
+        assert!(missing.contains("no field \"State\""), "{}", missing);
+        let unsupported = render("{{range .Name}}", &value).unwrap_err();
+        assert!(unsupported.contains("unsupported template action"), "{}", unsupported);
+    }
+}
@@ -11,6 +11,15 @@ pub enum AethelError {
     Namespace(String),
     Cgroup(String),
     Process(String),
+    Network(String),
+    Registry(String),
+    NotFound(String),
+    UnsupportedMediaType(String),
+    InvalidDigest(String),
+    DigestMismatch { expected: String, actual: String },
+    SizeMismatch { digest: String, expected: u64, actual: u64 },
+    Build(String),
+    Volume(String),
 }
 
 impl fmt::Display for AethelError {
@@ -23,6 +32,21 @@ impl fmt::Display for AethelError {
             AethelError::Namespace(s) => write!(f, "Namespace Error: {}", s),
             AethelError::Cgroup(s) => write!(f, "Cgroup Error: {}", s),
             AethelError::Process(s) => write!(f, "Process Error: {}", s),
+            AethelError::Network(s) => write!(f, "Network Error: {}", s),
+            AethelError::Registry(s) => write!(f, "Registry Error: {}", s),
+            AethelError::NotFound(s) => write!(f, "Not Found: {}", s),
+            AethelError::UnsupportedMediaType(s) => write!(f, "Unsupported Media Type: {}", s),
+            AethelError::InvalidDigest(s) => write!(f, "Invalid Digest: {}", s),
+            AethelError::DigestMismatch { expected, actual } => {
+                write!(f, "Digest Mismatch: expected {}, got {}", expected, actual)
+            }
+            AethelError::SizeMismatch { digest, expected, actual } => write!(
+                f,
+                "Size Mismatch: {} should be {} bytes, got {}",
+                digest, expected, actual
+            ),
+            AethelError::Build(s) => write!(f, "Build Error: {}", s),
+            AethelError::Volume(s) => write!(f, "Volume Error: {}", s),
         }
     }
 }
@@ -31,7 +55,26 @@ impl std::error::Error for AethelError {}
 
 impl From<io::Error> for AethelError {
     fn from(err: io::Error) -> AethelError {
-        AethelError::Io(err)
+        // Readers report our own errors wrapped in io::Error; unwrap them so
+        // callers can still match on the variant.
+        if !err.get_ref().is_some_and(|inner| inner.is::<AethelError>()) {
+            return AethelError::Io(err);
+        }
+        let kind = err.kind();
+        match err.into_inner().map(|inner| inner.downcast::<AethelError>()) {
+            Some(Ok(inner)) => *inner,
+            Some(Err(inner)) => AethelError::Io(io::Error::new(kind, inner)),
+            None => AethelError::Io(kind.into()),
+        }
+    }
+}
+
+impl From<AethelError> for io::Error {
+    fn from(err: AethelError) -> io::Error {
+        match err {
+            AethelError::Io(e) => e,
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
     }
 }
 
@@ -1,4 +1,5 @@
 pub mod error;
+pub mod time;
 
 pub mod proto {
     tonic::include_proto!("aethel");
@@ -0,0 +1,121 @@
+use std::os::unix::io::OwnedFd;
+use std::sync::Arc;
+
+use aethel_common::error::Result;
+use aethel_common::proto::aethel::{AttachOutput, InputEvent, LogEntry};
+use aethel_run::terminal;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+use tonic::{Status, Streaming};
+
+/// The daemon's end of a process's stdin, shared by every client attached
+/// to it.
+#[derive(Debug, Clone, Default)]
+pub struct Stdin {
+    /// `None` when the process has no stdin pipe, or it was closed.
+    writer: Arc<Mutex<Option<tokio::fs::File>>>,
+    /// The terminal's master, to resize it; set when the process has one.
+    terminal: Option<Arc<OwnedFd>>,
+}
+
+impl Stdin {
+    pub fn pipe(fd: Option<OwnedFd>) -> Self {
+        Stdin {
+            writer: Arc::new(Mutex::new(fd.map(file))),
+            terminal: None,
+        }
+    }
+
+    pub fn terminal(master: &OwnedFd) -> Result<Self> {
+        Ok(Stdin {
+            writer: Arc::new(Mutex::new(Some(file(master.try_clone()?)))),
+            terminal: Some(Arc::new(master.try_clone()?)),
+        })
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        self.terminal.is_some()
+    }
+
+    async fn handle(&self, event: InputEvent) -> Result<()> {
+        if let Some(master) = &self.terminal {
+            if event.rows > 0 && event.cols > 0 {
+                terminal::resize(&**master, event.rows as u16, event.cols as u16)?;
+            }
+        }
+        let mut writer = self.writer.lock().await;
+        if let Some(w) = writer.as_mut() {
+            if !event.data.is_empty() {
+                w.write_all(&event.data).await?;
+                w.flush().await?;
+            }
+        }
+        // A terminal has no EOF of its own; the client types it.
+        if event.close && self.terminal.is_none() {
+            *writer = None;
+        }
+        Ok(())
+    }
+}
+
+fn file(fd: OwnedFd) -> tokio::fs::File {
+    tokio::fs::File::from_std(std::fs::File::from(fd))
+}
+
+/// Feeds `first`, then the input of every request, to `stdin` until the
+/// client hangs up or the process stops reading.
+pub async fn forward_input<T>(
+    mut requests: Streaming<T>,
+    first: Option<InputEvent>,
+    stdin: Stdin,
+    input: fn(T) -> Option<InputEvent>,
+) {
+    if let Some(event) = first {
+        if stdin.handle(event).await.is_err() {
+            return;
+        }
+    }
+    while let Ok(Some(request)) = requests.message().await {
+        if let Some(event) = input(request) {
+            if stdin.handle(event).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Says whether the container has a terminal, then sends its output until
+/// it closes its end, or the client hangs up.
+pub async fn forward_output(
+    mut output: broadcast::Receiver<LogEntry>,
+    mut exited: watch::Receiver<bool>,
+    tty: bool,
+    tx: mpsc::Sender<std::result::Result<AttachOutput, Status>>,
+) {
+    let first = AttachOutput {
+        tty,
+        ..Default::default()
+    };
+    if tx.send(Ok(first)).await.is_err() {
+        return;
+    }
+    loop {
+        // Output first, so what was written just before the end still goes out.
+        let data = tokio::select! {
+            biased;
+            entry = output.recv() => match entry {
+                Ok(entry) => entry.data,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = exited.wait_for(|exited| *exited) => break,
+        };
+        let output = AttachOutput {
+            data,
+            ..Default::default()
+        };
+        if tx.send(Ok(output)).await.is_err() {
+            break;
+        }
+    }
+}
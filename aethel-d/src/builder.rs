@@ -0,0 +1,217 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use aethel_common::error::{AethelError, Result};
+use aethel_common::proto::aethel::BuildProgress;
+use aethel_run::container::{ContainerBuilder, ContainerIo};
+use aethel_storage::build::{self, BuildCache};
+use aethel_storage::commit::{self, CommitOptions};
+use aethel_storage::containerfile::{self, Containerfile, Instruction};
+use aethel_storage::layer::{LayerApplier, WhiteoutMode};
+use aethel_storage::oci::{ContainerConfig, Descriptor};
+use aethel_storage::snapshot::Snapshotter;
+use aethel_storage::store::ImageStore;
+use aethel_storage::{prepare_snapshot, resolve_image, snapshot_changes};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+
+/// One `aethel build`: a parsed Containerfile and the unpacked context its
+/// `COPY` and `ADD` read from.
+pub struct Build<'a> {
+    pub images: &'a ImageStore,
+    pub snapshotter: &'a Snapshotter,
+    pub context: PathBuf,
+    pub containerfile: Containerfile,
+    /// Runs every step even when the cache has its result.
+    pub no_cache: bool,
+}
+
+/// Tried in order when the client names no Containerfile.
+const CONTAINERFILES: [&str; 2] = ["Containerfile", "Dockerfile"];
+
+/// Unpacks the context tar from `reader` into `dir`, owned by root whatever
+/// the client's IDs, and parses its Containerfile: `name`, or the first of
+/// `CONTAINERFILES` present when empty.
+pub fn unpack_context<R: Read>(dir: &Path, reader: R, name: &str) -> Result<Containerfile> {
+    fs::create_dir_all(dir)?;
+    let mut reader = LayerApplier::new(dir)
+        .with_whiteout_mode(WhiteoutMode::Literal)
+        .with_preserve_ownership(false)
+        .apply(reader)?;
+    io::copy(&mut reader, &mut io::sink())?;
+
+    let candidates = if name.is_empty() { &CONTAINERFILES[..] } else { &[name][..] };
+    for candidate in candidates {
+        match build::resolve_source(dir, candidate) {
+            Ok(path) => return containerfile::parse(&fs::read_to_string(path)?),
+            Err(AethelError::NotFound(_)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(AethelError::Build(format!("no {} in the build context", candidates.join(" or "))))
+}
+
+fn progress(status: &str, message: String) -> BuildProgress {
+    BuildProgress {
+        status: status.to_string(),
+        message,
+        ..Default::default()
+    }
+}
+
+impl Build<'_> {
+    /// Runs the steps on top of `base`, one image per step, reporting as it
+    /// goes. Returns the image the last step produced.
+    pub fn run(&self, base: Descriptor, report: &dyn Fn(BuildProgress)) -> Result<Descriptor> {
+        let cache = BuildCache::new(self.images);
+        let steps = &self.containerfile.steps;
+        let mut current = base;
+
+        for (i, step) in steps.iter().enumerate() {
+            let text = step.text();
+            report(BuildProgress {
+                step: i as u32 + 1,
+                total: steps.len() as u32,
+                ..progress("Step", text.clone())
+            });
+
+            let (_, image) = resolve_image(self.images, &current, None)?;
+            let mut config = image.config;
+            let instruction = step.instruction(&config.env)?;
+            let content = match &instruction {
+                Instruction::Copy { sources, .. } | Instruction::Add { sources, .. } => {
+                    Some(build::hash_sources(&self.context, sources)?)
+                }
+                _ => None,
+            };
+
+            let key = build::cache_key(&current, &text, content.as_ref());
+            if !self.no_cache {
+                if let Some(cached) = cache.get(self.images, &key)? {
+                    report(BuildProgress {
+                        digest: cached.digest.clone(),
+                        ..progress("Cached", text)
+                    });
+                    current = cached;
+                    continue;
+                }
+            }
+
+            let options = CommitOptions {
+                created_by: Some(text),
+                ..Default::default()
+            };
+            current = if build::apply_config(&mut config, &instruction) {
+                commit::commit_config(self.images, &current, None, config, &options)?
+            } else {
+                self.layer_step(&current, &config, &instruction, &options, report)?
+            };
+            cache.insert(&key, &current)?;
+        }
+
+        Ok(current)
+    }
+
+    /// Runs a `RUN`, `COPY` or `ADD` in a snapshot of `parent` and commits
+    /// what it changed as a new layer. The snapshot is removed either way.
+    fn layer_step(
+        &self,
+        parent: &Descriptor,
+        config: &ContainerConfig,
+        instruction: &Instruction,
+        options: &CommitOptions,
+        report: &dyn Fn(BuildProgress),
+    ) -> Result<Descriptor> {
+        let id = format!("build-{}", uuid::Uuid::new_v4());
+        let working_dir = if config.working_dir.is_empty() { "/" } else { config.working_dir.as_str() };
+
+        let result = (|| {
+            let (rootfs, _) = prepare_snapshot(self.snapshotter, self.images, parent, &id, None)?;
+            match instruction {
+                Instruction::Run(argv) => run_command(&id, &rootfs, config, working_dir, argv, report)?,
+                Instruction::Copy { sources, destination } => {
+                    build::copy_sources(&self.context, &rootfs, sources, destination, working_dir, false)?
+                }
+                Instruction::Add { sources, destination } => {
+                    build::copy_sources(&self.context, &rootfs, sources, destination, working_dir, true)?
+                }
+                _ => unreachable!("config instructions are applied without a snapshot"),
+            }
+            let changes = snapshot_changes(self.snapshotter, self.images, parent, &id, None)?;
+            commit::commit(self.images, parent, None, &self.snapshotter.upper_dir(&id), &changes, options)
+        })();
+
+        let _ = self.snapshotter.remove(&id);
+        result
+    }
+}
+
+/// Runs `argv` in `rootfs` with the image's environment, working directory
+/// and user, reporting each line it prints. A non-zero exit fails the step.
+fn run_command(
+    id: &str,
+    rootfs: &Path,
+    config: &ContainerConfig,
+    working_dir: &str,
+    argv: &[String],
+    report: &dyn Fn(BuildProgress),
+) -> Result<()> {
+    build::ensure_working_dir(rootfs, working_dir)?;
+    let args: Vec<&str> = argv[1..].iter().map(String::as_str).collect();
+    let env: Vec<&str> = config.env.iter().map(String::as_str).collect();
+
+    let mut builder = ContainerBuilder::new(id, &argv[0])?
+        .args(&args)?
+        .env(&env)?
+        .with_rootfs(rootfs)
+        .with_working_dir(Path::new(working_dir));
+    if !config.user.is_empty() {
+        let (uid, gid) = build::resolve_user(rootfs, &config.user)?;
+        builder = builder.with_user(uid, gid);
+    }
+    let (child_pid, io) = builder.build()?;
+    let pid = Pid::from_raw(child_pid as i32);
+    let ContainerIo::Pipes { stdout, stderr, .. } = io else {
+        unreachable!("steps run without a terminal");
+    };
+
+    // Lines from both streams, in the order they arrive.
+    let (tx, rx) = mpsc::channel();
+    let output = thread::scope(|scope| -> Result<()> {
+        for fd in [stdout, stderr] {
+            let tx = tx.clone();
+            scope.spawn(move || {
+                for line in BufReader::new(File::from(fd)).split(b'\n') {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+        for line in rx {
+            report(progress("Output", String::from_utf8_lossy(&line?).into_owned()));
+        }
+        Ok(())
+    });
+    if let Err(e) = output {
+        // The command must not outlive its step.
+        let _ = kill(pid, Signal::SIGKILL);
+        let _ = waitpid(pid, None);
+        return Err(e);
+    }
+
+    let command = argv.join(" ");
+    match waitpid(pid, None)? {
+        WaitStatus::Exited(_, 0) => Ok(()),
+        WaitStatus::Exited(_, code) => Err(AethelError::Build(format!(
+            "'{}' returned a non-zero code: {}",
+            command, code
+        ))),
+        status => Err(AethelError::Build(format!("'{}' did not exit: {:?}", command, status))),
+    }
+}
@@ -0,0 +1,126 @@
+use std::io::{self, Read, Write};
+
+use aethel_common::proto::aethel::{ArchiveChunk, BuildImageRequest, CopyToContainerRequest};
+use tokio::sync::mpsc;
+use tonic::{Status, Streaming};
+
+/// Size of the chunks archives are streamed in.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A client-streamed message carrying the next chunk of an archive.
+pub trait Chunk: Send + 'static {
+    fn into_data(self) -> Vec<u8>;
+}
+
+impl Chunk for ArchiveChunk {
+    fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Chunk for CopyToContainerRequest {
+    fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Chunk for BuildImageRequest {
+    fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Lets blocking code read a client-streamed archive.
+pub struct ChunkReader {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChunkReader {
+    /// Forwards `stream` from a task on the runtime; the reader must be used
+    /// off it, e.g. in `spawn_blocking`.
+    pub fn new<C: Chunk>(mut stream: Streaming<C>) -> Self {
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                let item = match stream.message().await {
+                    Ok(Some(chunk)) => Ok(chunk.into_data()),
+                    Ok(None) => break,
+                    Err(status) => Err(io::Error::other(status.message().to_string())),
+                };
+                let failed = item.is_err();
+                if tx.send(item).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        ChunkReader {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Reads `data` before anything from the stream, for a first message
+    /// the caller already took off it.
+    pub fn with_initial(mut self, data: Vec<u8>) -> Self {
+        self.chunk = data;
+        self.pos = 0;
+        self
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.blocking_recv() {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Lets blocking code write an archive to a server stream.
+pub struct ChunkWriter {
+    tx: mpsc::Sender<Result<ArchiveChunk, Status>>,
+    buf: Vec<u8>,
+}
+
+impl ChunkWriter {
+    pub fn new(tx: mpsc::Sender<Result<ArchiveChunk, Status>>) -> Self {
+        ChunkWriter {
+            tx,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        self.tx
+            .blocking_send(Ok(ArchiveChunk { data }))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))
+    }
+}
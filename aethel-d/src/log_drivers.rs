@@ -0,0 +1,706 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::{Path, PathBuf};
+
+use aethel_common::error::{AethelError, Result};
+use aethel_common::proto::aethel::LogEntry;
+use serde::{Deserialize, Serialize};
+
+/// The driver for containers created without `--log-driver`, unless the
+/// daemon is started with `AETHEL_LOG_DRIVER`.
+pub const DEFAULT_DRIVER: &str = "json-file";
+
+/// The log file in a container's log directory; rotated files get `.1`,
+/// `.2` and so on appended, `.1` the newest.
+const LOG_FILE: &str = "json.log";
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// What every entry a driver sends elsewhere is tagged with, so it can be
+/// told apart from other containers' output there.
+pub struct LogContext {
+    pub id: String,
+    /// Containers have no names of their own; this is the short ID.
+    pub name: String,
+    pub image: String,
+}
+
+impl LogContext {
+    /// The default `tag`.
+    fn short_id(&self) -> &str {
+        &self.id[..self.id.len().min(12)]
+    }
+}
+
+/// Where a container's output goes, one entry at a time, as the daemon
+/// splits it into lines.
+pub trait LogDriver: Send {
+    fn write(&mut self, entry: &LogEntry) -> Result<()>;
+
+    /// What has been written so far, for drivers that keep it somewhere
+    /// the daemon can read it back; `None` for the rest.
+    fn read(&self) -> Result<Option<Replay>> {
+        Ok(None)
+    }
+
+    /// The file being written, for drivers that write one.
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Opens `driver` for a container, configured by its `--log-opt`s; `dir`
+/// is its log directory, and `defaults` the daemon's json-file rotation.
+pub fn open(
+    driver: &str,
+    opts: &HashMap<String, String>,
+    defaults: LogOptions,
+    dir: &Path,
+    context: &LogContext,
+) -> Result<Box<dyn LogDriver>> {
+    match driver {
+        "" | "json-file" => {
+            let mut options = defaults;
+            for (key, value) in opts {
+                options.set(key, value)?;
+            }
+            Ok(Box::new(JsonFile::create(dir, options)?))
+        }
+        "syslog" => Ok(Box::new(Syslog::open(opts, context)?)),
+        "journald" => Ok(Box::new(Journald::open(opts, context)?)),
+        "none" => match opts.keys().next() {
+            Some(key) => Err(unknown_option(key)),
+            None => Ok(Box::new(NoLog)),
+        },
+        _ => Err(AethelError::ContainerSetup(format!("unknown log driver {}", driver))),
+    }
+}
+
+fn unknown_option(key: &str) -> AethelError {
+    AethelError::ContainerSetup(format!("unknown log option {}", key))
+}
+
+/// The message an entry carries elsewhere: without the newline that ended
+/// it, and with replacement characters for what is not UTF-8.
+fn message(entry: &LogEntry) -> String {
+    let data = entry.data.strip_suffix(b"\n").unwrap_or(&entry.data);
+    String::from_utf8_lossy(data).into_owned()
+}
+
+/// How a container's log file is rotated: once a write would take it past
+/// `max_size` bytes it is renamed and a new one started, keeping at most
+/// `max_files` in all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogOptions {
+    pub max_size: u64,
+    pub max_files: u32,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions {
+            max_size: 20 << 20,
+            max_files: 5,
+        }
+    }
+}
+
+impl LogOptions {
+    /// The defaults, overridden by `AETHEL_LOG_MAX_SIZE` and
+    /// `AETHEL_LOG_MAX_FILES` in the form `--log-opt` takes.
+    pub fn from_env() -> Result<Self> {
+        let mut options = LogOptions::default();
+        for (var, key) in [("AETHEL_LOG_MAX_SIZE", "max-size"), ("AETHEL_LOG_MAX_FILES", "max-file")] {
+            if let Ok(value) = std::env::var(var) {
+                options.set(key, &value)?;
+            }
+        }
+        Ok(options)
+    }
+
+    /// Applies one `--log-opt`: `max-size`, a byte count with an optional
+    /// `k`, `m` or `g`, or `max-file`, a count.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let invalid = || AethelError::ContainerSetup(format!("invalid {} {:?}", key, value));
+        match key {
+            "max-size" => self.max_size = parse_size(value).ok_or_else(invalid)?,
+            "max-file" => self.max_files = value.parse().ok().filter(|&n| n > 0).ok_or_else(invalid)?,
+            _ => return Err(unknown_option(key)),
+        }
+        Ok(())
+    }
+}
+
+/// `10m`-style sizes, in powers of 1024 as docker reads them.
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'k' => (&value[..value.len() - 1], 10),
+        b'm' => (&value[..value.len() - 1], 20),
+        b'g' => (&value[..value.len() - 1], 30),
+        _ => (value.as_str(), 0),
+    };
+    digits.parse::<u64>().ok().filter(|&n| n > 0)?.checked_mul(1 << shift)
+}
+
+/// One line of a log file, as docker's json-file driver writes it. Output
+/// that is not UTF-8 is stored with replacement characters.
+#[derive(Serialize, Deserialize)]
+struct Line {
+    log: String,
+    stream: String,
+    time: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
+}
+
+fn rotated(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// The `json-file` driver: rotated JSON-lines files in the container's log
+/// directory, which `logs` reads back.
+struct JsonFile {
+    path: PathBuf,
+    file: File,
+    /// What has been written to `file`.
+    size: u64,
+    options: LogOptions,
+}
+
+impl JsonFile {
+    /// Starts an empty log in `dir`.
+    fn create(dir: &Path, options: LogOptions) -> Result<Self> {
+        let path = dir.join(LOG_FILE);
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(&path)?;
+        Ok(JsonFile {
+            path,
+            file,
+            size: 0,
+            options,
+        })
+    }
+
+    /// Shifts every file up one, dropping the oldest; with `max_files` 1
+    /// the log just starts over.
+    fn rotate(&mut self) -> Result<()> {
+        for n in (1..self.options.max_files).rev() {
+            let from = rotated(&self.path, n - 1);
+            if from.exists() {
+                fs::rename(&from, rotated(&self.path, n))?;
+            }
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl LogDriver for JsonFile {
+    fn write(&mut self, entry: &LogEntry) -> Result<()> {
+        let line = Line {
+            log: String::from_utf8_lossy(&entry.data).into_owned(),
+            stream: entry.stream.clone(),
+            time: entry.timestamp.clone(),
+            partial: entry.partial,
+        };
+        let mut line = serde_json::to_vec(&line)
+            .map_err(|e| AethelError::Filesystem(format!("Failed to encode log line: {}", e)))?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.options.max_size {
+            self.rotate()
+                .map_err(|e| AethelError::Filesystem(format!("Failed to rotate {}: {}", self.path.display(), e)))?;
+        }
+        self.file
+            .write_all(&line)
+            .map_err(|e| AethelError::Filesystem(format!("Failed to write {}: {}", self.path.display(), e)))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn read(&self) -> Result<Option<Replay>> {
+        // Opened now, a file still reads the same if it is rotated away.
+        let mut files = Vec::new();
+        for n in (1..self.options.max_files).rev() {
+            match File::open(rotated(&self.path, n)) {
+                Ok(f) => {
+                    let len = f.metadata()?.len();
+                    files.push(f.take(len));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        files.push(File::open(&self.path)?.take(self.size));
+        Ok(Some(Replay { files }))
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+/// Entries read back from a container's log files.
+pub struct Replay {
+    files: Vec<io::Take<File>>,
+}
+
+impl Replay {
+    /// Oldest first.
+    pub fn entries(self) -> impl Iterator<Item = Result<LogEntry>> {
+        self.files.into_iter().flat_map(|f| BufReader::new(f).lines()).map(|line| {
+            let line: Line = serde_json::from_str(&line?)
+                .map_err(|e| AethelError::Filesystem(format!("Corrupt log line: {}", e)))?;
+            Ok(LogEntry {
+                stream: line.stream,
+                timestamp: line.time,
+                data: line.log.into_bytes(),
+                partial: line.partial,
+            })
+        })
+    }
+}
+
+/// Facilities by the names `syslog-facility` takes, as RFC 5424 numbers
+/// them.
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp", "ntp",
+    "security", "console", "solaris-cron", "local0", "local1", "local2", "local3", "local4", "local5", "local6",
+    "local7",
+];
+
+/// The structured data ID container fields go under; 32473 is the
+/// enterprise number set aside for examples and private use.
+const SYSLOG_SD_ID: &str = "aethel@32473";
+
+enum SyslogSocket {
+    Datagram(UnixDatagram),
+    /// Messages end with a newline, which is how syslog daemons frame them
+    /// on a stream socket.
+    Stream(UnixStream),
+    Udp(UdpSocket),
+}
+
+/// The `syslog` driver: an RFC 5424 message per entry, stdout at severity
+/// info and stderr at err, with the container's fields as structured data.
+struct Syslog {
+    socket: SyslogSocket,
+    facility: u8,
+    /// Everything after the timestamp up to the message, the same for
+    /// every entry.
+    header: String,
+}
+
+impl Syslog {
+    /// Takes `syslog-address` (`unix://PATH`, `unixgram://PATH` or
+    /// `udp://HOST[:PORT]`, by default the local `/dev/log`),
+    /// `syslog-facility` (by default `daemon`) and `tag` (by default the
+    /// short ID).
+    fn open(opts: &HashMap<String, String>, context: &LogContext) -> Result<Self> {
+        let invalid = |key: &str, value: &str| AethelError::ContainerSetup(format!("invalid {} {:?}", key, value));
+        let mut address = format!("unix://{}", SYSLOG_SOCKET);
+        let mut facility = 3;
+        let mut tag = context.short_id().to_string();
+        for (key, value) in opts {
+            match key.as_str() {
+                "syslog-address" => address = value.clone(),
+                "syslog-facility" => {
+                    facility = FACILITIES
+                        .iter()
+                        .position(|f| f == value)
+                        .ok_or_else(|| invalid(key, value))? as u8
+                }
+                "tag" => tag = value.clone(),
+                _ => return Err(unknown_option(key)),
+            }
+        }
+
+        let connect_error = |e: io::Error| AethelError::ContainerSetup(format!("cannot reach syslog at {}: {}", address, e));
+        let socket = if let Some(path) = address.strip_prefix("unixgram://") {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path).map_err(connect_error)?;
+            SyslogSocket::Datagram(socket)
+        } else if let Some(path) = address.strip_prefix("unix://") {
+            // Most syslog daemons listen for datagrams; some for a stream.
+            let socket = UnixDatagram::unbound()?;
+            match socket.connect(path) {
+                Ok(()) => SyslogSocket::Datagram(socket),
+                Err(_) => SyslogSocket::Stream(UnixStream::connect(path).map_err(connect_error)?),
+            }
+        } else if let Some(host) = address.strip_prefix("udp://") {
+            let host = if host.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+                host.to_string()
+            } else {
+                format!("{}:514", host)
+            };
+            let remote = host
+                .to_socket_addrs()
+                .map_err(connect_error)?
+                .next()
+                .ok_or_else(|| invalid("syslog-address", &address))?;
+            let local = if remote.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            let socket = UdpSocket::bind(local)?;
+            socket.connect(remote).map_err(connect_error)?;
+            SyslogSocket::Udp(socket)
+        } else {
+            return Err(invalid("syslog-address", &address));
+        };
+
+        let hostname = nix::unistd::gethostname()?.to_string_lossy().into_owned();
+        let header = format!(
+            "{} {} - - [{} container_id=\"{}\" container_name=\"{}\" image=\"{}\"]",
+            syslog_name(&hostname, 255),
+            syslog_name(&tag, 48),
+            SYSLOG_SD_ID,
+            sd_escape(&context.id),
+            sd_escape(&context.name),
+            sd_escape(&context.image),
+        );
+        Ok(Syslog {
+            socket,
+            facility,
+            header,
+        })
+    }
+}
+
+impl LogDriver for Syslog {
+    fn write(&mut self, entry: &LogEntry) -> Result<()> {
+        let severity = if entry.stream == "stderr" { 3 } else { 6 };
+        let mut line = format!(
+            "<{}>1 {} {} {}",
+            self.facility as u32 * 8 + severity,
+            syslog_timestamp(&entry.timestamp),
+            self.header,
+            message(entry)
+        )
+        .into_bytes();
+        match &mut self.socket {
+            SyslogSocket::Datagram(socket) => socket.send(&line).map(drop)?,
+            SyslogSocket::Stream(socket) => {
+                line.push(b'\n');
+                socket.write_all(&line)?
+            }
+            SyslogSocket::Udp(socket) => socket.send(&line).map(drop)?,
+        }
+        Ok(())
+    }
+}
+
+/// RFC 5424 allows at most six fractional digits; entries are stamped with
+/// nine.
+fn syslog_timestamp(timestamp: &str) -> String {
+    match timestamp.split_once('.') {
+        Some((seconds, fraction)) => {
+            let digits = fraction.trim_end_matches('Z');
+            format!("{}.{}Z", seconds, &digits[..digits.len().min(6)])
+        }
+        None => timestamp.to_string(),
+    }
+}
+
+/// A header field: printable ASCII without spaces, at most `max` long, and
+/// `-` when there is nothing left.
+fn syslog_name(value: &str, max: usize) -> String {
+    let name: String = value.chars().filter(|c| c.is_ascii_graphic()).take(max).collect();
+    if name.is_empty() {
+        "-".to_string()
+    } else {
+        name
+    }
+}
+
+/// A structured data parameter value, with the characters RFC 5424
+/// reserves escaped.
+fn sd_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The `journald` driver: a datagram per entry in the journal's native
+/// protocol, with the container's fields alongside the message.
+struct Journald {
+    socket: UnixDatagram,
+    /// The fields every entry carries.
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Journald {
+    /// Takes `tag`, the entries' `SYSLOG_IDENTIFIER`, by default the short
+    /// ID.
+    fn open(opts: &HashMap<String, String>, context: &LogContext) -> Result<Self> {
+        let mut tag = context.short_id().to_string();
+        for (key, value) in opts {
+            match key.as_str() {
+                "tag" => tag = value.clone(),
+                _ => return Err(unknown_option(key)),
+            }
+        }
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET).map_err(|e| {
+            AethelError::ContainerSetup(format!("cannot reach the journal at {}: {}", JOURNAL_SOCKET, e))
+        })?;
+        Ok(Journald {
+            socket,
+            fields: vec![
+                ("SYSLOG_IDENTIFIER", tag),
+                ("CONTAINER_ID", context.short_id().to_string()),
+                ("CONTAINER_ID_FULL", context.id.clone()),
+                ("CONTAINER_NAME", context.name.clone()),
+                ("IMAGE_NAME", context.image.clone()),
+            ],
+        })
+    }
+}
+
+impl LogDriver for Journald {
+    fn write(&mut self, entry: &LogEntry) -> Result<()> {
+        let mut datagram = Vec::new();
+        journal_field(&mut datagram, "MESSAGE", message(entry).as_bytes());
+        journal_field(&mut datagram, "PRIORITY", if entry.stream == "stderr" { b"3" } else { b"6" });
+        for (key, value) in &self.fields {
+            journal_field(&mut datagram, key, value.as_bytes());
+        }
+        if entry.partial {
+            journal_field(&mut datagram, "CONTAINER_PARTIAL_MESSAGE", b"true");
+        }
+        self.socket.send(&datagram)?;
+        Ok(())
+    }
+}
+
+/// Appends `KEY=value`, or for a value with a newline in it, the key, a
+/// newline, the value's length as 64-bit little endian and the value.
+fn journal_field(datagram: &mut Vec<u8>, key: &str, value: &[u8]) {
+    datagram.extend_from_slice(key.as_bytes());
+    if value.contains(&b'\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value);
+    datagram.push(b'\n');
+}
+
+/// The `none` driver: output is only there for whoever is attached.
+struct NoLog;
+
+impl LogDriver for NoLog {
+    fn write(&mut self, _entry: &LogEntry) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn entry(stream: &str, timestamp: &str, data: &str, partial: bool) -> LogEntry {
+        LogEntry {
+            stream: stream.to_string(),
+            timestamp: timestamp.to_string(),
+            data: data.as_bytes().to_vec(),
+            partial,
+        }
+    }
+
+    fn context() -> LogContext {
+        LogContext {
+            id: "0123456789abcdef0123456789abcdef".to_string(),
+            name: "0123456789ab".to_string(),
+            image: "busybox:latest".to_string(),
+        }
+    }
+
+    fn replayed(driver: &dyn LogDriver) -> Vec<String> {
+        let replay = driver.read().unwrap().unwrap();
+        replay
+            .entries()
+            .map(|entry| String::from_utf8(entry.unwrap().data).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("10k"), Some(10 << 10));
+        assert_eq!(parse_size("10M"), Some(10 << 20));
+        assert_eq!(parse_size("2g"), Some(2 << 30));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("k"), None);
+        assert_eq!(parse_size("0m"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("1.5m"), None);
+        assert_eq!(parse_size("10t"), None);
+        assert_eq!(parse_size("99999999999g"), None);
+    }
+
+    #[test]
+    fn sets_log_options() {
+        let mut options = LogOptions::default();
+        options.set("max-size", "1m").unwrap();
+        options.set("max-file", "3").unwrap();
+        assert_eq!(options, LogOptions { max_size: 1 << 20, max_files: 3 });
+
+        for (key, value) in [("max-size", "big"), ("max-file", "0"), ("max-file", "-2")] {
+            let err = options.set(key, value).unwrap_err();
+            assert!(matches!(&err, AethelError::ContainerSetup(msg) if msg.starts_with("invalid")), "{}", err);
+        }
+        let err = options.set("compress", "true").unwrap_err();
+        assert!(err.to_string().contains("unknown log option compress"), "{}", err);
+        assert_eq!(options, LogOptions { max_size: 1 << 20, max_files: 3 });
+    }
+
+    #[test]
+    fn opens_drivers_by_name() {
+        let dir = TempDir::new().unwrap();
+        let no_opts = HashMap::new();
+        let json = open("", &no_opts, LogOptions::default(), dir.path(), &context()).unwrap();
+        assert_eq!(json.path(), Some(dir.path().join(LOG_FILE).as_path()));
+        assert!(open("none", &no_opts, LogOptions::default(), dir.path(), &context()).unwrap().read().unwrap().is_none());
+
+        let opts = HashMap::from([("max-size".to_string(), "1k".to_string())]);
+        assert!(open("none", &opts, LogOptions::default(), dir.path(), &context()).is_err());
+        assert!(open("fluentd", &no_opts, LogOptions::default(), dir.path(), &context()).is_err());
+        let opts = HashMap::from([("max-size".to_string(), "huge".to_string())]);
+        assert!(open("json-file", &opts, LogOptions::default(), dir.path(), &context()).is_err());
+    }
+
+    #[test]
+    fn json_file_rotates_and_replays_oldest_first() {
+        let dir = TempDir::new().unwrap();
+        // Each line is about 80 bytes, so a file holds two of them.
+        let options = LogOptions { max_size: 200, max_files: 3 };
+        let mut driver = JsonFile::create(dir.path(), options).unwrap();
+        for i in 0..7 {
+            driver
+                .write(&entry("stdout", "2024-01-02T03:04:05.000000000Z", &format!("line {}\n", i), false))
+                .unwrap();
+        }
+
+        let path = dir.path().join(LOG_FILE);
+        assert!(rotated(&path, 1).exists());
+        assert!(rotated(&path, 2).exists());
+        assert!(!rotated(&path, 3).exists());
+        // The oldest file went; what is left is in order across the rest.
+        assert_eq!(replayed(&driver), ["line 2\n", "line 3\n", "line 4\n", "line 5\n", "line 6\n"]);
+        assert_eq!(fs::read_to_string(rotated(&path, 2)).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn json_file_with_one_file_starts_over() {
+        let dir = TempDir::new().unwrap();
+        let mut driver = JsonFile::create(dir.path(), LogOptions { max_size: 100, max_files: 1 }).unwrap();
+        for i in 0..3 {
+            driver.write(&entry("stderr", "2024-01-02T03:04:05Z", &format!("line {}\n", i), false)).unwrap();
+        }
+        assert!(!rotated(&dir.path().join(LOG_FILE), 1).exists());
+        assert_eq!(replayed(&driver), ["line 2\n"]);
+    }
+
+    #[test]
+    fn json_file_keeps_stream_time_and_partial() {
+        let dir = TempDir::new().unwrap();
+        let mut driver = JsonFile::create(dir.path(), LogOptions::default()).unwrap();
+        driver.write(&entry("stderr", "2024-01-02T03:04:05.5Z", "prompt> ", true)).unwrap();
+
+        let line = fs::read_to_string(dir.path().join(LOG_FILE)).unwrap();
+        assert_eq!(
+            line,
+            "{\"log\":\"prompt> \",\"stream\":\"stderr\",\"time\":\"2024-01-02T03:04:05.5Z\",\"partial\":true}\n"
+        );
+        let replayed: Vec<LogEntry> = driver.read().unwrap().unwrap().entries().map(Result::unwrap).collect();
+        assert_eq!(replayed, [entry("stderr", "2024-01-02T03:04:05.5Z", "prompt> ", true)]);
+    }
+
+    #[test]
+    fn formats_syslog_fields() {
+        assert_eq!(syslog_timestamp("2024-01-02T03:04:05.123456789Z"), "2024-01-02T03:04:05.123456Z");
+        assert_eq!(syslog_timestamp("2024-01-02T03:04:05.5Z"), "2024-01-02T03:04:05.5Z");
+        assert_eq!(syslog_timestamp("2024-01-02T03:04:05Z"), "2024-01-02T03:04:05Z");
+
+        assert_eq!(sd_escape(r#"say "hi" \ [ok]"#), r#"say \"hi\" \\ [ok\]"#);
+        assert_eq!(syslog_name("web app", 48), "webapp");
+        assert_eq!(syslog_name("  ", 48), "-");
+        assert_eq!(syslog_name(&"x".repeat(60), 48).len(), 48);
+    }
+
+    #[test]
+    fn sends_rfc5424_lines_to_syslog() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("log.sock");
+        let server = UnixDatagram::bind(&path).unwrap();
+        let opts = HashMap::from([
+            ("syslog-address".to_string(), format!("unixgram://{}", path.display())),
+            ("syslog-facility".to_string(), "local0".to_string()),
+            ("tag".to_string(), "web".to_string()),
+        ]);
+        let mut context = context();
+        context.image = r#"odd"name]"#.to_string();
+        let mut driver = Syslog::open(&opts, &context).unwrap();
+
+        driver.write(&entry("stdout", "2024-01-02T03:04:05.123456789Z", "hello\n", false)).unwrap();
+        driver.write(&entry("stderr", "2024-01-02T03:04:06Z", "oops\n", false)).unwrap();
+
+        let hostname = nix::unistd::gethostname().unwrap().to_string_lossy().into_owned();
+        let mut buf = [0; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..n]),
+            format!(
+                "<134>1 2024-01-02T03:04:05.123456Z {} web - - [aethel@32473 \
+                 container_id=\"0123456789abcdef0123456789abcdef\" container_name=\"0123456789ab\" \
+                 image=\"odd\\\"name\\]\"] hello",
+                syslog_name(&hostname, 255)
+            )
+        );
+        let n = server.recv(&mut buf).unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("<131>1 2024-01-02T03:04:06Z "));
+
+        let opts = HashMap::from([("syslog-facility".to_string(), "local9".to_string())]);
+        assert!(Syslog::open(&opts, &context).is_err());
+        let opts = HashMap::from([("syslog-address".to_string(), "tcp://localhost".to_string())]);
+        assert!(Syslog::open(&opts, &context).is_err());
+    }
+
+    #[test]
+    fn encodes_journal_fields() {
+        let mut datagram = Vec::new();
+        journal_field(&mut datagram, "MESSAGE", b"hello");
+        journal_field(&mut datagram, "MESSAGE", b"two\nlines");
+
+        let mut expected = b"MESSAGE=hello\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        assert_eq!(datagram, expected);
+    }
+
+    #[test]
+    fn messages_drop_the_trailing_newline() {
+        assert_eq!(message(&entry("stdout", "", "hello\n", false)), "hello");
+        assert_eq!(message(&entry("stdout", "", "a\n\n", false)), "a\n");
+        let invalid = LogEntry {
+            data: vec![b'a', 0xff],
+            ..Default::default()
+        };
+        assert_eq!(message(&invalid), "a\u{fffd}");
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::os::unix::io::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use aethel_common::error::Result;
+use aethel_common::proto::aethel::LogEntry;
+use aethel_common::time;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::log_drivers::{self, LogContext, LogDriver, LogOptions, Replay};
+
+/// Entries waiting for the writer thread; past this many, the output
+/// forwarders wait, and so in turn does the container.
+const WRITE_QUEUE: usize = 1024;
+
+/// A container's output: handed to its log driver, then broadcast to
+/// everyone following it live. Drivers write files and sockets that can
+/// block, so a thread of the log's own does both.
+pub struct ContainerLog {
+    dir: PathBuf,
+    path: Option<PathBuf>,
+    driver: Arc<Mutex<Box<dyn LogDriver>>>,
+    broadcaster: broadcast::Sender<LogEntry>,
+    writer: mpsc::Sender<LogEntry>,
+}
+
+impl ContainerLog {
+    /// Starts an empty log in `dir`, kept by `driver` as `opts` say. The
+    /// writer thread ends once the log is dropped.
+    pub fn create(
+        dir: &Path,
+        driver: &str,
+        opts: &HashMap<String, String>,
+        defaults: LogOptions,
+        context: &LogContext,
+    ) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let driver = log_drivers::open(driver, opts, defaults, dir, context)?;
+        let path = driver.path().map(Path::to_path_buf);
+        let driver = Arc::new(Mutex::new(driver));
+        let (broadcaster, _) = broadcast::channel(1024);
+        let (writer, mut queue) = mpsc::channel::<LogEntry>(WRITE_QUEUE);
+
+        let (thread_driver, thread_broadcaster, thread_dir) = (driver.clone(), broadcaster.clone(), dir.to_path_buf());
+        thread::Builder::new().name("log-writer".to_string()).spawn(move || {
+            while let Some(entry) = queue.blocking_recv() {
+                // Broadcast under the lock, for `replay` to see each entry
+                // either in what was written or in what comes after.
+                let mut driver = thread_driver.lock().unwrap();
+                if let Err(e) = driver.write(&entry) {
+                    eprintln!("failed to log for {}: {}", thread_dir.display(), e);
+                }
+                let _ = thread_broadcaster.send(entry);
+            }
+        })?;
+
+        Ok(ContainerLog {
+            dir: dir.to_path_buf(),
+            path,
+            driver,
+            broadcaster,
+            writer,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The file the driver writes, if it writes one.
+    pub fn path(&self) -> Option<PathBuf> {
+        self.path.clone()
+    }
+
+    /// Queues `entry` for the driver and live followers.
+    pub async fn append(&self, entry: LogEntry) {
+        let _ = self.writer.send(entry).await;
+    }
+
+    /// Live entries only.
+    pub fn subscribe(&self) -> broadcast::Receiver<LogEntry> {
+        self.broadcaster.subscribe()
+    }
+
+    /// What the driver has kept so far, `None` if it keeps nothing the
+    /// daemon can read back, and a subscription to what comes after it,
+    /// with nothing missed or repeated in between. Waits for a write under
+    /// way, so call it off the async workers.
+    pub fn replay(&self) -> Result<(Option<Replay>, broadcast::Receiver<LogEntry>)> {
+        let driver = self.driver.lock().unwrap();
+        Ok((driver.read()?, self.broadcaster.subscribe()))
+    }
+}
+
+/// Which entries a `logs` request wants.
+pub struct LogFilter {
+    /// Bounds in the form entries are stamped with, so they compare as
+    /// strings.
+    since: Option<String>,
+    until: Option<String>,
+    streams: Vec<String>,
+}
+
+impl LogFilter {
+    pub fn new(since: &str, until: &str, streams: &[String]) -> std::result::Result<Self, String> {
+        let bound = |name: &str, value: &str| {
+            if value.is_empty() {
+                return Ok(None);
+            }
+            time::parse_rfc3339(value)
+                .map(|t| Some(time::format_rfc3339(t)))
+                .ok_or_else(|| format!("invalid {} time {:?}", name, value))
+        };
+        if let Some(stream) = streams.iter().find(|s| *s != "stdout" && *s != "stderr") {
+            return Err(format!("unknown stream {:?}", stream));
+        }
+        Ok(LogFilter {
+            since: bound("since", since)?,
+            until: bound("until", until)?,
+            streams: streams.to_vec(),
+        })
+    }
+
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        (self.streams.is_empty() || self.streams.contains(&entry.stream))
+            && self.since.as_ref().is_none_or(|since| entry.timestamp >= *since)
+            && !self.is_past_until(&entry.timestamp)
+    }
+
+    /// Whether nothing stamped `timestamp` or later is wanted.
+    pub fn is_past_until(&self, timestamp: &str) -> bool {
+        self.until.as_ref().is_some_and(|until| timestamp >= until.as_str())
+    }
+
+    /// How long until `until`, if there is one.
+    pub fn until_in(&self) -> Option<Duration> {
+        let until = time::parse_rfc3339(self.until.as_ref()?)?;
+        Some(until.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+}
+
+/// The last `lines` lines of `entries`, a line being the entries up to one
+/// that is not partial.
+pub fn tail(entries: impl Iterator<Item = Result<LogEntry>>, lines: usize) -> Result<VecDeque<LogEntry>> {
+    let mut kept = VecDeque::new();
+    if lines == 0 {
+        return Ok(kept);
+    }
+    let mut complete = 0;
+    for entry in entries {
+        let entry = entry?;
+        if !entry.partial {
+            complete += 1;
+        }
+        kept.push_back(entry);
+        while complete > lines {
+            if !kept.pop_front().is_some_and(|e| e.partial) {
+                complete -= 1;
+            }
+        }
+    }
+    Ok(kept)
+}
+
+/// Longer lines are split into entries of this size, as docker does.
+const MAX_LINE: usize = 16 * 1024;
+/// How long the start of a line waits for its end before it is sent as a
+/// partial entry, so a prompt without a newline still shows up.
+const PARTIAL_FLUSH: Duration = Duration::from_millis(100);
+
+fn entry(stream: &str, data: Vec<u8>, partial: bool) -> LogEntry {
+    LogEntry {
+        stream: stream.to_string(),
+        timestamp: time::format_rfc3339(SystemTime::now()),
+        data,
+        partial,
+    }
+}
+
+/// Takes the complete lines off the front of `pending`, and `MAX_LINE`
+/// pieces of any line that long, leaving the unfinished rest. The flag
+/// marks pieces that are partial.
+fn split_lines(pending: &mut Vec<u8>) -> Vec<(Vec<u8>, bool)> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    loop {
+        let rest = &pending[start..];
+        let (end, partial) = match rest.iter().position(|&b| b == b'\n') {
+            Some(i) if i < MAX_LINE => (i + 1, false),
+            _ if rest.len() >= MAX_LINE => (MAX_LINE, true),
+            _ => break,
+        };
+        lines.push((rest[..end].to_vec(), partial));
+        start += end;
+    }
+    pending.drain(..start);
+    lines
+}
+
+/// Appends what the container writes to `output` to its log, a line per
+/// entry of `stream`, until it closes it.
+pub async fn forward(output: OwnedFd, stream: &'static str, log: Arc<ContainerLog>) {
+    let mut reader = tokio::fs::File::from_std(std::fs::File::from(output));
+    let mut pending = Vec::new();
+    let mut buf = [0; 8192];
+
+    loop {
+        let read = if pending.is_empty() {
+            reader.read(&mut buf).await
+        } else {
+            // An unfinished read picks up where it left off next time.
+            match tokio::time::timeout(PARTIAL_FLUSH, reader.read(&mut buf)).await {
+                Ok(read) => read,
+                Err(_) => {
+                    log.append(entry(stream, std::mem::take(&mut pending), true)).await;
+                    continue;
+                }
+            }
+        };
+        // A terminal's master reads EIO once the container's side is closed.
+        let n = match read {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        pending.extend_from_slice(&buf[..n]);
+        for (line, partial) in split_lines(&mut pending) {
+            log.append(entry(stream, line, partial)).await;
+        }
+    }
+    if !pending.is_empty() {
+        log.append(entry(stream, pending, false)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aethel_common::error::AethelError;
+    use tempfile::TempDir;
+
+    fn stamped(stream: &str, timestamp: &str, data: &str, partial: bool) -> LogEntry {
+        LogEntry {
+            stream: stream.to_string(),
+            timestamp: timestamp.to_string(),
+            data: data.as_bytes().to_vec(),
+            partial,
+        }
+    }
+
+    fn data(entries: &VecDeque<LogEntry>) -> Vec<String> {
+        entries.iter().map(|e| String::from_utf8(e.data.clone()).unwrap()).collect()
+    }
+
+    #[test]
+    fn splits_complete_lines() {
+        let mut pending = b"one\ntwo\nthr".to_vec();
+        assert_eq!(split_lines(&mut pending), [(b"one\n".to_vec(), false), (b"two\n".to_vec(), false)]);
+        assert_eq!(pending, b"thr");
+        assert!(split_lines(&mut pending).is_empty());
+        assert_eq!(pending, b"thr");
+    }
+
+    #[test]
+    fn splits_long_lines_at_max_line() {
+        // A line of exactly MAX_LINE bytes with its newline is one byte too
+        // many for a single entry.
+        let mut pending = vec![b'a'; MAX_LINE];
+        pending.push(b'\n');
+        let lines = split_lines(&mut pending);
+        assert_eq!(lines.len(), 2);
+        assert_eq!((lines[0].0.len(), lines[0].1), (MAX_LINE, true));
+        assert_eq!(lines[1], (b"\n".to_vec(), false));
+        assert!(pending.is_empty());
+
+        let mut pending = vec![b'b'; MAX_LINE - 1];
+        pending.push(b'\n');
+        let lines = split_lines(&mut pending);
+        assert_eq!(lines.len(), 1);
+        assert_eq!((lines[0].0.len(), lines[0].1), (MAX_LINE, false));
+
+        // A full piece goes out before its newline arrives; the rest waits.
+        let mut pending = vec![b'c'; MAX_LINE * 2 + 10];
+        let lines = split_lines(&mut pending);
+        assert_eq!(lines.iter().map(|(l, p)| (l.len(), *p)).collect::<Vec<_>>(), [(MAX_LINE, true), (MAX_LINE, true)]);
+        assert_eq!(pending.len(), 10);
+    }
+
+    #[test]
+    fn tails_whole_lines() {
+        let entries = [
+            stamped("stdout", "", "one\n", false),
+            stamped("stdout", "", "tw", true),
+            stamped("stdout", "", "o\n", false),
+            stamped("stdout", "", "thr", true),
+            stamped("stdout", "", "ee\n", false),
+            stamped("stdout", "", "fo", true),
+        ];
+        let tail_of = |lines| tail(entries.iter().cloned().map(Ok), lines).unwrap();
+
+        assert!(tail_of(0).is_empty());
+        // The unfinished line at the end comes with the last complete one.
+        assert_eq!(data(&tail_of(1)), ["thr", "ee\n", "fo"]);
+        assert_eq!(data(&tail_of(2)), ["tw", "o\n", "thr", "ee\n", "fo"]);
+        assert_eq!(tail_of(10).len(), entries.len());
+
+        let failing = vec![Ok(stamped("stdout", "", "one\n", false)), Err(AethelError::Filesystem("corrupt".into()))];
+        assert!(tail(failing.into_iter(), 1).is_err());
+    }
+
+    #[test]
+    fn filters_by_stream_and_time() {
+        let filter = LogFilter::new(
+            "2024-01-02T03:04:05Z",
+            "2024-01-02T03:04:07+00:00",
+            &["stderr".to_string()],
+        )
+        .unwrap();
+        let at = |timestamp| stamped("stderr", timestamp, "x", false);
+
+        assert!(!filter.matches(&at("2024-01-02T03:04:04.999999999Z")));
+        assert!(filter.matches(&at("2024-01-02T03:04:05.000000000Z")));
+        assert!(filter.matches(&at("2024-01-02T03:04:06.999999999Z")));
+        // `until` is exclusive.
+        assert!(!filter.matches(&at("2024-01-02T03:04:07.000000000Z")));
+        assert!(filter.is_past_until("2024-01-02T03:04:07.000000000Z"));
+        assert!(!filter.matches(&stamped("stdout", "2024-01-02T03:04:06.000000000Z", "x", false)));
+        assert_eq!(filter.until_in(), Some(Duration::ZERO));
+
+        let open = LogFilter::new("", "", &[]).unwrap();
+        assert!(open.matches(&stamped("stdout", "1970-01-01T00:00:00.000000000Z", "x", false)));
+        assert!(!open.is_past_until("9999-12-31T23:59:59.999999999Z"));
+        assert_eq!(open.until_in(), None);
+
+        assert!(LogFilter::new("yesterday", "", &[]).is_err());
+        assert!(LogFilter::new("", "", &["stdin".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn replays_what_was_written_then_follows() {
+        let dir = TempDir::new().unwrap();
+        let context = LogContext {
+            id: "0123456789abcdef".to_string(),
+            name: "0123456789ab".to_string(),
+            image: "busybox".to_string(),
+        };
+        let log = ContainerLog::create(dir.path(), "json-file", &HashMap::new(), LogOptions::default(), &context).unwrap();
+        let mut live = log.subscribe();
+
+        log.append(stamped("stdout", "2024-01-02T03:04:05Z", "one\n", false)).await;
+        assert_eq!(live.recv().await.unwrap().data, b"one\n");
+        let (replay, mut following) = log.replay().unwrap();
+        log.append(stamped("stderr", "2024-01-02T03:04:06Z", "two\n", false)).await;
+
+        let replayed: Vec<LogEntry> = replay.unwrap().entries().map(Result::unwrap).collect();
+        assert_eq!(replayed, [stamped("stdout", "2024-01-02T03:04:05Z", "one\n", false)]);
+        assert_eq!(following.recv().await.unwrap().data, b"two\n");
+        assert_eq!(log.path(), Some(dir.path().join("json.log")));
+    }
+}
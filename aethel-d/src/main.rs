@@ -1,52 +1,591 @@
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::net::Ipv4Addr;
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::io::OwnedFd;
+use std::time::{Duration, SystemTime};
 
+use aethel_common::error::{AethelError, Result as AethelResult};
+use aethel_common::time;
 use aethel_common::proto::aethel::aethel_service_server::{AethelService, AethelServiceServer};
-use aethel_common::proto::aethel::{CreateContainerRequest, CreateContainerResponse, Empty, ContainerInfo, StopRequest, StopResponse, LogsRequest, LogEntry};
+use aethel_common::proto::aethel::{CreateContainerRequest, CreateContainerResponse, Empty, ContainerInfo, InspectContainerRequest, InspectContainerResponse, MountInfo, PauseContainerRequest, PauseContainerResponse, UnpauseContainerRequest, UnpauseContainerResponse, CheckNetworkRequest, CheckNetworkResponse, StatsRequest, StatsSample, StopRequest, StopResponse, LogsRequest, LogEntry, PullImageRequest, PullProgress, PushImageRequest, PushProgress, ImageInfo, InspectImageRequest, InspectImageResponse, TagImageRequest, TagImageResponse, RemoveImageRequest, RemoveImageResponse, ArchiveChunk, LoadedImage, SaveImagesRequest, PruneRequest, PruneResponse, CommitContainerRequest, CommitContainerResponse, DiffContainerRequest, DiffContainerResponse, FilesystemChange, ExportContainerRequest, CopyToContainerRequest, CopyToContainerResponse, CopyFromContainerRequest, BuildImageRequest, BuildProgress, CreateVolumeRequest, VolumeInfo, InspectVolumeRequest, RemoveVolumeRequest, RemoveVolumeResponse, PruneVolumesResponse, ExecRequest, ExecOutput, AttachRequest, AttachOutput};
+use aethel_net::cni::Cni;
 use aethel_run::ContainerBuilder;
-use aethel_storage::prepare_rootfs;
+use aethel_run::cgroup;
+use aethel_run::container::ContainerIo;
+use aethel_run::exec::{self, ExecBuilder, ExecProcess};
+use aethel_storage::build;
+use aethel_storage::gc::{self, Roots};
+use aethel_storage::oci::{ContainerConfig, Descriptor, Platform};
+use aethel_storage::credentials::CredentialStore;
+use aethel_storage::commit::{self, CommitOptions};
+use aethel_storage::copy;
+use aethel_storage::{export_snapshot, prepare_snapshot, resolve_image, snapshot_changes};
+use aethel_storage::archive::{self, ArchiveFormat};
+use aethel_storage::reference::Reference;
+use aethel_storage::registry::{self, RegistryClient};
+use aethel_storage::snapshot::Snapshotter;
+use aethel_storage::store::ImageStore;
+use aethel_storage::volume::{self, MountSpec, Volume, VolumeStore};
 
-use tokio::sync::{broadcast, mpsc, Mutex};
-use tokio::io::{AsyncReadExt, BufReader};
-use tokio_stream::wrappers::ReceiverStream;
-use tonic::{transport::Server, Request, Response, Status};
+use tokio::sync::{broadcast, mpsc, watch, Mutex, RwLock};
+use tokio::io::AsyncReadExt;
+use tokio_stream::wrappers::{ReceiverStream, UnboundedReceiverStream};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
 
+mod attach;
+mod builder;
+mod chunks;
+mod log_drivers;
+mod logs;
 mod network;
+mod stats;
+
+use chunks::{ChunkReader, ChunkWriter};
+use log_drivers::{LogContext, LogOptions};
+use logs::{ContainerLog, LogFilter};
 
 #[derive(Debug, Clone)]
 pub struct Container {
     id: String,
     image: String,
+    /// The manifest or index the container was created from.
+    image_target: Descriptor,
+    platform: Option<Platform>,
+    command: Vec<String>,
+    env: Vec<String>,
+    /// What the image exposes and is labelled with.
+    exposed_ports: Vec<String>,
+    labels: BTreeMap<String, String>,
     status: String,
     pid: u32,
+    started_at: String,
+    /// Set once the container has exited and been reaped.
+    finished_at: Option<String>,
+    exit_code: Option<i32>,
     ip_address: Ipv4Addr,
+    network: String,
+    /// Where its network namespace is bound for the CNI plugins, until DEL
+    /// has run; `None` on the bridge.
+    netns: Option<PathBuf>,
+    mounts: Vec<MountInfo>,
+    /// Named volumes the container mounts.
+    volumes: Vec<String>,
+    rootfs: PathBuf,
+    /// Its cgroup v2 group; `None` where the host has no cgroup v2.
+    cgroup: Option<PathBuf>,
+    /// The group's limits, as they were when it was created.
+    limits: cgroup::CgroupLimits,
+    /// Whether the OOM killer had killed one of its processes when it exited.
+    oom_killed: bool,
+    log_driver: String,
+    log_opts: HashMap<String, String>,
+    /// Whether it was created with a stdin to attach to.
+    open_stdin: bool,
+    stdin: attach::Stdin,
+    /// Set once the container's output has ended.
+    exited: watch::Receiver<bool>,
+    /// Output since the container started, for the first client to attach
+    /// to one created with stdin or a tty, so it misses nothing.
+    unattached_output: Arc<Mutex<Option<broadcast::Receiver<LogEntry>>>>,
 }
 
 pub struct MyAethelService {
     containers: Arc<Mutex<HashMap<String, Container>>>,
-    log_broadcasters: Arc<Mutex<HashMap<String, broadcast::Sender<String>>>>,
+    logs: Arc<Mutex<HashMap<String, Arc<ContainerLog>>>>,
+    /// Each container's log files are under `<log_dir>/<id>`.
+    log_dir: PathBuf,
+    /// The driver for containers created without `--log-driver`.
+    log_driver: String,
+    /// json-file rotation for containers created without `--log-opt`s of
+    /// their own.
+    log_options: LogOptions,
     next_ip: Arc<Mutex<u8>>,
     net_handle: Arc<rtnetlink::Handle>,
+    cni: Arc<Cni>,
+    snapshotter: Arc<Snapshotter>,
+    registry: Arc<RegistryClient>,
+    images: Arc<ImageStore>,
+    volumes: Arc<VolumeStore>,
+    /// Pulls, loads and container creation hold this for reading; garbage
+    /// collection and volume removal take it for writing so they never see
+    /// half-written images or a volume being mounted.
+    gc_lock: Arc<RwLock<()>>,
+    /// How many copies are under way in each container; it stays frozen
+    /// until the last of them is done.
+    copies: Arc<Mutex<HashMap<String, usize>>>,
+    /// Build contexts are unpacked under here for the length of a build.
+    build_dir: PathBuf,
+}
+
+/// Where images and snapshots live unless `AETHEL_ROOT` says otherwise.
+const DEFAULT_ROOT: &str = "/var/lib/aethel";
+/// Registry credentials, in the `auths` format written by `docker login`.
+const CREDENTIALS_FILE: &str = "/etc/aethel/auth.json";
+/// How long pausing waits for every process in a container to stop.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn parse_platform(platform: &str) -> AethelResult<Option<Platform>> {
+    if platform.is_empty() {
+        return Ok(None);
+    }
+    platform.parse().map(Some)
+}
+
+/// Maps image store errors onto gRPC codes.
+fn image_status(e: AethelError) -> Status {
+    match e {
+        AethelError::NotFound(msg) => Status::not_found(msg),
+        AethelError::Registry(msg) => Status::invalid_argument(msg),
+        AethelError::UnsupportedMediaType(msg) => Status::failed_precondition(msg),
+        AethelError::Build(msg) => Status::failed_precondition(msg),
+        AethelError::Volume(msg) => Status::failed_precondition(msg),
+        e => Status::internal(e.to_string()),
+    }
+}
+
+/// One row per tag, plus a `<none>` row for each image that is only
+/// reachable by digest and a `<none>:<none>` one for each untagged image,
+/// as `docker images` shows them.
+fn list_images(store: &ImageStore) -> AethelResult<Vec<ImageInfo>> {
+    let refs = store.references()?;
+    let mut rows = Vec::new();
+    for (reference, desc) in &refs {
+        let tag = match &reference.tag {
+            Some(tag) => tag.clone(),
+            None if refs
+                .iter()
+                .any(|(other, d)| other.tag.is_some() && other.name() == reference.name() && d.digest == desc.digest) =>
+            {
+                continue
+            }
+            None => "<none>".to_string(),
+        };
+        let repository = Reference {
+            tag: None,
+            digest: None,
+            ..reference.clone()
+        };
+        // An image whose manifest or config is unreadable is still listed.
+        let (created, size) = match resolve_image(store, desc, desc.platform.as_ref()) {
+            Ok((manifest, config)) => (config.created.unwrap_or_default(), manifest.content_size()),
+            Err(_) => (String::new(), desc.size),
+        };
+        rows.push(ImageInfo {
+            repository: repository.familiar(),
+            tag,
+            id: desc.digest.clone(),
+            created,
+            size,
+        });
+    }
+    for desc in store.untagged()? {
+        let (created, size) = match resolve_image(store, &desc, desc.platform.as_ref()) {
+            Ok((manifest, config)) => (config.created.unwrap_or_default(), manifest.content_size()),
+            Err(_) => (String::new(), desc.size),
+        };
+        rows.push(ImageInfo {
+            repository: "<none>".to_string(),
+            tag: "<none>".to_string(),
+            id: desc.digest,
+            created,
+            size,
+        });
+    }
+    Ok(rows)
+}
+
+fn inspect_image(store: &ImageStore, name: &str) -> AethelResult<InspectImageResponse> {
+    let desc = store.resolve(name)?;
+    let (manifest, config) = resolve_image(store, &desc, desc.platform.as_ref())?;
+
+    let (mut repo_tags, mut repo_digests) = (Vec::new(), Vec::new());
+    for (reference, other) in store.references()? {
+        if other.digest != desc.digest {
+            continue;
+        }
+        if reference.tag.is_some() {
+            repo_tags.push(reference.familiar());
+        }
+        let pinned = Reference {
+            tag: None,
+            digest: Some(desc.digest.parse()?),
+            ..reference
+        }
+        .familiar();
+        if !repo_digests.contains(&pinned) {
+            repo_digests.push(pinned);
+        }
+    }
+
+    Ok(InspectImageResponse {
+        id: desc.digest,
+        repo_tags,
+        repo_digests,
+        media_type: desc.media_type,
+        platform: config.platform().to_string(),
+        created: config.created.clone().unwrap_or_default(),
+        size: manifest.content_size(),
+        config: serde_json::to_string_pretty(&config)
+            .map_err(|e| AethelError::Filesystem(format!("Failed to encode image config: {}", e)))?,
+    })
+}
+
+fn volume_info(volume: Volume, ref_count: u32) -> VolumeInfo {
+    VolumeInfo {
+        name: volume.name,
+        mountpoint: volume.mountpoint.display().to_string(),
+        created: volume.created,
+        labels: volume.labels.into_iter().collect(),
+        ref_count,
+    }
+}
+
+fn pull_progress(event: registry::PullProgress) -> PullProgress {
+    let (status, digest, current, total) = match event {
+        registry::PullProgress::Resolved { digest } => ("Resolved", digest, 0, 0),
+        registry::PullProgress::Exists { digest } => ("Exists", digest, 0, 0),
+        registry::PullProgress::Downloading { digest, current, total } => ("Downloading", digest, current, total),
+        registry::PullProgress::Downloaded { digest } => ("Downloaded", digest, 0, 0),
+    };
+    PullProgress {
+        status: status.to_string(),
+        digest,
+        current,
+        total,
+    }
 }
 
-async fn log_forwarder(pipe_fd: RawFd, broadcaster: broadcast::Sender<String>) {
-    let pipe = unsafe { tokio::fs::File::from_raw_fd(pipe_fd) };
-    let mut reader = BufReader::new(pipe);
-    let mut buf = [0; 1024];
+fn push_progress(event: registry::PushProgress) -> PushProgress {
+    let (status, digest, current, total) = match event {
+        registry::PushProgress::Exists { digest } => ("Exists".to_string(), digest, 0, 0),
+        registry::PushProgress::Mounted { digest, from } => (format!("Mounted from {}", from), digest, 0, 0),
+        registry::PushProgress::Uploading { digest, current, total } => ("Uploading".to_string(), digest, current, total),
+        registry::PushProgress::Uploaded { digest } => ("Uploaded".to_string(), digest, 0, 0),
+    };
+    PushProgress {
+        status,
+        digest,
+        current,
+        total,
+    }
+}
 
-    while let Ok(n) = reader.read(&mut buf).await {
+/// Docker semantics: a command given at run time replaces the image's `Cmd`,
+/// and the image's `Entrypoint` is prepended either way.
+fn resolve_command(config: &ContainerConfig, command: &str, args: &[String]) -> Option<Vec<String>> {
+    let mut argv = config.entrypoint.clone();
+    if command.is_empty() {
+        argv.extend(config.cmd.iter().cloned());
+    } else {
+        argv.push(command.to_string());
+        argv.extend(args.iter().cloned());
+    }
+    if argv.is_empty() {
+        None
+    } else {
+        Some(argv)
+    }
+}
+
+/// Waits for a container's init process once its output has ended, and
+/// records how it exited.
+async fn reap(
+    containers: Arc<Mutex<HashMap<String, Container>>>,
+    cni: Arc<Cni>,
+    id: String,
+    pid: i32,
+    cgroup: Option<PathBuf>,
+    mut exited: watch::Receiver<bool>,
+) {
+    // Output ends when the container exits, unless it closed its own first.
+    let _ = exited.wait_for(|exited| *exited).await;
+    let waited = tokio::task::spawn_blocking(move || {
+        let code = exec::wait(nix::unistd::Pid::from_raw(pid)).ok();
+        let oom_killed = cgroup.is_some_and(|path| cgroup::oom_kills(&path).is_ok_and(|kills| kills > 0));
+        (code, oom_killed)
+    })
+    .await;
+    let (code, oom_killed) = waited.unwrap_or((None, false));
+    let finished_at = time::format_rfc3339(SystemTime::now());
+    if let Some(container) = containers.lock().await.get_mut(&id) {
+        container.exit_code = code;
+        container.oom_killed = oom_killed;
+        container.finished_at = Some(finished_at);
+        if matches!(container.status.as_str(), "Running" | "Paused") {
+            container.status = "Exited".to_string();
+        }
+    }
+    release_network(&containers, cni, &id).await;
+}
+
+/// Runs CNI DEL for a container that has exited, once: whoever takes its
+/// bound namespace out of its record runs it. A DEL that fails puts the
+/// namespace back, for a prune to try again.
+async fn release_network(containers: &Mutex<HashMap<String, Container>>, cni: Arc<Cni>, id: &str) {
+    let Some((network, netns)) = containers
+        .lock()
+        .await
+        .get_mut(id)
+        .and_then(|container| Some((container.network.clone(), container.netns.take()?)))
+    else {
+        return;
+    };
+    if let Err(e) = network::teardown_cni_net(cni, network, netns.clone(), id.to_string()).await {
+        eprintln!("container {}: network teardown failed: {}", id, e);
+        if let Some(container) = containers.lock().await.get_mut(id) {
+            container.netns = Some(netns);
+        }
+    }
+}
+
+/// A container kept frozen for a copy by `hold_for_copy`.
+struct CopyHold {
+    copies: Arc<Mutex<HashMap<String, usize>>>,
+    containers: Arc<Mutex<HashMap<String, Container>>>,
+    id: String,
+    /// `None` when the container was not running, so nothing is held.
+    cgroup: Option<PathBuf>,
+}
+
+impl CopyHold {
+    /// Lets the container run again once no other copy holds it, unless it
+    /// was paused in the meantime.
+    async fn release(self) {
+        let Some(cgroup) = self.cgroup else {
+            return;
+        };
+        let mut copies = self.copies.lock().await;
+        let count = copies.get(&self.id).copied().unwrap_or(1);
+        if count > 1 {
+            copies.insert(self.id, count - 1);
+            return;
+        }
+        copies.remove(&self.id);
+        let running = self
+            .containers
+            .lock()
+            .await
+            .get(&self.id)
+            .is_some_and(|container| container.status == "Running");
+        if running {
+            let thawed = tokio::task::spawn_blocking(move || cgroup::freeze(&cgroup, false, FREEZE_TIMEOUT)).await;
+            if let Ok(Err(e)) = thawed {
+                eprintln!("container {}: failed to thaw after a copy: {}", self.id, e);
+            }
+        }
+    }
+}
+
+/// Sends what an exec'd process writes to `fd` until it closes it.
+async fn exec_forwarder(fd: OwnedFd, stream: &'static str, tx: mpsc::Sender<Result<ExecOutput, Status>>) {
+    let mut pipe = tokio::fs::File::from_std(std::fs::File::from(fd));
+    let mut buf = [0; 8192];
+
+    while let Ok(n) = pipe.read(&mut buf).await {
         if n == 0 {
             break;
         }
-        if let Ok(line) = String::from_utf8(buf[..n].to_vec()) {
-            let _ = broadcaster.send(line);
+        let output = ExecOutput {
+            stream: stream.to_string(),
+            data: buf[..n].to_vec(),
+            ..Default::default()
+        };
+        if tx.send(Ok(output)).await.is_err() {
+            break;
         }
     }
 }
 
+impl MyAethelService {
+    /// The container with `id`, or `NOT_FOUND`.
+    async fn find_container(&self, id: &str) -> Result<Container, Status> {
+        self.containers
+            .lock()
+            .await
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("container {} not found", id)))
+    }
+
+    /// The container's mounted rootfs, as the daemon sees it.
+    async fn container_rootfs(&self, id: &str) -> Result<PathBuf, Status> {
+        let container = self.find_container(id).await?;
+        if !self.snapshotter.is_mounted(&container.id) {
+            return Err(Status::failed_precondition(format!(
+                "rootfs of container {} is not mounted",
+                id
+            )));
+        }
+        Ok(self.snapshotter.merged_dir(&container.id))
+    }
+
+    /// Freezes a running container's cgroup and marks it Paused, or thaws a
+    /// paused one and marks it Running again.
+    async fn set_paused(&self, id: &str, paused: bool) -> Result<(), Status> {
+        let (from, to, verb) = if paused { ("Running", "Paused", "pause") } else { ("Paused", "Running", "unpause") };
+        let container = self.find_container(id).await?;
+        if container.status != from {
+            return Err(Status::failed_precondition(format!(
+                "container {} is {}, not {}",
+                id,
+                container.status.to_lowercase(),
+                from.to_lowercase()
+            )));
+        }
+        let cgroup = container.cgroup.ok_or_else(|| {
+            Status::failed_precondition(format!("container {} has no cgroup of its own to freeze", id))
+        })?;
+
+        // A copy keeps the container frozen and leaves thawing it to the
+        // last copy out.
+        let copies = self.copies.lock().await;
+        if !copies.contains_key(id) {
+            tokio::task::spawn_blocking(move || cgroup::freeze(&cgroup, paused, FREEZE_TIMEOUT))
+                .await
+                .map_err(|e| Status::internal(format!("{} panicked: {}", verb, e)))?
+                .map_err(|e| Status::internal(format!("failed to {} container: {}", verb, e)))?;
+        }
+        if let Some(container) = self.containers.lock().await.get_mut(id) {
+            if container.status == from {
+                container.status = to.to_string();
+            }
+        }
+        Ok(())
+    }
+
+    /// Freezes a running container for the length of a copy. Paths are
+    /// resolved before they are read or written, and a running container
+    /// could swap one for a symlink out of its rootfs in between.
+    async fn hold_for_copy(&self, id: &str) -> Result<CopyHold, Status> {
+        let mut hold = CopyHold {
+            copies: self.copies.clone(),
+            containers: self.containers.clone(),
+            id: id.to_string(),
+            cgroup: None,
+        };
+        let mut copies = self.copies.lock().await;
+        let container = self.find_container(id).await?;
+        // Nothing runs in a container that has exited to race the copy.
+        if !matches!(container.status.as_str(), "Running" | "Paused") {
+            return Ok(hold);
+        }
+        let cgroup = container.cgroup.ok_or_else(|| {
+            Status::failed_precondition(format!("container {} has no cgroup of its own to freeze for the copy", id))
+        })?;
+
+        let count = copies.get(id).copied().unwrap_or(0);
+        if count == 0 && container.status == "Running" {
+            let path = cgroup.clone();
+            tokio::task::spawn_blocking(move || cgroup::freeze(&path, true, FREEZE_TIMEOUT))
+                .await
+                .map_err(|e| Status::internal(format!("freeze panicked: {}", e)))?
+                .map_err(|e| Status::internal(format!("failed to freeze container for the copy: {}", e)))?;
+        }
+        copies.insert(id.to_string(), count + 1);
+        hold.cgroup = Some(cgroup);
+        Ok(hold)
+    }
+
+    /// Undoes a create that failed part way: kills the container if it had
+    /// started and releases its network, snapshot, cgroup and log.
+    async fn discard_container(
+        &self,
+        id: &str,
+        pid: Option<i32>,
+        cgroup: Option<PathBuf>,
+        netns: Option<(String, PathBuf)>,
+    ) {
+        if let Some(pid) = pid {
+            let pid = nix::unistd::Pid::from_raw(pid);
+            let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL);
+            let _ = tokio::task::spawn_blocking(move || exec::wait(pid)).await;
+        }
+        // DEL cleans up after an ADD that failed as well.
+        if let Some((network, netns)) = netns {
+            if let Err(e) = network::teardown_cni_net(self.cni.clone(), network, netns, id.to_string()).await {
+                eprintln!("container {}: network teardown failed: {}", id, e);
+            }
+        }
+        let (snapshotter, snapshot) = (self.snapshotter.clone(), id.to_string());
+        if let Ok(Err(e)) = tokio::task::spawn_blocking(move || snapshotter.remove(&snapshot)).await {
+            eprintln!("container {}: failed to remove snapshot: {}", id, e);
+        }
+        if let Some(cgroup) = cgroup {
+            let _ = cgroup::remove(&cgroup);
+        }
+        self.logs.lock().await.remove(id);
+        let _ = std::fs::remove_dir_all(self.log_dir.join(id));
+    }
+
+    /// How many containers, running or stopped, mount the volume `name`.
+    async fn volume_ref_count(&self, name: &str) -> u32 {
+        let containers = self.containers.lock().await;
+        containers.values().filter(|c| c.volumes.iter().any(|v| v == name)).count() as u32
+    }
+
+    /// Garbage-collects images and snapshots nothing uses. With
+    /// `remove_stopped`, stopped containers are removed first and no longer
+    /// keep anything alive.
+    async fn prune(&self, dry_run: bool, remove_stopped: bool) -> AethelResult<PruneResponse> {
+        let _gc_guard = self.gc_lock.write().await;
+        let mut containers = self.containers.lock().await;
+
+        let stopped: Vec<String> = containers
+            .values()
+            .filter(|c| remove_stopped && !matches!(c.status.as_str(), "Running" | "Paused"))
+            .map(|c| c.id.clone())
+            .collect();
+        let kept = containers.values().filter(|c| !stopped.contains(&c.id));
+        let roots = Roots {
+            images: kept.clone().map(|c| c.image_target.clone()).collect(),
+            containers: kept.map(|c| c.id.clone()).collect(),
+        };
+
+        let (images, snapshotter) = (self.images.clone(), self.snapshotter.clone());
+        let report = tokio::task::spawn_blocking(move || gc::collect(&images, &snapshotter, &roots, dry_run))
+            .await
+            .map_err(|e| AethelError::Process(format!("prune panicked: {}", e)))??;
+
+        if !dry_run {
+            let mut logs = self.logs.lock().await;
+            for id in &stopped {
+                let Some(container) = containers.remove(id) else {
+                    continue;
+                };
+                // Left by a DEL that failed when it exited.
+                if let Some(netns) = container.netns {
+                    let (cni, network) = (self.cni.clone(), container.network);
+                    if let Err(e) = network::teardown_cni_net(cni, network, netns, id.clone()).await {
+                        eprintln!("container {}: network teardown failed: {}", id, e);
+                    }
+                }
+                if let Some(cgroup) = container.cgroup {
+                    let _ = cgroup::remove(&cgroup);
+                }
+                if let Some(log) = logs.remove(id) {
+                    let _ = std::fs::remove_dir_all(log.dir());
+                }
+            }
+        }
+        let mut removed = stopped;
+        for id in report.containers {
+            if !removed.contains(&id) {
+                removed.push(id);
+            }
+        }
+
+        Ok(PruneResponse {
+            containers: removed,
+            blobs: report.blobs,
+            layers: report.layers,
+            reclaimed: report.reclaimed,
+        })
+    }
+}
+
 #[tonic::async_trait]
 impl AethelService for MyAethelService {
     async fn create_container(
@@ -55,41 +594,235 @@ impl AethelService for MyAethelService {
     ) -> Result<Response<CreateContainerResponse>, Status> {
         let req = request.into_inner();
         let container_id = uuid::Uuid::new_v4().to_string();
-        let rootfs_path = format!("/tmp/aethel/{}", container_id);
-        let image_path = "./images";
-
-        prepare_rootfs(Path::new(image_path), &req.image_name, Path::new(&rootfs_path))
-            .map_err(|e| Status::internal(format!("prepare_rootfs failed: {}", e)))?;
+        let platform = parse_platform(&req.platform).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let specs = req
+            .volumes
+            .iter()
+            .map(|spec| spec.parse::<MountSpec>())
+            .collect::<AethelResult<Vec<_>>>()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let log_driver = if req.log_driver.is_empty() { &self.log_driver } else { &req.log_driver };
+        let context = LogContext {
+            id: container_id.clone(),
+            name: container_id[..12].to_string(),
+            image: req.image_name.clone(),
+        };
+        let log = ContainerLog::create(
+            &self.log_dir.join(&container_id),
+            log_driver,
+            &req.log_opts,
+            self.log_options,
+            &context,
+        )
+        .map_err(|e| match e {
+            AethelError::ContainerSetup(_) => Status::invalid_argument(e.to_string()),
+            e => Status::internal(format!("failed to create log: {}", e)),
+        })?;
+        let log = Arc::new(log);
 
-        let (log_tx, _) = broadcast::channel(1024);
-        self.log_broadcasters.lock().await.insert(container_id.clone(), log_tx.clone());
+        // Held until the container is registered, so a prune cannot sweep
+        // its image or snapshot in between.
+        let _gc_guard = self.gc_lock.read().await;
 
-        let builder = ContainerBuilder::new(&container_id, &req.command)
-            .map_err(|e| Status::internal(format!("container build failed: {}", e)))?;
-        let (child_pid, pipe_fd) = unsafe { builder.with_rootfs(Path::new(&rootfs_path)).build() }
-            .map_err(|e| Status::internal(format!("container build failed: {}", e)))?;
+        // Whatever the create gets as far as is undone if a later step fails.
+        let (mut started_pid, mut created_cgroup, mut created_netns) = (None, None, None);
+        let created: Result<Ipv4Addr, Status> = async {
+            let target = self.images.resolve(&req.image_name).map_err(image_status)?;
+            let image_target = target.clone();
 
-        tokio::spawn(log_forwarder(pipe_fd, log_tx));
+            let (snapshotter, images) = (self.snapshotter.clone(), self.images.clone());
+            let id = container_id.clone();
+            let image_platform = platform.clone();
+            let (rootfs_path, image_config) = tokio::task::spawn_blocking(move || {
+                prepare_snapshot(&snapshotter, &images, &target, &id, image_platform.as_ref())
+            })
+            .await
+            .map_err(|e| Status::internal(format!("prepare_snapshot panicked: {}", e)))?
+            .map_err(|e| match e {
+                AethelError::UnsupportedMediaType(_) => image_status(e),
+                e => Status::internal(format!("prepare_snapshot failed: {}", e)),
+            })?;
 
-        let mut next_ip_guard = self.next_ip.lock().await;
-        let ip = Ipv4Addr::new(172, 29, 0, *next_ip_guard);
-        *next_ip_guard += 1;
+            let destinations: Vec<String> = specs.iter().map(|spec| spec.destination.clone()).collect();
+            let (volumes, rootfs) = (self.volumes.clone(), rootfs_path.clone());
+            let mounts = tokio::task::spawn_blocking(move || {
+                specs
+                    .iter()
+                    .map(|spec| volume::prepare_mount(&volumes, &rootfs, spec))
+                    .collect::<AethelResult<Vec<_>>>()
+            })
+            .await
+            .map_err(|e| Status::internal(format!("prepare_mount panicked: {}", e)))?
+            .map_err(image_status)?;
 
-        network::setup_container_net(&self.net_handle, child_pid as i32, &container_id, ip)
+            // The image's WorkingDir is made if missing, as docker does, and
+            // its User looked up in the image's own passwd and group files.
+            let (user, working_dir) = (image_config.config.user.clone(), image_config.config.working_dir.clone());
+            let rootfs = rootfs_path.clone();
+            let user = tokio::task::spawn_blocking(move || -> AethelResult<Option<(u32, u32)>> {
+                if !working_dir.is_empty() {
+                    build::ensure_working_dir(&rootfs, &working_dir)?;
+                }
+                if user.is_empty() {
+                    return Ok(None);
+                }
+                build::resolve_user(&rootfs, &user).map(Some)
+            })
             .await
-            .map_err(|e| Status::internal(format!("network setup failed: {}", e)))?;
+            .map_err(|e| Status::internal(format!("resolve_user panicked: {}", e)))?
+            .map_err(|e| match e {
+                AethelError::Build(msg) => Status::invalid_argument(msg),
+                e => Status::internal(format!("container build failed: {}", e)),
+            })?;
 
-        let container = Container {
-            id: container_id.clone(),
-            image: req.image_name,
-            status: "Running".to_string(),
-            pid: child_pid as u32,
-            ip_address: ip,
-        };
+            let argv = resolve_command(&image_config.config, &req.command, &req.args)
+                .ok_or_else(|| Status::invalid_argument("no command given and the image has no Entrypoint or Cmd"))?;
+            let args: Vec<&str> = argv[1..].iter().map(String::as_str).collect();
+            let env: Vec<&str> = image_config.config.env.iter().map(String::as_str).collect();
 
-        self.containers.lock().await.insert(container_id.clone(), container);
+            self.logs.lock().await.insert(container_id.clone(), log.clone());
 
-        Ok(Response::new(CreateContainerResponse { container_id, ip_address: ip.to_string() }))
+            let builder = ContainerBuilder::new(&container_id, &argv[0])
+                .and_then(|b| b.args(&args))
+                .and_then(|b| b.env(&env))
+                .map_err(|e| Status::internal(format!("container build failed: {}", e)))?;
+            let mut builder = mounts
+                .iter()
+                .fold(builder, |b, mount| b.with_mount(&mount.source, &mount.target, mount.read_only));
+            if !image_config.config.working_dir.is_empty() {
+                builder = builder.with_working_dir(Path::new(&image_config.config.working_dir));
+            }
+            if let Some((uid, gid)) = user {
+                builder = builder.with_user(uid, gid);
+            }
+            if req.stdin {
+                builder = builder.with_stdin();
+            }
+            if req.tty {
+                builder = builder.with_terminal();
+            }
+            let cgroup = match cgroup::create(&container_id) {
+                Ok(path) => Some(path),
+                Err(e) => {
+                    eprintln!("container {} runs in the daemon's cgroup: {}", container_id, e);
+                    None
+                }
+            };
+            let limits = match cgroup.as_deref().map(cgroup::limits).transpose() {
+                Ok(limits) => limits.unwrap_or_default(),
+                Err(e) => {
+                    eprintln!("container {}: failed to read cgroup limits: {}", container_id, e);
+                    cgroup::CgroupLimits::default()
+                }
+            };
+            if let Some(path) = &cgroup {
+                builder = builder.with_cgroup(path);
+            }
+            created_cgroup = cgroup.clone();
+            let (child_pid, io) = unsafe { builder.with_rootfs(&rootfs_path).build() }
+                .map_err(|e| Status::internal(format!("container build failed: {}", e)))?;
+            started_pid = Some(child_pid as i32);
+            let started_at = time::format_rfc3339(SystemTime::now());
+            let (outputs, stdin) = match io {
+                ContainerIo::Pipes { stdin, stdout, stderr } => {
+                    (vec![(stdout, "stdout"), (stderr, "stderr")], attach::Stdin::pipe(stdin))
+                }
+                ContainerIo::Terminal(master) => {
+                    let stdin = attach::Stdin::terminal(&master)
+                        .map_err(|e| Status::internal(format!("container build failed: {}", e)))?;
+                    (vec![(master, "stdout")], stdin)
+                }
+            };
+
+            let unattached_output = (req.stdin || req.tty).then(|| log.subscribe());
+            let (exited_tx, exited) = watch::channel(false);
+            tokio::spawn(async move {
+                let forwarders = outputs.into_iter().map(|(fd, stream)| logs::forward(fd, stream, log.clone()));
+                futures::future::join_all(forwarders).await;
+                let _ = exited_tx.send(true);
+            });
+
+            let network = if req.network.is_empty() {
+                network::BRIDGE_NETWORK.to_string()
+            } else {
+                req.network
+            };
+
+            let ip = if network == network::BRIDGE_NETWORK {
+                let mut next_ip_guard = self.next_ip.lock().await;
+                let ip = Ipv4Addr::new(172, 29, 0, *next_ip_guard);
+                *next_ip_guard += 1;
+
+                network::setup_container_net(&self.net_handle, child_pid as i32, &container_id, ip)
+                    .await
+                    .map_err(|e| Status::internal(format!("network setup failed: {}", e)))?;
+                ip
+            } else {
+                let netns = network::bind_netns(child_pid as i32, &container_id)
+                    .map_err(|e| Status::internal(format!("network setup failed: {}", e)))?;
+                created_netns = Some((network.clone(), netns.clone()));
+                network::setup_cni_net(self.cni.clone(), network.clone(), netns, container_id.clone())
+                    .await
+                    .map_err(|e| Status::internal(format!("network setup failed: {}", e)))?
+            };
+
+            let mount_infos = mounts
+                .iter()
+                .zip(destinations)
+                .map(|(mount, destination)| MountInfo {
+                    name: mount.volume.clone().unwrap_or_default(),
+                    source: mount.source.to_string_lossy().into_owned(),
+                    destination,
+                    read_only: mount.read_only,
+                })
+                .collect();
+            let container = Container {
+                id: container_id.clone(),
+                image: req.image_name,
+                image_target,
+                platform,
+                command: argv,
+                env: image_config.config.env,
+                exposed_ports: image_config.config.exposed_ports.into_iter().collect(),
+                labels: image_config.config.labels,
+                status: "Running".to_string(),
+                pid: child_pid as u32,
+                started_at,
+                finished_at: None,
+                exit_code: None,
+                ip_address: ip,
+                network,
+                netns: created_netns.as_ref().map(|(_, netns)| netns.clone()),
+                mounts: mount_infos,
+                volumes: mounts.into_iter().filter_map(|mount| mount.volume).collect(),
+                rootfs: rootfs_path,
+                cgroup: cgroup.clone(),
+                limits,
+                oom_killed: false,
+                log_driver: log_driver.to_string(),
+                log_opts: req.log_opts,
+                open_stdin: req.stdin,
+                stdin,
+                exited: exited.clone(),
+                unattached_output: Arc::new(Mutex::new(unattached_output)),
+            };
+
+            self.containers.lock().await.insert(container_id.clone(), container);
+
+            // Registered first, so the exit is recorded however soon it comes.
+            let (containers, cni, id) = (self.containers.clone(), self.cni.clone(), container_id.clone());
+            tokio::spawn(reap(containers, cni, id, child_pid as i32, cgroup, exited));
+            Ok(ip)
+        }
+        .await;
+
+        match created {
+            Ok(ip) => Ok(Response::new(CreateContainerResponse { container_id, ip_address: ip.to_string() })),
+            Err(status) => {
+                self.discard_container(&container_id, started_pid, created_cgroup, created_netns).await;
+                Err(status)
+            }
+        }
     }
 
     type ListContainersStream = ReceiverStream<Result<ContainerInfo, Status>>;
@@ -120,23 +853,167 @@ impl AethelService for MyAethelService {
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 
+    async fn inspect_container(
+        &self,
+        request: Request<InspectContainerRequest>,
+    ) -> Result<Response<InspectContainerResponse>, Status> {
+        let req = request.into_inner();
+        let container = self.find_container(&req.container_id).await?;
+        let log_path = {
+            let logs = self.logs.lock().await;
+            logs.get(&container.id).and_then(|log| log.path())
+        };
+        let tty = container.stdin.is_terminal();
+        let mut argv = container.command.into_iter();
+
+        Ok(Response::new(InspectContainerResponse {
+            id: container.id,
+            image: container.image,
+            image_digest: container.image_target.digest,
+            platform: container.platform.map(|p| p.to_string()).unwrap_or_default(),
+            path: argv.next().unwrap_or_default(),
+            args: argv.collect(),
+            env: container.env,
+            mounts: container.mounts,
+            network: container.network,
+            ip_address: container.ip_address.to_string(),
+            exposed_ports: container.exposed_ports,
+            labels: container.labels.into_iter().collect(),
+            open_stdin: container.open_stdin,
+            tty,
+            log_driver: container.log_driver,
+            log_opts: container.log_opts,
+            status: container.status,
+            pid: container.pid,
+            started_at: container.started_at,
+            finished_at: container.finished_at.unwrap_or_default(),
+            exit_code: container.exit_code,
+            rootfs: container.rootfs.to_string_lossy().into_owned(),
+            log_path: log_path.map(|p| p.to_string_lossy().into_owned()).unwrap_or_default(),
+            memory_limit: container.limits.memory_max,
+            pids_limit: container.limits.pids_max,
+            cpu_quota: container.limits.cpu_quota_usec,
+            cpu_period: container.limits.cpu_period_usec,
+            oom_killed: container.oom_killed,
+        }))
+    }
+
+    type StatsStream = ReceiverStream<Result<StatsSample, Status>>;
+
+    async fn stats(&self, request: Request<StatsRequest>) -> Result<Response<Self::StatsStream>, Status> {
+        let req = request.into_inner();
+        let containers: Vec<Container> = if req.container_ids.is_empty() {
+            let containers = self.containers.lock().await;
+            containers
+                .values()
+                .filter(|c| matches!(c.status.as_str(), "Running" | "Paused") && c.cgroup.is_some())
+                .cloned()
+                .collect()
+        } else {
+            let mut found = Vec::with_capacity(req.container_ids.len());
+            for id in &req.container_ids {
+                let container = self.find_container(id).await?;
+                if container.cgroup.is_none() {
+                    return Err(Status::failed_precondition(format!(
+                        "container {} has no cgroup of its own",
+                        id
+                    )));
+                }
+                found.push(container);
+            }
+            found
+        };
+
+        let cni = self.cni.clone();
+        let targets = tokio::task::spawn_blocking(move || {
+            containers
+                .into_iter()
+                .map(|c| stats::Target {
+                    interfaces: network::host_interfaces(&cni, &c.network, c.netns.as_deref(), &c.id),
+                    cgroup: c.cgroup.unwrap_or_default(),
+                    id: c.id,
+                })
+                .collect()
+        })
+        .await
+        .map_err(|e| Status::internal(format!("stats panicked: {}", e)))?;
+
+        let (tx, rx) = mpsc::channel(4);
+        let handle = self.net_handle.clone();
+        tokio::spawn(async move { stats::stream(targets, &handle, !req.stream, tx).await });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn pause_container(
+        &self,
+        request: Request<PauseContainerRequest>,
+    ) -> Result<Response<PauseContainerResponse>, Status> {
+        self.set_paused(&request.into_inner().container_id, true).await?;
+        Ok(Response::new(PauseContainerResponse {}))
+    }
+
+    async fn unpause_container(
+        &self,
+        request: Request<UnpauseContainerRequest>,
+    ) -> Result<Response<UnpauseContainerResponse>, Status> {
+        self.set_paused(&request.into_inner().container_id, false).await?;
+        Ok(Response::new(UnpauseContainerResponse {}))
+    }
+
+    async fn check_network(
+        &self,
+        request: Request<CheckNetworkRequest>,
+    ) -> Result<Response<CheckNetworkResponse>, Status> {
+        let container = self.find_container(&request.into_inner().container_id).await?;
+        if container.network == network::BRIDGE_NETWORK {
+            return Err(Status::failed_precondition(format!(
+                "container {} is on the built-in bridge, not a CNI network",
+                container.id
+            )));
+        }
+        let netns = match container.netns {
+            Some(netns) if matches!(container.status.as_str(), "Running" | "Paused") => netns,
+            _ => {
+                return Err(Status::failed_precondition(format!(
+                    "container {} is {}",
+                    container.id,
+                    container.status.to_lowercase()
+                )))
+            }
+        };
+        network::check_cni_net(self.cni.clone(), container.network, netns, container.id)
+            .await
+            .map_err(|e| Status::internal(format!("network check failed: {}", e)))?;
+        Ok(Response::new(CheckNetworkResponse {}))
+    }
+
     async fn stop_container(
         &self,
         request: Request<StopRequest>,
     ) -> Result<Response<StopResponse>, Status> {
         let req = request.into_inner();
         let mut containers = self.containers.lock().await;
-        if let Some(container) = containers.get_mut(&req.container_id) {
-            nix::sys::signal::kill(
-                nix::unistd::Pid::from_raw(container.pid as i32),
-                nix::sys::signal::Signal::SIGKILL,
-            )
-            .map_err(|e| Status::internal(format!("failed to stop container: {}", e)))?;
-            container.status = "Stopped".to_string();
-            Ok(Response::new(StopResponse { success: true }))
-        } else {
-            Ok(Response::new(StopResponse { success: false }))
+        let Some(container) = containers.get_mut(&req.container_id) else {
+            return Ok(Response::new(StopResponse { success: false }));
+        };
+        if container.status == "Paused" && !req.force {
+            return Err(Status::failed_precondition(format!(
+                "container {} is paused; unpause it first, or force the stop",
+                container.id
+            )));
+        }
+        // Once reaped, its pid may be someone else's.
+        if !matches!(container.status.as_str(), "Running" | "Paused") {
+            return Ok(Response::new(StopResponse { success: true }));
         }
+        nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(container.pid as i32),
+            nix::sys::signal::Signal::SIGKILL,
+        )
+        .map_err(|e| Status::internal(format!("failed to stop container: {}", e)))?;
+        // Reaping it releases its network.
+        container.status = "Stopped".to_string();
+        Ok(Response::new(StopResponse { success: true }))
     }
 
     type StreamLogsStream = ReceiverStream<Result<LogEntry, Status>>;
@@ -146,34 +1023,850 @@ impl AethelService for MyAethelService {
         request: Request<LogsRequest>,
     ) -> Result<Response<Self::StreamLogsStream>, Status> {
         let req = request.into_inner();
+        let filter = LogFilter::new(&req.since, &req.until, &req.streams).map_err(Status::invalid_argument)?;
+        let filter = Arc::new(filter);
+        let (tail, follow) = (req.tail, req.follow);
+        let container = self.find_container(&req.container_id).await?;
         let (tx, rx) = mpsc::channel(100);
 
-        let broadcaster = {
-            let broadcasters = self.log_broadcasters.lock().await;
-            broadcasters.get(&req.container_id).cloned()
+        let log = {
+            let logs = self.logs.lock().await;
+            logs.get(&req.container_id).cloned()
         };
 
-        let Some(broadcaster) = broadcaster else {
+        let Some(log) = log else {
             return Err(Status::not_found("Container not found"));
         };
+        let (replay, mut subscriber) = tokio::task::spawn_blocking(move || log.replay())
+            .await
+            .map_err(|e| Status::internal(format!("replay panicked: {}", e)))?
+            .map_err(|e| Status::internal(format!("failed to read log: {}", e)))?;
+        let Some(replay) = replay else {
+            return Err(Status::failed_precondition("the container's log driver keeps nothing to read back"));
+        };
 
         tokio::spawn(async move {
-            let mut subscriber = broadcaster.subscribe();
+            let (replay_tx, replay_filter) = (tx.clone(), filter.clone());
+            let replayed = tokio::task::spawn_blocking(move || {
+                let entries = replay
+                    .entries()
+                    .filter(|entry| entry.as_ref().map_or(true, |entry| replay_filter.matches(entry)));
+                let entries: Box<dyn Iterator<Item = AethelResult<LogEntry>>> = match tail {
+                    Some(lines) => match logs::tail(entries, lines as usize) {
+                        Ok(kept) => Box::new(kept.into_iter().map(Ok)),
+                        Err(e) => Box::new(std::iter::once(Err(e))),
+                    },
+                    None => Box::new(entries),
+                };
+                for entry in entries {
+                    let entry = entry.map_err(|e| Status::internal(format!("failed to read log: {}", e)));
+                    let failed = entry.is_err();
+                    if replay_tx.blocking_send(entry).is_err() || failed {
+                        return false;
+                    }
+                }
+                true
+            })
+            .await
+            .unwrap_or(false);
+            if !replayed || !follow {
+                return;
+            }
+
+            let mut exited = container.exited;
+            let until = filter.until_in();
+            let deadline = async {
+                match until {
+                    Some(wait) => tokio::time::sleep(wait).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::pin!(deadline);
             loop {
-                match subscriber.recv().await {
-                    Ok(line) => {
-                        if tx.send(Ok(LogEntry { entry: line })).await.is_err() {
+                // Entries first, so what was written just before the end still goes out.
+                let entry = tokio::select! {
+                    biased;
+                    entry = subscriber.recv() => match entry {
+                        Ok(entry) => entry,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = exited.wait_for(|exited| *exited) => break,
+                    () = &mut deadline => break,
+                };
+                if filter.is_past_until(&entry.timestamp) {
+                    break;
+                }
+                if filter.matches(&entry) && tx.send(Ok(entry)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type PullImageStream = UnboundedReceiverStream<Result<PullProgress, Status>>;
+
+    async fn pull_image(
+        &self,
+        request: Request<PullImageRequest>,
+    ) -> Result<Response<Self::PullImageStream>, Status> {
+        let req = request.into_inner();
+        let reference: Reference = req.image.parse().map_err(|e: AethelError| Status::invalid_argument(e.to_string()))?;
+        let platform = parse_platform(&req.platform)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?
+            .unwrap_or_else(Platform::host);
+        let (registry, images) = (self.registry.clone(), self.images.clone());
+        let gc_lock = self.gc_lock.clone();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let _gc_guard = gc_lock.read().await;
+            let progress_tx = tx.clone();
+            let report = move |event| {
+                let _ = progress_tx.send(Ok(pull_progress(event)));
+            };
+            let result = registry.pull(&reference, &platform, &images, &report).await;
+            let _ = tx.send(match result {
+                Ok(pulled) => Ok(PullProgress {
+                    status: "Pulled".to_string(),
+                    digest: pulled.digest.to_string(),
+                    current: 0,
+                    total: 0,
+                }),
+                Err(e) => Err(Status::internal(format!("pull failed: {}", e))),
+            });
+        });
+
+        Ok(Response::new(UnboundedReceiverStream::new(rx)))
+    }
+
+    type PushImageStream = UnboundedReceiverStream<Result<PushProgress, Status>>;
+
+    async fn push_image(
+        &self,
+        request: Request<PushImageRequest>,
+    ) -> Result<Response<Self::PushImageStream>, Status> {
+        let req = request.into_inner();
+        let reference: Reference = req.image.parse().map_err(|e: AethelError| Status::invalid_argument(e.to_string()))?;
+        let target = self.images.resolve(&reference.to_string()).map_err(image_status)?;
+        let (registry, images) = (self.registry.clone(), self.images.clone());
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let progress_tx = tx.clone();
+            let report = move |event| {
+                let _ = progress_tx.send(Ok(push_progress(event)));
+            };
+            let result = registry.push(&reference, &images, &target, &report).await;
+            let _ = tx.send(match result {
+                Ok(digest) => Ok(PushProgress {
+                    status: "Pushed".to_string(),
+                    digest: digest.to_string(),
+                    current: 0,
+                    total: 0,
+                }),
+                Err(e) => Err(Status::internal(format!("push failed: {}", e))),
+            });
+        });
+
+        Ok(Response::new(UnboundedReceiverStream::new(rx)))
+    }
+
+    type ListImagesStream = ReceiverStream<Result<ImageInfo, Status>>;
+
+    async fn list_images(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListImagesStream>, Status> {
+        let images = self.images.clone();
+        let rows = tokio::task::spawn_blocking(move || list_images(&images))
+            .await
+            .map_err(|e| Status::internal(format!("list_images panicked: {}", e)))?
+            .map_err(image_status)?;
+        let (tx, rx) = mpsc::channel(4);
+
+        tokio::spawn(async move {
+            for row in rows {
+                if tx.send(Ok(row)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn inspect_image(
+        &self,
+        request: Request<InspectImageRequest>,
+    ) -> Result<Response<InspectImageResponse>, Status> {
+        let req = request.into_inner();
+        let images = self.images.clone();
+        let response = tokio::task::spawn_blocking(move || inspect_image(&images, &req.image))
+            .await
+            .map_err(|e| Status::internal(format!("inspect_image panicked: {}", e)))?
+            .map_err(image_status)?;
+        Ok(Response::new(response))
+    }
+
+    async fn tag_image(
+        &self,
+        request: Request<TagImageRequest>,
+    ) -> Result<Response<TagImageResponse>, Status> {
+        let req = request.into_inner();
+        let reference = self.images.tag(&req.source, &req.target).map_err(image_status)?;
+        Ok(Response::new(TagImageResponse {
+            name: reference.familiar(),
+        }))
+    }
+
+    async fn remove_image(
+        &self,
+        request: Request<RemoveImageRequest>,
+    ) -> Result<Response<RemoveImageResponse>, Status> {
+        let req = request.into_inner();
+        let target = self.images.resolve(&req.image).map_err(image_status)?;
+
+        if !req.force {
+            // Untagging one of several tags is always fine; removing the
+            // image itself is not while a running container uses it.
+            let tags = self
+                .images
+                .references()
+                .map_err(image_status)?
+                .into_iter()
+                .filter(|(reference, desc)| reference.tag.is_some() && desc.digest == target.digest)
+                .count();
+            let is_tag = req.image.parse::<Reference>().is_ok_and(|r| r.tag.is_some() && r.digest.is_none());
+            let containers = self.containers.lock().await;
+            let user = containers
+                .values()
+                .find(|c| matches!(c.status.as_str(), "Running" | "Paused") && c.image_target.digest == target.digest);
+            if let Some(container) = user {
+                if !(is_tag && tags > 1) {
+                    return Err(Status::failed_precondition(format!(
+                        "image {} is in use by running container {}",
+                        req.image, container.id
+                    )));
+                }
+            }
+        }
+
+        let removed = self.images.remove(&req.image).map_err(image_status)?;
+        Ok(Response::new(RemoveImageResponse {
+            untagged: removed.untagged,
+            deleted: removed.deleted.unwrap_or_default(),
+        }))
+    }
+
+    async fn prune_images(
+        &self,
+        request: Request<PruneRequest>,
+    ) -> Result<Response<PruneResponse>, Status> {
+        let req = request.into_inner();
+        let response = self
+            .prune(req.dry_run, false)
+            .await
+            .map_err(|e| Status::internal(format!("prune failed: {}", e)))?;
+        Ok(Response::new(response))
+    }
+
+    async fn prune_system(
+        &self,
+        request: Request<PruneRequest>,
+    ) -> Result<Response<PruneResponse>, Status> {
+        let req = request.into_inner();
+        let response = self
+            .prune(req.dry_run, true)
+            .await
+            .map_err(|e| Status::internal(format!("prune failed: {}", e)))?;
+        Ok(Response::new(response))
+    }
+
+    type LoadImagesStream = ReceiverStream<Result<LoadedImage, Status>>;
+
+    async fn load_images(
+        &self,
+        request: Request<Streaming<ArchiveChunk>>,
+    ) -> Result<Response<Self::LoadImagesStream>, Status> {
+        let reader = ChunkReader::new(request.into_inner());
+        let images = self.images.clone();
+        let gc_lock = self.gc_lock.clone();
+        let (tx, rx) = mpsc::channel(4);
+
+        tokio::spawn(async move {
+            let _gc_guard = gc_lock.read().await;
+            let result = match tokio::task::spawn_blocking(move || archive::load(&images, reader)).await {
+                Ok(Ok(loaded)) => Ok(loaded),
+                Ok(Err(e)) => Err(Status::internal(format!("load failed: {}", e))),
+                Err(e) => Err(Status::internal(format!("load panicked: {}", e))),
+            };
+            match result {
+                Ok(loaded) => {
+                    for image in loaded {
+                        let message = LoadedImage {
+                            image: image.reference.map(|r| r.familiar()).unwrap_or_default(),
+                            digest: image.digest,
+                        };
+                        if tx.send(Ok(message)).await.is_err() {
                             break;
                         }
                     }
-                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
-                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+                Err(status) => {
+                    let _ = tx.send(Err(status)).await;
                 }
             }
         });
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
+
+    type SaveImagesStream = ReceiverStream<Result<ArchiveChunk, Status>>;
+
+    async fn save_images(
+        &self,
+        request: Request<SaveImagesRequest>,
+    ) -> Result<Response<Self::SaveImagesStream>, Status> {
+        let req = request.into_inner();
+        if req.images.is_empty() {
+            return Err(Status::invalid_argument("no images given"));
+        }
+        let format: ArchiveFormat = if req.format.is_empty() {
+            ArchiveFormat::Docker
+        } else {
+            req.format.parse().map_err(|e: AethelError| Status::invalid_argument(e.to_string()))?
+        };
+        // Unknown names fail the call rather than a half-sent archive.
+        for name in &req.images {
+            self.images.resolve(name).map_err(image_status)?;
+        }
+
+        let images = self.images.clone();
+        let (tx, rx) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let result = archive::save(&images, &req.images, format, ChunkWriter::new(tx.clone()))
+                .and_then(|mut writer| Ok(writer.flush()?));
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(Status::internal(format!("save failed: {}", e))));
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn commit_container(
+        &self,
+        request: Request<CommitContainerRequest>,
+    ) -> Result<Response<CommitContainerResponse>, Status> {
+        let req = request.into_inner();
+        let reference: Reference = req.reference.parse().map_err(|e: AethelError| Status::invalid_argument(e.to_string()))?;
+        if reference.digest.is_some() {
+            return Err(Status::invalid_argument(format!(
+                "cannot commit to a digest reference: {}",
+                req.reference
+            )));
+        }
+
+        let container = self.find_container(&req.container_id).await?;
+        let options = CommitOptions {
+            reference: Some(reference.clone()),
+            author: (!req.author.is_empty()).then_some(req.author),
+            comment: (!req.message.is_empty()).then_some(req.message),
+            created_by: Some(container.command.join(" ")),
+        };
+
+        // The new image has no reference until the commit finishes.
+        let _gc_guard = self.gc_lock.read().await;
+        let (snapshotter, images) = (self.snapshotter.clone(), self.images.clone());
+        let committed = tokio::task::spawn_blocking(move || {
+            let (target, platform) = (&container.image_target, container.platform.as_ref());
+            let changes = snapshot_changes(&snapshotter, &images, target, &container.id, platform)?;
+            commit::commit(&images, target, platform, &snapshotter.upper_dir(&container.id), &changes, &options)
+        })
+        .await
+        .map_err(|e| Status::internal(format!("commit panicked: {}", e)))?
+        .map_err(image_status)?;
+
+        Ok(Response::new(CommitContainerResponse {
+            id: committed.digest,
+            reference: reference.familiar(),
+        }))
+    }
+
+    async fn diff_container(
+        &self,
+        request: Request<DiffContainerRequest>,
+    ) -> Result<Response<DiffContainerResponse>, Status> {
+        let req = request.into_inner();
+        let container = self.find_container(&req.container_id).await?;
+
+        let (snapshotter, images) = (self.snapshotter.clone(), self.images.clone());
+        let changes = tokio::task::spawn_blocking(move || {
+            snapshot_changes(&snapshotter, &images, &container.image_target, &container.id, container.platform.as_ref())
+        })
+        .await
+        .map_err(|e| Status::internal(format!("diff panicked: {}", e)))?
+        .map_err(image_status)?;
+
+        Ok(Response::new(DiffContainerResponse {
+            changes: changes
+                .into_iter()
+                .map(|change| FilesystemChange {
+                    kind: change.kind.to_string(),
+                    path: Path::new("/").join(&change.path).display().to_string(),
+                })
+                .collect(),
+        }))
+    }
+
+    type ExportContainerStream = ReceiverStream<Result<ArchiveChunk, Status>>;
+
+    async fn export_container(
+        &self,
+        request: Request<ExportContainerRequest>,
+    ) -> Result<Response<Self::ExportContainerStream>, Status> {
+        let req = request.into_inner();
+        let container = self.find_container(&req.container_id).await?;
+
+        // A system prune must not remove the snapshot mid-export.
+        let gc_guard = self.gc_lock.clone().read_owned().await;
+        let (snapshotter, images) = (self.snapshotter.clone(), self.images.clone());
+        let (tx, rx) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let _gc_guard = gc_guard;
+            let writer = ChunkWriter::new(tx.clone());
+            let result = export_snapshot(
+                &snapshotter,
+                &images,
+                &container.image_target,
+                &container.id,
+                container.platform.as_ref(),
+                writer,
+            )
+            .and_then(|mut writer| Ok(writer.flush()?));
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(image_status(e)));
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn copy_to_container(
+        &self,
+        request: Request<Streaming<CopyToContainerRequest>>,
+    ) -> Result<Response<CopyToContainerResponse>, Status> {
+        let mut stream = request.into_inner();
+        let first = stream
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("empty copy request"))?;
+        let rootfs = self.container_rootfs(&first.container_id).await?;
+        let (path, name) = (PathBuf::from(first.path), OsString::from(first.name));
+        let reader = ChunkReader::new(stream).with_initial(first.data);
+
+        let _gc_guard = self.gc_lock.read().await;
+        let hold = self.hold_for_copy(&first.container_id).await?;
+        let copied = tokio::task::spawn_blocking(move || copy::copy_to(&rootfs, &path, &name, reader)).await;
+        hold.release().await;
+        copied
+            .map_err(|e| Status::internal(format!("copy panicked: {}", e)))?
+            .map_err(image_status)?;
+        Ok(Response::new(CopyToContainerResponse {}))
+    }
+
+    type CopyFromContainerStream = ReceiverStream<Result<ArchiveChunk, Status>>;
+
+    async fn copy_from_container(
+        &self,
+        request: Request<CopyFromContainerRequest>,
+    ) -> Result<Response<Self::CopyFromContainerStream>, Status> {
+        let req = request.into_inner();
+        let rootfs = self.container_rootfs(&req.container_id).await?;
+
+        let gc_guard = self.gc_lock.clone().read_owned().await;
+        let hold = self.hold_for_copy(&req.container_id).await?;
+        let (tx, rx) = mpsc::channel(4);
+        let copied = tokio::task::spawn_blocking(move || {
+            let _gc_guard = gc_guard;
+            let result = copy::copy_from(&rootfs, Path::new(&req.path), ChunkWriter::new(tx.clone()))
+                .and_then(|mut writer| Ok(writer.flush()?));
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(image_status(e)));
+            }
+        });
+        tokio::spawn(async move {
+            let _ = copied.await;
+            hold.release().await;
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type BuildImageStream = UnboundedReceiverStream<Result<BuildProgress, Status>>;
+
+    async fn build_image(
+        &self,
+        request: Request<Streaming<BuildImageRequest>>,
+    ) -> Result<Response<Self::BuildImageStream>, Status> {
+        let mut stream = request.into_inner();
+        let first = stream
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("empty build request"))?;
+        let reference = if first.tag.is_empty() {
+            None
+        } else {
+            let reference: Reference = first.tag.parse().map_err(|e: AethelError| Status::invalid_argument(e.to_string()))?;
+            if reference.digest.is_some() {
+                return Err(Status::invalid_argument(format!(
+                    "cannot tag a build with a digest reference: {}",
+                    first.tag
+                )));
+            }
+            Some(reference)
+        };
+
+        let context = self.build_dir.join(uuid::Uuid::new_v4().to_string());
+        let (dir, name) = (context.clone(), first.containerfile);
+        let reader = ChunkReader::new(stream).with_initial(first.data);
+        let unpacked = match tokio::task::spawn_blocking(move || builder::unpack_context(&dir, reader, &name)).await {
+            Ok(result) => result.map_err(|e| match e {
+                AethelError::Build(msg) => Status::invalid_argument(msg),
+                e => image_status(e),
+            }),
+            Err(e) => Err(Status::internal(format!("build panicked: {}", e))),
+        };
+        let containerfile = match unpacked {
+            Ok(containerfile) => containerfile,
+            Err(status) => {
+                let _ = std::fs::remove_dir_all(&context);
+                return Err(status);
+            }
+        };
+
+        let (registry, images, snapshotter) = (self.registry.clone(), self.images.clone(), self.snapshotter.clone());
+        let gc_lock = self.gc_lock.clone();
+        let no_cache = first.no_cache;
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            // Intermediate images have no reference until the build tags
+            // the last one; a prune in between would sweep them.
+            let gc_guard = gc_lock.read_owned().await;
+            let result = async {
+                let base = match containerfile.from.as_str() {
+                    "scratch" => build::scratch_image(&images).map_err(image_status)?,
+                    from => match images.resolve(from) {
+                        Ok(base) => base,
+                        Err(AethelError::NotFound(_)) => {
+                            let base: Reference = from.parse().map_err(|e: AethelError| Status::invalid_argument(e.to_string()))?;
+                            registry
+                                .pull(&base, &Platform::host(), &images, &|_: registry::PullProgress| {})
+                                .await
+                                .map_err(|e| Status::internal(format!("pull failed: {}", e)))?;
+                            images.resolve(&base.to_string()).map_err(image_status)?
+                        }
+                        Err(e) => return Err(image_status(e)),
+                    },
+                };
+
+                let progress_tx = tx.clone();
+                let context = context.clone();
+                tokio::task::spawn_blocking(move || {
+                    let _gc_guard = gc_guard;
+                    let build = builder::Build {
+                        images: &images,
+                        snapshotter: &snapshotter,
+                        context,
+                        containerfile,
+                        no_cache,
+                    };
+                    let report = |event| {
+                        let _ = progress_tx.send(Ok(event));
+                    };
+                    let built = build.run(base, &report)?;
+                    if let Some(reference) = &reference {
+                        images.set_reference(reference, built.clone())?;
+                    }
+                    Ok((built, reference))
+                })
+                .await
+                .map_err(|e| Status::internal(format!("build panicked: {}", e)))?
+                .map_err(image_status)
+            }
+            .await;
+            let _ = std::fs::remove_dir_all(&context);
+
+            match result {
+                Ok((built, reference)) => {
+                    let _ = tx.send(Ok(BuildProgress {
+                        status: "Built".to_string(),
+                        digest: built.digest,
+                        ..Default::default()
+                    }));
+                    if let Some(reference) = reference {
+                        let _ = tx.send(Ok(BuildProgress {
+                            status: "Tagged".to_string(),
+                            message: reference.familiar(),
+                            ..Default::default()
+                        }));
+                    }
+                }
+                Err(status) => {
+                    let _ = tx.send(Err(status));
+                }
+            }
+        });
+
+        Ok(Response::new(UnboundedReceiverStream::new(rx)))
+    }
+
+    async fn create_volume(
+        &self,
+        request: Request<CreateVolumeRequest>,
+    ) -> Result<Response<VolumeInfo>, Status> {
+        let req = request.into_inner();
+        let name = (!req.name.is_empty()).then_some(req.name.as_str());
+        let volume = self
+            .volumes
+            .create(name, req.labels.into_iter().collect())
+            .map_err(|e| match e {
+                AethelError::Volume(msg) => Status::invalid_argument(msg),
+                e => image_status(e),
+            })?;
+        let ref_count = self.volume_ref_count(&volume.name).await;
+        Ok(Response::new(volume_info(volume, ref_count)))
+    }
+
+    type ListVolumesStream = ReceiverStream<Result<VolumeInfo, Status>>;
+
+    async fn list_volumes(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListVolumesStream>, Status> {
+        let volumes = self.volumes.list().map_err(image_status)?;
+        let mut rows = Vec::with_capacity(volumes.len());
+        for volume in volumes {
+            let ref_count = self.volume_ref_count(&volume.name).await;
+            rows.push(volume_info(volume, ref_count));
+        }
+        let (tx, rx) = mpsc::channel(4);
+
+        tokio::spawn(async move {
+            for row in rows {
+                if tx.send(Ok(row)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn inspect_volume(
+        &self,
+        request: Request<InspectVolumeRequest>,
+    ) -> Result<Response<VolumeInfo>, Status> {
+        let req = request.into_inner();
+        let volume = self.volumes.get(&req.name).map_err(image_status)?;
+        let ref_count = self.volume_ref_count(&volume.name).await;
+        Ok(Response::new(volume_info(volume, ref_count)))
+    }
+
+    async fn remove_volume(
+        &self,
+        request: Request<RemoveVolumeRequest>,
+    ) -> Result<Response<RemoveVolumeResponse>, Status> {
+        let req = request.into_inner();
+        // Keeps a container being created from mounting it meanwhile.
+        let _gc_guard = self.gc_lock.write().await;
+        let users = self.volume_ref_count(&req.name).await;
+        if users > 0 {
+            return Err(Status::failed_precondition(format!(
+                "volume {} is in use by {} container(s)",
+                req.name, users
+            )));
+        }
+
+        let volumes = self.volumes.clone();
+        tokio::task::spawn_blocking(move || volumes.remove(&req.name))
+            .await
+            .map_err(|e| Status::internal(format!("remove_volume panicked: {}", e)))?
+            .map_err(image_status)?;
+        Ok(Response::new(RemoveVolumeResponse {}))
+    }
+
+    async fn prune_volumes(
+        &self,
+        request: Request<PruneRequest>,
+    ) -> Result<Response<PruneVolumesResponse>, Status> {
+        let req = request.into_inner();
+        let _gc_guard = self.gc_lock.write().await;
+        let in_use: Vec<String> = {
+            let containers = self.containers.lock().await;
+            containers.values().flat_map(|c| c.volumes.iter().cloned()).collect()
+        };
+
+        let volumes = self.volumes.clone();
+        let response = tokio::task::spawn_blocking(move || -> AethelResult<PruneVolumesResponse> {
+            let mut response = PruneVolumesResponse::default();
+            for volume in volumes.list()? {
+                if in_use.contains(&volume.name) {
+                    continue;
+                }
+                response.reclaimed += volumes.disk_usage(&volume);
+                if !req.dry_run {
+                    volumes.remove(&volume.name)?;
+                }
+                response.volumes.push(volume.name);
+            }
+            Ok(response)
+        })
+        .await
+        .map_err(|e| Status::internal(format!("prune panicked: {}", e)))?
+        .map_err(|e| Status::internal(format!("prune failed: {}", e)))?;
+        Ok(Response::new(response))
+    }
+
+    type ExecStream = ReceiverStream<Result<ExecOutput, Status>>;
+
+    async fn exec(
+        &self,
+        request: Request<Streaming<ExecRequest>>,
+    ) -> Result<Response<Self::ExecStream>, Status> {
+        let mut requests = request.into_inner();
+        let mut req = requests
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("empty exec request"))?;
+        let first_input = req.input.take();
+        if req.command.is_empty() {
+            return Err(Status::invalid_argument("no command given"));
+        }
+        let container = self.find_container(&req.container_id).await?;
+        if container.status == "Paused" {
+            return Err(Status::failed_precondition(format!("container {} is paused", container.id)));
+        }
+        if container.status != "Running" {
+            return Err(Status::failed_precondition(format!(
+                "container {} is not running",
+                container.id
+            )));
+        }
+        let rootfs = self.container_rootfs(&container.id).await?;
+
+        let images = self.images.clone();
+        let process = tokio::task::spawn_blocking(move || -> AethelResult<ExecProcess> {
+            let (_, image) = resolve_image(&images, &container.image_target, container.platform.as_ref())?;
+            let mut env = image.config.env;
+            for var in &req.env {
+                let key = var.split_once('=').map_or(var.as_str(), |(key, _)| key);
+                env.retain(|pair| pair.split_once('=').map_or(pair.as_str(), |(k, _)| k) != key);
+                env.push(var.clone());
+            }
+            let working_dir = if req.working_dir.is_empty() { image.config.working_dir } else { req.working_dir };
+            let args: Vec<&str> = req.args.iter().map(String::as_str).collect();
+            let env: Vec<&str> = env.iter().map(String::as_str).collect();
+
+            let mut builder = ExecBuilder::new(container.pid as i32, &req.command)?.args(&args)?.env(&env)?;
+            if !working_dir.is_empty() {
+                builder = builder.with_working_dir(Path::new(&working_dir));
+            }
+            if !req.user.is_empty() {
+                let (uid, gid) = build::resolve_user(&rootfs, &req.user)?;
+                builder = builder.with_user(uid, gid);
+            }
+            if req.stdin {
+                builder = builder.with_stdin();
+            }
+            if req.tty {
+                builder = builder.with_terminal();
+            }
+            builder.spawn()
+        })
+        .await
+        .map_err(|e| Status::internal(format!("exec panicked: {}", e)))?
+        .map_err(|e| match e {
+            AethelError::Build(msg) => Status::invalid_argument(msg),
+            e => Status::internal(format!("exec failed: {}", e)),
+        })?;
+
+        let ExecProcess { pid, io } = process;
+        let (stdin, output) = match io {
+            ContainerIo::Pipes { stdin, stdout, stderr } => (attach::Stdin::pipe(stdin), (stdout, Some(stderr))),
+            ContainerIo::Terminal(master) => {
+                let stdin = attach::Stdin::terminal(&master)
+                    .map_err(|e| Status::internal(format!("exec failed: {}", e)))?;
+                (stdin, (master, None))
+            }
+        };
+        tokio::spawn(attach::forward_input(requests, first_input, stdin, |req| req.input));
+
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let (stdout, stderr) = output;
+            let stderr = async {
+                if let Some(stderr) = stderr {
+                    exec_forwarder(stderr, "stderr", tx.clone()).await;
+                }
+            };
+            tokio::join!(exec_forwarder(stdout, "stdout", tx.clone()), stderr);
+            let result = match tokio::task::spawn_blocking(move || exec::wait(pid)).await {
+                Ok(Ok(code)) => Ok(ExecOutput {
+                    exited: true,
+                    exit_code: code,
+                    ..Default::default()
+                }),
+                Ok(Err(e)) => Err(Status::internal(format!("exec failed: {}", e))),
+                Err(e) => Err(Status::internal(format!("exec panicked: {}", e))),
+            };
+            let _ = tx.send(result).await;
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type AttachStream = ReceiverStream<Result<AttachOutput, Status>>;
+
+    async fn attach(
+        &self,
+        request: Request<Streaming<AttachRequest>>,
+    ) -> Result<Response<Self::AttachStream>, Status> {
+        let mut requests = request.into_inner();
+        let first = requests
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("empty attach request"))?;
+        let container = self.find_container(&first.container_id).await?;
+        if container.status != "Running" {
+            return Err(Status::failed_precondition(format!(
+                "container {} is not running",
+                container.id
+            )));
+        }
+
+        let output = match container.unattached_output.lock().await.take() {
+            Some(output) => output,
+            None => self
+                .logs
+                .lock()
+                .await
+                .get(&container.id)
+                .ok_or_else(|| Status::not_found("Container not found"))?
+                .subscribe(),
+        };
+        let tty = container.stdin.is_terminal();
+        tokio::spawn(attach::forward_input(requests, first.input, container.stdin, |req| req.input));
+
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(attach::forward_output(output, container.exited, tty, tx));
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
 }
 
 #[tokio::main]
@@ -191,12 +1884,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Err("Failed to set up NAT".into());
     }
 
+    let root = std::env::var_os("AETHEL_ROOT").map_or_else(|| PathBuf::from(DEFAULT_ROOT), PathBuf::from);
+
     let addr = "[::1]:50051".parse()?;
     let service = MyAethelService {
         containers: Arc::new(Mutex::new(HashMap::new())),
-        log_broadcasters: Arc::new(Mutex::new(HashMap::new())),
+        logs: Arc::new(Mutex::new(HashMap::new())),
+        log_dir: root.join("containers"),
+        log_driver: std::env::var("AETHEL_LOG_DRIVER").unwrap_or_else(|_| log_drivers::DEFAULT_DRIVER.to_string()),
+        log_options: LogOptions::from_env()?,
         next_ip: Arc::new(Mutex::new(2)),
         net_handle: Arc::new(handle),
+        cni: Arc::new(Cni::default()),
+        snapshotter: Arc::new(Snapshotter::new(&root.join("overlay"))?),
+        registry: Arc::new(
+            RegistryClient::new()?.with_credentials(CredentialStore::load(Path::new(CREDENTIALS_FILE))?),
+        ),
+        images: Arc::new(ImageStore::new(&root.join("images"))?),
+        volumes: Arc::new(VolumeStore::new(&root.join("volumes"))?),
+        gc_lock: Arc::new(RwLock::new(())),
+        copies: Arc::new(Mutex::new(HashMap::new())),
+        build_dir: root.join("builds"),
     };
 
     Server::builder()
@@ -1,9 +1,22 @@
+use aethel_common::error::{AethelError, Result as AethelResult};
+use aethel_net::cni::{Cni, RuntimeConf};
+use futures::TryStreamExt;
+use netlink_packet_route::link::nlas::{Nla, Stats64, Stats64Buffer};
+use netlink_packet_utils::Parseable;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use rtnetlink::{new_connection, Handle};
+use std::fs::{self, File};
 use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+pub const BRIDGE_NETWORK: &str = "bridge";
 const BRIDGE_NAME: &str = "aethel0";
 const BRIDGE_IP: Ipv4Addr = Ipv4Addr::new(172, 29, 0, 1);
 const PREFIX_LEN: u8 = 24;
+/// A CNI container's network namespace is bound here, under its ID, so the
+/// plugins can still be handed it once the container has exited.
+const NETNS_DIR: &str = "/run/aethel/netns";
 
 pub async fn setup_bridge(handle: &Handle) -> Result<(), rtnetlink::Error> {
     let mut links = handle.link().get().match_name(BRIDGE_NAME.to_string()).execute();
@@ -25,7 +38,7 @@ pub async fn setup_container_net(
     container_id: &str,
     ip: Ipv4Addr,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let veth_name = format!("veth{}", &container_id[..8]);
+    let veth_name = host_veth(container_id);
     let peer_name = format!("vethp{}", &container_id[..8]);
 
     handle.link().add().veth(veth_name.clone(), peer_name.clone()).execute().await?;
@@ -50,4 +63,124 @@ pub async fn setup_container_net(
     new_handle.address().add(peer.header.index, ip.into(), PREFIX_LEN).execute().await?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// The host end of a bridge container's veth pair.
+fn host_veth(container_id: &str) -> String {
+    format!("veth{}", &container_id[..8])
+}
+
+/// The host ends of a container's interfaces: the bridge's veth, or those
+/// the CNI plugins reported for the namespace bound at `netns`.
+pub fn host_interfaces(cni: &Cni, network: &str, netns: Option<&Path>, container_id: &str) -> Vec<String> {
+    if network == BRIDGE_NETWORK {
+        return vec![host_veth(container_id)];
+    }
+    let Some(netns) = netns else {
+        return Vec::new();
+    };
+    let rt = RuntimeConf::new(container_id, netns);
+    match cni.cached_result(network, &rt) {
+        Ok(Some(result)) => result
+            .interfaces
+            .into_iter()
+            .filter(|interface| interface.sandbox.is_none())
+            .map(|interface| interface.name)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Bytes received and sent on the host interface `name`.
+pub async fn interface_counters(handle: &Handle, name: &str) -> AethelResult<(u64, u64)> {
+    let link = handle
+        .link()
+        .get()
+        .match_name(name.to_string())
+        .execute()
+        .try_next()
+        .await
+        .map_err(|e| AethelError::Network(format!("Failed to get link {}: {}", name, e)))?
+        .ok_or_else(|| AethelError::Network(format!("No link {}", name)))?;
+    let stats = link
+        .nlas
+        .iter()
+        .find_map(|nla| match nla {
+            Nla::Stats64(bytes) => Some(bytes),
+            _ => None,
+        })
+        .ok_or_else(|| AethelError::Network(format!("No counters for link {}", name)))?;
+    let stats = Stats64Buffer::new_checked(stats)
+        .and_then(|buf| Stats64::parse(&buf))
+        .map_err(|e| AethelError::Network(format!("Bad counters for link {}: {}", name, e)))?;
+    Ok((stats.rx_bytes, stats.tx_bytes))
+}
+
+/// Bind-mounts the network namespace of `container_pid`, which must still
+/// be running, where it outlives the process, and returns its path.
+pub fn bind_netns(container_pid: i32, container_id: &str) -> AethelResult<PathBuf> {
+    let path = Path::new(NETNS_DIR).join(container_id);
+    fs::create_dir_all(NETNS_DIR)?;
+    File::create(&path)?;
+    let source = PathBuf::from(format!("/proc/{}/ns/net", container_pid));
+    if let Err(e) = mount(Some(&source), &path, None::<&str>, MsFlags::MS_BIND, None::<&str>) {
+        let _ = fs::remove_file(&path);
+        return Err(AethelError::Network(format!("Failed to bind {}: {}", source.display(), e)));
+    }
+    Ok(path)
+}
+
+/// Unmounts and removes a namespace `bind_netns` bound.
+fn release_netns(path: &Path) -> AethelResult<()> {
+    umount2(path, MntFlags::MNT_DETACH)
+        .map_err(|e| AethelError::Network(format!("Failed to unmount {}: {}", path.display(), e)))?;
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+pub async fn setup_cni_net(
+    cni: Arc<Cni>,
+    network: String,
+    netns: PathBuf,
+    container_id: String,
+) -> AethelResult<Ipv4Addr> {
+    tokio::task::spawn_blocking(move || {
+        let net = cni.load_network(&network)?;
+        let rt = RuntimeConf::new(&container_id, &netns);
+        let result = cni.add(&net, &rt)?;
+        Ok(result.primary_ipv4().unwrap_or(Ipv4Addr::UNSPECIFIED))
+    })
+    .await
+    .map_err(|e| AethelError::Network(format!("CNI ADD task failed: {}", e)))?
+}
+
+/// Runs DEL against the namespace bound at `netns`, then releases it. A
+/// DEL that fails keeps it, for the next try.
+pub async fn teardown_cni_net(
+    cni: Arc<Cni>,
+    network: String,
+    netns: PathBuf,
+    container_id: String,
+) -> AethelResult<()> {
+    tokio::task::spawn_blocking(move || {
+        let rt = RuntimeConf::new(&container_id, &netns);
+        cni.del(&network, &rt)?;
+        release_netns(&netns)
+    })
+    .await
+    .map_err(|e| AethelError::Network(format!("CNI DEL task failed: {}", e)))?
+}
+
+pub async fn check_cni_net(
+    cni: Arc<Cni>,
+    network: String,
+    netns: PathBuf,
+    container_id: String,
+) -> AethelResult<()> {
+    tokio::task::spawn_blocking(move || {
+        let rt = RuntimeConf::new(&container_id, &netns);
+        cni.check(&network, &rt)
+    })
+    .await
+    .map_err(|e| AethelError::Network(format!("CNI CHECK task failed: {}", e)))?
+}
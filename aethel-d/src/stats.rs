@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use aethel_common::proto::aethel::{ContainerStats, StatsSample};
+use aethel_common::time;
+use aethel_run::cgroup::{self, CgroupStats};
+use rtnetlink::Handle;
+use tokio::sync::mpsc;
+use tonic::Status;
+
+use crate::network;
+
+/// How long between samples.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// A container being sampled.
+pub struct Target {
+    pub id: String,
+    pub cgroup: PathBuf,
+    /// The host ends of its interfaces.
+    pub interfaces: Vec<String>,
+}
+
+/// Sends a sample of every target each interval, until the client hangs
+/// up; with `once`, just the first. The first takes an interval too, for
+/// there to be CPU time to compare.
+pub async fn stream(
+    targets: Vec<Target>,
+    handle: &Handle,
+    once: bool,
+    tx: mpsc::Sender<Result<StatsSample, Status>>,
+) {
+    let mut previous: HashMap<String, (Instant, u64)> = HashMap::new();
+    let mut ticker = tokio::time::interval(INTERVAL);
+    loop {
+        ticker.tick().await;
+        let cgroups: Vec<PathBuf> = targets.iter().map(|t| t.cgroup.clone()).collect();
+        let read = tokio::task::spawn_blocking(move || {
+            cgroups.iter().map(|path| cgroup::stats(path)).collect::<Result<Vec<_>, _>>()
+        })
+        .await;
+        let readings = match read {
+            Ok(Ok(readings)) => readings,
+            Ok(Err(e)) => {
+                let _ = tx.send(Err(Status::internal(format!("failed to read stats: {}", e)))).await;
+                return;
+            }
+            Err(e) => {
+                let _ = tx.send(Err(Status::internal(format!("stats panicked: {}", e)))).await;
+                return;
+            }
+        };
+        let now = Instant::now();
+
+        let mut sample = StatsSample {
+            timestamp: time::format_rfc3339(SystemTime::now()),
+            containers: Vec::with_capacity(targets.len()),
+        };
+        let mut complete = true;
+        for (target, reading) in targets.iter().zip(readings) {
+            let last = previous.insert(target.id.clone(), (now, reading.cpu_usage_usec));
+            let Some((then, usage)) = last else {
+                complete = false;
+                continue;
+            };
+            let elapsed = now.duration_since(then).as_micros() as f64;
+            let used = reading.cpu_usage_usec.saturating_sub(usage) as f64;
+            let cpu_percent = if elapsed > 0.0 { used / elapsed * 100.0 } else { 0.0 };
+            sample.containers.push(container_stats(target, reading, cpu_percent, handle).await);
+        }
+        if !complete {
+            continue;
+        }
+        if tx.send(Ok(sample)).await.is_err() || once {
+            return;
+        }
+    }
+}
+
+async fn container_stats(target: &Target, reading: CgroupStats, cpu_percent: f64, handle: &Handle) -> ContainerStats {
+    // What the host end receives, the container sent; an interface that
+    // has gone away counts nothing.
+    let (mut rx, mut tx) = (0, 0);
+    for name in &target.interfaces {
+        if let Ok((received, sent)) = network::interface_counters(handle, name).await {
+            rx += sent;
+            tx += received;
+        }
+    }
+    ContainerStats {
+        container_id: target.id.clone(),
+        cpu_percent,
+        cpu_usage_usec: reading.cpu_usage_usec,
+        memory_usage: reading.memory_current.saturating_sub(reading.memory_inactive_file),
+        memory_limit: reading.memory_max.unwrap_or(0),
+        memory_cache: reading.memory_file,
+        pids: reading.pids_current,
+        block_read_bytes: reading.io_read_bytes,
+        block_write_bytes: reading.io_write_bytes,
+        net_rx_bytes: rx,
+        net_tx_bytes: tx,
+    }
+}
@@ -0,0 +1,703 @@
+use aethel_common::error::{AethelError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+pub const DEFAULT_CONF_DIR: &str = "/etc/cni/net.d";
+pub const DEFAULT_BIN_DIR: &str = "/opt/cni/bin";
+pub const DEFAULT_CACHE_DIR: &str = "/var/lib/aethel/cni";
+
+/// A CNI network configuration list (`.conflist`), or a single plugin
+/// `.conf` file normalised into a list of one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkConfigList {
+    #[serde(rename = "cniVersion")]
+    pub cni_version: String,
+    pub name: String,
+    #[serde(rename = "disableCheck", default)]
+    pub disable_check: bool,
+    pub plugins: Vec<Map<String, Value>>,
+}
+
+impl NetworkConfigList {
+    fn from_bytes(bytes: &[u8], is_list: bool) -> Result<Self> {
+        if is_list {
+            return serde_json::from_slice(bytes)
+                .map_err(|e| AethelError::Network(format!("Failed to parse conflist: {}", e)));
+        }
+
+        let plugin: Map<String, Value> = serde_json::from_slice(bytes)
+            .map_err(|e| AethelError::Network(format!("Failed to parse conf: {}", e)))?;
+        let field = |key: &str| {
+            plugin
+                .get(key)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| AethelError::Network(format!("CNI conf is missing '{}'", key)))
+        };
+
+        Ok(NetworkConfigList {
+            cni_version: field("cniVersion")?,
+            name: field("name")?,
+            disable_check: false,
+            plugins: vec![plugin],
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeConf {
+    pub container_id: String,
+    pub netns: PathBuf,
+    pub if_name: String,
+    pub args: Vec<(String, String)>,
+    /// Values for plugin `capabilities`, e.g. `portMappings` for the portmap plugin.
+    pub capability_args: Map<String, Value>,
+}
+
+impl RuntimeConf {
+    pub fn new(container_id: &str, netns: &Path) -> Self {
+        RuntimeConf {
+            container_id: container_id.to_string(),
+            netns: netns.to_path_buf(),
+            if_name: "eth0".to_string(),
+            args: vec![],
+            capability_args: Map::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interface {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IpConfig {
+    pub address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Route {
+    pub dst: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gw: Option<String>,
+}
+
+/// The result returned by an ADD, as defined by the CNI spec. Fields we do
+/// not model are kept in `extra` so the result can be passed on unchanged
+/// as `prevResult`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CniResult {
+    #[serde(rename = "cniVersion")]
+    pub cni_version: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interfaces: Vec<Interface>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ips: Vec<IpConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Route>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl CniResult {
+    pub fn primary_ipv4(&self) -> Option<Ipv4Addr> {
+        self.ips.iter().find_map(|ip| {
+            let addr = ip.address.split('/').next()?;
+            addr.parse().ok()
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CniErrorReply {
+    code: u32,
+    msg: String,
+    #[serde(default)]
+    details: String,
+}
+
+/// What is written to the cache directory after a successful ADD. CHECK and
+/// DEL need the exact config and result the network was set up with.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedAttachment {
+    #[serde(rename = "containerId")]
+    container_id: String,
+    #[serde(rename = "ifName")]
+    if_name: String,
+    netns: PathBuf,
+    #[serde(rename = "networkName")]
+    network_name: String,
+    config: NetworkConfigList,
+    result: CniResult,
+}
+
+#[derive(Clone, Copy)]
+enum CniCommand {
+    Add,
+    Check,
+    Del,
+}
+
+impl CniCommand {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CniCommand::Add => "ADD",
+            CniCommand::Check => "CHECK",
+            CniCommand::Del => "DEL",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cni {
+    conf_dir: PathBuf,
+    bin_dirs: Vec<PathBuf>,
+    cache_dir: PathBuf,
+}
+
+impl Default for Cni {
+    fn default() -> Self {
+        Cni::new(
+            Path::new(DEFAULT_CONF_DIR),
+            vec![PathBuf::from(DEFAULT_BIN_DIR)],
+            Path::new(DEFAULT_CACHE_DIR),
+        )
+    }
+}
+
+impl Cni {
+    pub fn new(conf_dir: &Path, bin_dirs: Vec<PathBuf>, cache_dir: &Path) -> Self {
+        Cni {
+            conf_dir: conf_dir.to_path_buf(),
+            bin_dirs,
+            cache_dir: cache_dir.to_path_buf(),
+        }
+    }
+
+    pub fn list_networks(&self) -> Result<Vec<NetworkConfigList>> {
+        let mut files: Vec<PathBuf> = fs::read_dir(&self.conf_dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                matches!(
+                    path.extension().and_then(|ext| ext.to_str()),
+                    Some("conflist") | Some("conf") | Some("json")
+                )
+            })
+            .collect();
+        files.sort();
+
+        let mut networks = Vec::with_capacity(files.len());
+        for path in files {
+            let bytes = fs::read(&path)?;
+            let is_list = path.extension().and_then(|ext| ext.to_str()) == Some("conflist");
+            networks.push(NetworkConfigList::from_bytes(&bytes, is_list)?);
+        }
+        Ok(networks)
+    }
+
+    pub fn load_network(&self, name: &str) -> Result<NetworkConfigList> {
+        self.list_networks()?
+            .into_iter()
+            .find(|net| net.name == name)
+            .ok_or_else(|| {
+                AethelError::Network(format!(
+                    "CNI network '{}' not found in {}",
+                    name,
+                    self.conf_dir.display()
+                ))
+            })
+    }
+
+    /// Runs ADD for every plugin in the list, chaining each result into the
+    /// next plugin as `prevResult`, and caches the final result. If a plugin
+    /// fails, DEL is run for it and every plugin before it, so a failed ADD
+    /// leaves nothing behind.
+    pub fn add(&self, net: &NetworkConfigList, rt: &RuntimeConf) -> Result<CniResult> {
+        let mut prev: Option<CniResult> = None;
+        for (i, plugin) in net.plugins.iter().enumerate() {
+            let added = self.exec_plugin(CniCommand::Add, net, plugin, rt, prev.as_ref()).and_then(|stdout| {
+                serde_json::from_slice::<CniResult>(&stdout).map_err(|e| {
+                    AethelError::Network(format!("Failed to parse result from CNI plugin: {}", e))
+                })
+            });
+            match added {
+                Ok(result) => prev = Some(result),
+                Err(e) => {
+                    for plugin in net.plugins[..=i].iter().rev() {
+                        let _ = self.exec_plugin(CniCommand::Del, net, plugin, rt, prev.as_ref());
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let result = prev.ok_or_else(|| {
+            AethelError::Network(format!("CNI network '{}' has no plugins", net.name))
+        })?;
+        self.write_cache(net, rt, &result)?;
+        Ok(result)
+    }
+
+    /// Runs CHECK for every plugin against the config and result cached by
+    /// ADD.
+    pub fn check(&self, network: &str, rt: &RuntimeConf) -> Result<()> {
+        let cached = self.read_cache(network, rt)?.ok_or_else(|| {
+            AethelError::Network(format!(
+                "No cached result for container {} on network '{}'",
+                rt.container_id, network
+            ))
+        })?;
+        let net = &cached.config;
+        if net.disable_check {
+            return Ok(());
+        }
+        if !supports_check(&net.cni_version) {
+            return Err(AethelError::Network(format!(
+                "CHECK is not supported by CNI version {}",
+                net.cni_version
+            )));
+        }
+
+        for plugin in &net.plugins {
+            self.exec_plugin(CniCommand::Check, net, plugin, rt, Some(&cached.result))?;
+        }
+        Ok(())
+    }
+
+    /// Runs DEL for every plugin in reverse order and drops the cached result.
+    /// DEL uses the config ADD cached, so a network whose conf file has since
+    /// changed or gone is still torn down as it was set up; only without a
+    /// cache entry is the conf dir consulted. DEL must be idempotent.
+    pub fn del(&self, network: &str, rt: &RuntimeConf) -> Result<()> {
+        let cached = self.read_cache(network, rt)?;
+        let (net, prev) = match &cached {
+            Some(cached) => (cached.config.clone(), Some(&cached.result)),
+            None => (self.load_network(network)?, None),
+        };
+        for plugin in net.plugins.iter().rev() {
+            self.exec_plugin(CniCommand::Del, &net, plugin, rt, prev)?;
+        }
+
+        match fs::remove_file(self.cache_path(network, rt)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn cached_result(&self, network: &str, rt: &RuntimeConf) -> Result<Option<CniResult>> {
+        Ok(self.read_cache(network, rt)?.map(|c| c.result))
+    }
+
+    fn exec_plugin(
+        &self,
+        command: CniCommand,
+        net: &NetworkConfigList,
+        plugin: &Map<String, Value>,
+        rt: &RuntimeConf,
+        prev: Option<&CniResult>,
+    ) -> Result<Vec<u8>> {
+        let plugin_type = plugin
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or_else(|| AethelError::Network("CNI plugin config is missing 'type'".to_string()))?;
+        let binary = self.find_plugin(plugin_type)?;
+        let stdin = serde_json::to_vec(&plugin_config(net, plugin, rt, prev)).map_err(|e| {
+            AethelError::Network(format!("Failed to encode config for {}: {}", plugin_type, e))
+        })?;
+
+        let cni_args = rt
+            .args
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(";");
+        let cni_path = std::env::join_paths(&self.bin_dirs)
+            .map_err(|e| AethelError::Network(format!("Invalid CNI_PATH: {}", e)))?;
+
+        let mut child = Command::new(&binary)
+            .env("CNI_COMMAND", command.as_str())
+            .env("CNI_CONTAINERID", &rt.container_id)
+            .env("CNI_NETNS", &rt.netns)
+            .env("CNI_IFNAME", &rt.if_name)
+            .env("CNI_ARGS", cni_args)
+            .env("CNI_PATH", cni_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        if let Some(mut pipe) = child.stdin.take() {
+            pipe.write_all(&stdin)?;
+        }
+        let output = child.wait_with_output()?;
+
+        if output.status.success() {
+            return Ok(output.stdout);
+        }
+
+        let reason = match serde_json::from_slice::<CniErrorReply>(&output.stdout) {
+            Ok(reply) if reply.details.is_empty() => format!("code {}: {}", reply.code, reply.msg),
+            Ok(reply) => format!("code {}: {} ({})", reply.code, reply.msg, reply.details),
+            Err(_) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        };
+        Err(AethelError::Network(format!(
+            "CNI plugin {} {} failed: {}",
+            plugin_type,
+            command.as_str(),
+            reason
+        )))
+    }
+
+    fn find_plugin(&self, plugin_type: &str) -> Result<PathBuf> {
+        self.bin_dirs
+            .iter()
+            .map(|dir| dir.join(plugin_type))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                AethelError::Network(format!("CNI plugin '{}' not found in CNI_PATH", plugin_type))
+            })
+    }
+
+    fn cache_path(&self, network: &str, rt: &RuntimeConf) -> PathBuf {
+        self.cache_dir
+            .join("results")
+            .join(format!("{}-{}-{}", network, rt.container_id, rt.if_name))
+    }
+
+    fn write_cache(&self, net: &NetworkConfigList, rt: &RuntimeConf, result: &CniResult) -> Result<()> {
+        let path = self.cache_path(&net.name, rt);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let entry = CachedAttachment {
+            container_id: rt.container_id.clone(),
+            if_name: rt.if_name.clone(),
+            netns: rt.netns.clone(),
+            network_name: net.name.clone(),
+            config: net.clone(),
+            result: result.clone(),
+        };
+        let bytes = serde_json::to_vec(&entry)
+            .map_err(|e| AethelError::Network(format!("Failed to encode CNI cache entry: {}", e)))?;
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    fn read_cache(&self, network: &str, rt: &RuntimeConf) -> Result<Option<CachedAttachment>> {
+        let bytes = match fs::read(self.cache_path(network, rt)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| AethelError::Network(format!("Failed to parse CNI cache entry: {}", e)))
+    }
+}
+
+/// Builds the stdin document for one plugin invocation: the plugin's own
+/// config plus the network-level fields, `prevResult` and any
+/// `runtimeConfig` entries for capabilities the plugin declares.
+fn plugin_config(
+    net: &NetworkConfigList,
+    plugin: &Map<String, Value>,
+    rt: &RuntimeConf,
+    prev: Option<&CniResult>,
+) -> Value {
+    let mut conf = plugin.clone();
+    conf.insert("cniVersion".to_string(), Value::String(net.cni_version.clone()));
+    conf.insert("name".to_string(), Value::String(net.name.clone()));
+    conf.remove("capabilities");
+
+    if let Some(prev) = prev {
+        if let Ok(value) = serde_json::to_value(prev) {
+            conf.insert("prevResult".to_string(), value);
+        }
+    }
+
+    if let Some(Value::Object(caps)) = plugin.get("capabilities") {
+        let runtime_config: Map<String, Value> = caps
+            .iter()
+            .filter(|(_, enabled)| enabled.as_bool() == Some(true))
+            .filter_map(|(cap, _)| rt.capability_args.get(cap).map(|v| (cap.clone(), v.clone())))
+            .collect();
+        if !runtime_config.is_empty() {
+            conf.insert("runtimeConfig".to_string(), Value::Object(runtime_config));
+        }
+    }
+
+    Value::Object(conf)
+}
+
+/// CHECK was introduced in CNI 0.4.0.
+fn supports_check(version: &str) -> bool {
+    let mut parts = version.split('.').map(|p| p.parse::<u32>().unwrap_or(0));
+    let major = parts.next().unwrap_or(0);
+    let minor = parts.next().unwrap_or(0);
+    major > 0 || minor >= 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    /// A conf dir, a bin dir of stub plugins and a cache dir. Each stub
+    /// appends `<command> <type> <stdin>` to `calls`, fails the command
+    /// named in its config's `fail` field, and answers ADD with an
+    /// interface named after itself.
+    struct Fixture {
+        dir: TempDir,
+        cni: Cni,
+    }
+
+    impl Fixture {
+        fn new(plugins: &[&str]) -> Self {
+            let dir = TempDir::new().unwrap();
+            let (conf, bin, cache) = (dir.path().join("net.d"), dir.path().join("bin"), dir.path().join("cache"));
+            fs::create_dir_all(&conf).unwrap();
+            fs::create_dir_all(&bin).unwrap();
+            let calls = dir.path().join("calls");
+            for plugin in plugins {
+                let script = format!(
+                    r#"#!/bin/sh
+input=$(cat)
+echo "$CNI_COMMAND {plugin} $input" >> {calls}
+case "$input" in
+*'"fail":"'$CNI_COMMAND'"'*)
+    echo '{{"code":7,"msg":"told to fail"}}'
+    exit 1 ;;
+esac
+if [ "$CNI_COMMAND" = ADD ]; then
+    echo '{{"cniVersion":"1.0.0","interfaces":[{{"name":"{plugin}0"}}],"ips":[{{"address":"10.88.0.5/16"}}]}}'
+fi
+"#,
+                    plugin = plugin,
+                    calls = calls.display()
+                );
+                let path = bin.join(plugin);
+                fs::write(&path, script).unwrap();
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            let cni = Cni::new(&conf, vec![bin], &cache);
+            Fixture { dir, cni }
+        }
+
+        fn write_conf(&self, file: &str, conf: &Value) {
+            fs::write(self.dir.path().join("net.d").join(file), serde_json::to_vec(conf).unwrap()).unwrap();
+        }
+
+        /// `(command, plugin, stdin)` for every plugin run so far.
+        fn calls(&self) -> Vec<(String, String, Value)> {
+            let calls = fs::read_to_string(self.dir.path().join("calls")).unwrap_or_default();
+            calls
+                .lines()
+                .map(|line| {
+                    let mut parts = line.splitn(3, ' ');
+                    let command = parts.next().unwrap().to_string();
+                    let plugin = parts.next().unwrap().to_string();
+                    (command, plugin, serde_json::from_str(parts.next().unwrap()).unwrap())
+                })
+                .collect()
+        }
+
+        fn commands(&self) -> Vec<String> {
+            self.calls().into_iter().map(|(command, plugin, _)| format!("{} {}", command, plugin)).collect()
+        }
+    }
+
+    fn conflist(version: &str, plugins: Value) -> Value {
+        json!({ "cniVersion": version, "name": "testnet", "plugins": plugins })
+    }
+
+    fn runtime() -> RuntimeConf {
+        RuntimeConf::new("c0ffee", Path::new("/proc/1/ns/net"))
+    }
+
+    #[test]
+    fn parses_conflists_and_single_confs() {
+        let fixture = Fixture::new(&[]);
+        fixture.write_conf(
+            "10-list.conflist",
+            &json!({
+                "cniVersion": "1.0.0",
+                "name": "listnet",
+                "disableCheck": true,
+                "plugins": [{ "type": "bridge" }, { "type": "portmap" }],
+            }),
+        );
+        fixture.write_conf("20-single.conf", &json!({ "cniVersion": "0.3.1", "name": "single", "type": "bridge" }));
+        fixture.write_conf("README", &json!({}));
+
+        let networks = fixture.cni.list_networks().unwrap();
+
+        assert_eq!(networks.len(), 2);
+        assert_eq!(networks[0].name, "listnet");
+        assert!(networks[0].disable_check);
+        assert_eq!(networks[0].plugins.len(), 2);
+        assert_eq!(networks[1].name, "single");
+        assert_eq!(networks[1].cni_version, "0.3.1");
+        assert_eq!(networks[1].plugins[0]["type"], "bridge");
+        assert!(fixture.cni.load_network("missing").is_err());
+
+        let missing_name = NetworkConfigList::from_bytes(br#"{"cniVersion":"0.4.0","type":"bridge"}"#, false);
+        assert!(matches!(missing_name, Err(AethelError::Network(msg)) if msg.contains("'name'")));
+        assert!(NetworkConfigList::from_bytes(b"{", true).is_err());
+    }
+
+    #[test]
+    fn check_needs_cni_0_4() {
+        assert!(!supports_check("0.3.1"));
+        assert!(!supports_check("0.2.0"));
+        assert!(!supports_check(""));
+        assert!(supports_check("0.4.0"));
+        assert!(supports_check("1.0.0"));
+        assert!(supports_check("1.1"));
+    }
+
+    #[test]
+    fn builds_plugin_config() {
+        let net: NetworkConfigList = serde_json::from_value(conflist(
+            "1.0.0",
+            json!([{
+                "type": "portmap",
+                "name": "overridden",
+                "capabilities": { "portMappings": true, "bandwidth": false },
+            }]),
+        ))
+        .unwrap();
+        let mut rt = runtime();
+        rt.capability_args.insert("portMappings".to_string(), json!([{ "hostPort": 8080 }]));
+        rt.capability_args.insert("bandwidth".to_string(), json!({ "ingressRate": 1 }));
+        let prev: CniResult = serde_json::from_value(json!({
+            "cniVersion": "1.0.0",
+            "ips": [{ "address": "10.88.0.5/16" }],
+            "dns": { "nameservers": ["10.88.0.1"] },
+        }))
+        .unwrap();
+
+        let conf = plugin_config(&net, &net.plugins[0], &rt, Some(&prev));
+
+        assert_eq!(conf["cniVersion"], "1.0.0");
+        assert_eq!(conf["name"], "testnet");
+        assert_eq!(conf["type"], "portmap");
+        assert!(conf.get("capabilities").is_none());
+        assert_eq!(conf["runtimeConfig"], json!({ "portMappings": [{ "hostPort": 8080 }] }));
+        assert_eq!(conf["prevResult"]["ips"][0]["address"], "10.88.0.5/16");
+        assert_eq!(conf["prevResult"]["dns"]["nameservers"][0], "10.88.0.1");
+
+        let bare = plugin_config(&net, &json!({ "type": "bridge" }).as_object().unwrap().clone(), &rt, None);
+        assert!(bare.get("prevResult").is_none());
+        assert!(bare.get("runtimeConfig").is_none());
+    }
+
+    #[test]
+    fn add_chains_results_and_caches_them() {
+        let fixture = Fixture::new(&["first", "second"]);
+        let net: NetworkConfigList =
+            serde_json::from_value(conflist("1.0.0", json!([{ "type": "first" }, { "type": "second" }]))).unwrap();
+        let rt = runtime();
+
+        let result = fixture.cni.add(&net, &rt).unwrap();
+
+        assert_eq!(result.primary_ipv4(), Some(Ipv4Addr::new(10, 88, 0, 5)));
+        assert_eq!(result.interfaces[0].name, "second0");
+        let calls = fixture.calls();
+        assert_eq!(fixture.commands(), ["ADD first", "ADD second"]);
+        assert!(calls[0].2.get("prevResult").is_none());
+        assert_eq!(calls[1].2["prevResult"]["interfaces"][0]["name"], "first0");
+        let cached = fixture.cni.cached_result("testnet", &rt).unwrap().unwrap();
+        assert_eq!(cached.interfaces[0].name, "second0");
+        assert!(fixture.cni.cached_result("othernet", &rt).unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_add_is_rolled_back() {
+        let fixture = Fixture::new(&["first", "second", "third"]);
+        let net: NetworkConfigList = serde_json::from_value(conflist(
+            "1.0.0",
+            json!([{ "type": "first" }, { "type": "second", "fail": "ADD" }, { "type": "third" }]),
+        ))
+        .unwrap();
+        let rt = runtime();
+
+        let err = fixture.cni.add(&net, &rt).unwrap_err();
+
+        assert!(err.to_string().contains("code 7: told to fail"), "{}", err);
+        assert_eq!(fixture.commands(), ["ADD first", "ADD second", "DEL second", "DEL first"]);
+        assert!(fixture.cni.cached_result("testnet", &rt).unwrap().is_none());
+    }
+
+    #[test]
+    fn del_uses_the_cached_config() {
+        let fixture = Fixture::new(&["first", "second"]);
+        fixture.write_conf(
+            "10-test.conflist",
+            &conflist("1.0.0", json!([{ "type": "first", "generation": 1 }, { "type": "second" }])),
+        );
+        let rt = runtime();
+        fixture.cni.add(&fixture.cni.load_network("testnet").unwrap(), &rt).unwrap();
+        // Changed after ADD; DEL must undo what ADD did, not this.
+        fixture.write_conf("10-test.conflist", &conflist("1.0.0", json!([{ "type": "first", "generation": 2 }])));
+
+        fixture.cni.del("testnet", &rt).unwrap();
+
+        let calls = fixture.calls();
+        assert_eq!(fixture.commands(), ["ADD first", "ADD second", "DEL second", "DEL first"]);
+        assert_eq!(calls[3].2["generation"], 1);
+        assert_eq!(calls[3].2["prevResult"]["interfaces"][0]["name"], "second0");
+        assert!(fixture.cni.cached_result("testnet", &rt).unwrap().is_none());
+
+        // Without a cache entry, DEL falls back to the conf dir.
+        fixture.cni.del("testnet", &rt).unwrap();
+        let calls = fixture.calls();
+        assert_eq!(calls.len(), 5);
+        assert_eq!(calls[4].2["generation"], 2);
+        assert!(calls[4].2.get("prevResult").is_none());
+    }
+
+    #[test]
+    fn check_runs_against_the_cached_result() {
+        let fixture = Fixture::new(&["first"]);
+        let rt = runtime();
+        assert!(fixture.cni.check("testnet", &rt).is_err());
+
+        let net: NetworkConfigList = serde_json::from_value(conflist("1.0.0", json!([{ "type": "first" }]))).unwrap();
+        fixture.cni.add(&net, &rt).unwrap();
+        fixture.cni.check("testnet", &rt).unwrap();
+        let calls = fixture.calls();
+        assert_eq!(fixture.commands(), ["ADD first", "CHECK first"]);
+        assert_eq!(calls[1].2["prevResult"]["interfaces"][0]["name"], "first0");
+
+        let old: NetworkConfigList =
+            serde_json::from_value(conflist("0.3.1", json!([{ "type": "first" }]))).unwrap();
+        fixture.cni.add(&old, &rt).unwrap();
+        assert!(fixture.cni.check("testnet", &rt).is_err());
+
+        let disabled = NetworkConfigList {
+            disable_check: true,
+            ..net
+        };
+        fixture.cni.add(&disabled, &rt).unwrap();
+        fixture.cni.check("testnet", &rt).unwrap();
+        assert_eq!(fixture.commands().iter().filter(|c| c.starts_with("CHECK")).count(), 1);
+    }
+}
@@ -0,0 +1,227 @@
+use aethel_common::error::{AethelError, Result};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek};
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Where the unified cgroup v2 hierarchy is mounted.
+const MOUNT_ROOT: &str = "/sys/fs/cgroup";
+/// Containers' cgroups are made under this one, off the root.
+const PARENT: &str = "aethel";
+/// Handed down to containers where the host has them.
+const CONTROLLERS: [&str; 4] = ["cpu", "io", "memory", "pids"];
+
+/// Creates the cgroup v2 group for container `id`, with whichever of the
+/// controllers `stats` reads the host can hand down, and returns its path.
+pub fn create(id: &str) -> Result<PathBuf> {
+    let root = Path::new(MOUNT_ROOT);
+    if !root.join("cgroup.controllers").exists() {
+        return Err(AethelError::Cgroup(format!("No cgroup v2 hierarchy at {}", MOUNT_ROOT)));
+    }
+    let parent = root.join(PARENT);
+    fs::create_dir_all(&parent)
+        .map_err(|e| AethelError::Cgroup(format!("Failed to create {}: {}", parent.display(), e)))?;
+    // A controller is only in a group if every group above enables it for
+    // their children; some may be missing, which only leaves their files out.
+    for dir in [root, parent.as_path()] {
+        for controller in CONTROLLERS {
+            let _ = fs::write(dir.join("cgroup.subtree_control"), format!("+{}", controller));
+        }
+    }
+
+    let path = parent.join(id);
+    fs::create_dir(&path).map_err(|e| AethelError::Cgroup(format!("Failed to create {}: {}", path.display(), e)))?;
+    Ok(path)
+}
+
+/// Removes a container's group once nothing is left in it.
+pub fn remove(path: &Path) -> Result<()> {
+    match fs::remove_dir(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(AethelError::Cgroup(format!("Failed to remove {}: {}", path.display(), e))),
+    }
+}
+
+/// Freezes every process in the group at `path`, or thaws them, and waits
+/// up to `timeout` for the kernel to say it is done: a task stuck in the
+/// kernel only stops once it gets back out. A group that does not freeze
+/// in time is thawed again rather than left half frozen.
+pub fn freeze(path: &Path, frozen: bool, timeout: Duration) -> Result<()> {
+    let failed = |what: &str, e: io::Error| AethelError::Cgroup(format!("Failed to {} {}: {}", what, path.display(), e));
+    let mut events = File::open(path.join("cgroup.events")).map_err(|e| failed("watch", e))?;
+    fs::write(path.join("cgroup.freeze"), if frozen { "1" } else { "0" }).map_err(|e| failed("freeze", e))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut content = String::new();
+    loop {
+        content.clear();
+        events.rewind().map_err(|e| failed("read", e))?;
+        events.read_to_string(&mut content).map_err(|e| failed("read", e))?;
+        if is_frozen(&content) == frozen {
+            return Ok(());
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            if frozen {
+                let _ = fs::write(path.join("cgroup.freeze"), "0");
+            }
+            let state = if frozen { "freeze" } else { "thaw" };
+            return Err(AethelError::Cgroup(format!("Timed out waiting for {} to {}", path.display(), state)));
+        }
+        // The file signals a change as an exceptional condition.
+        let mut fds = [PollFd::new(events.as_fd(), PollFlags::POLLPRI)];
+        poll(&mut fds, PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX))?;
+    }
+}
+
+/// Whether `cgroup.events` says the group is frozen.
+fn is_frozen(events: &str) -> bool {
+    events.lines().any(|line| line.trim() == "frozen 1")
+}
+
+/// What a group has used so far, as its files say; counters are since it
+/// was created.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CgroupStats {
+    /// CPU time in microseconds, in all and in user and kernel mode.
+    pub cpu_usage_usec: u64,
+    pub cpu_user_usec: u64,
+    pub cpu_system_usec: u64,
+    /// Memory charged to the group, page cache included.
+    pub memory_current: u64,
+    /// `None` when unlimited.
+    pub memory_max: Option<u64>,
+    /// Page cache the kernel would reclaim first, which docker leaves out
+    /// of the usage it shows.
+    pub memory_inactive_file: u64,
+    /// All page cache.
+    pub memory_file: u64,
+    pub io_read_bytes: u64,
+    pub io_write_bytes: u64,
+    pub pids_current: u64,
+}
+
+/// Reads the stats of the group at `path`. The files of a controller the
+/// group does not have count as zero.
+pub fn stats(path: &Path) -> Result<CgroupStats> {
+    let read = |name: &str| read_file(path, name);
+    let single = |name: &str| -> Result<u64> { Ok(read(name)?.trim().parse().unwrap_or(0)) };
+
+    let (cpu, memory) = (read("cpu.stat")?, read("memory.stat")?);
+    let (cpu, memory) = (parse_flat_keyed(&cpu), parse_flat_keyed(&memory));
+    let (io_read_bytes, io_write_bytes) = parse_io_stat(&read("io.stat")?);
+    let field = |map: &HashMap<&str, u64>, key| map.get(key).copied().unwrap_or(0);
+    Ok(CgroupStats {
+        cpu_usage_usec: field(&cpu, "usage_usec"),
+        cpu_user_usec: field(&cpu, "user_usec"),
+        cpu_system_usec: field(&cpu, "system_usec"),
+        memory_current: single("memory.current")?,
+        memory_max: read("memory.max")?.trim().parse().ok(),
+        memory_inactive_file: field(&memory, "inactive_file"),
+        memory_file: field(&memory, "file"),
+        io_read_bytes,
+        io_write_bytes,
+        pids_current: single("pids.current")?,
+    })
+}
+
+/// The limits set on a group, 0 where there is none.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CgroupLimits {
+    pub memory_max: u64,
+    pub pids_max: u64,
+    /// CPU time in microseconds the group may use every period.
+    pub cpu_quota_usec: u64,
+    pub cpu_period_usec: u64,
+}
+
+/// Reads the limits of the group at `path`, as `stats` reads its usage.
+pub fn limits(path: &Path) -> Result<CgroupLimits> {
+    let read = |name: &str| read_file(path, name);
+    let single = |name: &str| -> Result<u64> { Ok(read(name)?.trim().parse().unwrap_or(0)) };
+
+    let (cpu_quota_usec, cpu_period_usec) = parse_cpu_max(&read("cpu.max")?);
+    Ok(CgroupLimits {
+        memory_max: single("memory.max")?,
+        pids_max: single("pids.max")?,
+        cpu_quota_usec,
+        cpu_period_usec,
+    })
+}
+
+/// Processes the OOM killer has killed in the group at `path`, as its
+/// `memory.events` counts them.
+pub fn oom_kills(path: &Path) -> Result<u64> {
+    let events = read_file(path, "memory.events")?;
+    Ok(parse_flat_keyed(&events).get("oom_kill").copied().unwrap_or(0))
+}
+
+/// A file of the group at `path`; empty when its controller is missing.
+fn read_file(path: &Path, name: &str) -> Result<String> {
+    match fs::read_to_string(path.join(name)) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(AethelError::Cgroup(format!("Failed to read {}: {}", path.join(name).display(), e))),
+    }
+}
+
+/// `cpu.max` is `QUOTA PERIOD`, with `max` as the quota when unlimited.
+fn parse_cpu_max(content: &str) -> (u64, u64) {
+    let mut fields = content.split_whitespace().map(|field| field.parse().unwrap_or(0));
+    (fields.next().unwrap_or(0), fields.next().unwrap_or(0))
+}
+
+/// `key value` lines, as `cpu.stat` and `memory.stat` have.
+fn parse_flat_keyed(content: &str) -> HashMap<&str, u64> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key, value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Bytes read and written over every device; `io.stat` has a line per
+/// device, `MAJ:MIN rbytes=N wbytes=N ...`.
+fn parse_io_stat(content: &str) -> (u64, u64) {
+    let (mut read, mut written) = (0, 0);
+    for line in content.lines() {
+        for field in line.split_whitespace().skip(1) {
+            match field.split_once('=') {
+                Some(("rbytes", n)) => read += n.parse().unwrap_or(0),
+                Some(("wbytes", n)) => written += n.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+    }
+    (read, written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stat_files() {
+        let cpu = parse_flat_keyed("usage_usec 1500\nuser_usec 1000\nsystem_usec 500\nnr_periods 0\n");
+        assert_eq!(cpu.get("usage_usec"), Some(&1500));
+        assert_eq!(cpu.get("system_usec"), Some(&500));
+
+        let io = "8:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n\
+                  8:16 rbytes=1024 wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n";
+        assert_eq!(parse_io_stat(io), (5120, 8192));
+        assert_eq!(parse_io_stat(""), (0, 0));
+
+        assert_eq!(parse_cpu_max("max 100000\n"), (0, 100000));
+        assert_eq!(parse_cpu_max("50000 100000\n"), (50000, 100000));
+        assert_eq!(parse_cpu_max(""), (0, 0));
+
+        assert!(is_frozen("populated 1\nfrozen 1\n"));
+        assert!(!is_frozen("populated 1\nfrozen 0\n"));
+    }
+}
@@ -1,7 +1,9 @@
 use crate::process::{AethelProcess, Process};
 use aethel_common::error::{AethelError, Result};
 use std::ffi::CString;
-use std::os::unix::io::RawFd;
+use std::os::fd::{AsRawFd, OwnedFd};
+use nix::fcntl::OFlag;
+use nix::mount::{mount, MsFlags};
 use nix::unistd;
 
 pub struct Container<P: Process> {
@@ -21,11 +23,37 @@ impl<P: Process> Container<P> {
 
 use std::path::{Path, PathBuf};
 
+/// A host path bound into the rootfs before the container starts.
+struct BindMount {
+    source: PathBuf,
+    target: PathBuf,
+    read_only: bool,
+}
+
+/// The caller's ends of a container's standard streams.
+pub enum ContainerIo {
+    /// `stdin` is a pipe only when `with_stdin` asked for one.
+    Pipes {
+        stdin: Option<OwnedFd>,
+        stdout: OwnedFd,
+        stderr: OwnedFd,
+    },
+    /// stdin, stdout and stderr are all the terminal this is the master of.
+    Terminal(OwnedFd),
+}
+
 pub struct ContainerBuilder {
     id: String,
     command: CString,
     args: Vec<CString>,
     rootfs: PathBuf,
+    env: Vec<CString>,
+    working_dir: Option<PathBuf>,
+    user: Option<(u32, u32)>,
+    mounts: Vec<BindMount>,
+    stdin: bool,
+    terminal: bool,
+    cgroup: Option<PathBuf>,
 }
 
 impl ContainerBuilder {
@@ -37,6 +65,13 @@ impl ContainerBuilder {
             command,
             args: vec![],
             rootfs: PathBuf::from("/"),
+            env: vec![],
+            working_dir: None,
+            user: None,
+            mounts: vec![],
+            stdin: false,
+            terminal: false,
+            cgroup: None,
         })
     }
 
@@ -57,26 +92,140 @@ impl ContainerBuilder {
         Ok(self)
     }
 
-    pub fn build(self) -> Result<(isize, RawFd)> {
-        let (pipe_read, pipe_write) = unistd::pipe()?;
+    /// `NAME=value` pairs the command starts with.
+    pub fn env(mut self, vars: &[&str]) -> Result<Self> {
+        let mut parsed = Vec::with_capacity(vars.len());
+        for var in vars {
+            let c = CString::new(*var).map_err(|_| {
+                AethelError::ContainerSetup("Environment variable contains interior NUL byte".to_string())
+            })?;
+            parsed.push(c);
+        }
+        self.env = parsed;
+        Ok(self)
+    }
+
+    /// Where the command starts, inside the rootfs.
+    pub fn with_working_dir(mut self, path: &Path) -> Self {
+        self.working_dir = Some(path.to_path_buf());
+        self
+    }
+
+    /// Runs the command as `uid` and `gid` instead of root.
+    pub fn with_user(mut self, uid: u32, gid: u32) -> Self {
+        self.user = Some((uid, gid));
+        self
+    }
+
+    /// Binds the host path `source` onto `target`, an existing path inside
+    /// the rootfs as the host sees it.
+    pub fn with_mount(mut self, source: &Path, target: &Path, read_only: bool) -> Self {
+        self.mounts.push(BindMount {
+            source: source.to_path_buf(),
+            target: target.to_path_buf(),
+            read_only,
+        });
+        self
+    }
+
+    /// Gives the container a stdin pipe the caller writes to.
+    pub fn with_stdin(mut self) -> Self {
+        self.stdin = true;
+        self
+    }
+
+    /// Runs the command on a pseudo-terminal of its own; the caller gets
+    /// the master end, which is its stdin and output both.
+    pub fn with_terminal(mut self) -> Self {
+        self.terminal = true;
+        self
+    }
+
+    /// Starts the container in the cgroup v2 group at `path`, so everything
+    /// it runs is accounted there.
+    pub fn with_cgroup(mut self, path: &Path) -> Self {
+        self.cgroup = Some(path.to_path_buf());
+        self
+    }
+
+    pub fn build(self) -> Result<(isize, ContainerIo)> {
+        // Close-on-exec, so only the descriptors dup'd onto 0, 1 and 2
+        // reach the command.
+        let console = if self.terminal { Some(crate::terminal::console_socket()?) } else { None };
+        let output = if self.terminal {
+            None
+        } else {
+            Some((unistd::pipe2(OFlag::O_CLOEXEC)?, unistd::pipe2(OFlag::O_CLOEXEC)?))
+        };
+        let stdin = if self.stdin && !self.terminal { Some(unistd::pipe2(OFlag::O_CLOEXEC)?) } else { None };
+        // The child gets a copy of the closure, so it borrows: the caller's
+        // ends must not be closed when it is dropped here.
+        let (console_ref, output_ref, stdin_ref) = (&console, &output, &stdin);
         let mut stack = [0; 1024 * 1024];
         let child_pid = AethelProcess::new(move || {
-            if let Err(e) = {
-                if let Err(err) = unistd::dup2(pipe_write, 1) { return Err(err); }
-                if let Err(err) = unistd::dup2(pipe_write, 2) { return Err(err); }
-                if let Err(err) = unistd::close(pipe_read) { return Err(err); }
-                if let Err(err) = unistd::close(pipe_write) { return Err(err); }
-                crate::namespaces::pivot_root(&self.rootfs)
-            } {
+            let setup = || -> Result<()> {
+                // Before anything it starts, which would stay behind; 0 moves
+                // the writing process.
+                if let Some(cgroup) = &self.cgroup {
+                    std::fs::write(cgroup.join("cgroup.procs"), "0")
+                        .map_err(|e| AethelError::Cgroup(format!("Failed to join {}: {}", cgroup.display(), e)))?;
+                }
+                if let Some((_, theirs)) = console_ref {
+                    crate::terminal::setup_console(theirs)?;
+                }
+                if let Some(((_, stdout), (_, stderr))) = output_ref {
+                    unistd::dup2(stdout.as_raw_fd(), 1)?;
+                    unistd::dup2(stderr.as_raw_fd(), 2)?;
+                }
+                if let Some((read, _)) = stdin_ref {
+                    unistd::dup2(read.as_raw_fd(), 0)?;
+                }
+                // Keep the binds below, and anything mounted later, out of
+                // the host's mount namespace.
+                mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>)?;
+                for bind in &self.mounts {
+                    let flags = MsFlags::MS_BIND | MsFlags::MS_REC;
+                    mount(Some(&bind.source), &bind.target, None::<&str>, flags, None::<&str>)?;
+                    if bind.read_only {
+                        let flags = flags | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+                        mount(None::<&str>, &bind.target, None::<&str>, flags, None::<&str>)?;
+                    }
+                }
+                crate::namespaces::pivot_root(&self.rootfs)?;
+                if let Some(dir) = &self.working_dir {
+                    unistd::chdir(dir.as_path())?;
+                }
+                if let Some((uid, gid)) = self.user {
+                    unistd::setgroups(&[])?;
+                    unistd::setgid(unistd::Gid::from_raw(gid))?;
+                    unistd::setuid(unistd::Uid::from_raw(uid))?;
+                }
+                let mut argv = vec![self.command.clone()];
+                argv.extend(self.args.iter().cloned());
+                unistd::execvpe(&self.command, &argv, &self.env)?;
+                Ok(())
+            };
+            if let Err(e) = setup() {
                 eprintln!("container setup failed: {}", e);
                 return -1;
             }
-            println!("Inside container");
             0
         }, &mut stack)?;
 
-        unistd::close(pipe_write)?;
+        let io = match (console, output) {
+            (Some((ours, theirs)), _) => {
+                // Without this end the read below sees EOF if setup fails.
+                drop(theirs);
+                ContainerIo::Terminal(crate::terminal::receive_console(&ours)?)
+            }
+            (None, Some(((stdout, _), (stderr, _)))) => ContainerIo::Pipes {
+                stdin: stdin.map(|(_, write)| write),
+                stdout,
+                stderr,
+            },
+            (None, None) => unreachable!("a container without a terminal has output pipes"),
+        };
 
-        Ok((child_pid, pipe_read))
+        Ok((child_pid, io))
     }
 }
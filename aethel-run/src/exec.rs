@@ -0,0 +1,424 @@
+use crate::container::ContainerIo;
+use aethel_common::error::{AethelError, Result};
+use nix::fcntl::OFlag;
+use nix::sched::{setns, CloneFlags};
+use nix::sys::stat::stat;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{self, ForkResult, Gid, Pid, Uid};
+use std::ffi::CString;
+use std::fs;
+use std::io::Write;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+/// The namespaces an exec joins, in order. The user namespace comes first,
+/// as it owns the others; the mount namespace last: entering it moves the
+/// root, and the other files are already open.
+const NAMESPACES: [(&str, CloneFlags); 8] = [
+    ("user", CloneFlags::CLONE_NEWUSER),
+    ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("net", CloneFlags::CLONE_NEWNET),
+    ("pid", CloneFlags::CLONE_NEWPID),
+    // Not in nix; like the PID namespace, it applies to children only.
+    ("time", CloneFlags::from_bits_retain(libc::CLONE_NEWTIME)),
+    ("mnt", CloneFlags::CLONE_NEWNS),
+];
+
+/// The exit code when the process could not be set up or started, as a
+/// shell reports a command it cannot execute.
+const EXEC_FAILED: i32 = 126;
+
+/// What a container's init runs with, read from `/proc/<pid>/status`, so
+/// an exec gets no more than the container itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecurityProfile {
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: Vec<u32>,
+    pub no_new_privs: bool,
+    /// The capability bounding set, one bit per capability.
+    pub bounding_set: u64,
+}
+
+impl SecurityProfile {
+    pub fn of(pid: i32) -> Result<Self> {
+        let status = fs::read_to_string(format!("/proc/{}/status", pid))
+            .map_err(|e| AethelError::Process(format!("Failed to read status of process {}: {}", pid, e)))?;
+        Self::parse(&status).ok_or_else(|| AethelError::Process(format!("Unexpected status format of process {}", pid)))
+    }
+
+    fn parse(status: &str) -> Option<Self> {
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .map(str::trim)
+        };
+        // Real, effective, saved and filesystem IDs; the effective one counts.
+        let effective = |name: &str| field(name)?.split_whitespace().nth(1)?.parse().ok();
+        Some(SecurityProfile {
+            uid: effective("Uid")?,
+            gid: effective("Gid")?,
+            groups: field("Groups")?
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<std::result::Result<_, _>>()
+                .ok()?,
+            no_new_privs: field("NoNewPrivs").is_some_and(|v| v == "1"),
+            bounding_set: u64::from_str_radix(field("CapBnd")?, 16).ok()?,
+        })
+    }
+
+    /// Drops what the profile does not have, and the inheritable and
+    /// ambient capabilities, which could otherwise outlive the bounding set
+    /// across the exec. Must run in the child, before it changes user.
+    fn apply(&self, last_cap: u32, user: Option<(u32, u32)>) -> Result<()> {
+        for cap in 0..=last_cap {
+            if self.bounding_set & (1u64 << cap) == 0 {
+                let dropped = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) };
+                if dropped != 0 {
+                    return Err(nix::Error::last().into());
+                }
+            }
+        }
+        let cleared = unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) };
+        if cleared != 0 {
+            return Err(nix::Error::last().into());
+        }
+        clear_inheritable()?;
+        if self.no_new_privs {
+            nix::sys::prctl::set_no_new_privs()?;
+        }
+        let (uid, gid, groups) = match user {
+            Some((uid, gid)) => (uid, gid, Vec::new()),
+            None => (self.uid, self.gid, self.groups.iter().map(|&g| Gid::from_raw(g)).collect()),
+        };
+        unistd::setgroups(&groups)?;
+        unistd::setgid(Gid::from_raw(gid))?;
+        unistd::setuid(Uid::from_raw(uid))?;
+        Ok(())
+    }
+}
+
+/// `struct __user_cap_header_struct` of `<linux/capability.h>`.
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+/// `struct __user_cap_data_struct`; version 3 takes two, for capabilities
+/// 0-31 and 32-63.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Empties the calling thread's inheritable set, keeping the others.
+fn clear_inheritable() -> Result<()> {
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let mut data = [CapData::default(); 2];
+    let got = unsafe { libc::syscall(libc::SYS_capget, &mut header as *mut CapHeader, data.as_mut_ptr()) };
+    if got != 0 {
+        return Err(nix::Error::last().into());
+    }
+    for half in &mut data {
+        half.inheritable = 0;
+    }
+    let set = unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut CapHeader, data.as_ptr()) };
+    if set != 0 {
+        return Err(nix::Error::last().into());
+    }
+    Ok(())
+}
+
+/// Starts another process in a running container: it joins the
+/// namespaces and cgroups of the container's init `pid` and runs with the
+/// same security profile.
+pub struct ExecBuilder {
+    pid: i32,
+    command: CString,
+    args: Vec<CString>,
+    env: Vec<CString>,
+    working_dir: Option<PathBuf>,
+    user: Option<(u32, u32)>,
+    stdin: bool,
+    terminal: bool,
+}
+
+/// An exec'd process and the caller's ends of its standard streams.
+pub struct ExecProcess {
+    /// The process to `wait` for; its exit code is the command's.
+    pub pid: Pid,
+    pub io: ContainerIo,
+}
+
+/// Waits for the process `ExecBuilder::spawn` started, or a container's
+/// init, and returns the command's exit code, 128 plus the signal number
+/// when a signal killed it.
+pub fn wait(pid: Pid) -> Result<i32> {
+    match waitpid(pid, None)? {
+        WaitStatus::Exited(_, code) => Ok(code),
+        WaitStatus::Signaled(_, signal, _) => Ok(128 + signal as i32),
+        status => Err(AethelError::Process(format!("Unexpected wait status {:?}", status))),
+    }
+}
+
+impl ExecBuilder {
+    pub fn new(pid: i32, command: &str) -> Result<Self> {
+        let command = CString::new(command)
+            .map_err(|_| AethelError::ContainerSetup("Command contains interior NUL byte".to_string()))?;
+        Ok(ExecBuilder {
+            pid,
+            command,
+            args: vec![],
+            env: vec![],
+            working_dir: None,
+            user: None,
+            stdin: false,
+            terminal: false,
+        })
+    }
+
+    pub fn args(mut self, args: &[&str]) -> Result<Self> {
+        self.args = to_cstrings(args, "Argument")?;
+        Ok(self)
+    }
+
+    /// `NAME=value` pairs the command starts with.
+    pub fn env(mut self, vars: &[&str]) -> Result<Self> {
+        self.env = to_cstrings(vars, "Environment variable")?;
+        Ok(self)
+    }
+
+    /// Where the command starts, inside the container.
+    pub fn with_working_dir(mut self, path: &Path) -> Self {
+        self.working_dir = Some(path.to_path_buf());
+        self
+    }
+
+    /// Runs the command as `uid` and `gid` instead of the container's user.
+    pub fn with_user(mut self, uid: u32, gid: u32) -> Self {
+        self.user = Some((uid, gid));
+        self
+    }
+
+    /// Gives the command a stdin pipe the caller writes to.
+    pub fn with_stdin(mut self) -> Self {
+        self.stdin = true;
+        self
+    }
+
+    /// Runs the command on a pseudo-terminal of its own; the caller gets
+    /// the master end, which is its stdin and output both.
+    pub fn with_terminal(mut self) -> Self {
+        self.terminal = true;
+        self
+    }
+
+    /// Forks a child that joins the container and forks the command, since
+    /// only children of a process that entered a PID namespace are in it.
+    /// The child exits with the command's code.
+    pub fn spawn(self) -> Result<ExecProcess> {
+        let mut profile = SecurityProfile::of(self.pid)?;
+        let last_cap = fs::read_to_string("/proc/sys/kernel/cap_last_cap")?
+            .trim()
+            .parse()
+            .map_err(|_| AethelError::Process("Unexpected cap_last_cap".to_string()))?;
+        let cgroups = cgroup_procs_files(self.pid)?;
+        let namespaces = self.open_namespaces()?;
+        let mut argv = vec![self.command.clone()];
+        argv.extend(self.args.iter().cloned());
+
+        // Close-on-exec, so processes forked while this one is set up do
+        // not hold its write ends open.
+        let console = if self.terminal { Some(crate::terminal::console_socket()?) } else { None };
+        let pipes = if self.terminal {
+            None
+        } else {
+            Some((unistd::pipe2(OFlag::O_CLOEXEC)?, unistd::pipe2(OFlag::O_CLOEXEC)?))
+        };
+        let stdin = if self.stdin && !self.terminal { Some(unistd::pipe2(OFlag::O_CLOEXEC)?) } else { None };
+
+        // The daemon is multi-threaded, so what the child needs is prepared
+        // above and it mostly makes system calls from here on.
+        match unsafe { unistd::fork() }? {
+            ForkResult::Parent { child } => {
+                let io = match (console, pipes) {
+                    (Some((ours, theirs)), _) => {
+                        // Without this end the read below sees EOF if setup fails.
+                        drop(theirs);
+                        let master = crate::terminal::receive_console(&ours).inspect_err(|_| {
+                            let _ = wait(child);
+                        })?;
+                        ContainerIo::Terminal(master)
+                    }
+                    (None, Some(((stdout, _), (stderr, _)))) => ContainerIo::Pipes {
+                        stdin: stdin.map(|(_, write)| write),
+                        stdout,
+                        stderr,
+                    },
+                    (None, None) => unreachable!("an exec without a terminal has output pipes"),
+                };
+                Ok(ExecProcess { pid: child, io })
+            }
+            ForkResult::Child => {
+                let setup = || -> Result<i32> {
+                    // Before entering the mount namespace: the terminal
+                    // comes from the host's /dev/ptmx.
+                    if let Some((ours, theirs)) = console {
+                        drop(ours);
+                        crate::terminal::setup_console(&theirs)?;
+                    }
+                    if let Some(((stdout_read, stdout_write), (stderr_read, stderr_write))) = pipes {
+                        unistd::dup2(stdout_write.as_raw_fd(), 1)?;
+                        unistd::dup2(stderr_write.as_raw_fd(), 2)?;
+                        drop((stdout_read, stdout_write, stderr_read, stderr_write));
+                    }
+                    if let Some((read, write)) = stdin {
+                        unistd::dup2(read.as_raw_fd(), 0)?;
+                        drop((read, write));
+                    }
+
+                    for procs in &cgroups {
+                        // 0 moves the writing process.
+                        fs::OpenOptions::new().write(true).open(procs)?.write_all(b"0")?;
+                    }
+                    for (fd, flag) in &namespaces {
+                        setns(fd, *flag)?;
+                        if *flag == CloneFlags::CLONE_NEWUSER {
+                            // The status file shows IDs as its opener's
+                            // user namespace maps them: now the container's.
+                            profile = SecurityProfile::of(self.pid)?;
+                        }
+                    }
+                    if let Some(dir) = &self.working_dir {
+                        unistd::chdir(dir.as_path())?;
+                    }
+                    profile.apply(last_cap, self.user)?;
+
+                    match unsafe { unistd::fork() }? {
+                        ForkResult::Parent { child } => wait(child),
+                        ForkResult::Child => {
+                            let err = unistd::execvpe(&self.command, &argv, &self.env).unwrap_err();
+                            Err(AethelError::Process(format!("exec failed: {}", err)))
+                        }
+                    }
+                };
+                let code = setup().unwrap_or_else(|e| {
+                    eprintln!("exec setup failed: {}", e);
+                    EXEC_FAILED
+                });
+                unsafe { libc::_exit(code) }
+            }
+        }
+    }
+
+    /// Opens the namespaces of the container's init that differ from ours,
+    /// of those the kernel has.
+    fn open_namespaces(&self) -> Result<Vec<(OwnedFd, CloneFlags)>> {
+        let mut namespaces = Vec::new();
+        for (name, flag) in NAMESPACES {
+            let theirs = format!("/proc/{}/ns/{}", self.pid, name);
+            let ours = format!("/proc/self/ns/{}", name);
+            if !Path::new(&ours).exists() {
+                continue;
+            }
+            let (a, b) = (stat(theirs.as_str())?, stat(ours.as_str())?);
+            if (a.st_dev, a.st_ino) == (b.st_dev, b.st_ino) {
+                continue;
+            }
+            let fd = fs::File::open(&theirs)
+                .map_err(|e| AethelError::Namespace(format!("Failed to open {}: {}", theirs, e)))?;
+            namespaces.push((OwnedFd::from(fd), flag));
+        }
+        Ok(namespaces)
+    }
+}
+
+fn to_cstrings(values: &[&str], what: &str) -> Result<Vec<CString>> {
+    values
+        .iter()
+        .map(|value| {
+            CString::new(*value)
+                .map_err(|_| AethelError::ContainerSetup(format!("{} contains interior NUL byte", what)))
+        })
+        .collect()
+}
+
+/// The `cgroup.procs` files of every cgroup `pid` is in: the unified
+/// hierarchy's on cgroup v2, one per mounted controller on v1.
+fn cgroup_procs_files(pid: i32) -> Result<Vec<PathBuf>> {
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .map_err(|e| AethelError::Cgroup(format!("Failed to read cgroups of process {}: {}", pid, e)))?;
+    Ok(parse_cgroups(Path::new("/sys/fs/cgroup"), &cgroups)
+        .into_iter()
+        .filter(|procs| procs.exists())
+        .collect())
+}
+
+/// Lines are `hierarchy-ID:controllers:path`; v2 has ID 0 and no
+/// controllers, v1 mounts each hierarchy under its controller names.
+fn parse_cgroups(mount_root: &Path, cgroups: &str) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for line in cgroups.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (Some(id), Some(controllers), Some(path)) = (fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        let rel = path.trim_start_matches('/');
+        let hierarchy = match (id, controllers) {
+            ("0", "") => mount_root.to_path_buf(),
+            (_, "") => continue,
+            (_, controllers) => mount_root.join(controllers.trim_start_matches("name=")),
+        };
+        files.push(hierarchy.join(rel).join("cgroup.procs"));
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_security_profile() {
+        let status = "Name:\tnginx\nUid:\t0\t101\t101\t101\nGid:\t0\t101\t101\t101\nGroups:\t101 4 \nNoNewPrivs:\t1\nCapBnd:\t00000000a80425fb\n";
+        let profile = SecurityProfile::parse(status).unwrap();
+
+        assert_eq!(
+            profile,
+            SecurityProfile {
+                uid: 101,
+                gid: 101,
+                groups: vec![101, 4],
+                no_new_privs: true,
+                bounding_set: 0xa80425fb,
+            }
+        );
+        assert!(SecurityProfile::parse("Name:\tnginx\n").is_none());
+    }
+
+    #[test]
+    fn finds_cgroup_procs_files() {
+        let root = Path::new("/sys/fs/cgroup");
+        let v2 = parse_cgroups(root, "0::/aethel/web\n");
+        let v1 = parse_cgroups(root, "12:cpu,cpuacct:/aethel/web\n3:name=systemd:/user.slice\n1::/ignored\n");
+
+        assert_eq!(v2, vec![PathBuf::from("/sys/fs/cgroup/aethel/web/cgroup.procs")]);
+        assert_eq!(
+            v1,
+            vec![
+                PathBuf::from("/sys/fs/cgroup/cpu,cpuacct/aethel/web/cgroup.procs"),
+                PathBuf::from("/sys/fs/cgroup/systemd/user.slice/cgroup.procs"),
+            ]
+        );
+    }
+}
@@ -1,3 +1,6 @@
+pub mod cgroup;
 pub mod container;
+pub mod exec;
 pub mod namespaces;
-pub mod process;
\ No newline at end of file
+pub mod process;
+pub mod terminal;
\ No newline at end of file
@@ -0,0 +1,94 @@
+use aethel_common::error::{AethelError, Result};
+use nix::pty::{openpty, Winsize};
+use nix::sys::socket::{
+    recvmsg, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType,
+};
+use nix::unistd;
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+
+nix::ioctl_write_int_bad!(tiocsctty, libc::TIOCSCTTY);
+nix::ioctl_write_ptr_bad!(tiocswinsz, libc::TIOCSWINSZ, Winsize);
+
+/// A socket pair the child sends its terminal's master end over, as an
+/// OCI runtime hands a console to its caller: `.0` stays with the caller,
+/// `.1` goes to the child.
+pub(crate) fn console_socket() -> Result<(OwnedFd, OwnedFd)> {
+    Ok(socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )?)
+}
+
+/// Run in the child: allocates a pseudo-terminal, makes it the controlling
+/// terminal and stdin, stdout and stderr of a new session, and sends the
+/// master end over `socket`. The host's `/dev/ptmx` is used, so this must
+/// happen before the child changes its root.
+pub(crate) fn setup_console(socket: &OwnedFd) -> Result<()> {
+    let pty = openpty(None, None)?;
+    unistd::setsid()?;
+    unsafe { tiocsctty(pty.slave.as_raw_fd(), 0) }?;
+    for fd in 0..3 {
+        unistd::dup2(pty.slave.as_raw_fd(), fd)?;
+    }
+
+    let fds = [pty.master.as_raw_fd()];
+    let cmsgs = [ControlMessage::ScmRights(&fds)];
+    sendmsg::<()>(socket.as_raw_fd(), &[IoSlice::new(b"c")], &cmsgs, MsgFlags::empty(), None)?;
+    Ok(())
+}
+
+/// Receives the master end `setup_console` sent over `socket`.
+pub(crate) fn receive_console(socket: &OwnedFd) -> Result<OwnedFd> {
+    let mut byte = [0u8; 1];
+    let mut iov = [IoSliceMut::new(&mut byte)];
+    let mut cmsg_buffer = nix::cmsg_space!([std::os::fd::RawFd; 1]);
+    let msg = recvmsg::<()>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(&fd) = fds.first() {
+                return Ok(unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+        }
+    }
+    Err(AethelError::ContainerSetup(
+        "Process exited before sending its terminal".to_string(),
+    ))
+}
+
+/// Tells the terminal whose master is `master` that its window is now
+/// `rows` by `cols`; the process on it gets `SIGWINCH`.
+pub fn resize(master: impl AsFd, rows: u16, cols: u16) -> Result<()> {
+    let size = Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    unsafe { tiocswinsz(master.as_fd().as_raw_fd(), &size) }?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    nix::ioctl_read_bad!(tiocgwinsz, libc::TIOCGWINSZ, Winsize);
+
+    #[test]
+    fn resizes_terminal() {
+        let pty = openpty(None, None).unwrap();
+        resize(&pty.master, 24, 80).unwrap();
+
+        let mut size: Winsize = unsafe { std::mem::zeroed() };
+        unsafe { tiocgwinsz(pty.slave.as_raw_fd(), &mut size) }.unwrap();
+        assert_eq!((size.ws_row, size.ws_col), (24, 80));
+    }
+}
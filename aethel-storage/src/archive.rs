@@ -0,0 +1,721 @@
+use aethel_common::error::{AethelError, Result};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tar::{Archive, Builder, EntryType, Header};
+
+use crate::digest::{Algorithm, Digest, Hasher};
+use crate::oci::{self, Descriptor, ImageConfig, OciIndex, OciManifest, Platform};
+use crate::reference::Reference;
+use crate::store::{ImageStore, MAX_METADATA_BLOB};
+use crate::{encode_json, MAX_INDEX_DEPTH};
+
+const DOCKER_MANIFEST: &str = "manifest.json";
+const OCI_LAYOUT: &str = "oci-layout";
+const OCI_INDEX: &str = "index.json";
+const OCI_LAYOUT_VERSION: &str = "1.0.0";
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// Links inside an archive are followed at most this many times.
+const MAX_LINK_FOLLOWS: usize = 8;
+
+static STAGING_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The tarball layouts `load` reads and `save` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// What `docker save` writes: a `manifest.json` listing each image's
+    /// config, layer tarballs and tags.
+    Docker,
+    /// An OCI image layout (`oci-layout`, `index.json`, `blobs/`) in a tar.
+    Oci,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = AethelError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "docker" | "docker-archive" => Ok(ArchiveFormat::Docker),
+            "oci" | "oci-archive" => Ok(ArchiveFormat::Oci),
+            other => Err(AethelError::Filesystem(format!(
+                "Unknown archive format '{}' (expected docker or oci)",
+                other
+            ))),
+        }
+    }
+}
+
+/// An image `load` added to the store, and the name it was given; `None`
+/// for an image saved by ID, which the store keeps untagged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loaded {
+    pub reference: Option<Reference>,
+    pub digest: String,
+}
+
+/// One image in a `docker save` `manifest.json`. Paths are relative to the
+/// archive root.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifestEntry {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+/// Imports every image in a `docker save` or OCI archive, which may be
+/// gzip-compressed, and points the names recorded in the archive at them.
+/// Images the archive does not name are kept by ID.
+/// Docker archives written by newer releases carry both layouts; their
+/// `manifest.json` is used, since it lists only what was actually saved.
+pub fn load<R: Read>(store: &ImageStore, reader: R) -> Result<Vec<Loaded>> {
+    let mut reader = BufReader::new(reader);
+    let mut staging = Staging::new(store)?;
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        staging.read(GzDecoder::new(reader))?;
+    } else {
+        staging.read(reader)?;
+    }
+
+    if staging.contains(DOCKER_MANIFEST) {
+        load_docker(store, &staging)
+    } else if staging.contains(OCI_LAYOUT) && staging.contains(OCI_INDEX) {
+        load_oci(store, &staging)
+    } else {
+        Err(AethelError::Filesystem(
+            "Archive is neither a docker save archive nor an OCI image layout".to_string(),
+        ))
+    }
+}
+
+/// Writes the images `names` resolve to as a `format` archive. Names that
+/// are references are recorded in the archive; an image saved by ID goes
+/// in without a name.
+pub fn save<W: Write>(store: &ImageStore, names: &[String], format: ArchiveFormat, writer: W) -> Result<W> {
+    let known = store.references()?;
+    let mut images: Vec<(Descriptor, Vec<Reference>)> = Vec::new();
+    for name in names {
+        let desc = store.resolve(name)?;
+        let reference = name
+            .parse::<Reference>()
+            .ok()
+            .filter(|reference| known.iter().any(|(known, _)| known == reference));
+        match images.iter_mut().find(|(saved, _)| saved.digest == desc.digest) {
+            Some((_, references)) => references.extend(reference),
+            None => images.push((desc, reference.into_iter().collect())),
+        }
+    }
+
+    let mut archive = ArchiveWriter::new(writer);
+    match format {
+        ArchiveFormat::Docker => save_docker(store, &images, &mut archive)?,
+        ArchiveFormat::Oci => save_oci(store, &images, &mut archive)?,
+    }
+    archive.finish()
+}
+
+fn load_docker(store: &ImageStore, staging: &Staging) -> Result<Vec<Loaded>> {
+    let entries: Vec<DockerManifestEntry> = staging.read_json(DOCKER_MANIFEST, DOCKER_MANIFEST)?;
+
+    let mut loaded = Vec::new();
+    for entry in entries {
+        let references = entry
+            .repo_tags
+            .unwrap_or_default()
+            .iter()
+            .map(|tag| tag.parse::<Reference>())
+            .collect::<Result<Vec<_>>>()?;
+
+        let config: ImageConfig = staging.read_json(&entry.config, "image config")?;
+        let config_desc = staging.descriptor(&entry.config, oci::MEDIA_TYPE_OCI_CONFIG)?;
+        // Describe every layer before importing any: importing moves the
+        // staged file, which other layer paths may link to.
+        let layers = entry
+            .layers
+            .iter()
+            .map(|name| staging.descriptor(name, staging.layer_media_type(name)?))
+            .collect::<Result<Vec<_>>>()?;
+        staging.import(store, &entry.config, &config_desc)?;
+        for (name, layer) in entry.layers.iter().zip(&layers) {
+            staging.import(store, name, layer)?;
+        }
+
+        // The archive has no manifest of its own; write one for the store.
+        let manifest = OciManifest {
+            schema_version: 2,
+            media_type: Some(oci::MEDIA_TYPE_OCI_MANIFEST.to_string()),
+            config: config_desc,
+            layers,
+            annotations: HashMap::new(),
+        };
+        let bytes = encode_json(&manifest, "manifest")?;
+        let digest = Digest::of_bytes(Algorithm::Sha256, &bytes);
+        store.write_blob(&digest, &bytes)?;
+        let target = Descriptor {
+            media_type: oci::MEDIA_TYPE_OCI_MANIFEST.to_string(),
+            digest: digest.to_string(),
+            size: bytes.len() as u64,
+            platform: (!config.os.is_empty()).then(|| config.platform()),
+            annotations: HashMap::new(),
+        };
+
+        if references.is_empty() {
+            store.add_untagged(target.clone())?;
+            loaded.push(Loaded {
+                reference: None,
+                digest: target.digest,
+            });
+            continue;
+        }
+        for reference in references {
+            store.set_reference(&reference, target.clone())?;
+            loaded.push(Loaded {
+                reference: Some(reference),
+                digest: target.digest.clone(),
+            });
+        }
+    }
+    Ok(loaded)
+}
+
+fn load_oci(store: &ImageStore, staging: &Staging) -> Result<Vec<Loaded>> {
+    let index: OciIndex = staging.read_json(OCI_INDEX, OCI_INDEX)?;
+
+    let mut loaded = Vec::new();
+    for desc in &index.manifests {
+        let reference = image_name(desc).transpose()?;
+        import_image(store, staging, desc, 0)?;
+        let target = Descriptor {
+            annotations: HashMap::new(),
+            ..desc.clone()
+        };
+        match &reference {
+            Some(reference) => store.set_reference(reference, target)?,
+            None => store.add_untagged(target)?,
+        }
+        loaded.push(Loaded {
+            reference,
+            digest: desc.digest.clone(),
+        });
+    }
+    Ok(loaded)
+}
+
+/// The name an OCI archive records for a manifest: containerd's full-name
+/// annotation, or the ref name when it is a reference rather than a bare
+/// tag.
+fn image_name(desc: &Descriptor) -> Option<Result<Reference>> {
+    let name = desc.annotations.get(oci::ANNOTATION_IMAGE_NAME).or_else(|| {
+        desc.annotations
+            .get(oci::ANNOTATION_REF_NAME)
+            .filter(|name| name.contains(['/', ':']))
+    })?;
+    Some(name.parse())
+}
+
+/// Imports `desc` and everything it refers to from the archive's `blobs/`.
+fn import_image(store: &ImageStore, staging: &Staging, desc: &Descriptor, depth: usize) -> Result<()> {
+    staging.import(store, &blob_name(desc)?, desc)?;
+    if oci::is_index_media_type(&desc.media_type) {
+        if depth == MAX_INDEX_DEPTH {
+            return Err(AethelError::Filesystem(format!(
+                "Image index nesting exceeds {} levels",
+                MAX_INDEX_DEPTH
+            )));
+        }
+        let index: OciIndex = crate::read_blob_json(store, desc, "image index")?;
+        for child in &index.manifests {
+            import_image(store, staging, child, depth + 1)?;
+        }
+    } else {
+        let manifest: OciManifest = crate::read_blob_json(store, desc, "manifest")?;
+        for blob in std::iter::once(&manifest.config).chain(&manifest.layers) {
+            staging.import(store, &blob_name(blob)?, blob)?;
+        }
+    }
+    Ok(())
+}
+
+fn save_docker<W: Write>(
+    store: &ImageStore,
+    images: &[(Descriptor, Vec<Reference>)],
+    archive: &mut ArchiveWriter<W>,
+) -> Result<()> {
+    let mut entries = Vec::new();
+    for (desc, references) in images {
+        // A docker archive holds one platform per image.
+        let platform = desc.platform.clone().unwrap_or_else(Platform::host);
+        let manifest = crate::resolve_manifest(store, desc, &platform)?;
+        archive.add_blob(store, &manifest.config)?;
+        for layer in &manifest.layers {
+            archive.add_blob(store, layer)?;
+        }
+        entries.push(DockerManifestEntry {
+            config: blob_name(&manifest.config)?,
+            repo_tags: Some(
+                references
+                    .iter()
+                    .filter(|reference| reference.tag.is_some())
+                    .map(Reference::familiar)
+                    .collect(),
+            ),
+            layers: manifest.layers.iter().map(blob_name).collect::<Result<_>>()?,
+        });
+    }
+    archive.add_file(DOCKER_MANIFEST, &encode_json(&entries, DOCKER_MANIFEST)?)
+}
+
+fn save_oci<W: Write>(
+    store: &ImageStore,
+    images: &[(Descriptor, Vec<Reference>)],
+    archive: &mut ArchiveWriter<W>,
+) -> Result<()> {
+    let layout = serde_json::json!({ "imageLayoutVersion": OCI_LAYOUT_VERSION });
+    archive.add_file(OCI_LAYOUT, &encode_json(&layout, OCI_LAYOUT)?)?;
+
+    let mut index = OciIndex {
+        schema_version: 2,
+        media_type: Some(oci::MEDIA_TYPE_OCI_INDEX.to_string()),
+        manifests: Vec::new(),
+        annotations: HashMap::new(),
+    };
+    for (desc, references) in images {
+        for blob in crate::image_blobs(store, desc)? {
+            archive.add_blob(store, &blob)?;
+        }
+        let entry = Descriptor {
+            annotations: HashMap::new(),
+            ..desc.clone()
+        };
+        if references.is_empty() {
+            index.manifests.push(entry.clone());
+        }
+        for reference in references {
+            let mut entry = entry.clone();
+            entry
+                .annotations
+                .insert(oci::ANNOTATION_IMAGE_NAME.to_string(), reference.to_string());
+            if let Some(tag) = &reference.tag {
+                entry.annotations.insert(oci::ANNOTATION_REF_NAME.to_string(), tag.clone());
+            }
+            index.manifests.push(entry);
+        }
+    }
+    archive.add_file(OCI_INDEX, &encode_json(&index, OCI_INDEX)?)
+}
+
+/// A regular file copied out of the archive.
+struct StagedFile {
+    path: PathBuf,
+    digest: Digest,
+    size: u64,
+}
+
+/// The regular files of an archive, copied into a scratch directory and
+/// hashed on the way, plus its links. Archives list entries in any order,
+/// so nothing can be interpreted until the whole stream has been read.
+struct Staging {
+    dir: PathBuf,
+    files: HashMap<PathBuf, StagedFile>,
+    links: HashMap<PathBuf, PathBuf>,
+}
+
+impl Staging {
+    fn new(store: &ImageStore) -> Result<Self> {
+        let dir = store.ingest_dir().join(format!(
+            "load-{}-{}",
+            process::id(),
+            STAGING_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir)?;
+        Ok(Staging {
+            dir,
+            files: HashMap::new(),
+            links: HashMap::new(),
+        })
+    }
+
+    fn read<R: Read>(&mut self, reader: R) -> Result<()> {
+        let mut archive = Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = clean_path(Path::new(""), &entry.path()?)?;
+            let link = || -> Result<PathBuf> {
+                Ok(entry
+                    .link_name()?
+                    .ok_or_else(|| AethelError::Filesystem(format!("Link {} has no target", path.display())))?
+                    .into_owned())
+            };
+            match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous => {
+                    let staged = self.dir.join(self.files.len().to_string());
+                    let (digest, size) = copy_hashed(&mut entry, &staged)?;
+                    self.files.insert(
+                        path,
+                        StagedFile {
+                            path: staged,
+                            digest,
+                            size,
+                        },
+                    );
+                }
+                EntryType::Symlink => {
+                    let target = clean_path(path.parent().unwrap_or(Path::new("")), &link()?)?;
+                    self.links.insert(path, target);
+                }
+                EntryType::Link => {
+                    let target = clean_path(Path::new(""), &link()?)?;
+                    self.links.insert(path, target);
+                }
+                // Directories and special files carry nothing an image needs.
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<&StagedFile> {
+        let mut path = clean_path(Path::new(""), Path::new(name))?;
+        for _ in 0..=MAX_LINK_FOLLOWS {
+            if let Some(file) = self.files.get(&path) {
+                return Ok(file);
+            }
+            match self.links.get(&path) {
+                Some(target) => path = target.clone(),
+                None => return Err(AethelError::NotFound(format!("{} is not in the archive", name))),
+            }
+        }
+        Err(AethelError::Filesystem(format!("Too many levels of links resolving {}", name)))
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.get(name).is_ok()
+    }
+
+    fn read_json<T: serde::de::DeserializeOwned>(&self, name: &str, what: &str) -> Result<T> {
+        let file = self.get(name)?;
+        if file.size > MAX_METADATA_BLOB {
+            return Err(AethelError::Filesystem(format!(
+                "{} in the archive is too large ({} bytes)",
+                name, file.size
+            )));
+        }
+        crate::parse_json(&fs::read(&file.path)?, what)
+    }
+
+    fn descriptor(&self, name: &str, media_type: &str) -> Result<Descriptor> {
+        let file = self.get(name)?;
+        Ok(Descriptor {
+            media_type: media_type.to_string(),
+            digest: file.digest.to_string(),
+            size: file.size,
+            platform: None,
+            annotations: HashMap::new(),
+        })
+    }
+
+    /// `docker save` writes uncompressed layers, but other tools compress
+    /// them; the media type follows what the file starts with.
+    fn layer_media_type(&self, name: &str) -> Result<&'static str> {
+        let mut magic = [0; 4];
+        let mut file = File::open(&self.get(name)?.path)?;
+        let read = file.read(&mut magic)?;
+        Ok(if magic[..read].starts_with(&GZIP_MAGIC) {
+            oci::MEDIA_TYPE_OCI_LAYER_GZIP
+        } else if magic[..read] == ZSTD_MAGIC {
+            oci::MEDIA_TYPE_OCI_LAYER_ZSTD
+        } else {
+            oci::MEDIA_TYPE_OCI_LAYER
+        })
+    }
+
+    /// Moves `name` into the store after checking it is the blob `desc`
+    /// describes.
+    fn import(&self, store: &ImageStore, name: &str, desc: &Descriptor) -> Result<()> {
+        let file = self.get(name)?;
+        let digest: Digest = desc.digest.parse()?;
+        if digest.algorithm() != file.digest.algorithm() {
+            return Err(AethelError::InvalidDigest(format!(
+                "only sha256 blobs can be loaded, not {}",
+                digest
+            )));
+        }
+        if file.digest != digest {
+            return Err(AethelError::DigestMismatch {
+                expected: digest.to_string(),
+                actual: file.digest.to_string(),
+            });
+        }
+        if file.size != desc.size {
+            return Err(AethelError::SizeMismatch {
+                digest: digest.to_string(),
+                expected: desc.size,
+                actual: file.size,
+            });
+        }
+        // Two names for the same content share one staged file, which the
+        // first import moved.
+        if store.has_blob(&digest) {
+            return Ok(());
+        }
+        store.commit_blob(&digest, &file.path)
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Writes regular files into a tar stream, each blob at most once.
+struct ArchiveWriter<W: Write> {
+    builder: Builder<W>,
+    written: HashSet<String>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    fn new(writer: W) -> Self {
+        ArchiveWriter {
+            builder: Builder::new(writer),
+            written: HashSet::new(),
+        }
+    }
+
+    fn add_file(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
+        self.builder.append_data(&mut file_header(bytes.len() as u64), name, bytes)?;
+        Ok(())
+    }
+
+    fn add_blob(&mut self, store: &ImageStore, desc: &Descriptor) -> Result<()> {
+        let name = blob_name(desc)?;
+        if !self.written.insert(name.clone()) {
+            return Ok(());
+        }
+        // Reading through the verifying reader checks the blob on the way out.
+        let blob = store.open_blob(desc)?;
+        self.builder.append_data(&mut file_header(desc.size), &name, blob)?;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<W> {
+        Ok(self.builder.into_inner()?)
+    }
+}
+
+fn file_header(size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header
+}
+
+/// Where an OCI layout keeps the blob.
+fn blob_name(desc: &Descriptor) -> Result<String> {
+    let digest: Digest = desc.digest.parse()?;
+    Ok(format!("blobs/{}/{}", digest.algorithm().name(), digest.hex()))
+}
+
+/// Joins `path` onto `base` inside the archive, resolving `.` and `..`.
+/// Absolute paths are taken relative to the archive root, and nothing may
+/// climb above it.
+fn clean_path(base: &Path, path: &Path) -> Result<PathBuf> {
+    let mut clean = if path.is_absolute() {
+        PathBuf::new()
+    } else {
+        base.to_path_buf()
+    };
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir if clean.pop() => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(AethelError::Filesystem(format!(
+                    "Archive path {} escapes the archive",
+                    path.display()
+                )))
+            }
+        }
+    }
+    Ok(clean)
+}
+
+fn copy_hashed<R: Read>(reader: &mut R, dest: &Path) -> Result<(Digest, u64)> {
+    let mut file = File::create(dest)?;
+    let mut hasher = Hasher::new(Algorithm::Sha256);
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        hasher.update(&buf[..n]);
+        file.write_all(&buf[..n])?;
+        size += n as u64;
+    }
+    Ok((hasher.finish(), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tempfile::TempDir;
+
+    fn store(dir: &TempDir) -> ImageStore {
+        ImageStore::new(dir.path()).unwrap()
+    }
+
+    fn layer_tar(path: &str, contents: &[u8]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        builder.append_data(&mut file_header(contents.len() as u64), path, contents).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn config() -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "architecture": Platform::host().architecture,
+            "os": "linux",
+            "config": { "Cmd": ["/bin/sh"] },
+            "rootfs": { "type": "layers", "diff_ids": [] },
+        }))
+        .unwrap()
+    }
+
+    /// What an older `docker save` writes: a config, one directory per
+    /// layer, and a symlink where two images share a layer.
+    fn docker_save_archive() -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        let mut add = |name: &str, bytes: &[u8]| {
+            builder.append_data(&mut file_header(bytes.len() as u64), name, bytes).unwrap();
+        };
+        add("cfg.json", &config());
+        add("base/layer.tar", &layer_tar("etc/hostname", b"base\n"));
+        add("app/layer.tar", &layer_tar("app/run.sh", b"echo hi\n"));
+        add(
+            "manifest.json",
+            br#"[{"Config":"cfg.json","RepoTags":["app:v1","registry.example.com/team/app:v1"],"Layers":["base/layer.tar","copy/layer.tar","app/layer.tar"]}]"#,
+        );
+        let mut link = Header::new_gnu();
+        link.set_entry_type(EntryType::Symlink);
+        link.set_size(0);
+        builder.append_link(&mut link, "copy/layer.tar", "../base/layer.tar").unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn loads_docker_save_archive() {
+        let dir = TempDir::new().unwrap();
+        let store = store(&dir);
+
+        let loaded = load(&store, gzip(&docker_save_archive()).as_slice()).unwrap();
+
+        let names: Vec<String> = loaded.iter().filter_map(|l| l.reference.as_ref()).map(Reference::familiar).collect();
+        assert_eq!(names, vec!["app:v1", "registry.example.com/team/app:v1"]);
+        let target = store.resolve("app:v1").unwrap();
+        let rootfs = dir.path().join("rootfs");
+        let config = crate::prepare_rootfs(&store, &target, &rootfs, None).unwrap();
+        assert_eq!(config.config.cmd, vec!["/bin/sh"]);
+        assert_eq!(fs::read(rootfs.join("etc/hostname")).unwrap(), b"base\n");
+        assert_eq!(fs::read(rootfs.join("app/run.sh")).unwrap(), b"echo hi\n");
+        let (manifest, _) = crate::resolve_image(&store, &target, None).unwrap();
+        assert_eq!(manifest.layers[0].media_type, oci::MEDIA_TYPE_OCI_LAYER);
+        assert_eq!(manifest.layers[0].digest, manifest.layers[1].digest);
+        assert!(!store.ingest_dir().read_dir().unwrap().any(|_| true));
+    }
+
+    #[test]
+    fn round_trips_both_formats() {
+        let source_dir = TempDir::new().unwrap();
+        let source = store(&source_dir);
+        load(&source, docker_save_archive().as_slice()).unwrap();
+        let names = vec!["app:v1".to_string()];
+
+        for format in [ArchiveFormat::Docker, ArchiveFormat::Oci] {
+            let archive = save(&source, &names, format, Vec::new()).unwrap();
+            let dir = TempDir::new().unwrap();
+            let target = store(&dir);
+
+            let loaded = load(&target, archive.as_slice()).unwrap();
+
+            assert_eq!(loaded.len(), 1, "{:?}", format);
+            assert_eq!(loaded[0].reference.as_ref().map(Reference::familiar).as_deref(), Some("app:v1"));
+            let desc = target.resolve("app:v1").unwrap();
+            if format == ArchiveFormat::Oci {
+                assert_eq!(desc.digest, source.resolve("app:v1").unwrap().digest);
+            }
+            for blob in crate::image_blobs(&target, &desc).unwrap() {
+                target.open_blob(&blob).unwrap().finish().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_images_saved_by_id() {
+        let source_dir = TempDir::new().unwrap();
+        let source = store(&source_dir);
+        load(&source, docker_save_archive().as_slice()).unwrap();
+        let ids = vec![source.resolve("app:v1").unwrap().digest];
+
+        for format in [ArchiveFormat::Docker, ArchiveFormat::Oci] {
+            let archive = save(&source, &ids, format, Vec::new()).unwrap();
+            let dir = TempDir::new().unwrap();
+            let target = store(&dir);
+
+            let loaded = load(&target, archive.as_slice()).unwrap();
+
+            assert_eq!(loaded.len(), 1, "{:?}", format);
+            assert_eq!(loaded[0].reference, None);
+            assert!(target.references().unwrap().is_empty());
+            let desc = target.resolve(&loaded[0].digest).unwrap();
+            if format == ArchiveFormat::Oci {
+                assert_eq!(desc.digest, ids[0]);
+            }
+            for blob in crate::image_blobs(&target, &desc).unwrap() {
+                target.open_blob(&blob).unwrap().finish().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_links_out_of_the_archive() {
+        let mut builder = Builder::new(Vec::new());
+        let manifest = br#"[{"Config":"cfg.json","RepoTags":["app:v1"],"Layers":["evil/layer.tar"]}]"#;
+        builder
+            .append_data(&mut file_header(manifest.len() as u64), "manifest.json", &manifest[..])
+            .unwrap();
+        let mut link = Header::new_gnu();
+        link.set_entry_type(EntryType::Symlink);
+        link.set_size(0);
+        builder.append_link(&mut link, "evil/layer.tar", "../../etc/passwd").unwrap();
+        let dir = TempDir::new().unwrap();
+
+        let result = load(&store(&dir), builder.into_inner().unwrap().as_slice());
+
+        assert!(matches!(result, Err(AethelError::Filesystem(ref e)) if e.contains("escapes")), "{:?}", result);
+        assert!(store(&dir).references().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,491 @@
+use aethel_common::error::{AethelError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::commit::write_image;
+use crate::containerfile::Instruction;
+use crate::copy::container_path;
+use crate::diff::{self, Change, ChangeKind};
+use crate::digest::{Algorithm, Digest, Hasher, HashingWriter};
+use crate::layer::{resolve_in_root, LayerApplier, LayerReader, WhiteoutMode};
+use crate::oci::{self, ContainerConfig, Descriptor, ImageConfig, Platform};
+use crate::store::{write_atomic, ImageStore};
+
+const CACHE_FILE: &str = "build-cache.json";
+
+#[derive(Default, Serialize, Deserialize)]
+struct CacheFile {
+    #[serde(default)]
+    steps: BTreeMap<String, Descriptor>,
+}
+
+/// The image each build step produced, keyed by `cache_key`. Lives next to
+/// the store's references in `build-cache.json`.
+///
+/// Entries are not garbage collection roots: a prune that sweeps an
+/// intermediate image turns its entry into a miss, never into a broken
+/// image.
+pub struct BuildCache {
+    path: PathBuf,
+    /// Serializes read-modify-write cycles of the cache file.
+    lock: Mutex<()>,
+}
+
+impl BuildCache {
+    pub fn new(store: &ImageStore) -> Self {
+        BuildCache {
+            path: store.root().join(CACHE_FILE),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// The image cached under `key`, if every blob it is made of is still
+    /// in `store`.
+    pub fn get(&self, store: &ImageStore, key: &Digest) -> Result<Option<Descriptor>> {
+        let Some(target) = ({
+            let _guard = self.lock.lock().unwrap();
+            self.load()?.steps.remove(&key.to_string())
+        }) else {
+            return Ok(None);
+        };
+
+        let complete = crate::image_blobs(store, &target).is_ok_and(|blobs| {
+            blobs
+                .iter()
+                .all(|blob| blob.digest.parse().is_ok_and(|digest| store.has_blob(&digest)))
+        });
+        Ok(complete.then_some(target))
+    }
+
+    pub fn insert(&self, key: &Digest, target: &Descriptor) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let mut cache = self.load()?;
+        cache.steps.insert(key.to_string(), target.clone());
+        let json = serde_json::to_vec_pretty(&cache)
+            .map_err(|e| AethelError::Filesystem(format!("Failed to encode {}: {}", CACHE_FILE, e)))?;
+        write_atomic(&self.path, &json)
+    }
+
+    fn load(&self) -> Result<CacheFile> {
+        match fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| AethelError::Filesystem(format!("Corrupt {}: {}", CACHE_FILE, e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(CacheFile::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The cache key of running `step`, as written, on the image `parent`.
+/// `content` is the hash of the files a `COPY` or `ADD` reads.
+pub fn cache_key(parent: &Descriptor, step: &str, content: Option<&Digest>) -> Digest {
+    let mut hasher = Hasher::new(Algorithm::Sha256);
+    for part in [parent.digest.as_str(), step] {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\0");
+    }
+    if let Some(content) = content {
+        hasher.update(content.to_string().as_bytes());
+    }
+    hasher.finish()
+}
+
+/// Hashes the names, types, permissions and contents of `sources` and
+/// everything beneath them. Ownership and timestamps are left out, so a
+/// fresh checkout of the same files hits the cache.
+pub fn hash_sources(context: &Path, sources: &[String]) -> Result<Digest> {
+    let mut hasher = Hasher::new(Algorithm::Sha256);
+    for source in sources {
+        let path = resolve_source(context, source)?;
+        hasher.update(source.as_bytes());
+        hasher.update(b"\0");
+
+        let mut entries = vec![Change::new(PathBuf::new(), ChangeKind::Added)];
+        if path.is_dir() {
+            diff::add_tree(&path, Path::new(""), &mut entries)?;
+        }
+        for entry in entries {
+            hash_entry(&path, &entry.path, &mut hasher)?;
+        }
+    }
+    Ok(hasher.finish())
+}
+
+fn hash_entry(root: &Path, rel: &Path, hasher: &mut Hasher) -> Result<()> {
+    // The source itself may be a symlink, which COPY follows.
+    let (full, meta) = if rel.as_os_str().is_empty() {
+        (root.to_path_buf(), fs::metadata(root)?)
+    } else {
+        let full = root.join(rel);
+        let meta = fs::symlink_metadata(&full)?;
+        (full, meta)
+    };
+    hasher.update(format!("{}\0{:o}\0", rel.display(), meta.mode()).as_bytes());
+    if meta.is_file() {
+        let mut writer = HashingWriter::new(io::sink(), Algorithm::Sha256);
+        io::copy(&mut File::open(&full)?, &mut writer)?;
+        hasher.update(writer.finish().1.to_string().as_bytes());
+    } else if meta.file_type().is_symlink() {
+        hasher.update(fs::read_link(&full)?.as_os_str().as_encoded_bytes());
+    }
+    hasher.update(b"\0");
+    Ok(())
+}
+
+/// Writes an image with no layers for the host platform, what `FROM
+/// scratch` builds on.
+pub fn scratch_image(store: &ImageStore) -> Result<Descriptor> {
+    let platform = Platform::host();
+    let config = ImageConfig {
+        architecture: platform.architecture,
+        os: platform.os,
+        variant: platform.variant,
+        ..Default::default()
+    };
+    write_image(store, Vec::new(), &config)
+}
+
+/// Applies an instruction that only changes the image config. Returns
+/// false for the ones that need a container.
+pub fn apply_config(config: &mut ContainerConfig, instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Env(pairs) => {
+            for (key, value) in pairs {
+                config.env.retain(|pair| pair.split_once('=').map_or(pair.as_str(), |(k, _)| k) != key);
+                config.env.push(format!("{}={}", key, value));
+            }
+        }
+        Instruction::Workdir(dir) => config.working_dir = working_dir(&config.working_dir, dir),
+        Instruction::User(user) => config.user = user.clone(),
+        // As in Docker, a new entrypoint drops the base image's command.
+        Instruction::Entrypoint(argv) => {
+            config.entrypoint = argv.clone();
+            config.cmd.clear();
+        }
+        Instruction::Cmd(argv) => config.cmd = argv.clone(),
+        Instruction::Label(pairs) => config.labels.extend(pairs.iter().cloned()),
+        Instruction::Expose(ports) => config.exposed_ports.extend(ports.iter().cloned()),
+        Instruction::Run(_) | Instruction::Copy { .. } | Instruction::Add { .. } => return false,
+    }
+    true
+}
+
+/// `dir` taken relative to the current working directory, `/` when unset,
+/// as an absolute path without `.` or `..`.
+pub fn working_dir(current: &str, dir: &str) -> String {
+    let current = if current.is_empty() { "/" } else { current };
+    Path::new("/")
+        .join(container_path(&Path::new(current).join(dir)))
+        .display()
+        .to_string()
+}
+
+/// Copies `sources` from the build context into `rootfs` at `destination`,
+/// relative to `working_dir`, as `COPY` does: a directory's contents are
+/// copied rather than the directory itself, and a file lands inside the
+/// destination when it is a directory or ends in `/`. With
+/// `extract_archives`, as for `ADD`, a tar file (plain, gzip or zstd) is
+/// unpacked into the destination instead.
+///
+/// Sources are resolved inside the context and the destination inside the
+/// rootfs, symlinks included. Files keep their mode; ownership is that of
+/// the context, which the daemon unpacks as root.
+pub fn copy_sources(
+    context: &Path,
+    rootfs: &Path,
+    sources: &[String],
+    destination: &str,
+    working_dir: &str,
+    extract_archives: bool,
+) -> Result<()> {
+    let dest = container_path(&Path::new(working_dir).join(destination));
+    let into_dir = destination.ends_with('/') || resolve_in_root(rootfs, &dest)?.is_dir();
+
+    for source in sources {
+        if extract_archives && (source.starts_with("http://") || source.starts_with("https://")) {
+            return Err(AethelError::Build(format!("ADD from a URL is not supported: {}", source)));
+        }
+        let path = resolve_source(context, source)?;
+        if path.is_dir() {
+            let mut entries = Vec::new();
+            diff::add_tree(&path, Path::new(""), &mut entries)?;
+            copy_entries(&path, &entries, rootfs, Path::new(""), &dest)?;
+            continue;
+        }
+
+        if extract_archives {
+            if let Some(media_type) = archive_media_type(&path)? {
+                LayerApplier::new(rootfs)
+                    .with_whiteout_mode(WhiteoutMode::Literal)
+                    .with_rebase(Path::new(""), &dest)
+                    .apply(LayerReader::new(media_type, File::open(&path)?)?)?;
+                continue;
+            }
+        }
+
+        let name = Path::new(path.file_name().expect("resolved sources are named"));
+        let target = if into_dir { dest.join(name) } else { dest.clone() };
+        let parent = path.parent().expect("resolved sources have a parent");
+        copy_entries(parent, &[Change::new(name.to_path_buf(), ChangeKind::Added)], rootfs, name, &target)?;
+    }
+    Ok(())
+}
+
+/// Streams `entries` from `root` through a pipe into `rootfs`, moving
+/// those under `from` to `to`.
+pub(crate) fn copy_entries(root: &Path, entries: &[Change], rootfs: &Path, from: &Path, to: &Path) -> Result<()> {
+    let (mut reader, writer) = io::pipe()?;
+    thread::scope(|scope| {
+        let writing = scope.spawn(move || diff::write_layer(root, entries, writer).map(drop));
+        let applied = LayerApplier::new(rootfs)
+            .with_whiteout_mode(WhiteoutMode::Literal)
+            .with_rebase(from, to)
+            .apply(&mut reader)
+            .map(drop);
+        // Reading stops at the end-of-archive marker; drain the padding
+        // after it, or let a failed apply unblock the writer by dropping
+        // the read end.
+        let drained = applied.and_then(|()| Ok(io::copy(&mut reader, &mut io::sink()).map(drop)?));
+        drop(reader);
+        let written = writing.join().expect("layer writer panicked");
+        drained.and(written)
+    })
+}
+
+/// Resolves `source` inside the build context; `..` may not leave it.
+pub fn resolve_source(context: &Path, source: &str) -> Result<PathBuf> {
+    let rel: PathBuf = Path::new(source)
+        .components()
+        .filter(|component| !matches!(component, Component::RootDir | Component::CurDir))
+        .collect();
+    let path = resolve_in_root(context, &rel).map_err(|e| match e {
+        AethelError::Filesystem(_) => AethelError::Build(format!("{} is outside the build context", source)),
+        e => e,
+    })?;
+    match fs::metadata(&path) {
+        Ok(_) => Ok(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(AethelError::NotFound(format!(
+            "{} not found in the build context",
+            source
+        ))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The layer media type matching a tar file's compression, judged by its
+/// first bytes, or `None` for a file that is not an archive.
+fn archive_media_type(path: &Path) -> Result<Option<&'static str>> {
+    let mut header = Vec::with_capacity(262);
+    File::open(path)?.take(262).read_to_end(&mut header)?;
+    Ok(if header.starts_with(&[0x1f, 0x8b]) {
+        Some(oci::MEDIA_TYPE_OCI_LAYER_GZIP)
+    } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Some(oci::MEDIA_TYPE_OCI_LAYER_ZSTD)
+    } else if header.get(257..262) == Some(b"ustar".as_slice()) {
+        Some(oci::MEDIA_TYPE_OCI_LAYER)
+    } else {
+        None
+    })
+}
+
+/// Resolves `user[:group]`, names or numeric IDs, against the rootfs'
+/// `/etc/passwd` and `/etc/group`. A user without a group runs with its
+/// primary group, or group 0 when the user is not in `/etc/passwd`.
+pub fn resolve_user(rootfs: &Path, user: &str) -> Result<(u32, u32)> {
+    let (name, group) = match user.split_once(':') {
+        Some((name, group)) => (name, Some(group)),
+        None => (user, None),
+    };
+    let passwd = read_database(rootfs, "etc/passwd")?;
+    let entry = passwd
+        .iter()
+        .find(|fields| fields[0] == name || (name.parse::<u32>().is_ok() && fields[2] == name));
+    let uid = match (name.parse::<u32>(), entry) {
+        (Ok(uid), _) => uid,
+        (Err(_), Some(fields)) => parse_id(&fields[2], user)?,
+        (Err(_), None) => {
+            return Err(AethelError::Build(format!("user {} not found in /etc/passwd", name)))
+        }
+    };
+
+    let gid = match group {
+        None => entry.map_or(Ok(0), |fields| parse_id(&fields[3], user))?,
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => {
+                let groups = read_database(rootfs, "etc/group")?;
+                let fields = groups
+                    .iter()
+                    .find(|fields| fields[0] == group)
+                    .ok_or_else(|| AethelError::Build(format!("group {} not found in /etc/group", group)))?;
+                parse_id(&fields[2], user)?
+            }
+        },
+    };
+    Ok((uid, gid))
+}
+
+/// The colon-separated records of a passwd-style file, each with at least
+/// four fields; a missing file has none.
+fn read_database(rootfs: &Path, rel: &str) -> Result<Vec<Vec<String>>> {
+    let contents = match fs::read_to_string(resolve_in_root(rootfs, Path::new(rel))?) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(contents
+        .lines()
+        .map(|line| line.split(':').map(str::to_string).collect::<Vec<_>>())
+        .filter(|fields| fields.len() >= 4)
+        .collect())
+}
+
+fn parse_id(field: &str, user: &str) -> Result<u32> {
+    field
+        .parse()
+        .map_err(|_| AethelError::Build(format!("invalid ID '{}' resolving user {}", field, user)))
+}
+
+/// Makes sure the working directory exists before a `RUN` or a container
+/// starts in it, as Docker does.
+pub fn ensure_working_dir(rootfs: &Path, working_dir: &str) -> Result<()> {
+    let dir = resolve_in_root(rootfs, &container_path(Path::new(working_dir)))?;
+    if !dir.is_dir() {
+        fs::create_dir_all(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn context() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let (context, rootfs) = (dir.path().join("context"), dir.path().join("rootfs"));
+        fs::create_dir_all(context.join("conf/site")).unwrap();
+        fs::write(context.join("conf/app.conf"), "port=80").unwrap();
+        fs::write(context.join("conf/site/index.html"), "<h1>").unwrap();
+        fs::write(context.join("run.sh"), "#!/bin/sh").unwrap();
+        fs::set_permissions(context.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir_all(rootfs.join("etc")).unwrap();
+        symlink("/etc", rootfs.join("config")).unwrap();
+        (dir, context, rootfs)
+    }
+
+    #[test]
+    fn copies_directory_contents_and_files() {
+        let (dir, context, rootfs) = context();
+
+        copy_sources(&context, &rootfs, &["conf".to_string()], "/config/app", "/", false).unwrap();
+        copy_sources(&context, &rootfs, &["./run.sh".to_string()], "bin/", "/srv", false).unwrap();
+        copy_sources(&context, &rootfs, &["run.sh".to_string()], "entrypoint", "/", false).unwrap();
+        let escape = copy_sources(&context, &rootfs, &["../rootfs".to_string()], "/", "/", false);
+        let missing = copy_sources(&context, &rootfs, &["nope".to_string()], "/", "/", false);
+
+        assert_eq!(fs::read_to_string(rootfs.join("etc/app/app.conf")).unwrap(), "port=80");
+        assert_eq!(fs::read_to_string(rootfs.join("etc/app/site/index.html")).unwrap(), "<h1>");
+        assert!(!dir.path().join("etc").exists());
+        let script = fs::metadata(rootfs.join("srv/bin/run.sh")).unwrap();
+        assert_eq!(script.permissions().mode() & 0o777, 0o755);
+        assert_eq!(fs::read_to_string(rootfs.join("entrypoint")).unwrap(), "#!/bin/sh");
+        assert!(matches!(escape, Err(AethelError::Build(_))));
+        assert!(matches!(missing, Err(AethelError::NotFound(_))));
+    }
+
+    #[test]
+    fn add_extracts_archives() {
+        let (_dir, context, rootfs) = context();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_mtime(1);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_size(5);
+        builder.append_data(&mut header, "vendor/lib.txt", "hello".as_bytes()).unwrap();
+        fs::write(context.join("vendor.tar.gz"), builder.into_inner().unwrap().finish().unwrap()).unwrap();
+
+        copy_sources(&context, &rootfs, &["vendor.tar.gz".to_string()], "/opt", "/", true).unwrap();
+        copy_sources(&context, &rootfs, &["vendor.tar.gz".to_string()], "/copied/", "/", false).unwrap();
+
+        assert_eq!(fs::read_to_string(rootfs.join("opt/vendor/lib.txt")).unwrap(), "hello");
+        assert!(rootfs.join("copied/vendor.tar.gz").is_file());
+    }
+
+    #[test]
+    fn source_hash_follows_content_not_timestamps() {
+        let (_dir, context, _rootfs) = context();
+        let sources = ["conf".to_string(), "run.sh".to_string()];
+        let before = hash_sources(&context, &sources).unwrap();
+
+        fs::write(context.join("conf/app.conf"), "port=80").unwrap();
+        assert_eq!(hash_sources(&context, &sources).unwrap(), before);
+        fs::write(context.join("conf/app.conf"), "port=81").unwrap();
+        assert_ne!(hash_sources(&context, &sources).unwrap(), before);
+    }
+
+    #[test]
+    fn cache_misses_once_the_image_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        let cache = BuildCache::new(&store);
+        let image = scratch_image(&store).unwrap();
+        let key = cache_key(&image, "RUN true", None);
+
+        assert!(cache.get(&store, &key).unwrap().is_none());
+        cache.insert(&key, &image).unwrap();
+        assert_eq!(cache.get(&store, &key).unwrap().unwrap().digest, image.digest);
+
+        store.remove_blob(&image.digest.parse().unwrap()).unwrap();
+        assert!(cache.get(&store, &key).unwrap().is_none());
+    }
+
+    #[test]
+    fn applies_config_instructions() {
+        let mut config = ContainerConfig {
+            env: vec!["PATH=/bin".to_string()],
+            cmd: vec!["sh".to_string()],
+            ..Default::default()
+        };
+        for instruction in [
+            Instruction::Env(vec![("PATH".to_string(), "/usr/bin:/bin".to_string())]),
+            Instruction::Workdir("/srv".to_string()),
+            Instruction::Workdir("app/../www".to_string()),
+            Instruction::Entrypoint(vec!["nginx".to_string()]),
+            Instruction::Expose(vec!["80/tcp".to_string()]),
+        ] {
+            assert!(apply_config(&mut config, &instruction));
+        }
+
+        assert_eq!(config.env, ["PATH=/usr/bin:/bin"]);
+        assert_eq!(config.working_dir, "/srv/www");
+        assert_eq!(config.entrypoint, ["nginx"]);
+        assert!(config.cmd.is_empty());
+        assert!(config.exposed_ports.contains("80/tcp"));
+        assert!(!apply_config(&mut config, &Instruction::Run(vec!["true".to_string()])));
+    }
+
+    #[test]
+    fn resolves_users_from_the_rootfs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("etc")).unwrap();
+        fs::write(dir.path().join("etc/passwd"), "root:x:0:0::/root:/bin/sh\nnginx:x:101:102::/:/sbin/nologin\n").unwrap();
+        fs::write(dir.path().join("etc/group"), "root:x:0:\nwww:x:33:nginx\n").unwrap();
+
+        assert_eq!(resolve_user(dir.path(), "nginx").unwrap(), (101, 102));
+        assert_eq!(resolve_user(dir.path(), "nginx:www").unwrap(), (101, 33));
+        assert_eq!(resolve_user(dir.path(), "1000").unwrap(), (1000, 0));
+        assert_eq!(resolve_user(dir.path(), "101:7").unwrap(), (101, 7));
+        assert!(matches!(resolve_user(dir.path(), "nobody"), Err(AethelError::Build(_))));
+    }
+}
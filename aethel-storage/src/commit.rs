@@ -0,0 +1,250 @@
+use aethel_common::error::Result;
+use aethel_common::time::format_rfc3339;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use crate::diff::{self, Change};
+use crate::digest::{Algorithm, Digest, HashingWriter};
+use crate::oci::{self, ContainerConfig, Descriptor, History, ImageConfig, OciManifest, Platform};
+use crate::reference::Reference;
+use crate::store::ImageStore;
+use crate::{encode_json, resolve_image};
+
+static INGEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// What a commit records besides the new layer.
+#[derive(Debug, Clone, Default)]
+pub struct CommitOptions {
+    /// Pointed at the new image when set.
+    pub reference: Option<Reference>,
+    pub author: Option<String>,
+    pub comment: Option<String>,
+    /// The history entry's `created_by`, usually the container's command.
+    pub created_by: Option<String>,
+}
+
+/// Builds a new image from the image `target` plus one layer holding
+/// `changes`, whose content is read from `root`: the container's upperdir
+/// for overlay changes, its rootfs for tree changes. The config gains the
+/// layer's diff ID and a history entry. Returns the new manifest.
+pub fn commit(
+    store: &ImageStore,
+    target: &Descriptor,
+    platform: Option<&Platform>,
+    root: &Path,
+    changes: &[Change],
+    options: &CommitOptions,
+) -> Result<Descriptor> {
+    let (manifest, mut config) = resolve_image(store, target, platform)?;
+    let (layer, diff_id) = write_layer_blob(store, root, changes)?;
+
+    config.rootfs.diff_ids.push(diff_id.to_string());
+    record_history(&mut config, options, false);
+
+    let mut layers = manifest.layers;
+    layers.push(layer);
+    finish(store, layers, &config, options)
+}
+
+/// Builds a new image from the image `target` with its execution defaults
+/// replaced by `container_config` and no new layer. The history entry is
+/// marked empty. Returns the new manifest.
+pub fn commit_config(
+    store: &ImageStore,
+    target: &Descriptor,
+    platform: Option<&Platform>,
+    container_config: ContainerConfig,
+    options: &CommitOptions,
+) -> Result<Descriptor> {
+    let (manifest, mut config) = resolve_image(store, target, platform)?;
+    config.config = container_config;
+    record_history(&mut config, options, true);
+    finish(store, manifest.layers, &config, options)
+}
+
+fn record_history(config: &mut ImageConfig, options: &CommitOptions, empty_layer: bool) {
+    let created = format_rfc3339(SystemTime::now());
+    config.created = Some(created.clone());
+    if options.author.is_some() {
+        config.author = options.author.clone();
+    }
+    config.history.push(History {
+        created: Some(created),
+        created_by: options.created_by.clone(),
+        author: options.author.clone(),
+        comment: options.comment.clone(),
+        empty_layer,
+    });
+}
+
+fn finish(store: &ImageStore, layers: Vec<Descriptor>, config: &ImageConfig, options: &CommitOptions) -> Result<Descriptor> {
+    let target = write_image(store, layers, config)?;
+    if let Some(reference) = &options.reference {
+        store.set_reference(reference, target.clone())?;
+    }
+    Ok(target)
+}
+
+/// Writes `config` and a manifest listing `layers` into the store. Returns
+/// the manifest, carrying the config's platform.
+pub(crate) fn write_image(store: &ImageStore, layers: Vec<Descriptor>, config: &ImageConfig) -> Result<Descriptor> {
+    let manifest = OciManifest {
+        schema_version: 2,
+        media_type: Some(oci::MEDIA_TYPE_OCI_MANIFEST.to_string()),
+        config: write_json_blob(store, oci::MEDIA_TYPE_OCI_CONFIG, config, "image config")?,
+        layers,
+        annotations: HashMap::new(),
+    };
+    let mut target = write_json_blob(store, oci::MEDIA_TYPE_OCI_MANIFEST, &manifest, "manifest")?;
+    target.platform = (!config.os.is_empty()).then(|| config.platform());
+    Ok(target)
+}
+
+/// Writes the gzipped layer into the store. Returns its descriptor and the
+/// digest of the uncompressed tar, its diff ID.
+fn write_layer_blob(store: &ImageStore, root: &Path, changes: &[Change]) -> Result<(Descriptor, Digest)> {
+    let ingest = store.ingest_dir();
+    fs::create_dir_all(&ingest)?;
+    let path = ingest.join(format!(
+        "commit-{}-{}",
+        process::id(),
+        INGEST_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = (|| -> Result<(Descriptor, Digest)> {
+        let blob = HashingWriter::new(File::create(&path)?, Algorithm::Sha256);
+        let tar = HashingWriter::new(GzEncoder::new(blob, flate2::Compression::default()), Algorithm::Sha256);
+        let (gzip, diff_id, _) = diff::write_layer(root, changes, tar)?.finish();
+        let (file, digest, size) = gzip.finish()?.finish();
+        file.sync_all()?;
+        store.commit_blob(&digest, &path)?;
+
+        let layer = Descriptor {
+            media_type: oci::MEDIA_TYPE_OCI_LAYER_GZIP.to_string(),
+            digest: digest.to_string(),
+            size,
+            platform: None,
+            annotations: HashMap::new(),
+        };
+        Ok((layer, diff_id))
+    })();
+
+    if path.exists() {
+        let _ = fs::remove_file(&path);
+    }
+    result
+}
+
+fn write_json_blob<T: Serialize>(store: &ImageStore, media_type: &str, value: &T, what: &str) -> Result<Descriptor> {
+    let bytes = encode_json(value, what)?;
+    let digest = Digest::of_bytes(Algorithm::Sha256, &bytes);
+    store.write_blob(&digest, &bytes)?;
+    Ok(Descriptor {
+        media_type: media_type.to_string(),
+        digest: digest.to_string(),
+        size: bytes.len() as u64,
+        platform: None,
+        annotations: HashMap::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::tree_changes;
+    use crate::layer::LayerApplier;
+    use crate::prepare_rootfs;
+    use tar::{Builder, EntryType, Header};
+
+    fn base_layer() -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for (path, contents) in [("etc/hostname", "base"), ("etc/motd", "hello"), ("usr/bin/tool", "#!")] {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_mtime(1);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_size(contents.len() as u64);
+            builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn write_base_image(store: &ImageStore, layer: &[u8]) -> Descriptor {
+        let platform = Platform::host();
+        let layer_digest = Digest::of_bytes(Algorithm::Sha256, layer);
+        store.write_blob(&layer_digest, layer).unwrap();
+        let mut config = ImageConfig {
+            architecture: platform.architecture.clone(),
+            os: platform.os.clone(),
+            variant: platform.variant.clone(),
+            ..Default::default()
+        };
+        config.rootfs.diff_ids.push(layer_digest.to_string());
+
+        let manifest = OciManifest {
+            schema_version: 2,
+            media_type: Some(oci::MEDIA_TYPE_OCI_MANIFEST.to_string()),
+            config: write_json_blob(store, oci::MEDIA_TYPE_OCI_CONFIG, &config, "image config").unwrap(),
+            layers: vec![Descriptor {
+                media_type: oci::MEDIA_TYPE_OCI_LAYER.to_string(),
+                digest: layer_digest.to_string(),
+                size: layer.len() as u64,
+                platform: None,
+                annotations: HashMap::new(),
+            }],
+            annotations: HashMap::new(),
+        };
+        write_json_blob(store, oci::MEDIA_TYPE_OCI_MANIFEST, &manifest, "manifest").unwrap()
+    }
+
+    fn unpack(layer: &[u8], root: &Path) {
+        LayerApplier::new(root)
+            .with_preserve_ownership(false)
+            .apply(layer)
+            .unwrap();
+    }
+
+    #[test]
+    fn commits_tree_changes_as_a_new_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ImageStore::new(&dir.path().join("store")).unwrap();
+        let layer = base_layer();
+        let base_image = write_base_image(&store, &layer);
+
+        let (base, rootfs) = (dir.path().join("base"), dir.path().join("rootfs"));
+        unpack(&layer, &base);
+        unpack(&layer, &rootfs);
+        fs::write(rootfs.join("etc/hostname"), "committed").unwrap();
+        fs::remove_file(rootfs.join("etc/motd")).unwrap();
+        fs::create_dir(rootfs.join("data")).unwrap();
+        fs::write(rootfs.join("data/new"), "new").unwrap();
+
+        let changes = tree_changes(&base, &rootfs).unwrap();
+        let options = CommitOptions {
+            reference: Some("example/committed:v1".parse().unwrap()),
+            author: Some("tester".to_string()),
+            comment: Some("snapshot".to_string()),
+            created_by: None,
+        };
+        let target = commit(&store, &base_image, None, &rootfs, &changes, &options).unwrap();
+
+        assert_eq!(store.resolve("example/committed:v1").unwrap().digest, target.digest);
+        let out = dir.path().join("out");
+        let config = prepare_rootfs(&store, &target, &out, None).unwrap();
+        assert_eq!(config.rootfs.diff_ids.len(), 2);
+        assert_eq!(config.history.last().unwrap().comment.as_deref(), Some("snapshot"));
+        assert_eq!(config.author.as_deref(), Some("tester"));
+        assert_eq!(fs::read_to_string(out.join("etc/hostname")).unwrap(), "committed");
+        assert_eq!(fs::read_to_string(out.join("data/new")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(out.join("usr/bin/tool")).unwrap(), "#!");
+        assert!(!out.join("etc/motd").exists());
+    }
+}
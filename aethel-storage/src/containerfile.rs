@@ -0,0 +1,505 @@
+use aethel_common::error::{AethelError, Result};
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// The shell that shell-form `RUN`, `CMD` and `ENTRYPOINT` run under.
+const SHELL: [&str; 2] = ["/bin/sh", "-c"];
+
+/// A parsed Containerfile: a single `FROM` and the steps on top of it.
+#[derive(Debug, Clone)]
+pub struct Containerfile {
+    /// The base image, or `scratch` for none.
+    pub from: String,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Keyword {
+    Run,
+    Copy,
+    Add,
+    Env,
+    Workdir,
+    User,
+    Entrypoint,
+    Cmd,
+    Label,
+    Expose,
+}
+
+impl Keyword {
+    fn name(&self) -> &'static str {
+        match self {
+            Keyword::Run => "RUN",
+            Keyword::Copy => "COPY",
+            Keyword::Add => "ADD",
+            Keyword::Env => "ENV",
+            Keyword::Workdir => "WORKDIR",
+            Keyword::User => "USER",
+            Keyword::Entrypoint => "ENTRYPOINT",
+            Keyword::Cmd => "CMD",
+            Keyword::Label => "LABEL",
+            Keyword::Expose => "EXPOSE",
+        }
+    }
+}
+
+/// One instruction after `FROM`, as written. Variables are only expanded
+/// by `Step::instruction`, once the environment the step runs in is known.
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// The line the instruction starts on.
+    pub line: usize,
+    keyword: Keyword,
+    args: String,
+}
+
+/// A step with its arguments split, unquoted and expanded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    /// The command to run, shell form already wrapped in `/bin/sh -c`.
+    Run(Vec<String>),
+    Copy { sources: Vec<String>, destination: String },
+    /// Like `Copy`, but local tar archives are extracted.
+    Add { sources: Vec<String>, destination: String },
+    Env(Vec<(String, String)>),
+    Workdir(String),
+    User(String),
+    Entrypoint(Vec<String>),
+    Cmd(Vec<String>),
+    Label(Vec<(String, String)>),
+    /// Ports in `port/protocol` form.
+    Expose(Vec<String>),
+}
+
+impl Step {
+    /// The instruction as written, continuation lines joined; the image
+    /// history records this.
+    pub fn text(&self) -> String {
+        format!("{} {}", self.keyword.name(), self.args)
+    }
+
+    /// Interprets the step with `env`, `NAME=value` pairs, as the
+    /// environment `$NAME` and `${NAME}` expand from. Exec-form (JSON)
+    /// commands and shell-form `RUN` are not expanded; the shell does that.
+    pub fn instruction(&self, env: &[String]) -> Result<Instruction> {
+        self.interpret(env)
+            .map_err(|e| AethelError::Build(format!("line {}: {}: {}", self.line, self.keyword.name(), e)))
+    }
+
+    fn interpret(&self, env: &[String]) -> std::result::Result<Instruction, String> {
+        let args = self.args.as_str();
+        Ok(match self.keyword {
+            Keyword::Run => {
+                let argv = command(args);
+                if argv.is_empty() {
+                    return Err("needs a command".to_string());
+                }
+                Instruction::Run(argv)
+            }
+            Keyword::Entrypoint => Instruction::Entrypoint(command(args)),
+            Keyword::Cmd => Instruction::Cmd(command(args)),
+            Keyword::Copy | Keyword::Add => {
+                let mut paths = match exec_form(args) {
+                    Some(paths) => paths
+                        .iter()
+                        .map(|path| expand(path, env))
+                        .collect::<std::result::Result<Vec<_>, _>>()?,
+                    None => split_words(args, env)?,
+                };
+                if let Some(flag) = paths.iter().find(|path| path.starts_with("--")) {
+                    return Err(format!("flag {} is not supported", flag));
+                }
+                if paths.len() < 2 {
+                    return Err("needs at least one source and a destination".to_string());
+                }
+                let destination = paths.pop().expect("checked above");
+                if paths.len() > 1 && !destination.ends_with('/') {
+                    return Err("the destination of several sources must be a directory ending in /".to_string());
+                }
+                if self.keyword == Keyword::Copy {
+                    Instruction::Copy { sources: paths, destination }
+                } else {
+                    Instruction::Add { sources: paths, destination }
+                }
+            }
+            Keyword::Env => Instruction::Env(key_values(args, env, true)?),
+            Keyword::Label => Instruction::Label(key_values(args, env, false)?),
+            Keyword::Workdir => Instruction::Workdir(expand(args, env)?),
+            Keyword::User => Instruction::User(expand(args, env)?),
+            Keyword::Expose => Instruction::Expose(
+                split_words(args, env)?
+                    .iter()
+                    .map(String::as_str)
+                    .map(exposed_port)
+                    .collect::<std::result::Result<_, _>>()?,
+            ),
+        })
+    }
+}
+
+/// Parses the subset of the Containerfile format the builder supports:
+/// one `FROM`, then `RUN`, `COPY`, `ADD`, `ENV`, `WORKDIR`, `USER`,
+/// `ENTRYPOINT`, `CMD`, `LABEL` and `EXPOSE`. Lines ending in `\` continue
+/// on the next one and `#` starts a comment line. Every step is checked
+/// here, so a malformed one fails before anything runs.
+pub fn parse(source: &str) -> Result<Containerfile> {
+    let mut from = None;
+    let mut steps = Vec::new();
+
+    for (line, text) in logical_lines(source) {
+        let fail = |msg: String| AethelError::Build(format!("line {}: {}", line, msg));
+        let (word, args) = text.split_once(char::is_whitespace).unwrap_or((text.as_str(), ""));
+        let args = args.trim();
+        let name = word.to_ascii_uppercase();
+        if args.is_empty() {
+            return Err(fail(format!("{} needs arguments", name)));
+        }
+
+        let keyword = match name.as_str() {
+            "FROM" => {
+                if from.is_some() {
+                    return Err(fail("multi-stage builds are not supported".to_string()));
+                }
+                from = Some(base_image(args).map_err(fail)?);
+                continue;
+            }
+            _ if from.is_none() => return Err(fail(format!("{} before FROM", name))),
+            "RUN" => Keyword::Run,
+            "COPY" => Keyword::Copy,
+            "ADD" => Keyword::Add,
+            "ENV" => Keyword::Env,
+            "WORKDIR" => Keyword::Workdir,
+            "USER" => Keyword::User,
+            "ENTRYPOINT" => Keyword::Entrypoint,
+            "CMD" => Keyword::Cmd,
+            "LABEL" => Keyword::Label,
+            "EXPOSE" => Keyword::Expose,
+            other => return Err(fail(format!("unsupported instruction {}", other))),
+        };
+        let step = Step {
+            line,
+            keyword,
+            args: args.to_string(),
+        };
+        step.instruction(&[])?;
+        steps.push(step);
+    }
+
+    let from = from.ok_or_else(|| AethelError::Build("no FROM instruction".to_string()))?;
+    Ok(Containerfile { from, steps })
+}
+
+/// Joins continuation lines and drops blank and comment lines. Each
+/// instruction comes with the number of its first line.
+fn logical_lines(source: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut pending: Option<(usize, String)> = None;
+
+    for (index, raw) in source.lines().enumerate() {
+        let trimmed = raw.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let (start, mut text) = pending.take().unwrap_or((index + 1, String::new()));
+        let raw = raw.trim_end();
+        match raw.strip_suffix('\\') {
+            Some(head) => {
+                text.push_str(head);
+                pending = Some((start, text));
+            }
+            None => {
+                text.push_str(raw);
+                lines.push((start, text.trim().to_string()));
+            }
+        }
+    }
+    if let Some((start, text)) = pending {
+        lines.push((start, text.trim().to_string()));
+    }
+    lines
+}
+
+/// `image [AS name]`; the stage name is accepted and ignored.
+fn base_image(args: &str) -> std::result::Result<String, String> {
+    let words: Vec<&str> = args.split_whitespace().collect();
+    match words.as_slice() {
+        [flag, ..] if flag.starts_with("--") => Err(format!("FROM flag {} is not supported", flag)),
+        [image] => Ok(image.to_string()),
+        [image, as_, _] if as_.eq_ignore_ascii_case("as") => Ok(image.to_string()),
+        _ => Err(format!("invalid FROM '{}'", args)),
+    }
+}
+
+/// A JSON array of strings, the exec form; anything else is shell form.
+fn exec_form(args: &str) -> Option<Vec<String>> {
+    if !args.starts_with('[') {
+        return None;
+    }
+    serde_json::from_str(args).ok()
+}
+
+fn command(args: &str) -> Vec<String> {
+    exec_form(args).unwrap_or_else(|| SHELL.iter().map(|s| s.to_string()).chain([args.to_string()]).collect())
+}
+
+/// `KEY=value ...`. `ENV` also takes the older `KEY value` form, where the
+/// value is the rest of the line.
+fn key_values(args: &str, env: &[String], allow_legacy: bool) -> std::result::Result<Vec<(String, String)>, String> {
+    let words = split_words(args, env)?;
+    if allow_legacy && !words[0].contains('=') {
+        let (key, value) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let value = split_words(value, env)?.join(" ");
+        return Ok(vec![(key.to_string(), value)]);
+    }
+
+    words
+        .into_iter()
+        .map(|word| match word.split_once('=') {
+            Some(("", _)) => Err(format!("'{}' has an empty key", word)),
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            None => Err(format!("'{}' is not KEY=value", word)),
+        })
+        .collect()
+}
+
+/// `port[/protocol]` or `first-last[/protocol]`, normalised to name the
+/// protocol, `tcp` by default.
+fn exposed_port(port: &str) -> std::result::Result<String, String> {
+    let (ports, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+    let protocol = protocol.to_ascii_lowercase();
+    let valid_ports = ports.split('-').count() <= 2 && ports.split('-').all(|p| p.parse::<u16>().is_ok());
+    if !valid_ports || !matches!(protocol.as_str(), "tcp" | "udp" | "sctp") {
+        return Err(format!("invalid port '{}'", port));
+    }
+    Ok(format!("{}/{}", ports, protocol))
+}
+
+/// Splits `text` into words as the shell would: whitespace separates them,
+/// quotes group them and `\` escapes the next character. Variables expand
+/// from `env` except inside single quotes.
+fn split_words(text: &str, env: &[String]) -> std::result::Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.push(c),
+            (_, '\\') => {
+                in_word = true;
+                word.push(chars.next().unwrap_or('\\'));
+            }
+            (_, '$') => {
+                in_word = true;
+                expand_variable(&mut chars, env, &mut word)?;
+            }
+            (_, c) => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err(format!("unterminated quote in '{}'", text));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Expands variables in `text`, leaving everything else as written. `\$`
+/// is a literal `$`.
+fn expand(text: &str, env: &[String]) -> std::result::Result<String, String> {
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'$') => out.push(chars.next().expect("peeked")),
+            '$' => expand_variable(&mut chars, env, &mut out)?,
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+/// Expands the variable after a `$`: `NAME`, `{NAME}`, `{NAME:-default}`
+/// (when unset or empty) or `{NAME:+replacement}` (when set and not empty).
+/// A `$` not followed by a name is literal.
+fn expand_variable(chars: &mut Peekable<Chars>, env: &[String], out: &mut String) -> std::result::Result<(), String> {
+    if chars.peek() == Some(&'{') {
+        chars.next();
+        let mut inner = String::new();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => inner.push(c),
+                None => return Err("unterminated ${".to_string()),
+            }
+        }
+        let (name, modifier) = match inner.find(':') {
+            Some(at) => (&inner[..at], Some(&inner[at..])),
+            None => (inner.as_str(), None),
+        };
+        if !is_name(name) {
+            return Err(format!("invalid variable name '{}'", name));
+        }
+        let value = lookup(env, name).filter(|value| !value.is_empty());
+        match (modifier, value) {
+            (None, value) => out.push_str(value.unwrap_or_default()),
+            (Some(m), None) if m.starts_with(":-") => out.push_str(&expand(&m[2..], env)?),
+            (Some(m), Some(value)) if m.starts_with(":-") => out.push_str(value),
+            (Some(m), Some(_)) if m.starts_with(":+") => out.push_str(&expand(&m[2..], env)?),
+            (Some(m), None) if m.starts_with(":+") => {}
+            (Some(m), _) => return Err(format!("unsupported modifier '{}' in ${{{}}}", m, inner)),
+        }
+        return Ok(());
+    }
+
+    let mut name = String::new();
+    while let Some(&c) = chars.peek() {
+        if !(c == '_' || c.is_ascii_alphanumeric()) || (name.is_empty() && c.is_ascii_digit()) {
+            break;
+        }
+        name.push(c);
+        chars.next();
+    }
+    if name.is_empty() {
+        out.push('$');
+    } else {
+        out.push_str(lookup(env, &name).unwrap_or_default());
+    }
+    Ok(())
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// The value of `name` in `env`, `NAME=value` pairs; the last one wins.
+pub fn lookup<'a>(env: &'a [String], name: &str) -> Option<&'a str> {
+    env.iter()
+        .rev()
+        .find_map(|pair| pair.split_once('=').filter(|(key, _)| *key == name).map(|(_, value)| value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(pairs: &[&str]) -> Vec<String> {
+        pairs.iter().map(|pair| pair.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_steps_with_continuations_and_comments() {
+        let file = parse(
+            "# syntax=docker/dockerfile:1\n\
+             FROM alpine:3.19 AS base\n\
+             \n\
+             RUN apk add --no-cache curl \\\n\
+             # a comment inside the continuation\n\
+                 && rm -rf /tmp/*\n\
+             CMD [\"curl\", \"--help\"]\n\
+             expose 80 53/UDP 8000-8010\n",
+        )
+        .unwrap();
+
+        assert_eq!(file.from, "alpine:3.19");
+        let instructions: Vec<Instruction> = file.steps.iter().map(|step| step.instruction(&[]).unwrap()).collect();
+        assert_eq!(
+            instructions,
+            [
+                Instruction::Run(vec![
+                    "/bin/sh".to_string(),
+                    "-c".to_string(),
+                    "apk add --no-cache curl && rm -rf /tmp/*".to_string(),
+                ]),
+                Instruction::Cmd(vec!["curl".to_string(), "--help".to_string()]),
+                Instruction::Expose(vec!["80/tcp".to_string(), "53/udp".to_string(), "8000-8010/tcp".to_string()]),
+            ]
+        );
+        assert_eq!(file.steps[0].line, 4);
+        assert_eq!(file.steps[2].text(), "EXPOSE 80 53/UDP 8000-8010");
+    }
+
+    #[test]
+    fn expands_variables_from_the_environment() {
+        let file = parse(
+            "FROM scratch\n\
+             ENV APP_HOME=/srv/app GREETING=\"hello world\" LITERAL='$HOME'\n\
+             ENV LEGACY value with spaces\n\
+             WORKDIR ${APP_HOME}/bin\n\
+             COPY [\"conf/$NAME.conf\", \"${DEST:-/etc}/\"]\n\
+             LABEL maintainer=\"${USER:+someone}\" escaped=\\$APP_HOME\n\
+             RUN echo $APP_HOME\n",
+        )
+        .unwrap();
+        let vars = env(&["APP_HOME=/opt", "NAME=site", "USER=root"]);
+        let instructions: Vec<Instruction> = file.steps.iter().map(|step| step.instruction(&vars).unwrap()).collect();
+
+        assert_eq!(
+            instructions[0],
+            Instruction::Env(vec![
+                ("APP_HOME".to_string(), "/srv/app".to_string()),
+                ("GREETING".to_string(), "hello world".to_string()),
+                ("LITERAL".to_string(), "$HOME".to_string()),
+            ])
+        );
+        assert_eq!(instructions[1], Instruction::Env(vec![("LEGACY".to_string(), "value with spaces".to_string())]));
+        assert_eq!(instructions[2], Instruction::Workdir("/opt/bin".to_string()));
+        assert_eq!(
+            instructions[3],
+            Instruction::Copy {
+                sources: vec!["conf/site.conf".to_string()],
+                destination: "/etc/".to_string(),
+            }
+        );
+        assert_eq!(
+            instructions[4],
+            Instruction::Label(vec![
+                ("maintainer".to_string(), "someone".to_string()),
+                ("escaped".to_string(), "$APP_HOME".to_string()),
+            ])
+        );
+        assert_eq!(instructions[5], Instruction::Run(env(&["/bin/sh", "-c", "echo $APP_HOME"])));
+    }
+
+    #[test]
+    fn rejects_what_the_builder_cannot_do() {
+        for (source, message) in [
+            ("RUN true", "RUN before FROM"),
+            ("FROM a\nFROM b", "multi-stage"),
+            ("FROM a\nARG VERSION=1", "unsupported instruction ARG"),
+            ("FROM a\nCOPY --from=build /out /", "flag --from"),
+            ("FROM a\nCOPY a b c", "must be a directory"),
+            ("FROM a\nEXPOSE http", "invalid port"),
+            ("FROM a\nLABEL \"unterminated", "unterminated quote"),
+            ("FROM a\nWORKDIR", "WORKDIR needs arguments"),
+            ("FROM a\nRUN []", "RUN: needs a command"),
+            ("# nothing here", "no FROM"),
+        ] {
+            match parse(source) {
+                Err(AethelError::Build(e)) => assert!(e.contains(message), "{:?}: {}", source, e),
+                other => panic!("{:?} parsed: {:?}", source, other.map(|file| file.steps.len())),
+            }
+        }
+    }
+}
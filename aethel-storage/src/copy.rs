@@ -0,0 +1,168 @@
+use aethel_common::error::{AethelError, Result};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::diff::{self, Change, ChangeKind};
+use crate::layer::{resolve_in_root, LayerApplier, WhiteoutMode};
+
+/// Writes `path` from inside `rootfs` to `writer` as a tar named after its
+/// last component, as `docker cp` does: copying `/etc/nginx` yields `nginx`,
+/// `nginx/nginx.conf` and so on, while `/` yields the whole rootfs unnamed.
+/// Symlinks leading up to `path` are resolved inside `rootfs`; `path`
+/// itself is copied as it is. Mode, ownership and xattrs are kept.
+///
+/// Paths are resolved once, up front, so a running container must be frozen
+/// for the copy: it could otherwise swap a directory for a symlink midway.
+pub fn copy_from<W: Write>(rootfs: &Path, path: &Path, writer: W) -> Result<W> {
+    let rel = container_path(path);
+    let Some(name) = rel.file_name() else {
+        return diff::write_tree(rootfs, writer);
+    };
+    let parent = resolve_in_root(rootfs, rel.parent().unwrap_or(Path::new("")))?;
+    let meta = match fs::symlink_metadata(parent.join(name)) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(AethelError::NotFound(format!("{} does not exist", path.display())))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = vec![Change::new(PathBuf::from(name), ChangeKind::Added)];
+    if meta.is_dir() {
+        diff::add_tree(&parent, Path::new(name), &mut entries)?;
+    }
+    diff::write_layer(&parent, &entries, writer)
+}
+
+/// Extracts the tar in `reader`, whose entries all sit under `name`, into
+/// `rootfs` at `path`: inside it when it is a directory, otherwise as it, as
+/// `docker cp` does. Every entry is resolved inside `rootfs`, symlinks
+/// included, and keeps its mode and ownership; `.wh.` files are ordinary
+/// files here. As for `copy_from`, a running container must be frozen.
+pub fn copy_to<R: Read>(rootfs: &Path, path: &Path, name: &OsStr, reader: R) -> Result<()> {
+    let mut components = Path::new(name).components();
+    if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+        return Err(AethelError::Filesystem(format!(
+            "'{}' is not a valid name to copy",
+            name.to_string_lossy()
+        )));
+    }
+
+    let rel = container_path(path);
+    let target = if resolve_in_root(rootfs, &rel)?.is_dir() {
+        rel.join(name)
+    } else {
+        let parent = rel.parent().unwrap_or(Path::new(""));
+        if path.as_os_str().as_bytes().ends_with(b"/") || !resolve_in_root(rootfs, parent)?.is_dir() {
+            return Err(AethelError::NotFound(format!(
+                "Directory {} does not exist",
+                Path::new("/").join(parent).display()
+            )));
+        }
+        rel
+    };
+
+    LayerApplier::new(rootfs)
+        .with_whiteout_mode(WhiteoutMode::Literal)
+        .with_rebase(Path::new(name), &target)
+        .apply(reader)?;
+    Ok(())
+}
+
+/// `path` as the container sees it, made relative to its root. Relative
+/// paths start at the root too, and `..` stops there as it would in a
+/// chroot.
+pub(crate) fn container_path(path: &Path) -> PathBuf {
+    let mut clean = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::ParentDir => {
+                clean.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    clean
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use tar::{Archive, Builder, EntryType, Header};
+
+    fn archive(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for (path, contents) in entries {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(0o600);
+            header.set_mtime(1);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_size(contents.len() as u64);
+            builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn rootfs() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("etc")).unwrap();
+        symlink("/etc", rootfs.join("config")).unwrap();
+        (dir, rootfs)
+    }
+
+    #[test]
+    fn copies_into_directories_through_container_symlinks() {
+        let (dir, rootfs) = rootfs();
+        let tar = archive(&[("app.conf", "port=80")]);
+
+        // `config` points at `/etc`, which must mean the container's.
+        copy_to(&rootfs, Path::new("/../config"), OsStr::new("app.conf"), tar.as_slice()).unwrap();
+        let stray = copy_to(&rootfs, Path::new("/etc"), OsStr::new("app.conf"), archive(&[("other", "x")]).as_slice());
+
+        let copied = rootfs.join("etc/app.conf");
+        assert_eq!(fs::read_to_string(&copied).unwrap(), "port=80");
+        assert_eq!(fs::metadata(&copied).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!dir.path().join("etc").exists());
+        assert!(matches!(stray, Err(AethelError::Filesystem(_))));
+        assert!(!rootfs.join("etc/other").exists());
+    }
+
+    #[test]
+    fn copies_as_a_new_name_when_the_destination_is_missing() {
+        let (_dir, rootfs) = rootfs();
+        let tar = archive(&[("conf.d/.wh.a", "kept"), ("conf.d/b", "b")]);
+
+        copy_to(&rootfs, Path::new("/etc/app.d"), OsStr::new("conf.d"), tar.as_slice()).unwrap();
+        let missing = copy_to(&rootfs, Path::new("/srv/app/"), OsStr::new("conf.d"), tar.as_slice());
+
+        assert_eq!(fs::read_to_string(rootfs.join("etc/app.d/.wh.a")).unwrap(), "kept");
+        assert_eq!(fs::read_to_string(rootfs.join("etc/app.d/b")).unwrap(), "b");
+        assert!(matches!(missing, Err(AethelError::NotFound(_))));
+    }
+
+    #[test]
+    fn copies_out_named_after_the_last_component() {
+        let (_dir, rootfs) = rootfs();
+        fs::create_dir(rootfs.join("etc/nginx")).unwrap();
+        fs::write(rootfs.join("etc/nginx/nginx.conf"), "events {}").unwrap();
+
+        let tar = copy_from(&rootfs, Path::new("/config/nginx"), Vec::new()).unwrap();
+        let missing = copy_from(&rootfs, Path::new("/etc/missing"), Vec::new());
+
+        let names: Vec<String> = Archive::new(tar.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string().trim_end_matches('/').to_string())
+            .collect();
+        assert_eq!(names, ["nginx", "nginx/nginx.conf"]);
+        assert!(matches!(missing, Err(AethelError::NotFound(_))));
+    }
+}
@@ -0,0 +1,117 @@
+use aethel_common::error::{AethelError, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::reference::DEFAULT_REGISTRY;
+
+/// Docker Hub credentials are traditionally stored under these keys.
+const DOCKER_HUB_ALIASES: [&str; 3] = ["index.docker.io", "registry-1.docker.io", "registry.hub.docker.com"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// The value of a `Basic` `Authorization` header.
+    pub fn basic_auth(&self) -> String {
+        format!("Basic {}", STANDARD.encode(format!("{}:{}", self.username, self.password)))
+    }
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+}
+
+#[derive(Deserialize)]
+struct AuthEntry {
+    #[serde(default)]
+    auth: String,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: String,
+}
+
+/// Registry credentials read from a file in the `auths` format written by
+/// `docker login`:
+///
+/// ```json
+/// { "auths": { "registry.example.com": { "auth": "<base64 user:password>" } } }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CredentialStore {
+    auths: HashMap<String, Credentials>,
+}
+
+impl CredentialStore {
+    /// Loads `path`. A missing file is the same as an empty one.
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(CredentialStore::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let bad = |why: String| AethelError::Registry(format!("Bad credentials file {}: {}", path.display(), why));
+        let config: ConfigFile = serde_json::from_slice(&bytes).map_err(|e| bad(e.to_string()))?;
+
+        let mut auths = HashMap::new();
+        for (key, entry) in config.auths {
+            let credentials = if !entry.auth.is_empty() {
+                let decoded = STANDARD
+                    .decode(entry.auth.trim())
+                    .ok()
+                    .and_then(|d| String::from_utf8(d).ok())
+                    .ok_or_else(|| bad(format!("auth for {} is not base64", key)))?;
+                let (username, password) = decoded
+                    .split_once(':')
+                    .ok_or_else(|| bad(format!("auth for {} is not user:password", key)))?;
+                Credentials {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                }
+            } else if !entry.username.is_empty() {
+                Credentials {
+                    username: entry.username,
+                    password: entry.password,
+                }
+            } else {
+                continue;
+            };
+            auths.insert(normalize_key(&key), credentials);
+        }
+        Ok(CredentialStore { auths })
+    }
+
+    pub fn insert(&mut self, registry: &str, credentials: Credentials) {
+        self.auths.insert(normalize_key(registry), credentials);
+    }
+
+    /// The credentials for `registry`, as it appears in a `Reference`.
+    pub fn get(&self, registry: &str) -> Option<&Credentials> {
+        self.auths.get(&normalize_key(registry))
+    }
+}
+
+/// Keys may be bare hosts or URLs such as `https://index.docker.io/v1/`.
+fn normalize_key(key: &str) -> String {
+    let host = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    if DOCKER_HUB_ALIASES.contains(&host) {
+        DEFAULT_REGISTRY.to_string()
+    } else {
+        host.to_string()
+    }
+}
@@ -0,0 +1,464 @@
+use aethel_common::error::{AethelError, Result};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, Metadata};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use tar::{Builder, EntryType, Header};
+
+use crate::layer::{OPAQUE_WHITEOUT, OVERLAY_OPAQUE_XATTR, WHITEOUT_PREFIX};
+
+/// Overlay bookkeeping that must never end up in an image.
+const OVERLAY_XATTR_PREFIX: &str = "trusted.overlay.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ChangeKind::Added => "A",
+            ChangeKind::Modified => "C",
+            ChangeKind::Deleted => "D",
+        })
+    }
+}
+
+/// A path where a container's rootfs differs from its image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Relative to the rootfs.
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    /// A directory that hides everything the image had beneath it.
+    pub opaque: bool,
+}
+
+impl Change {
+    pub(crate) fn new(path: PathBuf, kind: ChangeKind) -> Self {
+        Change {
+            path,
+            kind,
+            opaque: false,
+        }
+    }
+}
+
+/// The changes recorded in an overlay upperdir. `lowers` are the layer dirs
+/// beneath it, bottom layer first, holding overlay-style whiteouts as the
+/// snapshotter unpacks them; they only decide between added and modified.
+///
+/// Changes come out parents first, in path order.
+pub fn overlay_changes(upper: &Path, lowers: &[PathBuf]) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    walk_upper(upper, Path::new(""), lowers, &mut changes)?;
+    Ok(changes)
+}
+
+fn walk_upper(upper: &Path, rel: &Path, lowers: &[PathBuf], changes: &mut Vec<Change>) -> Result<()> {
+    for name in sorted_entries(&upper.join(rel))? {
+        let path = rel.join(name);
+        let full = upper.join(&path);
+        let meta = fs::symlink_metadata(&full)?;
+        if is_whiteout(&meta) {
+            changes.push(Change::new(path, ChangeKind::Deleted));
+            continue;
+        }
+
+        let kind = if in_lowers(lowers, &path) {
+            ChangeKind::Modified
+        } else {
+            ChangeKind::Added
+        };
+        changes.push(Change {
+            path: path.clone(),
+            kind,
+            opaque: meta.is_dir() && is_opaque(&full),
+        });
+        if meta.is_dir() {
+            walk_upper(upper, &path, lowers, changes)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `rel` is visible through the lower layers, looking from the top
+/// layer down the way overlayfs does.
+fn in_lowers(lowers: &[PathBuf], rel: &Path) -> bool {
+    for lower in lowers.iter().rev() {
+        if let Ok(meta) = fs::symlink_metadata(lower.join(rel)) {
+            return !is_whiteout(&meta);
+        }
+        // A parent this layer deletes, replaces with a non-directory or makes
+        // opaque hides every layer beneath it.
+        let hidden = rel
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| {
+                let path = lower.join(ancestor);
+                match fs::symlink_metadata(&path) {
+                    Ok(meta) => !meta.is_dir() || is_opaque(&path),
+                    Err(_) => false,
+                }
+            });
+        if hidden {
+            return false;
+        }
+    }
+    false
+}
+
+/// The changes between `base`, the image unpacked on its own, and `rootfs`,
+/// a plain extraction of the same image a container has been running in.
+/// Regular files count as modified when their size or mtime changed; their
+/// content is not compared. A directory with changes beneath it is always
+/// reported, so committing it keeps its permissions.
+///
+/// Changes come out parents first, in path order.
+pub fn tree_changes(base: &Path, rootfs: &Path) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    compare_dirs(base, rootfs, Path::new(""), &mut changes)?;
+    Ok(changes)
+}
+
+/// Returns whether anything beneath `rel` changed.
+fn compare_dirs(base: &Path, rootfs: &Path, rel: &Path, changes: &mut Vec<Change>) -> Result<bool> {
+    let start = changes.len();
+    let old_names: BTreeSet<OsString> = sorted_entries(&base.join(rel))?.into_iter().collect();
+    let new_names: BTreeSet<OsString> = sorted_entries(&rootfs.join(rel))?.into_iter().collect();
+
+    for name in old_names.union(&new_names) {
+        let path = rel.join(name);
+        let (old_path, new_path) = (base.join(&path), rootfs.join(&path));
+        if !new_names.contains(name) {
+            changes.push(Change::new(path, ChangeKind::Deleted));
+            continue;
+        }
+        let new = fs::symlink_metadata(&new_path)?;
+        if !old_names.contains(name) {
+            changes.push(Change::new(path.clone(), ChangeKind::Added));
+            if new.is_dir() {
+                add_tree(rootfs, &path, changes)?;
+            }
+            continue;
+        }
+
+        let old = fs::symlink_metadata(&old_path)?;
+        let index = changes.len();
+        let changed = metadata_differs(&old, &new, &old_path, &new_path)?;
+        if changed {
+            changes.push(Change::new(path.clone(), ChangeKind::Modified));
+        }
+        if new.is_dir() {
+            if !old.is_dir() {
+                add_tree(rootfs, &path, changes)?;
+            } else if compare_dirs(base, rootfs, &path, changes)? && !changed {
+                changes.insert(index, Change::new(path, ChangeKind::Modified));
+            }
+        }
+    }
+    Ok(changes.len() > start)
+}
+
+pub(crate) fn add_tree(root: &Path, rel: &Path, changes: &mut Vec<Change>) -> Result<()> {
+    for name in sorted_entries(&root.join(rel))? {
+        let path = rel.join(name);
+        let is_dir = fs::symlink_metadata(root.join(&path))?.is_dir();
+        changes.push(Change::new(path.clone(), ChangeKind::Added));
+        if is_dir {
+            add_tree(root, &path, changes)?;
+        }
+    }
+    Ok(())
+}
+
+/// Directory mtimes change with their entries, so only their ownership and
+/// mode are compared.
+fn metadata_differs(old: &Metadata, new: &Metadata, old_path: &Path, new_path: &Path) -> Result<bool> {
+    if old.file_type() != new.file_type()
+        || old.mode() != new.mode()
+        || old.uid() != new.uid()
+        || old.gid() != new.gid()
+    {
+        return Ok(true);
+    }
+    let file_type = new.file_type();
+    Ok(if file_type.is_file() {
+        old.len() != new.len() || old.mtime() != new.mtime() || old.mtime_nsec() != new.mtime_nsec()
+    } else if file_type.is_symlink() {
+        fs::read_link(old_path)? != fs::read_link(new_path)?
+    } else if file_type.is_char_device() || file_type.is_block_device() {
+        old.rdev() != new.rdev()
+    } else {
+        false
+    })
+}
+
+/// Writes everything under `root` as a tar, flattened the way
+/// `docker export` writes a container's filesystem.
+pub fn write_tree<W: Write>(root: &Path, writer: W) -> Result<W> {
+    let mut entries = Vec::new();
+    add_tree(root, Path::new(""), &mut entries)?;
+    write_layer(root, &entries, writer)
+}
+
+/// Writes `changes`, reading the content from `root`, as an OCI layer
+/// tarball. Deletions become `.wh.` whiteouts and opaque directories get a
+/// `.wh..wh..opq` entry. A file hard linked within the layer is stored once
+/// and linked to after that. Sockets cannot be archived and are skipped.
+pub fn write_layer<W: Write>(root: &Path, changes: &[Change], writer: W) -> Result<W> {
+    let mut builder = Builder::new(writer);
+    builder.follow_symlinks(false);
+    let mut links: HashMap<(u64, u64), &Path> = HashMap::new();
+
+    for change in changes {
+        if change.kind == ChangeKind::Deleted {
+            let name = change.path.file_name().ok_or_else(|| {
+                AethelError::Filesystem(format!("Cannot delete {} in a layer", change.path.display()))
+            })?;
+            let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+            whiteout.push(name);
+            append_empty(&mut builder, &change.path.with_file_name(whiteout))?;
+            continue;
+        }
+
+        let full = root.join(&change.path);
+        let meta = fs::symlink_metadata(&full)?;
+        if meta.file_type().is_socket() {
+            continue;
+        }
+        if meta.is_file() && meta.nlink() > 1 {
+            match links.entry((meta.dev(), meta.ino())) {
+                Entry::Occupied(first) => {
+                    let mut header = Header::new_gnu();
+                    header.set_entry_type(EntryType::Link);
+                    header.set_size(0);
+                    builder.append_link(&mut header, &change.path, first.get())?;
+                    continue;
+                }
+                Entry::Vacant(slot) => {
+                    slot.insert(&change.path);
+                }
+            }
+        }
+
+        let xattrs = pax_xattrs(&full)?;
+        if !xattrs.is_empty() {
+            let mut header = Header::new_ustar();
+            header.set_entry_type(EntryType::XHeader);
+            header.set_mode(0o644);
+            header.set_size(xattrs.len() as u64);
+            builder.append_data(&mut header, "PaxHeaders/xattrs", xattrs.as_slice())?;
+        }
+        builder.append_path_with_name(&full, &change.path)?;
+        if change.opaque {
+            append_empty(&mut builder, &change.path.join(OPAQUE_WHITEOUT))?;
+        }
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+fn append_empty<W: Write>(builder: &mut Builder<W>, path: &Path) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_size(0);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    builder.append_data(&mut header, path, io::empty())?;
+    Ok(())
+}
+
+/// The extended attributes of `path` as PAX `SCHILY.xattr.` records, the
+/// form `LayerApplier` restores them from.
+fn pax_xattrs(path: &Path) -> Result<Vec<u8>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records = Vec::new();
+    for name in names {
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name.starts_with(OVERLAY_XATTR_PREFIX) {
+            continue;
+        }
+        if let Some(value) = xattr::get(path, name)? {
+            pax_record(&mut records, &format!("SCHILY.xattr.{}", name), &value);
+        }
+    }
+    Ok(records)
+}
+
+/// Appends `"<len> <key>=<value>\n"`, where `<len>` counts the whole
+/// record including its own digits.
+fn pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    records.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+fn is_whiteout(meta: &Metadata) -> bool {
+    meta.file_type().is_char_device() && meta.rdev() == 0
+}
+
+fn is_opaque(path: &Path) -> bool {
+    matches!(xattr::get(path, OVERLAY_OPAQUE_XATTR), Ok(Some(value)) if value == b"y")
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<OsString>> {
+    let mut names = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+    use std::fs::File;
+    use tar::Archive;
+
+    fn write(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn listing(changes: &[Change]) -> Vec<String> {
+        changes
+            .iter()
+            .map(|change| format!("{} {}{}", change.kind, change.path.display(), if change.opaque { " (opaque)" } else { "" }))
+            .collect()
+    }
+
+    fn entry_names(layer: &[u8]) -> Vec<String> {
+        Archive::new(layer)
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string().trim_end_matches('/').to_string())
+            .collect()
+    }
+
+    #[test]
+    fn tree_changes_report_added_modified_and_deleted_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let (base, rootfs) = (dir.path().join("base"), dir.path().join("rootfs"));
+        for root in [&base, &rootfs] {
+            write(root, "etc/hosts", "localhost");
+            write(root, "etc/passwd", "root");
+            write(root, "usr/bin/tool", "#!");
+        }
+        for path in ["etc/hosts", "etc/passwd", "usr/bin/tool"] {
+            let mtime = fs::metadata(base.join(path)).unwrap().modified().unwrap();
+            File::options().write(true).open(rootfs.join(path)).unwrap().set_modified(mtime).unwrap();
+        }
+        write(&rootfs, "etc/passwd", "root,user");
+        fs::remove_file(rootfs.join("etc/hosts")).unwrap();
+        write(&rootfs, "tmp/new", "new");
+
+        let changes = tree_changes(&base, &rootfs).unwrap();
+
+        assert_eq!(listing(&changes), ["C etc", "D etc/hosts", "C etc/passwd", "A tmp", "A tmp/new"]);
+        let layer = write_layer(&rootfs, &changes, Vec::new()).unwrap();
+        assert_eq!(entry_names(&layer), ["etc", "etc/.wh.hosts", "etc/passwd", "tmp", "tmp/new"]);
+    }
+
+    #[test]
+    fn write_tree_stores_hard_links_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write(root, "bin/busybox", "binary");
+        fs::hard_link(root.join("bin/busybox"), root.join("bin/sh")).unwrap();
+        std::os::unix::fs::symlink("busybox", root.join("bin/ls")).unwrap();
+
+        let tar = write_tree(root, Vec::new()).unwrap();
+
+        let mut archive = Archive::new(tar.as_slice());
+        let entries: Vec<(String, EntryType, Option<PathBuf>)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let name = entry.path().unwrap().display().to_string().trim_end_matches('/').to_string();
+                let link = entry.link_name().unwrap().map(|link| link.into_owned());
+                (name, entry.header().entry_type(), link)
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("bin".to_string(), EntryType::Directory, None),
+                ("bin/busybox".to_string(), EntryType::Regular, None),
+                ("bin/ls".to_string(), EntryType::Symlink, Some(PathBuf::from("busybox"))),
+                ("bin/sh".to_string(), EntryType::Link, Some(PathBuf::from("bin/busybox"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn overlay_changes_translate_whiteouts_and_opaque_dirs() {
+        // Whiteout devices and trusted.* xattrs both need CAP_SYS_ADMIN/CAP_MKNOD.
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let (lower, upper) = (dir.path().join("lower"), dir.path().join("upper"));
+        write(&lower, "etc/shadow", "secret");
+        write(&lower, "etc/hostname", "base");
+        write(&lower, "var/cache/old", "stale");
+        write(&upper, "etc/hostname", "changed");
+        write(&upper, "etc/added", "new");
+        mknod(&upper.join("etc/shadow"), SFlag::S_IFCHR, Mode::empty(), makedev(0, 0)).unwrap();
+        write(&upper, "var/cache/fresh", "fresh");
+        xattr::set(upper.join("var/cache"), OVERLAY_OPAQUE_XATTR, b"y").unwrap();
+
+        let changes = overlay_changes(&upper, &[lower]).unwrap();
+
+        assert_eq!(
+            listing(&changes),
+            [
+                "C etc",
+                "A etc/added",
+                "C etc/hostname",
+                "D etc/shadow",
+                "C var",
+                "C var/cache (opaque)",
+                "A var/cache/fresh",
+            ]
+        );
+        let layer = write_layer(&upper, &changes, Vec::new()).unwrap();
+        assert_eq!(
+            entry_names(&layer),
+            [
+                "etc",
+                "etc/added",
+                "etc/hostname",
+                "etc/.wh.shadow",
+                "var",
+                "var/cache",
+                "var/cache/.wh..wh..opq",
+                "var/cache/fresh",
+            ]
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use aethel_common::error::{AethelError, Result};
+use sha2::{Digest as _, Sha256, Sha512};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::oci::Descriptor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+        }
+    }
+
+    fn hex_len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha512 => 128,
+        }
+    }
+}
+
+/// A validated `algorithm:hex` content digest.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Digest {
+    algorithm: Algorithm,
+    hex: String,
+}
+
+impl Digest {
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    pub fn hex(&self) -> &str {
+        &self.hex
+    }
+
+    /// Where the blob lives inside an OCI layout rooted at `root`.
+    pub fn blob_path(&self, root: &Path) -> PathBuf {
+        root.join("blobs").join(self.algorithm.name()).join(&self.hex)
+    }
+
+    pub fn of_bytes(algorithm: Algorithm, bytes: &[u8]) -> Self {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(bytes);
+        hasher.finish()
+    }
+}
+
+impl FromStr for Digest {
+    type Err = AethelError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (algorithm, hex) = s
+            .split_once(':')
+            .ok_or_else(|| AethelError::InvalidDigest(format!("'{}' has no algorithm prefix", s)))?;
+        let algorithm = match algorithm {
+            "sha256" => Algorithm::Sha256,
+            "sha512" => Algorithm::Sha512,
+            other => {
+                return Err(AethelError::InvalidDigest(format!(
+                    "unsupported algorithm '{}' in '{}'",
+                    other, s
+                )))
+            }
+        };
+        let valid_hex = hex.len() == algorithm.hex_len()
+            && hex.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        if !valid_hex {
+            return Err(AethelError::InvalidDigest(format!(
+                "'{}' is not a valid {} digest",
+                s,
+                algorithm.name()
+            )));
+        }
+        Ok(Digest {
+            algorithm,
+            hex: hex.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.hex)
+    }
+}
+
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(bytes),
+            Hasher::Sha512(h) => h.update(bytes),
+        }
+    }
+
+    pub fn finish(self) -> Digest {
+        match self {
+            Hasher::Sha256(h) => Digest {
+                algorithm: Algorithm::Sha256,
+                hex: format!("{:x}", h.finalize()),
+            },
+            Hasher::Sha512(h) => Digest {
+                algorithm: Algorithm::Sha512,
+                hex: format!("{:x}", h.finalize()),
+            },
+        }
+    }
+}
+
+/// Hashes everything read through it and fails the read that hits EOF if
+/// the content does not match the expected digest and size. Reading past
+/// the expected size fails immediately.
+pub struct VerifyingReader<R> {
+    inner: R,
+    hasher: Option<Hasher>,
+    actual: Option<Digest>,
+    expected: Digest,
+    expected_size: u64,
+    read: u64,
+}
+
+impl<R: Read> VerifyingReader<R> {
+    pub fn new(inner: R, expected: Digest, expected_size: u64) -> Self {
+        VerifyingReader {
+            inner,
+            hasher: Some(Hasher::new(expected.algorithm)),
+            actual: None,
+            expected,
+            expected_size,
+            read: 0,
+        }
+    }
+
+    pub fn for_descriptor(inner: R, desc: &Descriptor) -> Result<Self> {
+        Ok(VerifyingReader::new(inner, desc.digest.parse()?, desc.size))
+    }
+
+    /// Reads whatever the consumer left unread and checks the result.
+    /// Decompressors and tar readers routinely stop before EOF, so this must
+    /// be called once they are done.
+    pub fn finish(mut self) -> Result<()> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(())
+    }
+
+    fn verify(&mut self) -> Result<()> {
+        if self.read != self.expected_size {
+            return Err(AethelError::SizeMismatch {
+                digest: self.expected.to_string(),
+                expected: self.expected_size,
+                actual: self.read,
+            });
+        }
+        if let Some(hasher) = self.hasher.take() {
+            self.actual = Some(hasher.finish());
+        }
+        let actual = self.actual.as_ref().expect("hasher finished above");
+        if *actual != self.expected {
+            return Err(AethelError::DigestMismatch {
+                expected: self.expected.to_string(),
+                actual: actual.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 {
+            self.verify()?;
+            return Ok(0);
+        }
+
+        self.read += n as u64;
+        if self.read > self.expected_size {
+            return Err(AethelError::SizeMismatch {
+                digest: self.expected.to_string(),
+                expected: self.expected_size,
+                actual: self.read,
+            }
+            .into());
+        }
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// Hashes and counts everything written through it, for blobs whose digest
+/// is only known once they have been written.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+    written: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, algorithm: Algorithm) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Hasher::new(algorithm),
+            written: 0,
+        }
+    }
+
+    /// The inner writer, the digest of everything written and its size.
+    pub fn finish(self) -> (W, Digest, u64) {
+        (self.inner, self.hasher.finish(), self.written)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn read_all(reader: VerifyingReader<&[u8]>) -> Result<Vec<u8>> {
+        let mut reader = reader;
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    #[test]
+    fn parses_and_displays_digests() {
+        let digest: Digest = HELLO_SHA256.parse().unwrap();
+        assert_eq!(digest.algorithm(), Algorithm::Sha256);
+        assert_eq!(digest.to_string(), HELLO_SHA256);
+        assert_eq!(digest, Digest::of_bytes(Algorithm::Sha256, b"hello"));
+        assert_eq!(
+            digest.blob_path(Path::new("/store")),
+            Path::new("/store/blobs/sha256").join(digest.hex())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_and_short_digests() {
+        let hex = &HELLO_SHA256[7..];
+        for bad in [
+            "",
+            "sha",
+            "sha256",
+            "sha256:",
+            "sha256:abc",
+            &format!("sha256:{}", &hex[1..]),
+            &format!("sha256:{}0", hex),
+            &format!("sha256:{}", hex.to_uppercase()),
+            &format!("sha256:{}g", &hex[1..]),
+            &format!("sha512:{}", hex),
+            hex,
+        ] {
+            assert!(
+                matches!(bad.parse::<Digest>(), Err(AethelError::InvalidDigest(_))),
+                "{:?} parsed",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_algorithms() {
+        let err = format!("md5:{}", "0".repeat(32)).parse::<Digest>().unwrap_err();
+        assert!(matches!(&err, AethelError::InvalidDigest(msg) if msg.contains("unsupported algorithm 'md5'")), "{}", err);
+        assert!(format!("SHA256:{}", &HELLO_SHA256[7..]).parse::<Digest>().is_err());
+    }
+
+    #[test]
+    fn verifies_matching_content() {
+        let reader = VerifyingReader::new(&b"hello"[..], HELLO_SHA256.parse().unwrap(), 5);
+        assert_eq!(read_all(reader).unwrap(), b"hello");
+
+        // Whatever the consumer leaves unread is still checked.
+        let mut reader = VerifyingReader::new(&b"hello"[..], HELLO_SHA256.parse().unwrap(), 5);
+        reader.read_exact(&mut [0; 2]).unwrap();
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn reports_digest_mismatch() {
+        let reader = VerifyingReader::new(&b"jello"[..], HELLO_SHA256.parse().unwrap(), 5);
+        let err = read_all(reader).unwrap_err();
+        let expected_actual = Digest::of_bytes(Algorithm::Sha256, b"jello").to_string();
+        assert!(
+            matches!(&err, AethelError::DigestMismatch { expected, actual } if expected == HELLO_SHA256 && *actual == expected_actual),
+            "{:?}",
+            err
+        );
+
+        let reader = VerifyingReader::new(&b"jello"[..], HELLO_SHA256.parse().unwrap(), 5);
+        assert!(matches!(reader.finish(), Err(AethelError::DigestMismatch { .. })));
+    }
+
+    #[test]
+    fn reports_short_content() {
+        let reader = VerifyingReader::new(&b"hell"[..], HELLO_SHA256.parse().unwrap(), 5);
+        let err = read_all(reader).unwrap_err();
+        assert!(
+            matches!(&err, AethelError::SizeMismatch { digest, expected: 5, actual: 4 } if digest == HELLO_SHA256),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn reports_long_content_before_eof() {
+        let mut reader = VerifyingReader::new(&b"hello, world"[..], HELLO_SHA256.parse().unwrap(), 5);
+        let mut buf = [0; 64];
+        let err = AethelError::from(reader.read(&mut buf).unwrap_err());
+        assert!(matches!(err, AethelError::SizeMismatch { expected: 5, actual: 12, .. }), "{:?}", err);
+
+        let reader = VerifyingReader::new(&b"hello, world"[..], HELLO_SHA256.parse().unwrap(), 5);
+        assert!(matches!(reader.finish(), Err(AethelError::SizeMismatch { .. })));
+    }
+
+    #[test]
+    fn round_trips_sha512() {
+        let content = b"The quick brown fox jumps over the lazy dog";
+        let mut hasher = Hasher::new(Algorithm::Sha512);
+        hasher.update(&content[..10]);
+        hasher.update(&content[10..]);
+        let digest = hasher.finish();
+        assert_eq!(digest.algorithm(), Algorithm::Sha512);
+        assert_eq!(
+            digest.hex(),
+            "07e547d9586f6a73f73fbac0435ed76951218fb7d0c8d788a309d785436bbb64\
+             2e93a252a954f23912547d1e8a3b5ed6e1bfd7097821233fa0538f3db854fee6"
+        );
+        let parsed: Digest = digest.to_string().parse().unwrap();
+        assert_eq!(parsed, digest);
+
+        let reader = VerifyingReader::new(&content[..], parsed.clone(), content.len() as u64);
+        assert_eq!(read_all(reader).unwrap(), content);
+
+        let mut writer = HashingWriter::new(Vec::new(), Algorithm::Sha512);
+        writer.write_all(content).unwrap();
+        let (written, hashed, size) = writer.finish();
+        assert_eq!((written.as_slice(), hashed, size), (&content[..], parsed, content.len() as u64));
+    }
+}
@@ -0,0 +1,225 @@
+use aethel_common::error::Result;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use crate::digest::Digest;
+use crate::oci::Descriptor;
+use crate::snapshot::Snapshotter;
+use crate::store::ImageStore;
+
+/// What a collection must keep besides everything the store's references
+/// reach.
+#[derive(Debug, Default)]
+pub struct Roots {
+    /// Images containers were created from, whether or not a reference
+    /// still names them.
+    pub images: Vec<Descriptor>,
+    /// Containers whose snapshots are still in use.
+    pub containers: HashSet<String>,
+}
+
+/// What a collection removed, or would remove on a dry run.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// Digests of manifests, indexes, configs and layer blobs.
+    pub blobs: Vec<String>,
+    /// Digests of unpacked layers.
+    pub layers: Vec<String>,
+    /// Containers whose snapshot directories were removed.
+    pub containers: Vec<String>,
+    /// Bytes freed on disk.
+    pub reclaimed: u64,
+}
+
+/// Mark-and-sweep garbage collection of the image store and snapshotter.
+///
+/// Marks every blob reachable from a reference, an untagged image the store
+/// keeps or `roots.images`, then deletes the blobs and unpacked layers that
+/// are not marked, unfinished unpacks, and the snapshots of containers not
+/// in `roots.containers`.
+/// Nothing may be pulled, loaded or unpacked while this runs; the caller
+/// keeps writers out.
+pub fn collect(store: &ImageStore, snapshotter: &Snapshotter, roots: &Roots, dry_run: bool) -> Result<GcReport> {
+    let mut marked = HashSet::new();
+    let targets = store.references()?.into_iter().map(|(_, desc)| desc).chain(store.untagged()?);
+    for target in targets.chain(roots.images.iter().cloned()) {
+        // An image that cannot be walked fails the collection rather than
+        // losing blobs it may still need.
+        for blob in crate::image_blobs(store, &target)? {
+            marked.insert(blob.digest);
+        }
+    }
+
+    let mut report = GcReport::default();
+    for digest in store.blobs()? {
+        if marked.contains(&digest.to_string()) {
+            continue;
+        }
+        report.reclaimed += disk_usage(&store.blob_path(&digest));
+        if !dry_run {
+            store.remove_blob(&digest)?;
+        }
+        report.blobs.push(digest.to_string());
+    }
+
+    for digest in snapshotter.layers()? {
+        if marked.contains(&digest.to_string()) {
+            continue;
+        }
+        report.reclaimed += disk_usage(&layer_root(snapshotter, &digest));
+        if !dry_run {
+            snapshotter.remove_layer(&digest)?;
+        }
+        report.layers.push(digest.to_string());
+    }
+    for scratch in snapshotter.scratch_dirs()? {
+        report.reclaimed += disk_usage(&scratch);
+        if !dry_run {
+            fs::remove_dir_all(&scratch)?;
+        }
+    }
+
+    for id in snapshotter.containers()? {
+        if roots.containers.contains(&id) {
+            continue;
+        }
+        // The merged dir is a view of the layers and the upper dir, so
+        // only the latter counts.
+        report.reclaimed += disk_usage(&snapshotter.upper_dir(&id));
+        if !dry_run {
+            snapshotter.remove(&id)?;
+        }
+        report.containers.push(id);
+    }
+
+    Ok(report)
+}
+
+fn layer_root(snapshotter: &Snapshotter, digest: &Digest) -> std::path::PathBuf {
+    snapshotter
+        .layer_dir(digest)
+        .parent()
+        .expect("layer dir has a parent")
+        .to_path_buf()
+}
+
+/// Bytes allocated under `path`, not following symlinks. Hard links are
+/// counted once.
+pub(crate) fn disk_usage(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    fn walk(path: &Path, seen: &mut HashSet<(u64, u64)>) -> u64 {
+        let Ok(meta) = fs::symlink_metadata(path) else {
+            return 0;
+        };
+        if meta.nlink() > 1 && !meta.is_dir() && !seen.insert((meta.dev(), meta.ino())) {
+            return 0;
+        }
+        let mut total = meta.blocks() * 512;
+        if meta.is_dir() {
+            if let Ok(entries) = fs::read_dir(path) {
+                for entry in entries.flatten() {
+                    total += walk(&entry.path(), seen);
+                }
+            }
+        }
+        total
+    }
+
+    walk(path, &mut HashSet::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::Algorithm;
+    use crate::oci;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    fn put(store: &ImageStore, media_type: &str, bytes: &[u8]) -> Descriptor {
+        let digest = Digest::of_bytes(Algorithm::Sha256, bytes);
+        store.write_blob(&digest, bytes).unwrap();
+        Descriptor {
+            media_type: media_type.to_string(),
+            digest: digest.to_string(),
+            size: bytes.len() as u64,
+            platform: None,
+            annotations: HashMap::new(),
+        }
+    }
+
+    /// Writes a one-layer image and returns its manifest and layer.
+    fn image(store: &ImageStore, seed: &str) -> (Descriptor, Descriptor) {
+        let config = put(store, oci::MEDIA_TYPE_OCI_CONFIG, format!("{{\"seed\":\"{}\"}}", seed).as_bytes());
+        let layer = put(store, oci::MEDIA_TYPE_OCI_LAYER, seed.repeat(4096).as_bytes());
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": oci::MEDIA_TYPE_OCI_MANIFEST,
+            "config": config,
+            "layers": [layer],
+        });
+        let manifest = put(store, oci::MEDIA_TYPE_OCI_MANIFEST, &serde_json::to_vec(&manifest).unwrap());
+        (manifest, layer)
+    }
+
+    fn unpack(snapshotter: &Snapshotter, layer: &Descriptor) {
+        let dir = snapshotter.layer_dir(&layer.digest.parse().unwrap());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), "x".repeat(8192)).unwrap();
+    }
+
+    #[test]
+    fn sweeps_unreferenced_blobs_and_layers() {
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(&dir.path().join("images")).unwrap();
+        let snapshotter = Snapshotter::new(&dir.path().join("overlay")).unwrap();
+        let (kept, kept_layer) = image(&store, "a");
+        let (_, dropped_layer) = image(&store, "b");
+        store.set_reference(&"app:v1".parse().unwrap(), kept.clone()).unwrap();
+        unpack(&snapshotter, &kept_layer);
+        unpack(&snapshotter, &dropped_layer);
+
+        let dry = collect(&store, &snapshotter, &Roots::default(), true).unwrap();
+
+        assert_eq!(dry.blobs.len(), 3);
+        assert_eq!(dry.layers, vec![dropped_layer.digest.clone()]);
+        assert!(dry.reclaimed > 0);
+        assert_eq!(store.blobs().unwrap().len(), 6);
+
+        let report = collect(&store, &snapshotter, &Roots::default(), false).unwrap();
+
+        assert_eq!(report.reclaimed, dry.reclaimed);
+        assert_eq!(store.blobs().unwrap().len(), 3);
+        for blob in crate::image_blobs(&store, &kept).unwrap() {
+            store.open_blob(&blob).unwrap().finish().unwrap();
+        }
+        assert_eq!(snapshotter.layers().unwrap(), vec![kept_layer.digest.parse().unwrap()]);
+        assert!(collect(&store, &snapshotter, &Roots::default(), false).unwrap().blobs.is_empty());
+    }
+
+    #[test]
+    fn keeps_what_live_containers_use() {
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(&dir.path().join("images")).unwrap();
+        let snapshotter = Snapshotter::new(&dir.path().join("overlay")).unwrap();
+        let (untagged, layer) = image(&store, "a");
+        unpack(&snapshotter, &layer);
+        for id in ["live", "gone"] {
+            fs::create_dir_all(snapshotter.upper_dir(id)).unwrap();
+            fs::write(snapshotter.upper_dir(id).join("data"), "y".repeat(4096)).unwrap();
+        }
+        let roots = Roots {
+            images: vec![untagged],
+            containers: HashSet::from(["live".to_string()]),
+        };
+
+        let report = collect(&store, &snapshotter, &roots, false).unwrap();
+
+        assert!(report.blobs.is_empty());
+        assert!(report.layers.is_empty());
+        assert_eq!(report.containers, vec!["gone"]);
+        assert_eq!(snapshotter.containers().unwrap(), vec!["live"]);
+    }
+}
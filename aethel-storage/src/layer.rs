@@ -0,0 +1,731 @@
+use aethel_common::error::{AethelError, Result};
+use flate2::read::GzDecoder;
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use std::collections::{HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::lchown;
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, Entry, EntryType};
+
+use crate::oci;
+
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+pub const OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+/// AUFS metadata entries (`.wh..wh.plnk`, `.wh..wh.aufs`, ...) share this
+/// prefix and are never extracted.
+const WHITEOUT_META_PREFIX: &str = ".wh..wh.";
+const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+/// How a layer blob is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The compression a layer media type declares. Encrypted layers and
+    /// media types that are not layer tarballs are refused by name, so a
+    /// bad image fails before anything is unpacked.
+    pub fn of_media_type(media_type: &str) -> Result<Self> {
+        match media_type {
+            oci::MEDIA_TYPE_OCI_LAYER | oci::MEDIA_TYPE_OCI_LAYER_NONDISTRIBUTABLE | oci::MEDIA_TYPE_DOCKER_LAYER => {
+                Ok(Compression::None)
+            }
+            oci::MEDIA_TYPE_OCI_LAYER_GZIP
+            | oci::MEDIA_TYPE_OCI_LAYER_NONDISTRIBUTABLE_GZIP
+            | oci::MEDIA_TYPE_DOCKER_LAYER_GZIP
+            | oci::MEDIA_TYPE_DOCKER_FOREIGN_LAYER_GZIP => Ok(Compression::Gzip),
+            oci::MEDIA_TYPE_OCI_LAYER_ZSTD
+            | oci::MEDIA_TYPE_OCI_LAYER_NONDISTRIBUTABLE_ZSTD
+            | oci::MEDIA_TYPE_DOCKER_LAYER_ZSTD => Ok(Compression::Zstd),
+            encrypted if encrypted.contains("+encrypted") => Err(AethelError::UnsupportedMediaType(format!(
+                "layer is encrypted ({}); decrypt the image before using it",
+                encrypted
+            ))),
+            "" => Err(AethelError::UnsupportedMediaType("layer has no media type".to_string())),
+            other => Err(AethelError::UnsupportedMediaType(format!(
+                "'{}' is not a supported layer media type",
+                other
+            ))),
+        }
+    }
+}
+
+/// A layer blob with the compression its media type declares undone.
+pub enum LayerReader<R: Read> {
+    Plain(R),
+    Gzip(GzDecoder<R>),
+    Zstd(zstd::stream::read::Decoder<'static, BufReader<R>>),
+}
+
+impl<R: Read> LayerReader<R> {
+    pub fn new(media_type: &str, blob: R) -> Result<Self> {
+        Ok(match Compression::of_media_type(media_type)? {
+            Compression::None => LayerReader::Plain(blob),
+            Compression::Gzip => LayerReader::Gzip(GzDecoder::new(blob)),
+            Compression::Zstd => LayerReader::Zstd(zstd::stream::read::Decoder::new(blob)?),
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        match self {
+            LayerReader::Plain(blob) => blob,
+            LayerReader::Gzip(decoder) => decoder.into_inner(),
+            LayerReader::Zstd(decoder) => decoder.finish().into_inner(),
+        }
+    }
+}
+
+impl<R: Read> Read for LayerReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            LayerReader::Plain(blob) => blob.read(buf),
+            LayerReader::Gzip(decoder) => decoder.read(buf),
+            LayerReader::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// What to do with `.wh.` entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhiteoutMode {
+    /// Delete the hidden paths, flattening the layer onto its parents.
+    Apply,
+    /// Write overlayfs whiteouts (0/0 char devices and the opaque xattr) so
+    /// the layer directory can be used as an overlay lowerdir.
+    Overlay,
+    /// Extract them as ordinary files, for archives that are not layers.
+    Literal,
+}
+
+/// Applies OCI layer tarballs onto a rootfs directory.
+///
+/// Whiteouts remove content from lower layers, opaque markers hide a lower
+/// directory's children, and every path is resolved inside the rootfs so
+/// neither `..` entries nor symlinks planted by earlier layers can write
+/// outside of it.
+pub struct LayerApplier {
+    root: PathBuf,
+    preserve_ownership: bool,
+    whiteouts: WhiteoutMode,
+    rebase: Option<(PathBuf, PathBuf)>,
+}
+
+/// Paths written by the layer being applied. Whiteouts only ever apply to
+/// lower layers, so these must survive them.
+#[derive(Default)]
+struct LayerState {
+    added: HashSet<PathBuf>,
+    ancestors: HashSet<PathBuf>,
+}
+
+impl LayerState {
+    fn record(&mut self, root: &Path, path: &Path) {
+        self.added.insert(path.to_path_buf());
+        let mut parent = path.parent();
+        while let Some(dir) = parent {
+            if dir == root || !self.ancestors.insert(dir.to_path_buf()) {
+                break;
+            }
+            parent = dir.parent();
+        }
+    }
+
+    fn written(&self, path: &Path) -> bool {
+        self.added.contains(path) || self.ancestors.contains(path)
+    }
+}
+
+impl LayerApplier {
+    pub fn new(root: &Path) -> Self {
+        LayerApplier {
+            root: root.to_path_buf(),
+            preserve_ownership: nix::unistd::geteuid().is_root(),
+            whiteouts: WhiteoutMode::Apply,
+            rebase: None,
+        }
+    }
+
+    pub fn with_whiteout_mode(mut self, mode: WhiteoutMode) -> Self {
+        self.whiteouts = mode;
+        self
+    }
+
+    /// Extracts entries under `from` to `to` instead, both relative to the
+    /// root; any other entry is an error. Symlinks on the way to `to` are
+    /// still resolved inside the root.
+    pub fn with_rebase(mut self, from: &Path, to: &Path) -> Self {
+        self.rebase = Some((from.to_path_buf(), to.to_path_buf()));
+        self
+    }
+
+    /// Defaults to true when running as root, where `chown` is permitted.
+    pub fn with_preserve_ownership(mut self, preserve: bool) -> Self {
+        self.preserve_ownership = preserve;
+        self
+    }
+
+    /// Applies one uncompressed layer tarball and hands back the reader so
+    /// the caller can check it was consumed completely.
+    pub fn apply<R: Read>(&self, reader: R) -> Result<R> {
+        fs::create_dir_all(&self.root)?;
+
+        let mut archive = Archive::new(reader);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_ownerships(self.preserve_ownership);
+        archive.set_preserve_mtime(true);
+        archive.set_unpack_xattrs(false);
+        archive.set_overwrite(true);
+
+        let mut state = LayerState::default();
+        for entry in archive.entries()? {
+            self.apply_entry(&mut entry?, &mut state)?;
+        }
+
+        Ok(archive.into_inner())
+    }
+
+    fn apply_entry<R: Read>(&self, entry: &mut Entry<R>, state: &mut LayerState) -> Result<()> {
+        let rel = clean_entry_path(&entry.path()?)?;
+        if rel.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let rel = self.rebase(&rel)?;
+        let Some(name) = rel.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+            // The layer's own root entry ("./"); the rootfs already exists.
+            return Ok(());
+        };
+        let parent = self.resolve(rel.parent().unwrap_or(Path::new("")))?;
+
+        let literal = self.whiteouts == WhiteoutMode::Literal;
+        if name == OPAQUE_WHITEOUT && !literal {
+            fs::create_dir_all(&parent)?;
+            return match self.whiteouts {
+                WhiteoutMode::Apply => self.make_opaque(&parent, state),
+                WhiteoutMode::Overlay => Ok(xattr::set(&parent, OVERLAY_OPAQUE_XATTR, b"y")?),
+                WhiteoutMode::Literal => unreachable!("literal entries are not whiteouts"),
+            };
+        }
+        if name.starts_with(WHITEOUT_META_PREFIX) && !literal {
+            return Ok(());
+        }
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX).filter(|_| !literal) {
+            if hidden.is_empty() || hidden == "." || hidden == ".." {
+                return Err(AethelError::Filesystem(format!("Invalid whiteout entry {}", rel.display())));
+            }
+            let target = parent.join(hidden);
+            match self.whiteouts {
+                WhiteoutMode::Apply if !state.written(&target) => remove_path(&target)?,
+                WhiteoutMode::Apply => {}
+                WhiteoutMode::Overlay => {
+                    fs::create_dir_all(&parent)?;
+                    prepare_destination(&target, false)?;
+                    mknod(&target, SFlag::S_IFCHR, Mode::empty(), makedev(0, 0))?;
+                    state.record(&self.root, &target);
+                }
+                WhiteoutMode::Literal => unreachable!("literal entries are not whiteouts"),
+            }
+            return Ok(());
+        }
+
+        fs::create_dir_all(&parent)?;
+        let dst = parent.join(&name);
+        let kind = entry.header().entry_type();
+        prepare_destination(&dst, kind.is_dir())?;
+
+        match kind {
+            EntryType::Link => self.hard_link(entry, &dst)?,
+            EntryType::Char | EntryType::Block | EntryType::Fifo => {
+                self.make_node(entry, kind, &dst)?;
+                self.set_xattrs(entry, &dst)?;
+            }
+            EntryType::XGlobalHeader | EntryType::XHeader | EntryType::GNULongName | EntryType::GNULongLink => {
+                return Ok(());
+            }
+            _ => {
+                entry.unpack(&dst)?;
+                self.set_xattrs(entry, &dst)?;
+            }
+        }
+
+        state.record(&self.root, &dst);
+        Ok(())
+    }
+
+    fn resolve(&self, rel: &Path) -> Result<PathBuf> {
+        resolve_in_root(&self.root, rel)
+    }
+
+    /// Moves entries under `from` to `to`; see `with_rebase`.
+    fn rebase(&self, rel: &Path) -> Result<PathBuf> {
+        let Some((from, to)) = &self.rebase else {
+            return Ok(rel.to_path_buf());
+        };
+        match rel.strip_prefix(from) {
+            Ok(rest) if rest.as_os_str().is_empty() => Ok(to.clone()),
+            Ok(rest) => Ok(to.join(rest)),
+            Err(_) => Err(AethelError::Filesystem(format!(
+                "Archive entry {} is outside {}",
+                rel.display(),
+                from.display()
+            ))),
+        }
+    }
+
+    /// Removes everything under `dir` that was not written by this layer.
+    fn make_opaque(&self, dir: &Path, state: &LayerState) -> Result<()> {
+        for child in fs::read_dir(dir)? {
+            let path = child?.path();
+            if !state.written(&path) {
+                remove_path(&path)?;
+            } else if fs::symlink_metadata(&path)?.is_dir() {
+                self.make_opaque(&path, state)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hard links may point at files from lower layers, but never at
+    /// anything outside the rootfs or at a directory.
+    fn hard_link<R: Read>(&self, entry: &mut Entry<R>, dst: &Path) -> Result<()> {
+        let link_name = entry.link_name()?.ok_or_else(|| {
+            AethelError::Filesystem(format!("Hard link {} has no target", dst.display()))
+        })?;
+        let target_rel = self.rebase(&clean_entry_path(&link_name)?)?;
+        let target_name = target_rel.file_name().ok_or_else(|| {
+            AethelError::Filesystem(format!("Hard link {} has an empty target", dst.display()))
+        })?;
+        let target = self
+            .resolve(target_rel.parent().unwrap_or(Path::new("")))?
+            .join(target_name);
+
+        let meta = fs::symlink_metadata(&target).map_err(|e| {
+            AethelError::Filesystem(format!(
+                "Hard link target {} for {}: {}",
+                link_name.display(),
+                dst.display(),
+                e
+            ))
+        })?;
+        if meta.is_dir() {
+            return Err(AethelError::Filesystem(format!(
+                "Hard link {} points at directory {}",
+                dst.display(),
+                link_name.display()
+            )));
+        }
+        fs::hard_link(&target, dst)?;
+        Ok(())
+    }
+
+    fn make_node<R: Read>(&self, entry: &mut Entry<R>, kind: EntryType, dst: &Path) -> Result<()> {
+        let header = entry.header();
+        let sflag = match kind {
+            EntryType::Char => SFlag::S_IFCHR,
+            EntryType::Block => SFlag::S_IFBLK,
+            _ => SFlag::S_IFIFO,
+        };
+        let major = header.device_major()?.unwrap_or(0);
+        let minor = header.device_minor()?.unwrap_or(0);
+        let mode = Mode::from_bits_truncate(header.mode()?);
+
+        match mknod(dst, sflag, mode, makedev(major as u64, minor as u64)) {
+            Ok(()) => {}
+            // Unprivileged extraction cannot create device nodes; skip them
+            // rather than fail the whole image.
+            Err(nix::errno::Errno::EPERM) if !self.preserve_ownership && sflag != SFlag::S_IFIFO => {
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        if self.preserve_ownership {
+            lchown(dst, Some(header.uid()? as u32), Some(header.gid()? as u32))?;
+        }
+        Ok(())
+    }
+
+    fn set_xattrs<R: Read>(&self, entry: &mut Entry<R>, dst: &Path) -> Result<()> {
+        let Some(extensions) = entry.pax_extensions()? else {
+            return Ok(());
+        };
+        for extension in extensions {
+            let extension = extension?;
+            let Some(name) = extension.key().ok().and_then(|k| k.strip_prefix("SCHILY.xattr.")) else {
+                continue;
+            };
+            match xattr::set(dst, name, extension.value_bytes()) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+                // Without privileges `security.*` and `trusted.*` are off limits.
+                Err(_) if !self.preserve_ownership => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolves `rel` (already free of `..` components) to a host path under
+/// `root`. Symlinks are followed as if the root were `/`; a symlink that
+/// would climb above the root is an error.
+pub fn resolve_in_root(root: &Path, rel: &Path) -> Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    let mut pending: VecDeque<OsString> = rel.iter().map(|c| c.to_os_string()).collect();
+    let mut follows = 0;
+
+    while let Some(component) = pending.pop_front() {
+        if component == "." || component.is_empty() {
+            continue;
+        }
+        if component == ".." {
+            if !resolved.pop() {
+                return Err(AethelError::Filesystem(format!(
+                    "{} resolves outside the rootfs",
+                    rel.display()
+                )));
+            }
+            continue;
+        }
+
+        let candidate = root.join(&resolved).join(&component);
+        match fs::symlink_metadata(&candidate) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                follows += 1;
+                if follows > MAX_SYMLINK_FOLLOWS {
+                    return Err(AethelError::Filesystem(format!(
+                        "Too many levels of symlinks resolving {}",
+                        rel.display()
+                    )));
+                }
+                let target = fs::read_link(&candidate)?;
+                if target.is_absolute() {
+                    resolved.clear();
+                }
+                for part in target.iter().rev() {
+                    if part != "/" {
+                        pending.push_front(part.to_os_string());
+                    }
+                }
+            }
+            Ok(_) => resolved.push(&component),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => resolved.push(&component),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(root.join(resolved))
+}
+
+/// Normalises a tar entry path to a relative path. Leading `/` and `./` are
+/// dropped; `..` anywhere is rejected outright.
+fn clean_entry_path(path: &Path) -> Result<PathBuf> {
+    let mut clean = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(AethelError::Filesystem(format!(
+                    "Layer entry {} escapes the rootfs",
+                    path.display()
+                )))
+            }
+        }
+    }
+    Ok(clean)
+}
+
+/// Clears whatever a lower layer left at `dst` unless it is a directory that
+/// the new directory entry should merge into.
+fn prepare_destination(dst: &Path, is_dir: bool) -> Result<()> {
+    match fs::symlink_metadata(dst) {
+        Ok(meta) if meta.is_dir() && is_dir => Ok(()),
+        Ok(_) => remove_path(dst),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => other.map_err(AethelError::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    use tar::{Builder, Header};
+
+    enum Fixture<'a> {
+        Dir(&'a str),
+        File(&'a str, &'a str),
+        Symlink(&'a str, &'a str),
+        HardLink(&'a str, &'a str),
+        Fifo(&'a str),
+    }
+
+    fn set_raw_path(header: &mut Header, path: &str) {
+        let name = &mut header.as_old_mut().name;
+        name.fill(0);
+        name[..path.len()].copy_from_slice(path.as_bytes());
+    }
+
+    fn layer(entries: &[Fixture]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for fixture in entries {
+            let mut header = Header::new_old();
+            header.set_mtime(1);
+            header.set_uid(0);
+            header.set_gid(0);
+            let (path, data): (&str, &[u8]) = match fixture {
+                Fixture::Dir(path) => {
+                    header.set_entry_type(EntryType::Directory);
+                    header.set_mode(0o755);
+                    (path, b"")
+                }
+                Fixture::File(path, content) => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_mode(0o644);
+                    (path, content.as_bytes())
+                }
+                Fixture::Symlink(path, target) => {
+                    header.set_entry_type(EntryType::Symlink);
+                    header.set_mode(0o777);
+                    header.set_link_name(target).unwrap();
+                    (path, b"")
+                }
+                Fixture::HardLink(path, target) => {
+                    header.set_entry_type(EntryType::Link);
+                    header.set_mode(0o644);
+                    header.as_old_mut().linkname[..target.len()].copy_from_slice(target.as_bytes());
+                    (path, b"")
+                }
+                Fixture::Fifo(path) => {
+                    header.set_entry_type(EntryType::Fifo);
+                    header.set_mode(0o600);
+                    (path, b"")
+                }
+            };
+            // Written raw so fixtures can contain the hostile paths that
+            // `Header::set_path` refuses.
+            set_raw_path(&mut header, path);
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn apply(root: &Path, entries: &[Fixture]) -> Result<()> {
+        LayerApplier::new(root)
+            .with_preserve_ownership(false)
+            .apply(layer(entries).as_slice())
+            .map(|_| ())
+    }
+
+    #[test]
+    fn whiteout_removes_lower_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("rootfs");
+        apply(&root, &[Fixture::Dir("etc/"), Fixture::File("etc/passwd", "root"), Fixture::File("etc/shadow", "x")]).unwrap();
+        apply(&root, &[Fixture::File("etc/.wh.shadow", "")]).unwrap();
+
+        assert!(root.join("etc/passwd").exists());
+        assert!(!root.join("etc/shadow").exists());
+        assert!(!root.join("etc/.wh.shadow").exists());
+    }
+
+    #[test]
+    fn whiteout_does_not_hide_same_layer_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("rootfs");
+        apply(&root, &[Fixture::File("a", "lower")]).unwrap();
+        apply(&root, &[Fixture::File("a", "upper"), Fixture::File(".wh.a", "")]).unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("a")).unwrap(), "upper");
+    }
+
+    #[test]
+    fn opaque_directory_hides_lower_children_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("rootfs");
+        apply(
+            &root,
+            &[
+                Fixture::Dir("opt/"),
+                Fixture::File("opt/old", "old"),
+                Fixture::Dir("opt/sub/"),
+                Fixture::File("opt/sub/old", "old"),
+            ],
+        )
+        .unwrap();
+        apply(
+            &root,
+            &[
+                Fixture::Dir("opt/"),
+                Fixture::Dir("opt/sub/"),
+                Fixture::File("opt/sub/new", "new"),
+                Fixture::File("opt/.wh..wh..opq", ""),
+            ],
+        )
+        .unwrap();
+
+        assert!(!root.join("opt/old").exists());
+        assert!(!root.join("opt/sub/old").exists());
+        assert!(root.join("opt/sub/new").exists());
+        assert!(!root.join("opt/.wh..wh..opq").exists());
+    }
+
+    #[test]
+    fn rejects_parent_dir_traversal() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("rootfs");
+        let err = apply(&root, &[Fixture::File("../escape", "x")]).unwrap_err();
+
+        assert!(matches!(err, AethelError::Filesystem(_)));
+        assert!(!dir.path().join("escape").exists());
+    }
+
+    #[test]
+    fn rejects_writes_through_escaping_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("rootfs");
+        apply(&root, &[Fixture::Symlink("evil", "../..")]).unwrap();
+        let err = apply(&root, &[Fixture::File("evil/escape", "x")]).unwrap_err();
+
+        assert!(matches!(err, AethelError::Filesystem(_)));
+        assert!(!dir.path().join("escape").exists());
+    }
+
+    #[test]
+    fn absolute_symlinks_resolve_inside_rootfs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("rootfs");
+        apply(&root, &[Fixture::Dir("usr/"), Fixture::Dir("usr/lib/"), Fixture::Symlink("lib", "/usr/lib")]).unwrap();
+        apply(&root, &[Fixture::File("lib/libc.so", "elf")]).unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("usr/lib/libc.so")).unwrap(), "elf");
+        assert!(fs::symlink_metadata(root.join("lib")).unwrap().file_type().is_symlink());
+    }
+
+    #[test]
+    fn hard_links_across_layers_share_inode() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("rootfs");
+        apply(&root, &[Fixture::Dir("bin/"), Fixture::File("bin/busybox", "bb")]).unwrap();
+        apply(&root, &[Fixture::HardLink("bin/sh", "bin/busybox")]).unwrap();
+
+        let original = fs::metadata(root.join("bin/busybox")).unwrap();
+        let link = fs::metadata(root.join("bin/sh")).unwrap();
+        assert_eq!(original.ino(), link.ino());
+    }
+
+    #[test]
+    fn rejects_hard_link_outside_rootfs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("rootfs");
+        fs::write(dir.path().join("secret"), "s").unwrap();
+        let err = apply(&root, &[Fixture::HardLink("stolen", "../secret")]).unwrap_err();
+
+        assert!(matches!(err, AethelError::Filesystem(_)));
+        assert!(!root.join("stolen").exists());
+    }
+
+    #[test]
+    fn file_replaces_lower_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("rootfs");
+        apply(&root, &[Fixture::Dir("data/"), Fixture::File("data/inner", "x")]).unwrap();
+        apply(&root, &[Fixture::File("data", "now a file")]).unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("data")).unwrap(), "now a file");
+    }
+
+    #[test]
+    fn overlay_mode_writes_overlayfs_whiteouts() {
+        // Whiteout devices and trusted.* xattrs both need CAP_SYS_ADMIN/CAP_MKNOD.
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("layer");
+        LayerApplier::new(&root)
+            .with_whiteout_mode(WhiteoutMode::Overlay)
+            .apply(layer(&[Fixture::Dir("etc/"), Fixture::File("etc/.wh.shadow", ""), Fixture::File("etc/.wh..wh..opq", "")]).as_slice())
+            .unwrap();
+
+        let meta = fs::symlink_metadata(root.join("etc/shadow")).unwrap();
+        assert!(meta.file_type().is_char_device());
+        assert_eq!(meta.rdev(), 0);
+        assert_eq!(xattr::get(root.join("etc"), OVERLAY_OPAQUE_XATTR).unwrap(), Some(b"y".to_vec()));
+    }
+
+    #[test]
+    fn creates_fifo_nodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("rootfs");
+        apply(&root, &[Fixture::Fifo("run/pipe")]).unwrap();
+
+        let meta = fs::symlink_metadata(root.join("run/pipe")).unwrap();
+        assert!(meta.file_type().is_fifo());
+    }
+
+    #[test]
+    fn decompresses_layers_by_media_type() {
+        let tar = layer(&[Fixture::File("hello", "world")]);
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        io::Write::write_all(&mut gzip, &tar).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(tar.as_slice(), 0).unwrap();
+
+        for (media_type, blob) in [
+            (oci::MEDIA_TYPE_OCI_LAYER, &tar),
+            (oci::MEDIA_TYPE_DOCKER_LAYER, &tar),
+            (oci::MEDIA_TYPE_OCI_LAYER_GZIP, &gzip),
+            (oci::MEDIA_TYPE_DOCKER_LAYER_GZIP, &gzip),
+            (oci::MEDIA_TYPE_OCI_LAYER_ZSTD, &zstd),
+            (oci::MEDIA_TYPE_OCI_LAYER_NONDISTRIBUTABLE_ZSTD, &zstd),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().join("rootfs");
+            LayerApplier::new(&root)
+                .with_preserve_ownership(false)
+                .apply(LayerReader::new(media_type, blob.as_slice()).unwrap())
+                .unwrap();
+
+            assert_eq!(fs::read_to_string(root.join("hello")).unwrap(), "world", "{}", media_type);
+        }
+    }
+
+    #[test]
+    fn refuses_encrypted_and_unknown_layer_media_types() {
+        for media_type in [
+            "application/vnd.oci.image.layer.v1.tar+gzip+encrypted",
+            "application/vnd.oci.image.layer.v1.tar+bzip2",
+            oci::MEDIA_TYPE_OCI_CONFIG,
+            "",
+        ] {
+            let result = LayerReader::new(media_type, io::empty());
+
+            assert!(
+                matches!(result, Err(AethelError::UnsupportedMediaType(_))),
+                "{} was accepted",
+                media_type
+            );
+        }
+    }
+}
@@ -1,62 +1,249 @@
 use aethel_common::error::{AethelError, Result};
-use flate2::read::GzDecoder;
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::fs::{self, File};
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use tar::Archive;
 
-#[derive(Deserialize)]
-struct OciIndex {
-    manifests: Vec<OciManifestRef>,
+pub mod archive;
+pub mod build;
+pub mod commit;
+pub mod containerfile;
+pub mod copy;
+pub mod credentials;
+pub mod diff;
+pub mod digest;
+pub mod gc;
+pub mod layer;
+pub mod oci;
+pub mod reference;
+pub mod registry;
+pub mod snapshot;
+pub mod store;
+pub mod volume;
+
+use diff::Change;
+use digest::{Digest, VerifyingReader};
+use layer::{Compression, LayerApplier, LayerReader};
+use oci::{Descriptor, ImageConfig, OciIndex, OciManifest, Platform};
+use snapshot::Snapshotter;
+use store::ImageStore;
+
+/// Index nesting is bounded so a malformed layout cannot loop forever.
+pub(crate) const MAX_INDEX_DEPTH: usize = 4;
+
+/// Opens the blob for `desc`. Everything read from it is checked against
+/// the descriptor's digest and size.
+pub fn open_blob(image_path: &Path, desc: &Descriptor) -> Result<VerifyingReader<File>> {
+    let digest: Digest = desc.digest.parse()?;
+    let file = File::open(digest.blob_path(image_path))?;
+    Ok(VerifyingReader::new(file, digest, desc.size))
+}
+
+fn parse_json<T: DeserializeOwned>(bytes: &[u8], what: &str) -> Result<T> {
+    serde_json::from_slice(bytes)
+        .map_err(|e| AethelError::Filesystem(format!("Failed to parse {}: {}", what, e)))
+}
+
+pub(crate) fn encode_json<T: Serialize>(value: &T, what: &str) -> Result<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|e| AethelError::Filesystem(format!("Failed to encode {}: {}", what, e)))
+}
+
+/// Blobs are read fully (and so verified) before any of their content is
+/// parsed.
+fn read_blob_json<T: DeserializeOwned>(store: &ImageStore, desc: &Descriptor, what: &str) -> Result<T> {
+    parse_json(&store.read_blob(desc)?, what)
+}
+
+pub fn read_layout_index(image_path: &Path) -> Result<OciIndex> {
+    parse_json(&fs::read(image_path.join("index.json"))?, "index.json")
+}
+
+/// Every blob the image `target` is made of, `target` first: for an index
+/// its child manifests and theirs, for a manifest its config and layers.
+pub fn image_blobs(store: &ImageStore, target: &Descriptor) -> Result<Vec<Descriptor>> {
+    let mut blobs = Vec::new();
+    collect_blobs(store, target, 0, &mut blobs)?;
+    Ok(blobs)
+}
+
+fn collect_blobs(store: &ImageStore, desc: &Descriptor, depth: usize, blobs: &mut Vec<Descriptor>) -> Result<()> {
+    blobs.push(desc.clone());
+    if oci::is_index_media_type(&desc.media_type) {
+        if depth == MAX_INDEX_DEPTH {
+            return Err(AethelError::Filesystem(format!(
+                "Image index nesting exceeds {} levels",
+                MAX_INDEX_DEPTH
+            )));
+        }
+        let index: OciIndex = read_blob_json(store, desc, "image index")?;
+        for child in &index.manifests {
+            collect_blobs(store, child, depth + 1, blobs)?;
+        }
+    } else {
+        let manifest: OciManifest = read_blob_json(store, desc, "manifest")?;
+        blobs.push(manifest.config);
+        blobs.extend(manifest.layers);
+    }
+    Ok(())
+}
+
+/// Walks from `target` down to the image manifest for `platform`,
+/// following nested indexes (multi-platform images).
+pub fn resolve_manifest(store: &ImageStore, target: &Descriptor, platform: &Platform) -> Result<OciManifest> {
+    let mut desc = target.clone();
+    for _ in 0..MAX_INDEX_DEPTH {
+        if !oci::is_index_media_type(&desc.media_type) {
+            return read_blob_json(store, &desc, "manifest");
+        }
+        let index: OciIndex = read_blob_json(store, &desc, "image index")?;
+        desc = oci::select_manifest(&index.manifests, platform)?.clone();
+    }
+
+    Err(AethelError::Filesystem(format!(
+        "Image index nesting exceeds {} levels",
+        MAX_INDEX_DEPTH
+    )))
 }
 
-#[derive(Deserialize)]
-struct OciManifestRef {
-    digest: String,
+pub fn read_image_config(store: &ImageStore, manifest: &OciManifest) -> Result<ImageConfig> {
+    read_blob_json(store, &manifest.config, "image config")
 }
 
-#[derive(Deserialize)]
-struct OciManifest {
-    layers: Vec<OciLayer>,
+/// Resolves the image `target` to its manifest and config for `platform`,
+/// which defaults to the host platform.
+pub fn resolve_image(
+    store: &ImageStore,
+    target: &Descriptor,
+    platform: Option<&Platform>,
+) -> Result<(OciManifest, ImageConfig)> {
+    let platform = platform.cloned().unwrap_or_else(Platform::host);
+
+    let manifest = resolve_manifest(store, target, &platform)?;
+    let config = read_image_config(store, &manifest)?;
+    if !config.os.is_empty() && !platform.matches(&config.platform()) {
+        return Err(AethelError::Filesystem(format!(
+            "Image {} is built for {}, not {}",
+            target.digest,
+            config.platform(),
+            platform
+        )));
+    }
+
+    Ok((manifest, config))
 }
 
-#[derive(Deserialize)]
-struct OciLayer {
-    digest: String,
+/// Unpacks the image `target` into `rootfs` and returns its config.
+pub fn prepare_rootfs(
+    store: &ImageStore,
+    target: &Descriptor,
+    rootfs: &Path,
+    platform: Option<&Platform>,
+) -> Result<ImageConfig> {
+    let (manifest, config) = resolve_image(store, target, platform)?;
+    check_layer_media_types(&manifest)?;
+
+    fs::create_dir_all(rootfs)?;
+
+    if let Err(e) = unpack_layers(store, &manifest, rootfs) {
+        let _ = fs::remove_dir_all(rootfs);
+        return Err(e);
+    }
+
+    Ok(config)
 }
 
-pub fn prepare_rootfs(image_name: &str) -> Result<PathBuf> {
-    let image_path = Path::new("images").join(image_name);
-    let index_path = image_path.join("index.json");
-    let index_file = File::open(index_path)?;
-    let index: OciIndex = serde_json::from_reader(index_file)
-        .map_err(|e| AethelError::Filesystem(format!("Failed to parse index.json: {}", e)))?;
+/// Builds an overlay rootfs for `container_id` from the image's layers,
+/// unpacking any layer not already in the snapshotter. Returns the mounted
+/// rootfs path and the image config.
+pub fn prepare_snapshot(
+    snapshotter: &Snapshotter,
+    store: &ImageStore,
+    target: &Descriptor,
+    container_id: &str,
+    platform: Option<&Platform>,
+) -> Result<(PathBuf, ImageConfig)> {
+    let (manifest, config) = resolve_image(store, target, platform)?;
+    check_layer_media_types(&manifest)?;
 
-    let manifest_ref = index.manifests.get(0).ok_or_else(|| {
-        AethelError::Filesystem("No manifests found in index.json".to_string())
-    })?;
+    let lowers = manifest
+        .layers
+        .iter()
+        .map(|layer| snapshotter.ensure_layer(store, layer))
+        .collect::<Result<Vec<_>>>()?;
+    let rootfs = snapshotter.prepare(container_id, &lowers)?;
 
-    let manifest_path = image_path
-        .join("blobs")
-        .join("sha256")
-        .join(&manifest_ref.digest[7..]);
-    let manifest_file = File::open(manifest_path)?;
-    let manifest: OciManifest = serde_json::from_reader(manifest_file)
-        .map_err(|e| AethelError::Filesystem(format!("Failed to parse manifest: {}", e)))?;
+    Ok((rootfs, config))
+}
 
-    let rootfs_path = Path::new("rootfs").join(image_name);
-    fs::create_dir_all(&rootfs_path)?;
+/// What `container_id` changed on top of the image `target`, read from its
+/// overlay upperdir.
+pub fn snapshot_changes(
+    snapshotter: &Snapshotter,
+    store: &ImageStore,
+    target: &Descriptor,
+    container_id: &str,
+    platform: Option<&Platform>,
+) -> Result<Vec<Change>> {
+    check_snapshot(snapshotter, container_id)?;
+    let (manifest, _) = resolve_image(store, target, platform)?;
+    let lowers = manifest
+        .layers
+        .iter()
+        .map(|layer| Ok(snapshotter.layer_dir(&layer.digest.parse()?)))
+        .collect::<Result<Vec<_>>>()?;
+    diff::overlay_changes(&snapshotter.upper_dir(container_id), &lowers)
+}
 
-    for layer in manifest.layers {
-        let layer_path = image_path
-            .join("blobs")
-            .join("sha256")
-            .join(&layer.digest[7..]);
-        let tar_gz = File::open(layer_path)?;
-        let tar = GzDecoder::new(tar_gz);
-        let mut archive = Archive::new(tar);
-        archive.unpack(&rootfs_path)?;
+/// Writes the whole rootfs of `container_id`, its image and its changes
+/// flattened, to `writer` as a tar. A snapshot whose overlay is no longer
+/// mounted is mounted for the export and unmounted again after.
+pub fn export_snapshot<W: Write>(
+    snapshotter: &Snapshotter,
+    store: &ImageStore,
+    target: &Descriptor,
+    container_id: &str,
+    platform: Option<&Platform>,
+    writer: W,
+) -> Result<W> {
+    check_snapshot(snapshotter, container_id)?;
+    if snapshotter.is_mounted(container_id) {
+        return diff::write_tree(&snapshotter.merged_dir(container_id), writer);
     }
+    let (rootfs, _) = prepare_snapshot(snapshotter, store, target, container_id, platform)?;
+    let written = diff::write_tree(&rootfs, writer);
+    let unmounted = snapshotter.unmount(container_id);
+    let writer = written?;
+    unmounted?;
+    Ok(writer)
+}
 
-    Ok(rootfs_path)
-}
\ No newline at end of file
+fn check_snapshot(snapshotter: &Snapshotter, container_id: &str) -> Result<()> {
+    if !snapshotter.upper_dir(container_id).is_dir() {
+        return Err(AethelError::NotFound(format!("Container {} has no snapshot", container_id)));
+    }
+    Ok(())
+}
+
+/// Refuses an image with a layer that cannot be unpacked before any of
+/// its layers is.
+fn check_layer_media_types(manifest: &OciManifest) -> Result<()> {
+    for layer in &manifest.layers {
+        Compression::of_media_type(&layer.media_type)?;
+    }
+    Ok(())
+}
+
+/// A layer that fails verification aborts the whole unpack; the caller
+/// discards the partially written rootfs.
+fn unpack_layers(store: &ImageStore, manifest: &OciManifest, rootfs: &Path) -> Result<()> {
+    let applier = LayerApplier::new(rootfs);
+    for layer in &manifest.layers {
+        let blob = store.open_blob(layer)?;
+        applier
+            .apply(LayerReader::new(&layer.media_type, blob)?)?
+            .into_inner()
+            .finish()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,406 @@
+use aethel_common::error::{AethelError, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+pub const MEDIA_TYPE_OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const MEDIA_TYPE_OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const MEDIA_TYPE_OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+pub const MEDIA_TYPE_DOCKER_MANIFEST_LIST: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const MEDIA_TYPE_DOCKER_MANIFEST: &str =
+    "application/vnd.docker.distribution.manifest.v2+json";
+pub const MEDIA_TYPE_DOCKER_CONFIG: &str = "application/vnd.docker.container.image.v1+json";
+pub const MEDIA_TYPE_OCI_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+pub const MEDIA_TYPE_OCI_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+pub const MEDIA_TYPE_OCI_LAYER_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+pub const MEDIA_TYPE_OCI_LAYER_NONDISTRIBUTABLE: &str = "application/vnd.oci.image.layer.nondistributable.v1.tar";
+pub const MEDIA_TYPE_OCI_LAYER_NONDISTRIBUTABLE_GZIP: &str =
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip";
+pub const MEDIA_TYPE_OCI_LAYER_NONDISTRIBUTABLE_ZSTD: &str =
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd";
+pub const MEDIA_TYPE_DOCKER_LAYER: &str = "application/vnd.docker.image.rootfs.diff.tar";
+pub const MEDIA_TYPE_DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+pub const MEDIA_TYPE_DOCKER_LAYER_ZSTD: &str = "application/vnd.docker.image.rootfs.diff.tar.zstd";
+pub const MEDIA_TYPE_DOCKER_FOREIGN_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
+
+/// The tag of a manifest listed in an OCI layout's `index.json`.
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+/// The full image name containerd and Docker record alongside it.
+pub const ANNOTATION_IMAGE_NAME: &str = "io.containerd.image.name";
+
+pub fn is_index_media_type(media_type: &str) -> bool {
+    media_type == MEDIA_TYPE_OCI_INDEX || media_type == MEDIA_TYPE_DOCKER_MANIFEST_LIST
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// The platform of the running host, using OCI architecture names.
+    pub fn host() -> Self {
+        let architecture = oci_architecture(std::env::consts::ARCH, cfg!(target_endian = "little"));
+        let variant = match architecture {
+            "arm64" => Some("v8".to_string()),
+            _ => None,
+        };
+        Platform {
+            architecture: architecture.to_string(),
+            os: std::env::consts::OS.to_string(),
+            variant,
+        }
+    }
+
+    /// Whether an image built for `candidate` can run on `self`. A missing
+    /// variant on either side is treated as a wildcard.
+    pub fn matches(&self, candidate: &Platform) -> bool {
+        if self.os != candidate.os || self.architecture != candidate.architecture {
+            return false;
+        }
+        match (&self.variant, &candidate.variant) {
+            (Some(want), Some(have)) => want == have,
+            _ => true,
+        }
+    }
+}
+
+/// The OCI name of a Rust target architecture. OCI tells the byte orders
+/// of 64-bit POWER apart, where Rust only has `target_endian`.
+fn oci_architecture(arch: &str, little_endian: bool) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" if little_endian => "ppc64le",
+        "powerpc64" => "ppc64",
+        other => other,
+    }
+}
+
+impl FromStr for Platform {
+    type Err = AethelError;
+
+    /// Parses `os/arch[/variant]`, e.g. `linux/arm64/v8`.
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('/').collect();
+        match parts.as_slice() {
+            [os, arch] if !os.is_empty() && !arch.is_empty() => Ok(Platform {
+                architecture: arch.to_string(),
+                os: os.to_string(),
+                variant: None,
+            }),
+            [os, arch, variant] if !os.is_empty() && !arch.is_empty() && !variant.is_empty() => {
+                Ok(Platform {
+                    architecture: arch.to_string(),
+                    os: os.to_string(),
+                    variant: Some(variant.to_string()),
+                })
+            }
+            _ => Err(AethelError::Filesystem(format!(
+                "Invalid platform '{}', expected os/arch[/variant]",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.variant {
+            Some(variant) => write!(f, "{}/{}/{}", self.os, self.architecture, variant),
+            None => write!(f, "{}/{}", self.os, self.architecture),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Descriptor {
+    #[serde(rename = "mediaType", default)]
+    pub media_type: String,
+    pub digest: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OciIndex {
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: u32,
+    #[serde(rename = "mediaType", default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub manifests: Vec<Descriptor>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OciManifest {
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: u32,
+    #[serde(rename = "mediaType", default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+impl OciManifest {
+    /// The bytes the image takes in a blob store: config plus layers.
+    pub fn content_size(&self) -> u64 {
+        self.config.size + self.layers.iter().map(|layer| layer.size).sum::<u64>()
+    }
+}
+
+/// The image configuration blob referenced by a manifest.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub architecture: String,
+    #[serde(default)]
+    pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub config: ContainerConfig,
+    #[serde(default)]
+    pub rootfs: RootFs,
+    #[serde(default, deserialize_with = "null_as_default", skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<History>,
+}
+
+impl ImageConfig {
+    pub fn platform(&self) -> Platform {
+        Platform {
+            architecture: self.architecture.clone(),
+            os: self.os.clone(),
+            variant: self.variant.clone(),
+        }
+    }
+}
+
+/// The execution defaults in an image config. Field names follow the
+/// capitalised keys used by the OCI and Docker specs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerConfig {
+    #[serde(default, deserialize_with = "null_as_default", skip_serializing_if = "String::is_empty")]
+    pub user: String,
+    #[serde(default, with = "key_set", skip_serializing_if = "BTreeSet::is_empty")]
+    pub exposed_ports: BTreeSet<String>,
+    #[serde(default, deserialize_with = "null_as_default", skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,
+    #[serde(default, deserialize_with = "null_as_default", skip_serializing_if = "Vec::is_empty")]
+    pub entrypoint: Vec<String>,
+    #[serde(default, deserialize_with = "null_as_default", skip_serializing_if = "Vec::is_empty")]
+    pub cmd: Vec<String>,
+    #[serde(default, with = "key_set", skip_serializing_if = "BTreeSet::is_empty")]
+    pub volumes: BTreeSet<String>,
+    #[serde(default, deserialize_with = "null_as_default", skip_serializing_if = "String::is_empty")]
+    pub working_dir: String,
+    #[serde(default, deserialize_with = "null_as_default", skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, deserialize_with = "null_as_default", skip_serializing_if = "String::is_empty")]
+    pub stop_signal: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootFs {
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub diff_ids: Vec<String>,
+}
+
+impl Default for RootFs {
+    fn default() -> Self {
+        RootFs {
+            fs_type: "layers".to_string(),
+            diff_ids: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct History {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub empty_layer: bool,
+}
+
+/// Picks the manifest in an index that runs on `platform`. Entries with no
+/// platform are only used when no entry in the index declares one, which is
+/// how single-image OCI layouts are usually written.
+pub fn select_manifest<'a>(manifests: &'a [Descriptor], platform: &Platform) -> Result<&'a Descriptor> {
+    if let Some(desc) = manifests
+        .iter()
+        .find(|desc| desc.platform.as_ref().is_some_and(|p| platform.matches(p)))
+    {
+        return Ok(desc);
+    }
+
+    if manifests.iter().all(|desc| desc.platform.is_none()) {
+        if let Some(desc) = manifests.first() {
+            return Ok(desc);
+        }
+        return Err(AethelError::Filesystem("No manifests found in index".to_string()));
+    }
+
+    let available: Vec<String> = manifests
+        .iter()
+        .filter_map(|desc| desc.platform.as_ref().map(|p| p.to_string()))
+        .collect();
+    Err(AethelError::Filesystem(format!(
+        "No manifest for platform {} (available: {})",
+        platform,
+        available.join(", ")
+    )))
+}
+
+/// Docker writes `null` for empty lists and maps, which `#[serde(default)]`
+/// alone does not accept.
+fn null_as_default<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// `ExposedPorts` and `Volumes` are JSON objects whose values are always `{}`.
+mod key_set {
+    use serde::ser::SerializeMap;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::{BTreeSet, HashMap};
+
+    pub fn serialize<S: Serializer>(keys: &BTreeSet<String>, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(keys.len()))?;
+        for key in keys {
+            map.serialize_entry(key, &serde_json::Map::new())?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeSet<String>, D::Error> {
+        let map = Option::<HashMap<String, serde_json::Value>>::deserialize(deserializer)?;
+        Ok(map.unwrap_or_default().into_keys().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn platform(s: &str) -> Platform {
+        s.parse().unwrap()
+    }
+
+    fn manifest(digest: &str, platform: Option<&str>) -> Descriptor {
+        Descriptor {
+            media_type: MEDIA_TYPE_OCI_MANIFEST.to_string(),
+            digest: digest.to_string(),
+            size: 0,
+            platform: platform.map(|p| p.parse().unwrap()),
+            annotations: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn parses_and_displays_platforms() {
+        let amd64 = platform("linux/amd64");
+        assert_eq!((amd64.os.as_str(), amd64.architecture.as_str(), amd64.variant), ("linux", "amd64", None));
+        let arm = platform("linux/arm/v7");
+        assert_eq!(arm.variant.as_deref(), Some("v7"));
+        assert_eq!(arm.to_string(), "linux/arm/v7");
+        assert_eq!(platform("windows/amd64").to_string(), "windows/amd64");
+
+        for bad in ["", "linux", "linux/", "/amd64", "linux/arm/", "linux/arm/v7/extra"] {
+            assert!(bad.parse::<Platform>().is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn matches_platforms_with_variant_wildcards() {
+        let v7 = platform("linux/arm/v7");
+        assert!(v7.matches(&platform("linux/arm/v7")));
+        assert!(!v7.matches(&platform("linux/arm/v6")));
+        // A missing variant on either side matches any.
+        assert!(v7.matches(&platform("linux/arm")));
+        assert!(platform("linux/arm").matches(&v7));
+        assert!(!v7.matches(&platform("linux/arm64/v7")));
+        assert!(!platform("linux/amd64").matches(&platform("windows/amd64")));
+    }
+
+    #[test]
+    fn names_host_architectures_as_oci_does() {
+        assert_eq!(oci_architecture("x86_64", true), "amd64");
+        assert_eq!(oci_architecture("aarch64", true), "arm64");
+        assert_eq!(oci_architecture("powerpc64", true), "ppc64le");
+        assert_eq!(oci_architecture("powerpc64", false), "ppc64");
+        assert_eq!(oci_architecture("s390x", false), "s390x");
+        assert_eq!(oci_architecture("riscv64", true), "riscv64");
+    }
+
+    #[test]
+    fn selects_manifest_for_platform() {
+        let manifests = [
+            manifest("sha256:amd64", Some("linux/amd64")),
+            manifest("sha256:v6", Some("linux/arm/v6")),
+            manifest("sha256:v7", Some("linux/arm/v7")),
+            manifest("sha256:arm64", Some("linux/arm64/v8")),
+        ];
+
+        let select = |p: &str| select_manifest(&manifests, &platform(p)).unwrap().digest.clone();
+        assert_eq!(select("linux/amd64"), "sha256:amd64");
+        assert_eq!(select("linux/arm/v7"), "sha256:v7");
+        // Without a variant, the first for the architecture wins.
+        assert_eq!(select("linux/arm"), "sha256:v6");
+        assert_eq!(select("linux/arm64"), "sha256:arm64");
+
+        let err = select_manifest(&manifests, &platform("linux/s390x")).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("linux/s390x"), "{}", msg);
+        assert!(msg.contains("linux/amd64, linux/arm/v6, linux/arm/v7, linux/arm64/v8"), "{}", msg);
+        assert!(select_manifest(&manifests, &platform("linux/arm/v5")).is_err());
+    }
+
+    #[test]
+    fn selects_host_manifest_by_default() {
+        let host = Platform::host();
+        let other = if host.architecture == "amd64" { "linux/s390x" } else { "linux/amd64" };
+        let manifests = [manifest("sha256:other", Some(other)), manifest("sha256:host", Some(&host.to_string()))];
+        assert_eq!(select_manifest(&manifests, &host).unwrap().digest, "sha256:host");
+    }
+
+    #[test]
+    fn takes_the_first_manifest_when_none_name_a_platform() {
+        let manifests = [manifest("sha256:first", None), manifest("sha256:second", None)];
+        assert_eq!(select_manifest(&manifests, &platform("linux/amd64")).unwrap().digest, "sha256:first");
+        assert!(select_manifest(&[], &platform("linux/amd64")).is_err());
+
+        // Once any manifest names a platform, one without does not stand in.
+        let mixed = [manifest("sha256:bare", None), manifest("sha256:arm64", Some("linux/arm64"))];
+        assert!(select_manifest(&mixed, &platform("linux/amd64")).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use aethel_common::error::{AethelError, Result};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::digest::Digest;
+
+pub const DEFAULT_REGISTRY: &str = "docker.io";
+pub const DEFAULT_TAG: &str = "latest";
+
+/// Docker Hub is addressed as `docker.io` but served from another host.
+const DOCKER_HUB_API_HOST: &str = "registry-1.docker.io";
+
+/// A parsed `[registry/]repository[:tag][@digest]` image reference, with
+/// Docker's defaults applied: `alpine` is `docker.io/library/alpine:latest`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<Digest>,
+}
+
+impl Reference {
+    /// The host serving the distribution API for this reference.
+    pub fn api_host(&self) -> &str {
+        if self.registry == DEFAULT_REGISTRY {
+            DOCKER_HUB_API_HOST
+        } else {
+            &self.registry
+        }
+    }
+
+    /// The short form Docker shows: `alpine:3.19` rather than
+    /// `docker.io/library/alpine:3.19`.
+    pub fn familiar(&self) -> String {
+        let full = self.to_string();
+        match full.strip_prefix("docker.io/") {
+            Some(rest) => rest.strip_prefix("library/").unwrap_or(rest).to_string(),
+            None => full,
+        }
+    }
+
+    /// `registry/repository`, without tag or digest.
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.registry, self.repository)
+    }
+
+    /// What to ask the registry for: the digest when pinned, else the tag.
+    pub fn object(&self) -> String {
+        match (&self.digest, &self.tag) {
+            (Some(digest), _) => digest.to_string(),
+            (None, Some(tag)) => tag.clone(),
+            (None, None) => DEFAULT_TAG.to_string(),
+        }
+    }
+}
+
+impl FromStr for Reference {
+    type Err = AethelError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |why: &str| AethelError::Registry(format!("Invalid image reference '{}': {}", s, why));
+
+        let (name, digest) = match s.split_once('@') {
+            Some((name, digest)) => (name, Some(digest.parse::<Digest>()?)),
+            None => (s, None),
+        };
+
+        // A tag can only follow the last path component; a colon before that
+        // is a registry port.
+        let last_slash = name.rfind('/').map_or(0, |i| i + 1);
+        let (name, tag) = match name[last_slash..].rfind(':') {
+            Some(i) => (&name[..last_slash + i], Some(&name[last_slash + i + 1..])),
+            None => (name, None),
+        };
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((first, rest)) if first.contains(['.', ':']) || first == "localhost" => {
+                (first.to_string(), rest.to_string())
+            }
+            _ => (DEFAULT_REGISTRY.to_string(), name.to_string()),
+        };
+        let repository = if registry == DEFAULT_REGISTRY && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+
+        if registry.is_empty() || registry.contains(|c: char| !(c.is_ascii_alphanumeric() || ".-:[]".contains(c))) {
+            return Err(invalid("bad registry host"));
+        }
+        if !repository.split('/').all(valid_path_component) {
+            return Err(invalid("repository must be lowercase alphanumerics separated by '/', '.', '_' or '-'"));
+        }
+        if let Some(tag) = tag {
+            if !valid_tag(tag) {
+                return Err(invalid("bad tag"));
+            }
+        }
+
+        let tag = match (tag, &digest) {
+            (Some(tag), _) => Some(tag.to_string()),
+            (None, Some(_)) => None,
+            (None, None) => Some(DEFAULT_TAG.to_string()),
+        };
+
+        Ok(Reference {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lowercase alphanumerics, with `.`, `_` and `-` allowed only between them.
+fn valid_path_component(component: &str) -> bool {
+    let alnum = |b: u8| b.is_ascii_lowercase() || b.is_ascii_digit();
+    let bytes = component.as_bytes();
+    match (bytes.first(), bytes.last()) {
+        (Some(&first), Some(&last)) if alnum(first) && alnum(last) => {}
+        _ => return false,
+    }
+    bytes.iter().all(|&b| alnum(b) || b"._-".contains(&b)) && !component.contains("..")
+}
+
+fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= 128
+        && !tag.starts_with(['.', '-'])
+        && tag.bytes().all(|b| b.is_ascii_alphanumeric() || b"_.-".contains(&b))
+}
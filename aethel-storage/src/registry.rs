@@ -0,0 +1,1657 @@
+use aethel_common::error::{AethelError, Result};
+use futures::{StreamExt, TryStreamExt};
+use reqwest::header::{HeaderMap, ACCEPT, AUTHORIZATION, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE, WWW_AUTHENTICATE};
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::lock::Mutex as AsyncMutex;
+
+use crate::credentials::CredentialStore;
+use crate::digest::{Algorithm, Digest, Hasher};
+use crate::oci::{self, Descriptor, OciIndex, OciManifest, Platform};
+use crate::reference::Reference;
+use crate::store::{ImageStore, MAX_METADATA_BLOB};
+use crate::MAX_INDEX_DEPTH;
+
+const DEFAULT_CONCURRENCY: usize = 3;
+/// Blobs larger than this are uploaded in chunks of this size.
+const DEFAULT_CHUNK_SIZE: u64 = 8 << 20;
+/// How many times a blob download is resumed after a dropped connection.
+const MAX_BLOB_ATTEMPTS: usize = 3;
+/// Download progress is reported at most once per this many bytes.
+const PROGRESS_INTERVAL: u64 = 1 << 20;
+/// Downloaded bytes are written to disk in batches of about this size.
+const WRITE_BUFFER: usize = 1 << 20;
+
+/// Token scopes for reading and for writing a repository.
+const PULL: &str = "pull";
+const PUSH: &str = "pull,push";
+
+/// Records the reference an image was pulled from, so a push to another
+/// repository on the same registry can mount its blobs instead of
+/// uploading them.
+pub const SOURCE_ANNOTATION: &str = "io.aethel.image.source";
+
+const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PullProgress {
+    /// The reference resolved to the manifest with this digest.
+    Resolved { digest: String },
+    /// The blob is already in the local store.
+    Exists { digest: String },
+    Downloading { digest: String, current: u64, total: u64 },
+    Downloaded { digest: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushProgress {
+    /// The registry already has the blob.
+    Exists { digest: String },
+    /// The blob was mounted from another repository on the same registry.
+    Mounted { digest: String, from: String },
+    Uploading { digest: String, current: u64, total: u64 },
+    Uploaded { digest: String },
+}
+
+/// The outcome of a pull: the platform manifest that was stored.
+#[derive(Debug, Clone)]
+pub struct Pulled {
+    pub digest: Digest,
+    pub manifest: OciManifest,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: String,
+    #[serde(default)]
+    access_token: String,
+}
+
+/// A client for the OCI distribution API.
+///
+/// Registries on a loopback address, and any added with
+/// `with_insecure_registry`, are spoken to over plain HTTP.
+pub struct RegistryClient {
+    http: reqwest::Client,
+    insecure: HashSet<String>,
+    credentials: CredentialStore,
+    concurrency: usize,
+    chunk_size: u64,
+    /// `Authorization` values by `registry/repository:actions`.
+    auth_headers: Mutex<HashMap<String, String>>,
+    /// A lock per partial download under way, so concurrent pulls of the
+    /// same blob take turns instead of appending to one file together.
+    in_flight: Mutex<HashMap<PathBuf, Arc<AsyncMutex<()>>>>,
+}
+
+impl RegistryClient {
+    pub fn new() -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("aethel/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| AethelError::Registry(format!("Failed to create HTTP client: {}", e)))?;
+        Ok(RegistryClient {
+            http,
+            insecure: HashSet::new(),
+            credentials: CredentialStore::default(),
+            concurrency: DEFAULT_CONCURRENCY,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            auth_headers: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn with_insecure_registry(mut self, host: &str) -> Self {
+        self.insecure.insert(host.to_string());
+        self
+    }
+
+    /// Credentials used to answer `Basic` challenges and to request
+    /// bearer tokens.
+    pub fn with_credentials(mut self, credentials: CredentialStore) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// How many blobs are transferred at once.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Blobs larger than `chunk_size` are uploaded in chunks of that size;
+    /// smaller ones in a single request.
+    pub fn with_chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    fn registry_url(&self, reference: &Reference) -> String {
+        let host = reference.api_host();
+        let hostname = host.rsplit_once(':').map_or(host, |(name, _)| name);
+        let loopback = hostname == "localhost" || hostname.starts_with("127.") || hostname == "[::1]";
+        let scheme = if loopback || self.insecure.contains(host) { "http" } else { "https" };
+        format!("{}://{}", scheme, host)
+    }
+
+    fn base_url(&self, reference: &Reference) -> String {
+        format!("{}/v2/{}", self.registry_url(reference), reference.repository)
+    }
+
+    /// Pulls `reference` for `platform` into `store` and points the
+    /// reference at it. Blobs already in the store are not downloaded again,
+    /// and a blob left half-downloaded by an earlier pull is resumed.
+    pub async fn pull(
+        &self,
+        reference: &Reference,
+        platform: &Platform,
+        store: &ImageStore,
+        progress: &(dyn Fn(PullProgress) + Send + Sync),
+    ) -> Result<Pulled> {
+        let (mut bytes, mut media_type, mut digest) = self.fetch_manifest(reference, &reference.object()).await?;
+
+        let mut depth = 0;
+        while oci::is_index_media_type(&media_type) {
+            depth += 1;
+            if depth > MAX_INDEX_DEPTH {
+                return Err(AethelError::Registry(format!(
+                    "Image index nesting exceeds {} levels",
+                    MAX_INDEX_DEPTH
+                )));
+            }
+            let index: OciIndex = parse_json(&bytes, "image index")?;
+            let desc = oci::select_manifest(&index.manifests, platform)?.clone();
+            (bytes, media_type, digest) = self.fetch_manifest(reference, &desc.digest).await?;
+            check_descriptor(&desc, &digest, bytes.len() as u64)?;
+        }
+
+        let manifest: OciManifest = parse_json(&bytes, "manifest")?;
+        progress(PullProgress::Resolved {
+            digest: digest.to_string(),
+        });
+
+        // Built up front rather than in a stream combinator: a closure held
+        // across the await keeps the future from being `Send`. A layer can
+        // appear more than once; it is fetched once.
+        let mut seen = HashSet::new();
+        let fetches: Vec<_> = std::iter::once(&manifest.config)
+            .chain(&manifest.layers)
+            .filter(|desc| seen.insert(desc.digest.as_str()))
+            .map(|desc| self.fetch_blob(reference, desc, store, progress))
+            .collect();
+        futures::stream::iter(fetches)
+            .buffer_unordered(self.concurrency)
+            .try_collect::<Vec<()>>()
+            .await?;
+
+        store.write_blob(&digest, &bytes)?;
+        let target = Descriptor {
+            media_type,
+            digest: digest.to_string(),
+            size: bytes.len() as u64,
+            platform: Some(platform.clone()),
+            annotations: [(SOURCE_ANNOTATION.to_string(), reference.to_string())].into(),
+        };
+        // A tag moves; keep the digest it pointed at reachable by name too.
+        if reference.digest.is_none() {
+            let pinned = Reference {
+                tag: None,
+                digest: Some(digest.clone()),
+                ..reference.clone()
+            };
+            store.set_reference(&pinned, target.clone())?;
+        }
+        store.set_reference(reference, target)?;
+
+        Ok(Pulled { digest, manifest })
+    }
+
+    /// Fetches a manifest or index by tag or digest. Returns its bytes,
+    /// media type and digest; a digest reference is verified.
+    async fn fetch_manifest(&self, reference: &Reference, object: &str) -> Result<(Vec<u8>, String, Digest)> {
+        let url = format!("{}/manifests/{}", self.base_url(reference), object);
+        let response = self
+            .send(reference, PULL, || self.http.get(&url).header(ACCEPT, MANIFEST_ACCEPT))
+            .await?;
+        let response = expect_success(response, &url).await?;
+
+        let header_digest = header_str(response.headers(), "docker-content-digest");
+        let content_type = header_str(response.headers(), CONTENT_TYPE.as_str());
+        if response.content_length().is_some_and(|len| len > MAX_METADATA_BLOB) {
+            return Err(AethelError::Registry(format!("Manifest {} is too large", url)));
+        }
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|e| AethelError::Registry(format!("Failed to read {}: {}", url, e)))?
+            .to_vec();
+        if bytes.len() as u64 > MAX_METADATA_BLOB {
+            return Err(AethelError::Registry(format!("Manifest {} is too large", url)));
+        }
+
+        // Registries compute the digest over the exact bytes served, so ours
+        // must match whatever we asked for or were told.
+        let expected = match object.parse::<Digest>() {
+            Ok(digest) => Some(digest),
+            Err(_) => header_digest.and_then(|d| d.parse().ok()),
+        };
+        let digest = match expected {
+            Some(expected) => {
+                let actual = Digest::of_bytes(expected.algorithm(), &bytes);
+                if actual != expected {
+                    return Err(AethelError::DigestMismatch {
+                        expected: expected.to_string(),
+                        actual: actual.to_string(),
+                    });
+                }
+                actual
+            }
+            None => Digest::of_bytes(Algorithm::Sha256, &bytes),
+        };
+
+        // Prefer the mediaType in the document; Content-Type is often generic.
+        let media_type = serde_json::from_slice::<serde_json::Value>(&bytes)
+            .ok()
+            .and_then(|doc| doc.get("mediaType").and_then(|m| m.as_str()).map(str::to_string))
+            .or(content_type)
+            .unwrap_or_default();
+
+        Ok((bytes, media_type, digest))
+    }
+
+    /// Downloads one blob into the store unless it is already there.
+    async fn fetch_blob(
+        &self,
+        reference: &Reference,
+        desc: &Descriptor,
+        store: &ImageStore,
+        progress: &(dyn Fn(PullProgress) + Send + Sync),
+    ) -> Result<()> {
+        let digest: Digest = desc.digest.parse()?;
+        let partial = ingest_path(store, &digest);
+        let lock = self.in_flight.lock().unwrap().entry(partial.clone()).or_default().clone();
+        let guard = lock.lock().await;
+        let result = self.fetch_blob_locked(reference, desc, &digest, store, &partial, progress).await;
+        drop(guard);
+
+        // The map and this task hold the only references unless another
+        // pull is waiting its turn.
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if Arc::strong_count(&lock) == 2 {
+            in_flight.remove(&partial);
+        }
+        result
+    }
+
+    /// `fetch_blob` once no one else is writing `partial`. The blob may
+    /// have arrived while waiting.
+    async fn fetch_blob_locked(
+        &self,
+        reference: &Reference,
+        desc: &Descriptor,
+        digest: &Digest,
+        store: &ImageStore,
+        partial: &Path,
+        progress: &(dyn Fn(PullProgress) + Send + Sync),
+    ) -> Result<()> {
+        let target = store.blob_path(digest);
+        if target.is_file() {
+            progress(PullProgress::Exists {
+                digest: desc.digest.clone(),
+            });
+            return Ok(());
+        }
+
+        fs::create_dir_all(store.ingest_dir())?;
+
+        let mut last_error = None;
+        for _ in 0..MAX_BLOB_ATTEMPTS {
+            match self.download(reference, desc, digest, partial, progress).await {
+                Ok(()) => {
+                    fs::create_dir_all(target.parent().expect("blob path has a parent"))?;
+                    fs::rename(partial, &target)?;
+                    progress(PullProgress::Downloaded {
+                        digest: desc.digest.clone(),
+                    });
+                    return Ok(());
+                }
+                // Only a dropped transfer is worth resuming.
+                Err(DownloadError::Transfer(e)) => last_error = Some(e),
+                Err(DownloadError::Fatal(e)) => {
+                    let _ = fs::remove_file(partial);
+                    return Err(e);
+                }
+            }
+        }
+        Err(last_error.expect("at least one attempt was made"))
+    }
+
+    /// Appends the rest of the blob to `partial`, asking the registry to
+    /// resume from what is already there, then verifies the whole file.
+    async fn download(
+        &self,
+        reference: &Reference,
+        desc: &Descriptor,
+        digest: &Digest,
+        partial: &Path,
+        progress: &(dyn Fn(PullProgress) + Send + Sync),
+    ) -> std::result::Result<(), DownloadError> {
+        let (path, algorithm, size) = (partial.to_path_buf(), digest.algorithm(), desc.size);
+        let (mut file, mut hasher, mut offset) = blocking(move || open_partial(&path, algorithm, size)).await?;
+
+        // A previous attempt may have fetched everything but failed before
+        // the rename; asking for an empty range would only earn a 416.
+        if offset < desc.size {
+            let url = format!("{}/blobs/{}", self.base_url(reference), desc.digest);
+            let mut response = self
+                .send(reference, PULL, || {
+                    let request = self.http.get(&url);
+                    if offset > 0 {
+                        request.header(RANGE, format!("bytes={}-", offset))
+                    } else {
+                        request
+                    }
+                })
+                .await
+                .map_err(DownloadError::Transfer)?;
+
+            match response.status() {
+                StatusCode::PARTIAL_CONTENT if offset > 0 => {}
+                StatusCode::OK => {
+                    // The registry ignored the range; start over.
+                    if offset > 0 {
+                        let path = partial.to_path_buf();
+                        file = blocking(move || Ok(fs::File::create(path)?)).await?;
+                        hasher = Hasher::new(digest.algorithm());
+                        offset = 0;
+                    }
+                }
+                status => {
+                    let err = match expect_success(response, &url).await {
+                        Err(e) => e,
+                        Ok(_) => AethelError::Registry(format!("{} returned unexpected {}", url, status)),
+                    };
+                    return Err(DownloadError::Fatal(err));
+                }
+            }
+
+            // Chunks are gathered and handed to a blocking thread to hash
+            // and write, so neither holds up the async workers.
+            let mut pending = Vec::with_capacity(WRITE_BUFFER);
+            let mut reported = offset;
+            loop {
+                let chunk = match response.chunk().await {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => break,
+                    Err(e) => {
+                        append(file, hasher, pending, true).await?;
+                        return Err(DownloadError::Transfer(AethelError::Registry(format!(
+                            "Download of {} interrupted: {}",
+                            desc.digest, e
+                        ))));
+                    }
+                };
+                offset += chunk.len() as u64;
+                if offset > desc.size {
+                    return Err(DownloadError::Fatal(AethelError::SizeMismatch {
+                        digest: desc.digest.clone(),
+                        expected: desc.size,
+                        actual: offset,
+                    }));
+                }
+                pending.extend_from_slice(&chunk);
+                if pending.len() >= WRITE_BUFFER {
+                    let full = std::mem::replace(&mut pending, Vec::with_capacity(WRITE_BUFFER));
+                    (file, hasher) = append(file, hasher, full, false).await?;
+                }
+                if offset - reported >= PROGRESS_INTERVAL || offset == desc.size {
+                    reported = offset;
+                    progress(PullProgress::Downloading {
+                        digest: desc.digest.clone(),
+                        current: offset,
+                        total: desc.size,
+                    });
+                }
+            }
+            (_, hasher) = append(file, hasher, pending, true).await?;
+        }
+
+        if offset != desc.size {
+            return Err(DownloadError::Transfer(AethelError::SizeMismatch {
+                digest: desc.digest.clone(),
+                expected: desc.size,
+                actual: offset,
+            }));
+        }
+        let actual = hasher.finish();
+        if actual != *digest {
+            return Err(DownloadError::Fatal(AethelError::DigestMismatch {
+                expected: digest.to_string(),
+                actual: actual.to_string(),
+            }));
+        }
+        Ok(())
+    }
+
+    /// Pushes the image `root` from `store` to `reference`: every blob the
+    /// registry lacks, then the manifest under the reference's tag. Returns
+    /// the digest of the pushed manifest.
+    pub async fn push(
+        &self,
+        reference: &Reference,
+        store: &ImageStore,
+        root: &Descriptor,
+        progress: &(dyn Fn(PushProgress) + Send + Sync),
+    ) -> Result<Digest> {
+        let digest: Digest = root.digest.parse()?;
+        let bytes = store.read_blob(root)?;
+
+        let mount_from = root
+            .annotations
+            .get(SOURCE_ANNOTATION)
+            .and_then(|source| source.parse::<Reference>().ok())
+            .filter(|source| source.registry == reference.registry && source.repository != reference.repository)
+            .map(|source| source.repository);
+        let mount_from = mount_from.as_deref();
+
+        if oci::is_index_media_type(&root.media_type) {
+            // Platform manifests go first, by digest, so the index never
+            // refers to anything the registry does not have.
+            let image_index: OciIndex = parse_json(&bytes, "image index")?;
+            for child in &image_index.manifests {
+                if oci::is_index_media_type(&child.media_type) {
+                    return Err(AethelError::Registry("Pushing nested image indexes is not supported".to_string()));
+                }
+                let child_bytes = store.read_blob(child)?;
+                self.push_blobs(reference, store, &child_bytes, mount_from, progress).await?;
+                self.put_manifest(reference, &child.digest, &child.media_type, child_bytes).await?;
+            }
+        } else {
+            self.push_blobs(reference, store, &bytes, mount_from, progress).await?;
+        }
+        self.put_manifest(reference, &reference.object(), &root.media_type, bytes).await?;
+
+        Ok(digest)
+    }
+
+    async fn push_blobs(
+        &self,
+        reference: &Reference,
+        store: &ImageStore,
+        manifest: &[u8],
+        mount_from: Option<&str>,
+        progress: &(dyn Fn(PushProgress) + Send + Sync),
+    ) -> Result<()> {
+        let manifest: OciManifest = parse_json(manifest, "manifest")?;
+        // A layer can appear more than once; it is pushed once.
+        let mut seen = HashSet::new();
+        let uploads: Vec<_> = std::iter::once(&manifest.config)
+            .chain(&manifest.layers)
+            .filter(|desc| seen.insert(desc.digest.as_str()))
+            .map(|desc| self.push_blob(reference, store, desc, mount_from, progress))
+            .collect();
+        futures::stream::iter(uploads)
+            .buffer_unordered(self.concurrency)
+            .try_collect::<Vec<()>>()
+            .await?;
+        Ok(())
+    }
+
+    /// Makes one blob available in the target repository: nothing to do if
+    /// it is there already, a mount if the registry allows one, else an
+    /// upload in one request or in chunks depending on its size.
+    async fn push_blob(
+        &self,
+        reference: &Reference,
+        store: &ImageStore,
+        desc: &Descriptor,
+        mount_from: Option<&str>,
+        progress: &(dyn Fn(PushProgress) + Send + Sync),
+    ) -> Result<()> {
+        let base = self.base_url(reference);
+        let blob_url = format!("{}/blobs/{}", base, desc.digest);
+        let response = self.send(reference, PUSH, || self.http.head(&blob_url)).await?;
+        if response.status() == StatusCode::OK {
+            progress(PushProgress::Exists {
+                digest: desc.digest.clone(),
+            });
+            return Ok(());
+        }
+
+        let uploads_url = format!("{}/blobs/uploads/", base);
+        let response = match mount_from {
+            Some(from) => {
+                let query = [("mount", desc.digest.as_str()), ("from", from)];
+                let response = self
+                    .send(reference, PUSH, || self.http.post(&uploads_url).query(&query))
+                    .await?;
+                if response.status() == StatusCode::CREATED {
+                    progress(PushProgress::Mounted {
+                        digest: desc.digest.clone(),
+                        from: from.to_string(),
+                    });
+                    return Ok(());
+                }
+                // A refused mount still opens an ordinary upload session.
+                response
+            }
+            None => self.send(reference, PUSH, || self.http.post(&uploads_url)).await?,
+        };
+        let response = expect_status(response, StatusCode::ACCEPTED, &uploads_url).await?;
+        let mut location = self.upload_location(reference, &response)?;
+
+        // Reading the blob through to EOF verifies it, so a corrupt local
+        // blob fails before the upload is committed.
+        let mut blob = store.open_blob(desc)?;
+        if desc.size > self.chunk_size {
+            let mut chunk = vec![0; self.chunk_size as usize];
+            let mut offset = 0;
+            loop {
+                let n = read_full(&mut blob, &mut chunk)?;
+                if n == 0 {
+                    break;
+                }
+                let body = chunk[..n].to_vec();
+                let range = format!("{}-{}", offset, offset + n as u64 - 1);
+                let response = self
+                    .send(reference, PUSH, || {
+                        self.http
+                            .patch(location.clone())
+                            .header(CONTENT_TYPE, "application/octet-stream")
+                            .header(CONTENT_RANGE, &range)
+                            .body(body.clone())
+                    })
+                    .await?;
+                let response = expect_status(response, StatusCode::ACCEPTED, location.as_str()).await?;
+                location = self.upload_location(reference, &response)?;
+                offset += n as u64;
+                progress(PushProgress::Uploading {
+                    digest: desc.digest.clone(),
+                    current: offset,
+                    total: desc.size,
+                });
+            }
+            let url = with_digest(&location, &desc.digest);
+            let response = self.send(reference, PUSH, || self.http.put(url.clone())).await?;
+            expect_status(response, StatusCode::CREATED, url.as_str()).await?;
+        } else {
+            let mut body = Vec::with_capacity(desc.size as usize);
+            blob.read_to_end(&mut body)?;
+            let url = with_digest(&location, &desc.digest);
+            let response = self
+                .send(reference, PUSH, || {
+                    self.http
+                        .put(url.clone())
+                        .header(CONTENT_TYPE, "application/octet-stream")
+                        .body(body.clone())
+                })
+                .await?;
+            expect_status(response, StatusCode::CREATED, url.as_str()).await?;
+        }
+
+        progress(PushProgress::Uploaded {
+            digest: desc.digest.clone(),
+        });
+        Ok(())
+    }
+
+    async fn put_manifest(&self, reference: &Reference, object: &str, media_type: &str, bytes: Vec<u8>) -> Result<()> {
+        let media_type = if media_type.is_empty() {
+            oci::MEDIA_TYPE_OCI_MANIFEST
+        } else {
+            media_type
+        };
+        let url = format!("{}/manifests/{}", self.base_url(reference), object);
+        let response = self
+            .send(reference, PUSH, || {
+                self.http.put(&url).header(CONTENT_TYPE, media_type).body(bytes.clone())
+            })
+            .await?;
+        expect_status(response, StatusCode::CREATED, &url).await?;
+        Ok(())
+    }
+
+    /// Where to send the next part of an upload. Registries may answer with
+    /// a path relative to their root.
+    fn upload_location(&self, reference: &Reference, response: &Response) -> Result<Url> {
+        let location = header_str(response.headers(), LOCATION.as_str())
+            .ok_or_else(|| AethelError::Registry(format!("{} gave no upload location", reference.api_host())))?;
+        Url::parse(&format!("{}/", self.registry_url(reference)))
+            .and_then(|root| root.join(&location))
+            .map_err(|e| AethelError::Registry(format!("Bad upload location '{}': {}", location, e)))
+    }
+
+    /// Sends a request, answering one `Basic` or `Bearer` challenge if the
+    /// registry asks for credentials. `actions` is the access needed on the
+    /// repository: `PULL` or `PUSH`.
+    async fn send(&self, reference: &Reference, actions: &str, build: impl Fn() -> RequestBuilder) -> Result<Response> {
+        let key = format!("{}/{}:{}", reference.registry, reference.repository, actions);
+        let cached = self.auth_headers.lock().unwrap().get(&key).cloned();
+
+        let response = self.execute(with_auth(build(), cached.as_deref()), reference).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let challenge = header_str(response.headers(), WWW_AUTHENTICATE.as_str()).unwrap_or_default();
+        let (scheme, params) = challenge.split_once(' ').unwrap_or((&challenge, ""));
+        let header = match scheme.to_ascii_lowercase().as_str() {
+            "bearer" => format!(
+                "Bearer {}",
+                self.fetch_token(reference, actions, &parse_challenge(params)).await?
+            ),
+            "basic" => match self.credentials.get(&reference.registry) {
+                Some(credentials) => credentials.basic_auth(),
+                None => return Ok(response),
+            },
+            _ => return Ok(response),
+        };
+        self.auth_headers.lock().unwrap().insert(key, header.clone());
+        self.execute(with_auth(build(), Some(&header)), reference).await
+    }
+
+    async fn execute(&self, request: RequestBuilder, reference: &Reference) -> Result<Response> {
+        request
+            .send()
+            .await
+            .map_err(|e| AethelError::Registry(format!("Request to {} failed: {}", reference.api_host(), e)))
+    }
+
+    async fn fetch_token(&self, reference: &Reference, actions: &str, params: &HashMap<String, String>) -> Result<String> {
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| AethelError::Registry("Bearer challenge has no realm".to_string()))?;
+        let scope = format!("repository:{}:{}", reference.repository, actions);
+        let mut query = vec![("scope", scope.clone())];
+        // The registry may want more than we know to ask for, such as pull
+        // access to the source repository of a mount.
+        if let Some(wanted) = params.get("scope").filter(|wanted| **wanted != scope) {
+            query.push(("scope", wanted.clone()));
+        }
+        if let Some(service) = params.get("service") {
+            query.push(("service", service.clone()));
+        }
+
+        let request = self.http.get(realm).query(&query);
+        let request = match self.credentials.get(&reference.registry) {
+            Some(credentials) => request.header(AUTHORIZATION, credentials.basic_auth()),
+            None => request,
+        };
+        let response = request
+            .send()
+            .await
+            .map_err(|e| AethelError::Registry(format!("Token request to {} failed: {}", realm, e)))?;
+        let response = expect_success(response, realm).await?;
+        let body: TokenResponse = response
+            .json()
+            .await
+            .map_err(|e| AethelError::Registry(format!("Bad token response from {}: {}", realm, e)))?;
+
+        match (body.token, body.access_token) {
+            (token, _) if !token.is_empty() => Ok(token),
+            (_, token) if !token.is_empty() => Ok(token),
+            _ => Err(AethelError::Registry(format!("No token in response from {}", realm))),
+        }
+    }
+}
+
+/// Distinguishes failures worth resuming from ones that are not.
+enum DownloadError {
+    Transfer(AethelError),
+    Fatal(AethelError),
+}
+
+impl From<std::io::Error> for DownloadError {
+    fn from(err: std::io::Error) -> Self {
+        DownloadError::Fatal(err.into())
+    }
+}
+
+impl From<AethelError> for DownloadError {
+    fn from(err: AethelError) -> Self {
+        DownloadError::Fatal(err)
+    }
+}
+
+fn with_auth(request: RequestBuilder, header: Option<&str>) -> RequestBuilder {
+    match header {
+        Some(header) => request.header(AUTHORIZATION, header),
+        None => request,
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string)
+}
+
+async fn expect_success(response: Response, url: &str) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(AethelError::Registry(format!(
+        "{} returned {}: {}",
+        url,
+        status,
+        body.chars().take(512).collect::<String>()
+    )))
+}
+
+async fn expect_status(response: Response, expected: StatusCode, url: &str) -> Result<Response> {
+    if response.status() == expected {
+        return Ok(response);
+    }
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    Err(AethelError::Registry(format!(
+        "{} returned {} instead of {}: {}",
+        url,
+        status,
+        expected,
+        body.chars().take(512).collect::<String>()
+    )))
+}
+
+fn parse_json<T: serde::de::DeserializeOwned>(bytes: &[u8], what: &str) -> Result<T> {
+    serde_json::from_slice(bytes).map_err(|e| AethelError::Registry(format!("Failed to parse {}: {}", what, e)))
+}
+
+fn check_descriptor(desc: &Descriptor, digest: &Digest, size: u64) -> Result<()> {
+    if desc.digest != digest.to_string() {
+        return Err(AethelError::DigestMismatch {
+            expected: desc.digest.clone(),
+            actual: digest.to_string(),
+        });
+    }
+    if desc.size != 0 && desc.size != size {
+        return Err(AethelError::SizeMismatch {
+            digest: desc.digest.clone(),
+            expected: desc.size,
+            actual: size,
+        });
+    }
+    Ok(())
+}
+
+/// Parses the `key="value",...` parameters of a `WWW-Authenticate` header.
+fn parse_challenge(params: &str) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_ascii_lowercase();
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        result.insert(key, value.to_string());
+        rest = after.trim_start_matches(',').trim();
+    }
+    result
+}
+
+/// Fills `buf` unless the reader runs out first; returns how much was read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn with_digest(location: &Url, digest: &str) -> Url {
+    let mut url = location.clone();
+    url.query_pairs_mut().append_pair("digest", digest);
+    url
+}
+
+fn ingest_path(store: &ImageStore, digest: &Digest) -> PathBuf {
+    store
+        .ingest_dir()
+        .join(format!("{}-{}", digest.algorithm().name(), digest.hex()))
+}
+
+/// Runs file I/O for a download on a blocking thread.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| AethelError::Registry(format!("Blob I/O panicked: {}", e)))?
+}
+
+/// Hashes `bytes` and appends them to the partial download, syncing it
+/// to disk with `sync`.
+async fn append(mut file: fs::File, mut hasher: Hasher, bytes: Vec<u8>, sync: bool) -> Result<(fs::File, Hasher)> {
+    blocking(move || {
+        hasher.update(&bytes);
+        file.write_all(&bytes)?;
+        if sync {
+            file.sync_all()?;
+        }
+        Ok((file, hasher))
+    })
+    .await
+}
+
+/// Opens a partial download for appending and hashes what it already holds.
+fn open_partial(partial: &Path, algorithm: Algorithm, size: u64) -> Result<(fs::File, Hasher, u64)> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut offset = 0;
+    if let Ok(mut existing) = fs::File::open(partial) {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = existing.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            offset += n as u64;
+        }
+    }
+    if offset > size {
+        hasher = Hasher::new(algorithm);
+        offset = 0;
+        fs::File::create(partial)?;
+    }
+    let file = fs::OpenOptions::new().create(true).append(true).open(partial)?;
+    Ok((file, hasher, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::Credentials;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request as HttpRequest, Response as HttpResponse, Server};
+    use std::convert::Infallible;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    const REPOSITORY: &str = "test/app";
+    const PULL_TOKEN: &str = "pull-token";
+    const PUSH_TOKEN: &str = "push-token";
+    const USERNAME: &str = "aethel";
+    const PASSWORD: &str = "hunter2";
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Auth {
+        Anonymous,
+        /// Every request needs the username and password.
+        Basic,
+        /// Reads need a token, writes need one issued against the username
+        /// and password.
+        Bearer,
+    }
+
+    /// An in-process registry serving from a temp dir. Blobs are shared by
+    /// all repositories, as in real registries, but only visible in the
+    /// repositories they are linked to.
+    struct StandIn {
+        root: TempDir,
+        auth: Auth,
+        /// `<repository>@<digest>` for every blob a repository holds.
+        links: Mutex<HashSet<String>>,
+        uploads: Mutex<HashMap<usize, Vec<u8>>>,
+        next_upload: AtomicUsize,
+        /// Blobs whose next download is cut off half way.
+        interrupt: Mutex<HashSet<String>>,
+        /// `<method> <path and query> <range>` for every request received.
+        requests: Mutex<Vec<String>>,
+    }
+
+    impl StandIn {
+        fn new(auth: Auth) -> Arc<Self> {
+            let root = TempDir::new().unwrap();
+            fs::create_dir_all(root.path().join("manifests")).unwrap();
+            fs::create_dir_all(root.path().join("blobs")).unwrap();
+            Arc::new(StandIn {
+                root,
+                auth,
+                links: Mutex::new(HashSet::new()),
+                uploads: Mutex::new(HashMap::new()),
+                next_upload: AtomicUsize::new(0),
+                interrupt: Mutex::new(HashSet::new()),
+                requests: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn blob_file(&self, digest: &str) -> PathBuf {
+            self.root.path().join("blobs").join(digest)
+        }
+
+        fn manifest_file(&self, repository: &str, object: &str) -> PathBuf {
+            self.root.path().join("manifests").join(repository.replace('/', "_")).join(object)
+        }
+
+        fn has_blob(&self, repository: &str, digest: &str) -> bool {
+            self.links.lock().unwrap().contains(&format!("{}@{}", repository, digest)) && self.blob_file(digest).is_file()
+        }
+
+        fn put_blob(&self, repository: &str, media_type: &str, bytes: &[u8]) -> Descriptor {
+            let digest = Digest::of_bytes(Algorithm::Sha256, bytes);
+            fs::write(self.blob_file(&digest.to_string()), bytes).unwrap();
+            self.links.lock().unwrap().insert(format!("{}@{}", repository, digest));
+            descriptor(media_type, &digest, bytes.len())
+        }
+
+        fn put_manifest(&self, repository: &str, tag: Option<&str>, media_type: &str, doc: &serde_json::Value) -> Descriptor {
+            let bytes = serde_json::to_vec(doc).unwrap();
+            let digest = Digest::of_bytes(Algorithm::Sha256, &bytes);
+            self.store_manifest(repository, &digest.to_string(), &bytes);
+            if let Some(tag) = tag {
+                self.store_manifest(repository, tag, &bytes);
+            }
+            descriptor(media_type, &digest, bytes.len())
+        }
+
+        fn store_manifest(&self, repository: &str, object: &str, bytes: &[u8]) {
+            let path = self.manifest_file(repository, object);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, bytes).unwrap();
+        }
+
+        /// Stores a config and one layer for `arch` and returns the image
+        /// manifest descriptor and the layer.
+        fn put_image(&self, repository: &str, tag: Option<&str>, arch: &str, layer: &[u8]) -> (Descriptor, Descriptor) {
+            let config = self.put_blob(repository, oci::MEDIA_TYPE_OCI_CONFIG, &image_config(arch));
+            let layer = self.put_blob(repository, "application/vnd.oci.image.layer.v1.tar+gzip", layer);
+            let manifest = image_manifest(&config, &layer);
+            (self.put_manifest(repository, tag, oci::MEDIA_TYPE_OCI_MANIFEST, &manifest), layer)
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        async fn serve(self: &Arc<Self>) -> String {
+            let stand_in = self.clone();
+            let make = make_service_fn(move |_| {
+                let stand_in = stand_in.clone();
+                async move { Ok::<_, Infallible>(service_fn(move |req| stand_in.clone().handle(req))) }
+            });
+            let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make);
+            let addr = server.local_addr();
+            tokio::spawn(server);
+            addr.to_string()
+        }
+
+        fn authorized(&self, header: Option<&str>, write: bool) -> bool {
+            let basic = Credentials {
+                username: USERNAME.to_string(),
+                password: PASSWORD.to_string(),
+            }
+            .basic_auth();
+            match self.auth {
+                Auth::Anonymous => true,
+                Auth::Basic => header == Some(basic.as_str()),
+                Auth::Bearer => {
+                    header == Some(&format!("Bearer {}", PUSH_TOKEN))
+                        || (!write && header == Some(&format!("Bearer {}", PULL_TOKEN)))
+                }
+            }
+        }
+
+        async fn handle(self: Arc<Self>, req: HttpRequest<Body>) -> std::result::Result<HttpResponse<Body>, Infallible> {
+            let method = req.method().clone();
+            let path = req.uri().path().to_string();
+            let url = Url::parse(&format!("http://stand-in{}", req.uri())).unwrap();
+            let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+            let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+            let (authorization, range, host) = (header("authorization"), header("range"), header("host"));
+            let content_range = header("content-range");
+            self.requests.lock().unwrap().push(format!(
+                "{} {} {}",
+                method,
+                req.uri(),
+                range.clone().unwrap_or_default()
+            ));
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap().to_vec();
+
+            if path == "/token" {
+                let scopes: Vec<String> = url
+                    .query_pairs()
+                    .filter(|(k, _)| k == "scope")
+                    .map(|(_, v)| v.into_owned())
+                    .collect();
+                let wants_push = scopes.iter().any(|scope| scope.ends_with("push"));
+                let token = if !wants_push {
+                    PULL_TOKEN
+                } else if authorization.as_deref() == Some(Credentials {
+                    username: USERNAME.to_string(),
+                    password: PASSWORD.to_string(),
+                }
+                .basic_auth().as_str())
+                {
+                    PUSH_TOKEN
+                } else {
+                    return Ok(status(401));
+                };
+                return Ok(HttpResponse::new(Body::from(format!(r#"{{"token":"{}"}}"#, token))));
+            }
+
+            let Some(rest) = path.strip_prefix("/v2/") else {
+                return Ok(status(404));
+            };
+            let write = method != Method::GET && method != Method::HEAD;
+            let repository = ["/blobs/", "/manifests/"]
+                .iter()
+                .find_map(|kind| rest.split_once(kind).map(|(repository, _)| repository.to_string()))
+                .unwrap_or_default();
+
+            if !self.authorized(authorization.as_deref(), write) {
+                let challenge = match self.auth {
+                    Auth::Basic => r#"Basic realm="stand-in""#.to_string(),
+                    _ => format!(
+                        r#"Bearer realm="http://{}/token",service="stand-in",scope="repository:{}:{}""#,
+                        host.unwrap_or_default(),
+                        repository,
+                        if write { "pull,push" } else { "pull" }
+                    ),
+                };
+                return Ok(HttpResponse::builder()
+                    .status(401)
+                    .header("www-authenticate", challenge)
+                    .body(Body::empty())
+                    .unwrap());
+            }
+
+            if let Some((repository, id)) = rest.split_once("/blobs/uploads/") {
+                return Ok(self.upload(&method, repository, id, &query, content_range.as_deref(), body));
+            }
+            if let Some((repository, object)) = rest.split_once("/manifests/") {
+                return Ok(self.manifest(&method, repository, object, body));
+            }
+            let Some((repository, digest)) = rest.split_once("/blobs/") else {
+                return Ok(status(404));
+            };
+            if !self.has_blob(repository, digest) {
+                return Ok(status(404));
+            }
+            let bytes = fs::read(self.blob_file(digest)).unwrap();
+            if method == Method::HEAD {
+                return Ok(HttpResponse::builder()
+                    .header("content-length", bytes.len())
+                    .body(Body::empty())
+                    .unwrap());
+            }
+
+            let start = range
+                .as_deref()
+                .and_then(|r| r.strip_prefix("bytes="))
+                .and_then(|r| r.strip_suffix('-'))
+                .and_then(|r| r.parse::<usize>().ok());
+            let (code, body) = match start {
+                Some(start) => (206, bytes[start..].to_vec()),
+                None => (200, bytes),
+            };
+
+            let response = HttpResponse::builder()
+                .status(code)
+                .header("content-length", body.len());
+            if self.interrupt.lock().unwrap().remove(digest) {
+                let half = body[..body.len() / 2].to_vec();
+                // Give the client time to receive the first half before the
+                // connection drops.
+                let chunks = futures::stream::once(async { Ok::<_, io::Error>(half) }).chain(futures::stream::once(async {
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    Err(io::Error::other("connection dropped"))
+                }));
+                return Ok(response.body(Body::wrap_stream(chunks)).unwrap());
+            }
+            Ok(response.body(Body::from(body)).unwrap())
+        }
+
+        fn manifest(&self, method: &Method, repository: &str, object: &str, body: Vec<u8>) -> HttpResponse<Body> {
+            if *method == Method::PUT {
+                let digest = Digest::of_bytes(Algorithm::Sha256, &body);
+                self.store_manifest(repository, &digest.to_string(), &body);
+                self.store_manifest(repository, object, &body);
+                return status(201);
+            }
+            let Ok(bytes) = fs::read(self.manifest_file(repository, object)) else {
+                return status(404);
+            };
+            let digest = Digest::of_bytes(Algorithm::Sha256, &bytes);
+            HttpResponse::builder()
+                .header("content-type", "application/json")
+                .header("docker-content-digest", digest.to_string())
+                .body(Body::from(bytes))
+                .unwrap()
+        }
+
+        fn upload(
+            &self,
+            method: &Method,
+            repository: &str,
+            id: &str,
+            query: &HashMap<String, String>,
+            content_range: Option<&str>,
+            body: Vec<u8>,
+        ) -> HttpResponse<Body> {
+            let link = |digest: &str| format!("{}@{}", repository, digest);
+            if *method == Method::POST {
+                if let (Some(digest), Some(from)) = (query.get("mount"), query.get("from")) {
+                    if self.has_blob(from, digest) {
+                        self.links.lock().unwrap().insert(link(digest));
+                        return status(201);
+                    }
+                }
+                let id = self.next_upload.fetch_add(1, Ordering::Relaxed);
+                self.uploads.lock().unwrap().insert(id, Vec::new());
+                return upload_accepted(repository, id);
+            }
+
+            let Ok(id) = id.parse::<usize>() else {
+                return status(404);
+            };
+            let mut uploads = self.uploads.lock().unwrap();
+            let Some(data) = uploads.get_mut(&id) else {
+                return status(404);
+            };
+            if let Some(range) = content_range {
+                if range.split('-').next() != Some(&data.len().to_string()) {
+                    return status(416);
+                }
+            }
+            data.extend_from_slice(&body);
+            if *method == Method::PATCH {
+                return upload_accepted(repository, id);
+            }
+
+            let data = uploads.remove(&id).unwrap();
+            let digest = Digest::of_bytes(Algorithm::Sha256, &data);
+            if query.get("digest") != Some(&digest.to_string()) {
+                return status(400);
+            }
+            fs::write(self.blob_file(&digest.to_string()), &data).unwrap();
+            self.links.lock().unwrap().insert(link(&digest.to_string()));
+            status(201)
+        }
+    }
+
+    fn status(code: u16) -> HttpResponse<Body> {
+        HttpResponse::builder().status(code).body(Body::empty()).unwrap()
+    }
+
+    /// Upload locations are relative, as most registries send them.
+    fn upload_accepted(repository: &str, id: usize) -> HttpResponse<Body> {
+        HttpResponse::builder()
+            .status(202)
+            .header("location", format!("/v2/{}/blobs/uploads/{}", repository, id))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn descriptor(media_type: &str, digest: &Digest, size: usize) -> Descriptor {
+        Descriptor {
+            media_type: media_type.to_string(),
+            digest: digest.to_string(),
+            size: size as u64,
+            platform: None,
+            annotations: HashMap::new(),
+        }
+    }
+
+    fn image_config(arch: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "architecture": arch,
+            "os": "linux",
+            "config": { "Cmd": ["/bin/sh"] },
+            "rootfs": { "type": "layers", "diff_ids": [] },
+        }))
+        .unwrap()
+    }
+
+    fn image_manifest(config: &Descriptor, layer: &Descriptor) -> serde_json::Value {
+        serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": oci::MEDIA_TYPE_OCI_MANIFEST,
+            "config": config,
+            "layers": [layer],
+        })
+    }
+
+    /// Writes a one-layer image into `store` as `image`.
+    fn write_local_image(store: &ImageStore, image: &str, layer: &[u8]) -> (Descriptor, Descriptor) {
+        let put = |media_type: &str, bytes: &[u8]| {
+            let digest = Digest::of_bytes(Algorithm::Sha256, bytes);
+            store.write_blob(&digest, bytes).unwrap();
+            descriptor(media_type, &digest, bytes.len())
+        };
+        let config = put(oci::MEDIA_TYPE_OCI_CONFIG, &image_config("amd64"));
+        let layer = put("application/vnd.oci.image.layer.v1.tar+gzip", layer);
+        let manifest = put(
+            oci::MEDIA_TYPE_OCI_MANIFEST,
+            &serde_json::to_vec(&image_manifest(&config, &layer)).unwrap(),
+        );
+        store.set_reference(&image.parse().unwrap(), manifest.clone()).unwrap();
+        (manifest, layer)
+    }
+
+    fn linux(arch: &str) -> Platform {
+        Platform {
+            architecture: arch.to_string(),
+            os: "linux".to_string(),
+            variant: None,
+        }
+    }
+
+    fn layer_bytes(seed: u8) -> Vec<u8> {
+        (0..256 * 1024).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    fn credentials(host: &str) -> CredentialStore {
+        let mut store = CredentialStore::default();
+        store.insert(
+            host,
+            Credentials {
+                username: USERNAME.to_string(),
+                password: PASSWORD.to_string(),
+            },
+        );
+        store
+    }
+
+    async fn pull(host: &str, image: &str, platform: &Platform, store: &ImageStore) -> (Result<Pulled>, Vec<PullProgress>) {
+        let reference: Reference = format!("{}/{}", host, image).parse().unwrap();
+        let events = Mutex::new(Vec::new());
+        let result = RegistryClient::new()
+            .unwrap()
+            .pull(&reference, platform, store, &|event| events.lock().unwrap().push(event))
+            .await;
+        (result, events.into_inner().unwrap())
+    }
+
+    async fn push(client: RegistryClient, host: &str, image: &str, store: &ImageStore) -> (Result<Digest>, Vec<PushProgress>) {
+        let reference: Reference = format!("{}/{}", host, image).parse().unwrap();
+        let root = store.resolve(&reference.to_string()).unwrap();
+        let events = Mutex::new(Vec::new());
+        let result = client
+            .push(&reference, store, &root, &|event| events.lock().unwrap().push(event))
+            .await;
+        (result, events.into_inner().unwrap())
+    }
+
+    #[tokio::test]
+    async fn pulls_image_into_store() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let (manifest, layer) = stand_in.put_image(REPOSITORY, Some("v1"), "amd64", &layer_bytes(1));
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+
+        let (result, events) = pull(&host, "test/app:v1", &linux("amd64"), &store).await;
+        let pulled = result.unwrap();
+
+        assert_eq!(pulled.digest.to_string(), manifest.digest);
+        assert!(events.contains(&PullProgress::Resolved {
+            digest: manifest.digest.clone()
+        }));
+        assert!(events.contains(&PullProgress::Downloaded {
+            digest: layer.digest.clone()
+        }));
+        let target = store.resolve(&format!("{}/test/app:v1", host)).unwrap();
+        assert_eq!(target.digest, manifest.digest);
+        let (stored, config) = crate::resolve_image(&store, &target, Some(&linux("amd64"))).unwrap();
+        assert_eq!(stored.layers[0].digest, layer.digest);
+        assert_eq!(config.config.cmd, vec!["/bin/sh"]);
+        let pinned = store.resolve(&format!("{}/test/app@{}", host, manifest.digest)).unwrap();
+        assert_eq!(pinned.digest, manifest.digest);
+        assert!(!store.ingest_dir().read_dir().unwrap().any(|_| true));
+    }
+
+    #[tokio::test]
+    async fn selects_platform_manifest_from_index() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let (mut amd64, amd64_layer) = stand_in.put_image(REPOSITORY, None, "amd64", &layer_bytes(1));
+        let (mut arm64, arm64_layer) = stand_in.put_image(REPOSITORY, None, "arm64", &layer_bytes(2));
+        amd64.platform = Some(linux("amd64"));
+        arm64.platform = Some(linux("arm64"));
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": oci::MEDIA_TYPE_OCI_INDEX,
+            "manifests": [amd64, arm64],
+        });
+        stand_in.put_manifest(REPOSITORY, Some("latest"), oci::MEDIA_TYPE_OCI_INDEX, &index);
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+
+        let (result, _) = pull(&host, "test/app", &linux("arm64"), &store).await;
+
+        assert_eq!(result.unwrap().digest.to_string(), arm64.digest);
+        let blob = |desc: &Descriptor| desc.digest.parse::<Digest>().unwrap().blob_path(dir.path());
+        assert!(blob(&arm64_layer).is_file());
+        assert!(!blob(&amd64_layer).exists());
+    }
+
+    #[tokio::test]
+    async fn answers_bearer_token_challenge() {
+        let stand_in = StandIn::new(Auth::Bearer);
+        stand_in.put_image(REPOSITORY, Some("v1"), "amd64", &layer_bytes(1));
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+
+        let (result, _) = pull(&host, "test/app:v1", &linux("amd64"), &store).await;
+
+        result.unwrap();
+        let token_requests = stand_in.requests().iter().filter(|r| r.starts_with("GET /token")).count();
+        assert_eq!(token_requests, 1, "the token is cached for the repository");
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_blob_download() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let layer = layer_bytes(3);
+        let (_, layer_desc) = stand_in.put_image(REPOSITORY, Some("v1"), "amd64", &layer);
+        stand_in.interrupt.lock().unwrap().insert(layer_desc.digest.clone());
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+
+        let (result, _) = pull(&host, "test/app:v1", &linux("amd64"), &store).await;
+
+        result.unwrap();
+        let resumed = format!("GET /v2/{}/blobs/{} bytes={}-", REPOSITORY, layer_desc.digest, layer.len() / 2);
+        assert!(stand_in.requests().contains(&resumed), "{:?}", stand_in.requests());
+        let digest: Digest = layer_desc.digest.parse().unwrap();
+        assert_eq!(fs::read(digest.blob_path(dir.path())).unwrap(), layer);
+    }
+
+    #[tokio::test]
+    async fn fetches_duplicated_layer_once() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let config = stand_in.put_blob(REPOSITORY, oci::MEDIA_TYPE_OCI_CONFIG, &image_config("amd64"));
+        let layer = stand_in.put_blob(REPOSITORY, "application/vnd.oci.image.layer.v1.tar+gzip", &layer_bytes(4));
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": oci::MEDIA_TYPE_OCI_MANIFEST,
+            "config": config,
+            "layers": [layer, layer],
+        });
+        stand_in.put_manifest(REPOSITORY, Some("v1"), oci::MEDIA_TYPE_OCI_MANIFEST, &manifest);
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+
+        let (result, events) = pull(&host, "test/app:v1", &linux("amd64"), &store).await;
+
+        assert_eq!(result.unwrap().manifest.layers.len(), 2);
+        let fetch = format!("GET /v2/{}/blobs/{} ", REPOSITORY, layer.digest);
+        assert_eq!(stand_in.requests().iter().filter(|r| **r == fetch).count(), 1);
+        let downloaded = PullProgress::Downloaded {
+            digest: layer.digest.clone(),
+        };
+        assert_eq!(events.iter().filter(|e| **e == downloaded).count(), 1);
+        let digest: Digest = layer.digest.parse().unwrap();
+        assert_eq!(fs::read(digest.blob_path(dir.path())).unwrap(), layer_bytes(4));
+        assert!(!store.ingest_dir().read_dir().unwrap().any(|_| true));
+    }
+
+    #[tokio::test]
+    async fn concurrent_pulls_fetch_each_blob_once() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let (_, layer) = stand_in.put_image(REPOSITORY, Some("v1"), "amd64", &layer_bytes(5));
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        let client = RegistryClient::new().unwrap();
+        let reference: Reference = format!("{}/test/app:v1", host).parse().unwrap();
+        let platform = linux("amd64");
+
+        let (first, second) = futures::join!(
+            client.pull(&reference, &platform, &store, &|_| {}),
+            client.pull(&reference, &platform, &store, &|_| {}),
+        );
+
+        first.unwrap();
+        second.unwrap();
+        let fetch = format!("GET /v2/{}/blobs/{} ", REPOSITORY, layer.digest);
+        assert_eq!(stand_in.requests().iter().filter(|r| **r == fetch).count(), 1);
+        let digest: Digest = layer.digest.parse().unwrap();
+        assert_eq!(fs::read(digest.blob_path(dir.path())).unwrap(), layer_bytes(5));
+        assert!(client.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_blob_with_wrong_digest() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let (_, layer) = stand_in.put_image(REPOSITORY, Some("v1"), "amd64", &layer_bytes(1));
+        let mut tampered = layer_bytes(1);
+        tampered[0] ^= 0xff;
+        fs::write(stand_in.blob_file(&layer.digest), &tampered).unwrap();
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+
+        let (result, _) = pull(&host, "test/app:v1", &linux("amd64"), &store).await;
+
+        assert!(matches!(result, Err(AethelError::DigestMismatch { .. })), "{:?}", result);
+        let digest: Digest = layer.digest.parse().unwrap();
+        assert!(!digest.blob_path(dir.path()).exists());
+        assert!(!ingest_path(&store, &digest).exists());
+        assert!(store.references().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn skips_blobs_already_present() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let (_, layer) = stand_in.put_image(REPOSITORY, Some("v1"), "amd64", &layer_bytes(1));
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        pull(&host, "test/app:v1", &linux("amd64"), &store).await.0.unwrap();
+        let before = stand_in.requests().len();
+
+        let (result, events) = pull(&host, "test/app:v1", &linux("amd64"), &store).await;
+
+        result.unwrap();
+        assert!(events.contains(&PullProgress::Exists { digest: layer.digest }));
+        assert!(stand_in.requests()[before..].iter().all(|r| !r.contains("/blobs/")));
+    }
+
+    #[tokio::test]
+    async fn pushes_blobs_and_manifest() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        let layer = layer_bytes(4);
+        let (manifest, layer_desc) = write_local_image(&store, &format!("{}/test/app:v1", host), &layer);
+
+        let (result, events) = push(RegistryClient::new().unwrap(), &host, "test/app:v1", &store).await;
+
+        assert_eq!(result.unwrap().to_string(), manifest.digest);
+        assert!(events.contains(&PushProgress::Uploaded {
+            digest: layer_desc.digest.clone()
+        }));
+        let pulled_dir = TempDir::new().unwrap();
+        let pulled = ImageStore::new(pulled_dir.path()).unwrap();
+        pull(&host, "test/app:v1", &linux("amd64"), &pulled).await.0.unwrap();
+        let digest: Digest = layer_desc.digest.parse().unwrap();
+        assert_eq!(fs::read(pulled.blob_path(&digest)).unwrap(), layer);
+    }
+
+    #[tokio::test]
+    async fn pushes_a_repeated_layer_once() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        let image = format!("{}/test/app:v1", host);
+        let (manifest, layer) = write_local_image(&store, &image, &layer_bytes(10));
+        let mut repeated: serde_json::Value =
+            serde_json::from_slice(&fs::read(store.blob_path(&manifest.digest.parse().unwrap())).unwrap()).unwrap();
+        repeated["layers"] = serde_json::json!([layer, layer]);
+        let bytes = serde_json::to_vec(&repeated).unwrap();
+        let digest = Digest::of_bytes(Algorithm::Sha256, &bytes);
+        store.write_blob(&digest, &bytes).unwrap();
+        let repeated = descriptor(oci::MEDIA_TYPE_OCI_MANIFEST, &digest, bytes.len());
+        store.set_reference(&image.parse().unwrap(), repeated).unwrap();
+
+        let (result, events) = push(RegistryClient::new().unwrap(), &host, "test/app:v1", &store).await;
+
+        result.unwrap();
+        let heads = stand_in
+            .requests()
+            .into_iter()
+            .filter(|r| r.starts_with("HEAD ") && r.contains(&layer.digest))
+            .count();
+        assert_eq!(heads, 1);
+        let uploaded = events
+            .iter()
+            .filter(|event| **event == PushProgress::Uploaded { digest: layer.digest.clone() })
+            .count();
+        assert_eq!(uploaded, 1);
+    }
+
+    #[tokio::test]
+    async fn uploads_large_blobs_in_chunks() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        let layer = layer_bytes(5);
+        let (_, layer_desc) = write_local_image(&store, &format!("{}/test/app:v1", host), &layer);
+        let client = RegistryClient::new().unwrap().with_chunk_size(64 * 1024);
+
+        let (result, _) = push(client, &host, "test/app:v1", &store).await;
+
+        result.unwrap();
+        let patches = stand_in.requests().iter().filter(|r| r.starts_with("PATCH ")).count();
+        assert_eq!(patches, layer.len() / (64 * 1024));
+        assert!(stand_in.has_blob(REPOSITORY, &layer_desc.digest));
+        assert_eq!(fs::read(stand_in.blob_file(&layer_desc.digest)).unwrap(), layer);
+    }
+
+    #[tokio::test]
+    async fn skips_blobs_the_registry_has() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        let (_, layer) = write_local_image(&store, &format!("{}/test/app:v1", host), &layer_bytes(6));
+        push(RegistryClient::new().unwrap(), &host, "test/app:v1", &store).await.0.unwrap();
+        let before = stand_in.requests().len();
+
+        let (result, events) = push(RegistryClient::new().unwrap(), &host, "test/app:v1", &store).await;
+
+        result.unwrap();
+        assert!(events.contains(&PushProgress::Exists { digest: layer.digest }));
+        assert!(stand_in.requests()[before..].iter().all(|r| !r.contains("/blobs/uploads/")));
+    }
+
+    #[tokio::test]
+    async fn mounts_blobs_from_source_repository() {
+        let stand_in = StandIn::new(Auth::Anonymous);
+        let (_, layer) = stand_in.put_image("base/image", Some("v1"), "amd64", &layer_bytes(7));
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        pull(&host, "base/image:v1", &linux("amd64"), &store).await.0.unwrap();
+        store
+            .tag(&format!("{}/base/image:v1", host), &format!("{}/test/app:v1", host))
+            .unwrap();
+
+        let (result, events) = push(RegistryClient::new().unwrap(), &host, "test/app:v1", &store).await;
+
+        result.unwrap();
+        assert!(events.contains(&PushProgress::Mounted {
+            digest: layer.digest.clone(),
+            from: "base/image".to_string(),
+        }));
+        assert!(stand_in.has_blob(REPOSITORY, &layer.digest));
+        let uploads = stand_in
+            .requests()
+            .into_iter()
+            .filter(|r| r.starts_with("PATCH ") || (r.starts_with("PUT ") && r.contains("/blobs/uploads/")))
+            .count();
+        assert_eq!(uploads, 0);
+    }
+
+    #[tokio::test]
+    async fn uses_basic_auth_from_credentials() {
+        let stand_in = StandIn::new(Auth::Basic);
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        write_local_image(&store, &format!("{}/test/app:v1", host), &layer_bytes(8));
+
+        let (anonymous, _) = push(RegistryClient::new().unwrap(), &host, "test/app:v1", &store).await;
+        let (authenticated, _) = push(
+            RegistryClient::new().unwrap().with_credentials(credentials(&host)),
+            &host,
+            "test/app:v1",
+            &store,
+        )
+        .await;
+
+        assert!(matches!(anonymous, Err(AethelError::Registry(ref e)) if e.contains("401")), "{:?}", anonymous);
+        authenticated.unwrap();
+    }
+
+    #[tokio::test]
+    async fn requests_push_token_with_credentials() {
+        let stand_in = StandIn::new(Auth::Bearer);
+        let host = stand_in.serve().await;
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        write_local_image(&store, &format!("{}/test/app:v1", host), &layer_bytes(9));
+
+        let (anonymous, _) = push(RegistryClient::new().unwrap(), &host, "test/app:v1", &store).await;
+        let (authenticated, _) = push(
+            RegistryClient::new().unwrap().with_credentials(credentials(&host)),
+            &host,
+            "test/app:v1",
+            &store,
+        )
+        .await;
+
+        assert!(anonymous.is_err());
+        authenticated.unwrap();
+        assert!(stand_in
+            .requests()
+            .iter()
+            .any(|r| r.starts_with("GET /token") && r.contains("pull%2Cpush")));
+    }
+
+    #[test]
+    fn reads_docker_style_credentials_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("auth.json");
+        fs::write(
+            &path,
+            r#"{"auths": {
+                "https://index.docker.io/v1/": {"auth": "aHViOnNlY3JldA=="},
+                "registry.example.com:5000": {"username": "ci", "password": "pw"}
+            }}"#,
+        )
+        .unwrap();
+
+        let store = CredentialStore::load(&path).unwrap();
+
+        assert_eq!(store.get("docker.io").unwrap().username, "hub");
+        assert_eq!(store.get("docker.io").unwrap().password, "secret");
+        assert_eq!(store.get("registry.example.com:5000").unwrap().username, "ci");
+        assert!(store.get("registry.example.com").is_none());
+        assert!(CredentialStore::load(&dir.path().join("missing.json")).unwrap().get("docker.io").is_none());
+    }
+}
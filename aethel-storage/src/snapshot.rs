@@ -0,0 +1,229 @@
+use aethel_common::error::{AethelError, Result};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::digest::Digest;
+use crate::layer::{LayerApplier, LayerReader, WhiteoutMode};
+use crate::oci::Descriptor;
+use crate::store::ImageStore;
+
+/// The kernel rejects overlay mount data longer than a page.
+const MAX_MOUNT_DATA: usize = 4095;
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Overlay-based rootfs snapshots.
+///
+/// Each layer is unpacked once into `layers/<alg>/<hex>/fs`, keyed by the
+/// layer's digest. A container gets `containers/<id>/{upper,work,merged}`
+/// and an overlay mount stacking its upperdir on the shared layers.
+#[derive(Debug, Clone)]
+pub struct Snapshotter {
+    root: PathBuf,
+}
+
+impl Snapshotter {
+    pub fn new(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root.join("layers"))?;
+        fs::create_dir_all(root.join("containers"))?;
+        Ok(Snapshotter {
+            root: root.to_path_buf(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn layer_dir(&self, digest: &Digest) -> PathBuf {
+        self.root
+            .join("layers")
+            .join(digest.algorithm().name())
+            .join(digest.hex())
+            .join("fs")
+    }
+
+    pub fn container_dir(&self, container_id: &str) -> PathBuf {
+        self.root.join("containers").join(container_id)
+    }
+
+    pub fn upper_dir(&self, container_id: &str) -> PathBuf {
+        self.container_dir(container_id).join("upper")
+    }
+
+    pub fn merged_dir(&self, container_id: &str) -> PathBuf {
+        self.container_dir(container_id).join("merged")
+    }
+
+    /// Unpacks the layer blob described by `desc` unless it already is.
+    /// Layers are unpacked into a scratch directory and renamed into place,
+    /// so a half-written layer is never visible and concurrent unpacks of
+    /// the same layer are harmless.
+    pub fn ensure_layer(&self, store: &ImageStore, desc: &Descriptor) -> Result<PathBuf> {
+        let digest: Digest = desc.digest.parse()?;
+        let target = self.layer_dir(&digest);
+        if target.is_dir() {
+            return Ok(target);
+        }
+
+        let scratch = self.root.join("layers").join(format!(
+            "tmp-{}-{}",
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = (|| -> Result<()> {
+            let blob = store.open_blob(desc)?;
+            LayerApplier::new(&scratch.join("fs"))
+                .with_whiteout_mode(WhiteoutMode::Overlay)
+                .apply(LayerReader::new(&desc.media_type, blob)?)?
+                .into_inner()
+                .finish()?;
+
+            let layer_root = target.parent().expect("layer dir has a parent");
+            fs::create_dir_all(layer_root.parent().expect("layer root has a parent"))?;
+            match fs::rename(&scratch, layer_root) {
+                Ok(()) => Ok(()),
+                // Someone else finished the same layer first.
+                Err(_) if target.is_dir() => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        })();
+
+        if scratch.exists() {
+            let _ = fs::remove_dir_all(&scratch);
+        }
+        result.map(|()| target)
+    }
+
+    /// Creates the container's upper and work dirs and mounts the overlay.
+    /// `lowers` are ordered bottom layer first, as in the image manifest.
+    pub fn prepare(&self, container_id: &str, lowers: &[PathBuf]) -> Result<PathBuf> {
+        let dir = self.container_dir(container_id);
+        let upper = dir.join("upper");
+        let work = dir.join("work");
+        let merged = dir.join("merged");
+        for path in [&upper, &work, &merged] {
+            fs::create_dir_all(path)?;
+        }
+
+        // overlayfs needs at least one lowerdir, and lists the top one first.
+        let empty = self.root.join("layers").join("empty");
+        let lower_dirs: Vec<String> = if lowers.is_empty() {
+            fs::create_dir_all(&empty)?;
+            vec![empty.display().to_string()]
+        } else {
+            lowers.iter().rev().map(|p| p.display().to_string()).collect()
+        };
+
+        let data = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lower_dirs.join(":"),
+            upper.display(),
+            work.display()
+        );
+        if data.len() > MAX_MOUNT_DATA {
+            return Err(AethelError::Filesystem(format!(
+                "Overlay mount options for {} exceed {} bytes ({} layers)",
+                container_id,
+                MAX_MOUNT_DATA,
+                lowers.len()
+            )));
+        }
+
+        mount(
+            Some("overlay"),
+            &merged,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(data.as_str()),
+        )
+        .map_err(|e| AethelError::Filesystem(format!("Failed to mount overlay for {}: {}", container_id, e)))?;
+
+        Ok(merged)
+    }
+
+    pub fn is_mounted(&self, container_id: &str) -> bool {
+        let merged = self.merged_dir(container_id);
+        let Ok(mounts) = fs::read_to_string("/proc/self/mountinfo") else {
+            return false;
+        };
+        mounts
+            .lines()
+            .filter_map(|line| line.split(' ').nth(4))
+            .any(|mount_point| Path::new(mount_point) == merged)
+    }
+
+    /// The digests of every unpacked layer.
+    pub fn layers(&self) -> Result<Vec<Digest>> {
+        let mut layers = Vec::new();
+        for algorithm in fs::read_dir(self.root.join("layers"))? {
+            let algorithm = algorithm?;
+            // `empty` and scratch dirs share the directory with the
+            // per-algorithm ones.
+            let Some(name) = algorithm.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !algorithm.file_type()?.is_dir() {
+                continue;
+            }
+            for layer in fs::read_dir(algorithm.path())? {
+                let layer = layer?;
+                if let Ok(digest) = format!("{}:{}", name, layer.file_name().to_string_lossy()).parse() {
+                    layers.push(digest);
+                }
+            }
+        }
+        Ok(layers)
+    }
+
+    /// Scratch dirs left behind by unpacks that never finished.
+    pub fn scratch_dirs(&self) -> Result<Vec<PathBuf>> {
+        let mut dirs = Vec::new();
+        for entry in fs::read_dir(self.root.join("layers"))? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with("tmp-") {
+                dirs.push(entry.path());
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// The IDs of every container with a snapshot.
+    pub fn containers(&self) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(self.root.join("containers"))? {
+            ids.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        Ok(ids)
+    }
+
+    pub fn remove_layer(&self, digest: &Digest) -> Result<()> {
+        let dir = self.layer_dir(digest);
+        match fs::remove_dir_all(dir.parent().expect("layer dir has a parent")) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Unmounts the container's overlay, keeping its upper and work dirs.
+    pub fn unmount(&self, container_id: &str) -> Result<()> {
+        if self.is_mounted(container_id) {
+            umount2(&self.merged_dir(container_id), MntFlags::MNT_DETACH)?;
+        }
+        Ok(())
+    }
+
+    /// Unmounts the container's overlay and deletes its upper and work dirs.
+    pub fn remove(&self, container_id: &str) -> Result<()> {
+        let dir = self.container_dir(container_id);
+        self.unmount(container_id)?;
+        match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,432 @@
+use aethel_common::error::{AethelError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::digest::{Digest, VerifyingReader};
+use crate::oci::Descriptor;
+use crate::reference::Reference;
+
+const REFS_FILE: &str = "refs.json";
+
+/// Blobs read whole into memory (manifests, indexes, configs) are capped.
+pub(crate) const MAX_METADATA_BLOB: u64 = 4 << 20;
+
+#[derive(Default, Serialize, Deserialize)]
+struct RefsFile {
+    #[serde(default)]
+    refs: BTreeMap<String, Descriptor>,
+    /// Images no reference names, by digest, such as those loaded from an
+    /// archive that saved them by ID. Naming one moves it to `refs`.
+    #[serde(default)]
+    untagged: BTreeMap<String, Descriptor>,
+}
+
+/// What `ImageStore::remove` did.
+#[derive(Debug, Default)]
+pub struct Removed {
+    /// References that were removed.
+    pub untagged: Vec<String>,
+    /// The image digest, if no reference to it is left.
+    pub deleted: Option<String>,
+}
+
+/// The local image store.
+///
+/// - `blobs/<alg>/<hex>`: content shared by every image, laid out as in an
+///   OCI image layout.
+/// - `ingest/`: blobs still being downloaded.
+/// - `refs.json`: maps normalized references such as
+///   `docker.io/library/alpine:3.19` or `…/alpine@sha256:…` to the manifest
+///   or index they name, and keeps images without a name by digest.
+pub struct ImageStore {
+    root: PathBuf,
+    /// Serializes read-modify-write cycles of `refs.json`.
+    refs_lock: Mutex<()>,
+}
+
+impl ImageStore {
+    pub fn new(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root.join("blobs"))?;
+        fs::create_dir_all(root.join("ingest"))?;
+        Ok(ImageStore {
+            root: root.to_path_buf(),
+            refs_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn ingest_dir(&self) -> PathBuf {
+        self.root.join("ingest")
+    }
+
+    pub fn blob_path(&self, digest: &Digest) -> PathBuf {
+        digest.blob_path(&self.root)
+    }
+
+    pub fn has_blob(&self, digest: &Digest) -> bool {
+        self.blob_path(digest).is_file()
+    }
+
+    pub fn open_blob(&self, desc: &Descriptor) -> Result<VerifyingReader<File>> {
+        crate::open_blob(&self.root, desc)
+    }
+
+    /// Reads a manifest, index or config blob in full, verifying it.
+    pub fn read_blob(&self, desc: &Descriptor) -> Result<Vec<u8>> {
+        if desc.size > MAX_METADATA_BLOB {
+            return Err(AethelError::Filesystem(format!(
+                "Blob {} is too large to read into memory ({} bytes)",
+                desc.digest, desc.size
+            )));
+        }
+        let mut bytes = Vec::with_capacity(desc.size as usize);
+        self.open_blob(desc)?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Stores `bytes` as the blob `digest`, which they must hash to.
+    pub fn write_blob(&self, digest: &Digest, bytes: &[u8]) -> Result<()> {
+        let actual = Digest::of_bytes(digest.algorithm(), bytes);
+        if actual != *digest {
+            return Err(AethelError::DigestMismatch {
+                expected: digest.to_string(),
+                actual: actual.to_string(),
+            });
+        }
+        let path = self.blob_path(digest);
+        if path.is_file() {
+            return Ok(());
+        }
+        fs::create_dir_all(path.parent().expect("blob path has a parent"))?;
+        write_atomic(&path, bytes)
+    }
+
+    /// Moves `file`, already verified to hash to `digest`, into the store.
+    pub(crate) fn commit_blob(&self, digest: &Digest, file: &Path) -> Result<()> {
+        let path = self.blob_path(digest);
+        if path.is_file() {
+            return Ok(());
+        }
+        fs::create_dir_all(path.parent().expect("blob path has a parent"))?;
+        fs::rename(file, &path)?;
+        Ok(())
+    }
+
+    /// The digest of every blob in the store.
+    pub fn blobs(&self) -> Result<Vec<Digest>> {
+        let mut blobs = Vec::new();
+        for algorithm in fs::read_dir(self.root.join("blobs"))? {
+            let algorithm = algorithm?;
+            let name = algorithm.file_name().to_string_lossy().into_owned();
+            for blob in fs::read_dir(algorithm.path())? {
+                // Anything that is not a digest, e.g. an interrupted
+                // write's `.tmp` file, is left alone.
+                if let Ok(digest) = format!("{}:{}", name, blob?.file_name().to_string_lossy()).parse() {
+                    blobs.push(digest);
+                }
+            }
+        }
+        Ok(blobs)
+    }
+
+    pub fn remove_blob(&self, digest: &Digest) -> Result<()> {
+        match fs::remove_file(self.blob_path(digest)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every reference in the store, sorted by name.
+    pub fn references(&self) -> Result<Vec<(Reference, Descriptor)>> {
+        let _guard = self.refs_lock.lock().unwrap();
+        Ok(self
+            .load_refs()?
+            .refs
+            .into_iter()
+            .filter_map(|(name, desc)| name.parse().ok().map(|reference| (reference, desc)))
+            .collect())
+    }
+
+    /// Every image no reference names that the store keeps anyway.
+    pub fn untagged(&self) -> Result<Vec<Descriptor>> {
+        let _guard = self.refs_lock.lock().unwrap();
+        Ok(self.load_refs()?.untagged.into_values().collect())
+    }
+
+    /// Keeps `target` without a name, unless a reference already names it.
+    pub fn add_untagged(&self, target: Descriptor) -> Result<()> {
+        let _guard = self.refs_lock.lock().unwrap();
+        let mut refs = self.load_refs()?;
+        if refs.refs.values().any(|desc| desc.digest == target.digest) {
+            return Ok(());
+        }
+        refs.untagged.insert(target.digest.clone(), target);
+        self.save_refs(&refs)
+    }
+
+    /// Looks up `name`, which may be a reference in any form `Reference`
+    /// accepts or an image ID (the manifest digest, or a unique prefix of
+    /// its hex of at least four characters).
+    pub fn resolve(&self, name: &str) -> Result<Descriptor> {
+        let _guard = self.refs_lock.lock().unwrap();
+        let RefsFile { refs, untagged } = self.load_refs()?;
+
+        if let Ok(reference) = name.parse::<Reference>() {
+            if let Some(desc) = refs.get(&reference.to_string()) {
+                return Ok(desc.clone());
+            }
+            // `name@digest` also finds an image that was pulled by tag.
+            if let Some(digest) = &reference.digest {
+                let found = refs.iter().find(|(key, desc)| {
+                    desc.digest == digest.to_string() && same_repository(key, &reference)
+                });
+                if let Some((_, desc)) = found {
+                    return Ok(desc.clone());
+                }
+            }
+        }
+
+        let matches = find_by_id(refs.values().chain(untagged.values()), name)?;
+        match matches.as_slice() {
+            [desc] => Ok((*desc).clone()),
+            [] => Err(AethelError::NotFound(format!("No such image: {}", name))),
+            _ => Err(AethelError::NotFound(format!("Image ID {} is ambiguous", name))),
+        }
+    }
+
+    /// Points `reference` at `target`, replacing whatever it named before.
+    pub fn set_reference(&self, reference: &Reference, target: Descriptor) -> Result<()> {
+        let _guard = self.refs_lock.lock().unwrap();
+        let mut refs = self.load_refs()?;
+        refs.untagged.remove(&target.digest);
+        refs.refs.insert(reference.to_string(), target);
+        self.save_refs(&refs)
+    }
+
+    /// Adds the reference `target` for the image `source` names.
+    pub fn tag(&self, source: &str, target: &str) -> Result<Reference> {
+        let target: Reference = target.parse()?;
+        if target.digest.is_some() {
+            return Err(AethelError::Registry(format!(
+                "Cannot tag with a digest reference: {}",
+                target
+            )));
+        }
+        let desc = self.resolve(source)?;
+        self.set_reference(&target, desc)?;
+        Ok(target)
+    }
+
+    /// Removes a reference. An image ID instead removes every reference to
+    /// the image, or the image itself when it is untagged. Removing the
+    /// last tag of an image in a repository also removes its `name@digest`
+    /// references, as Docker does.
+    pub fn remove(&self, name: &str) -> Result<Removed> {
+        let _guard = self.refs_lock.lock().unwrap();
+        let mut refs = self.load_refs()?;
+
+        let direct = name
+            .parse::<Reference>()
+            .ok()
+            .map(|reference| reference.to_string())
+            .filter(|key| refs.refs.contains_key(key));
+        let (digest, mut removed) = match direct {
+            Some(key) => {
+                let desc = refs.refs.remove(&key).expect("checked above");
+                (desc.digest, vec![key])
+            }
+            None => {
+                let matches = find_by_id(refs.refs.values().chain(refs.untagged.values()), name)?;
+                let digest = match matches.as_slice() {
+                    [desc] => desc.digest.clone(),
+                    [] => return Err(AethelError::NotFound(format!("No such image: {}", name))),
+                    _ => return Err(AethelError::NotFound(format!("Image ID {} is ambiguous", name))),
+                };
+                let keys: Vec<String> = refs
+                    .refs
+                    .iter()
+                    .filter(|(_, desc)| desc.digest == digest)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in &keys {
+                    refs.refs.remove(key);
+                }
+                refs.untagged.remove(&digest);
+                (digest, keys)
+            }
+        };
+
+        let tagged = refs
+            .refs
+            .iter()
+            .any(|(key, desc)| desc.digest == digest && key.parse::<Reference>().is_ok_and(|r| r.tag.is_some()));
+        if !tagged {
+            let orphans: Vec<String> = refs
+                .refs
+                .iter()
+                .filter(|(_, desc)| desc.digest == digest)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in orphans {
+                refs.refs.remove(&key);
+                removed.push(key);
+            }
+        }
+
+        self.save_refs(&refs)?;
+        Ok(Removed {
+            untagged: removed,
+            deleted: (!tagged).then_some(digest),
+        })
+    }
+
+    fn load_refs(&self) -> Result<RefsFile> {
+        match fs::read(self.root.join(REFS_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| AethelError::Filesystem(format!("Corrupt {}: {}", REFS_FILE, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(RefsFile::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_refs(&self, refs: &RefsFile) -> Result<()> {
+        let json = serde_json::to_vec_pretty(refs)
+            .map_err(|e| AethelError::Filesystem(format!("Failed to encode {}: {}", REFS_FILE, e)))?;
+        write_atomic(&self.root.join(REFS_FILE), &json)
+    }
+}
+
+fn same_repository(key: &str, reference: &Reference) -> bool {
+    key.parse::<Reference>()
+        .is_ok_and(|r| r.registry == reference.registry && r.repository == reference.repository)
+}
+
+/// The distinct images among `images` whose digest hex starts with `id`.
+fn find_by_id<'a>(images: impl Iterator<Item = &'a Descriptor>, id: &str) -> Result<Vec<&'a Descriptor>> {
+    let hex = id.split_once(':').map_or(id, |(_, hex)| hex);
+    if hex.len() < 4 || !hex.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+        return Ok(vec![]);
+    }
+    let mut matches: BTreeMap<&str, &Descriptor> = BTreeMap::new();
+    for desc in images {
+        let digest: Digest = desc.digest.parse()?;
+        let algorithm_matches = !id.contains(':') || id.starts_with(digest.algorithm().name());
+        if algorithm_matches && digest.hex().starts_with(hex) {
+            matches.insert(&desc.digest, desc);
+        }
+    }
+    Ok(matches.into_values().collect())
+}
+
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::Algorithm;
+    use crate::oci::MEDIA_TYPE_OCI_MANIFEST;
+    use tempfile::TempDir;
+
+    fn manifest(store: &ImageStore, seed: &str) -> Descriptor {
+        let bytes = format!("{{\"schemaVersion\":2,\"seed\":\"{}\"}}", seed).into_bytes();
+        let digest = Digest::of_bytes(Algorithm::Sha256, &bytes);
+        store.write_blob(&digest, &bytes).unwrap();
+        Descriptor {
+            media_type: MEDIA_TYPE_OCI_MANIFEST.to_string(),
+            digest: digest.to_string(),
+            size: bytes.len() as u64,
+            platform: None,
+            annotations: Default::default(),
+        }
+    }
+
+    fn set(store: &ImageStore, name: &str, desc: &Descriptor) {
+        store.set_reference(&name.parse().unwrap(), desc.clone()).unwrap();
+    }
+
+    #[test]
+    fn resolves_normalized_names_digests_and_ids() {
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        let desc = manifest(&store, "a");
+        set(&store, "alpine:3.19", &desc);
+        let hex = desc.digest.split_once(':').unwrap().1;
+
+        assert_eq!(store.resolve("docker.io/library/alpine:3.19").unwrap().digest, desc.digest);
+        assert_eq!(store.resolve(&format!("alpine@{}", desc.digest)).unwrap().digest, desc.digest);
+        assert_eq!(store.resolve(&hex[..12]).unwrap().digest, desc.digest);
+        assert_eq!(store.resolve(&desc.digest).unwrap().digest, desc.digest);
+        assert!(matches!(store.resolve("alpine"), Err(AethelError::NotFound(_))));
+        assert!(matches!(store.resolve(&format!("busybox@{}", desc.digest)), Err(AethelError::NotFound(_))));
+    }
+
+    #[test]
+    fn tags_and_untags_without_deleting_shared_images() {
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        let desc = manifest(&store, "a");
+        set(&store, "alpine:3.19", &desc);
+
+        let tagged = store.tag("alpine:3.19", "registry.example.com/team/app:v1").unwrap();
+        assert_eq!(tagged.familiar(), "registry.example.com/team/app:v1");
+        assert!(store.tag("alpine:3.19", &format!("alpine@{}", desc.digest)).is_err());
+
+        let removed = store.remove("alpine:3.19").unwrap();
+        assert_eq!(removed.untagged, vec!["docker.io/library/alpine:3.19"]);
+        assert_eq!(removed.deleted, None);
+        assert_eq!(store.resolve("registry.example.com/team/app:v1").unwrap().digest, desc.digest);
+    }
+
+    #[test]
+    fn removing_by_id_drops_every_reference() {
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        let (a, b) = (manifest(&store, "a"), manifest(&store, "b"));
+        set(&store, "alpine:3.19", &a);
+        set(&store, "alpine:latest", &a);
+        set(&store, &format!("alpine@{}", a.digest), &a);
+        set(&store, "busybox", &b);
+
+        let removed = store.remove(&a.digest).unwrap();
+
+        assert_eq!(removed.untagged.len(), 3);
+        assert_eq!(removed.deleted.as_deref(), Some(a.digest.as_str()));
+        let left: Vec<String> = store.references().unwrap().iter().map(|(r, _)| r.familiar()).collect();
+        assert_eq!(left, vec!["busybox:latest"]);
+    }
+
+    #[test]
+    fn keeps_untagged_images_by_id() {
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        let (a, b) = (manifest(&store, "a"), manifest(&store, "b"));
+        store.add_untagged(a.clone()).unwrap();
+        store.add_untagged(b.clone()).unwrap();
+        let id = &a.digest["sha256:".len().."sha256:".len() + 12];
+
+        assert_eq!(store.resolve(id).unwrap().digest, a.digest);
+        assert!(store.references().unwrap().is_empty());
+
+        store.tag(id, "alpine:3.19").unwrap();
+        let untagged: Vec<String> = store.untagged().unwrap().into_iter().map(|desc| desc.digest).collect();
+        assert_eq!(untagged, vec![b.digest.clone()]);
+
+        let removed = store.remove(&b.digest).unwrap();
+        assert!(removed.untagged.is_empty());
+        assert_eq!(removed.deleted.as_deref(), Some(b.digest.as_str()));
+        assert!(store.untagged().unwrap().is_empty());
+        assert!(matches!(store.resolve(&b.digest), Err(AethelError::NotFound(_))));
+    }
+}
@@ -0,0 +1,355 @@
+use aethel_common::error::{AethelError, Result};
+use aethel_common::time::format_rfc3339;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::build::copy_entries;
+use crate::copy::container_path;
+use crate::diff;
+use crate::digest::{Algorithm, Hasher};
+use crate::gc::disk_usage;
+use crate::layer::resolve_in_root;
+use crate::store::write_atomic;
+
+const METADATA_FILE: &str = "volume.json";
+
+static NAME_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A named volume: a directory the daemon manages and containers mount.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Volume {
+    pub name: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub created: String,
+    /// Set once a container has mounted the volume: only the first mount
+    /// copies the image's content in, so emptying it sticks.
+    #[serde(default)]
+    pub copied: bool,
+    /// Where the volume's content lives on the host.
+    #[serde(skip)]
+    pub mountpoint: PathBuf,
+}
+
+/// Named volumes, one directory each: `<name>/volume.json` holds the
+/// metadata and `<name>/_data` the content containers see.
+///
+/// The store does not know which containers use a volume; the daemon counts
+/// them and keeps removal and pruning away from volumes in use.
+pub struct VolumeStore {
+    root: PathBuf,
+}
+
+impl VolumeStore {
+    pub fn new(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root)?;
+        Ok(VolumeStore {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Creates the volume `name`, or one with a generated name. Creating a
+    /// volume that exists returns it unchanged, as Docker does.
+    pub fn create(&self, name: Option<&str>, labels: BTreeMap<String, String>) -> Result<Volume> {
+        let name = match name {
+            Some(name) => {
+                check_name(name)?;
+                if let Some(volume) = self.load(name)? {
+                    return Ok(volume);
+                }
+                name.to_string()
+            }
+            None => generate_name(),
+        };
+
+        let dir = self.root.join(&name);
+        fs::create_dir_all(dir.join("_data"))?;
+        let volume = Volume {
+            name,
+            labels,
+            created: format_rfc3339(SystemTime::now()),
+            copied: false,
+            mountpoint: dir.join("_data"),
+        };
+        self.save(&volume)?;
+        Ok(volume)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Volume> {
+        check_name(name)?;
+        self.load(name)?
+            .ok_or_else(|| AethelError::NotFound(format!("Volume {} not found", name)))
+    }
+
+    /// Every volume, by name.
+    pub fn list(&self) -> Result<Vec<Volume>> {
+        let mut volumes = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            // A directory without metadata is a create that never finished.
+            if let Some(volume) = self.load(&name)? {
+                volumes.push(volume);
+            }
+        }
+        volumes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(volumes)
+    }
+
+    /// Deletes the volume and its content. The caller checks it is unused.
+    pub fn remove(&self, name: &str) -> Result<()> {
+        self.get(name)?;
+        fs::remove_dir_all(self.root.join(name))?;
+        Ok(())
+    }
+
+    /// Bytes the volume's content takes on disk.
+    pub fn disk_usage(&self, volume: &Volume) -> u64 {
+        disk_usage(&volume.mountpoint)
+    }
+
+    fn save(&self, volume: &Volume) -> Result<()> {
+        let json = serde_json::to_vec_pretty(volume)
+            .map_err(|e| AethelError::Filesystem(format!("Failed to encode {}: {}", METADATA_FILE, e)))?;
+        write_atomic(&self.root.join(&volume.name).join(METADATA_FILE), &json)
+    }
+
+    fn load(&self, name: &str) -> Result<Option<Volume>> {
+        let dir = self.root.join(name);
+        let bytes = match fs::read(dir.join(METADATA_FILE)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut volume: Volume = serde_json::from_slice(&bytes)
+            .map_err(|e| AethelError::Filesystem(format!("Corrupt {} of volume {}: {}", METADATA_FILE, name, e)))?;
+        volume.mountpoint = dir.join("_data");
+        Ok(Some(volume))
+    }
+}
+
+/// Names as Docker accepts them: `[a-zA-Z0-9][a-zA-Z0-9_.-]*`.
+fn check_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if !valid {
+        return Err(AethelError::Volume(format!("invalid volume name '{}'", name)));
+    }
+    Ok(())
+}
+
+/// 64 hex digits, the shape of Docker's anonymous volume names.
+fn generate_name() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let mut hasher = Hasher::new(Algorithm::Sha256);
+    hasher.update(format!("{}-{}-{}", nanos, process::id(), NAME_COUNTER.fetch_add(1, Ordering::Relaxed)).as_bytes());
+    hasher.finish().hex().to_string()
+}
+
+/// Where a mount's content comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MountSource {
+    /// A host path, mounted as it is.
+    Bind(PathBuf),
+    /// A named volume, created on first use.
+    Volume(String),
+}
+
+/// A `-v SOURCE:DESTINATION[:ro|rw]` argument. A source starting with `/`
+/// is a host path, anything else a volume name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountSpec {
+    pub source: MountSource,
+    /// An absolute path in the container.
+    pub destination: String,
+    pub read_only: bool,
+}
+
+impl FromStr for MountSpec {
+    type Err = AethelError;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let invalid = |why: &str| AethelError::Volume(format!("invalid volume spec '{}': {}", spec, why));
+        let parts: Vec<&str> = spec.split(':').collect();
+        let (source, destination, read_only) = match parts[..] {
+            [source, destination] => (source, destination, false),
+            [source, destination, "ro"] => (source, destination, true),
+            [source, destination, "rw"] => (source, destination, false),
+            [_, _, _] => return Err(invalid("mode must be ro or rw")),
+            _ => return Err(invalid("expected SOURCE:DESTINATION[:ro|rw]")),
+        };
+        if !destination.starts_with('/') {
+            return Err(invalid("destination must be an absolute path"));
+        }
+        let source = if source.starts_with('/') {
+            MountSource::Bind(PathBuf::from(source))
+        } else {
+            check_name(source)?;
+            MountSource::Volume(source.to_string())
+        };
+        Ok(MountSpec {
+            source,
+            destination: destination.to_string(),
+            read_only,
+        })
+    }
+}
+
+/// A mount resolved against a container's rootfs, ready to bind.
+#[derive(Debug, Clone)]
+pub struct Mount {
+    /// The host path mounted.
+    pub source: PathBuf,
+    /// The mount point, a host path inside the rootfs.
+    pub target: PathBuf,
+    pub read_only: bool,
+    /// The volume mounted, for named volumes.
+    pub volume: Option<String>,
+}
+
+/// Resolves `spec` inside `rootfs` and creates its mount point there. A
+/// named volume is created if missing, and on its first mount an empty one
+/// is filled with what the image has at the destination.
+pub fn prepare_mount(volumes: &VolumeStore, rootfs: &Path, spec: &MountSpec) -> Result<Mount> {
+    let target = resolve_in_root(rootfs, &container_path(Path::new(&spec.destination)))?;
+    let (source, volume) = match &spec.source {
+        MountSource::Bind(path) => {
+            let meta = fs::metadata(path).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => AethelError::NotFound(format!("Bind source {} does not exist", path.display())),
+                _ => e.into(),
+            })?;
+            if !meta.is_dir() && !target.exists() {
+                fs::create_dir_all(target.parent().expect("mount points have a parent"))?;
+                fs::File::create(&target)?;
+            }
+            (path.clone(), None)
+        }
+        MountSource::Volume(name) => {
+            let mut volume = volumes.create(Some(name), BTreeMap::new())?;
+            if !volume.copied {
+                copy_up(&target, &volume.mountpoint)?;
+                volume.copied = true;
+                volumes.save(&volume)?;
+            }
+            (volume.mountpoint, Some(volume.name))
+        }
+    };
+    if !target.exists() {
+        fs::create_dir_all(&target)?;
+    }
+    Ok(Mount {
+        source,
+        target,
+        read_only: spec.read_only,
+        volume,
+    })
+}
+
+/// Copies the image's directory at the mount point into an empty volume,
+/// keeping owners and modes, its own included, so the container still
+/// sees it.
+fn copy_up(image_dir: &Path, data: &Path) -> Result<()> {
+    if !image_dir.is_dir() || fs::read_dir(data)?.next().is_some() {
+        return Ok(());
+    }
+    let mut entries = Vec::new();
+    diff::add_tree(image_dir, Path::new(""), &mut entries)?;
+    if !entries.is_empty() {
+        copy_entries(image_dir, &entries, data, Path::new(""), Path::new(""))?;
+    }
+    let meta = fs::metadata(image_dir)?;
+    unix_fs::chown(data, Some(meta.uid()), Some(meta.gid()))?;
+    fs::set_permissions(data, fs::Permissions::from_mode(meta.mode() & 0o7777))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_lists_and_removes_volumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let volumes = VolumeStore::new(dir.path()).unwrap();
+        let labels = BTreeMap::from([("app".to_string(), "web".to_string())]);
+
+        let data = volumes.create(Some("data"), labels.clone()).unwrap();
+        fs::write(data.mountpoint.join("db"), "rows").unwrap();
+        let again = volumes.create(Some("data"), BTreeMap::new()).unwrap();
+        let anonymous = volumes.create(None, BTreeMap::new()).unwrap();
+
+        assert_eq!(again.labels, labels);
+        assert_eq!(again.created, data.created);
+        assert_eq!(fs::read_to_string(again.mountpoint.join("db")).unwrap(), "rows");
+        assert_eq!(anonymous.name.len(), 64);
+        let names: Vec<String> = volumes.list().unwrap().into_iter().map(|v| v.name).collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"data".to_string()));
+
+        volumes.remove("data").unwrap();
+        assert!(matches!(volumes.get("data"), Err(AethelError::NotFound(_))));
+        assert!(matches!(volumes.create(Some("../etc"), BTreeMap::new()), Err(AethelError::Volume(_))));
+    }
+
+    #[test]
+    fn parses_mount_specs() {
+        let bind: MountSpec = "/srv/www:/usr/share/nginx/html:ro".parse().unwrap();
+        let named: MountSpec = "pgdata:/var/lib/postgresql/data".parse().unwrap();
+
+        assert_eq!(bind.source, MountSource::Bind(PathBuf::from("/srv/www")));
+        assert!(bind.read_only);
+        assert_eq!(named.source, MountSource::Volume("pgdata".to_string()));
+        assert_eq!(named.destination, "/var/lib/postgresql/data");
+        assert!(!named.read_only);
+        for bad in ["pgdata", "pgdata:data", "pgdata:/data:rx", "-x:/data", "a:b:c:d"] {
+            assert!(bad.parse::<MountSpec>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn copies_image_content_into_empty_volumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("etc/app/conf.d")).unwrap();
+        fs::write(rootfs.join("etc/app/conf.d/default.conf"), "listen 80").unwrap();
+        fs::set_permissions(rootfs.join("etc/app/conf.d"), fs::Permissions::from_mode(0o750)).unwrap();
+        fs::set_permissions(rootfs.join("etc/app"), fs::Permissions::from_mode(0o710)).unwrap();
+        let volumes = VolumeStore::new(&dir.path().join("volumes")).unwrap();
+
+        let spec: MountSpec = "conf:/etc/app".parse().unwrap();
+        let mount = prepare_mount(&volumes, &rootfs, &spec).unwrap();
+        let data = volumes.get("conf").unwrap().mountpoint;
+
+        assert_eq!(mount.source, data);
+        assert_eq!(mount.target, rootfs.join("etc/app"));
+        assert_eq!(mount.volume.as_deref(), Some("conf"));
+        assert_eq!(fs::read_to_string(data.join("conf.d/default.conf")).unwrap(), "listen 80");
+        let mode = fs::metadata(data.join("conf.d")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+        let mode = fs::metadata(&data).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o710);
+        assert!(volumes.get("conf").unwrap().copied);
+
+        // A volume with content keeps it.
+        fs::write(data.join("conf.d/default.conf"), "listen 8080").unwrap();
+        prepare_mount(&volumes, &rootfs, &spec).unwrap();
+        assert_eq!(fs::read_to_string(data.join("conf.d/default.conf")).unwrap(), "listen 8080");
+
+        // So does one that was emptied after its first mount.
+        fs::remove_dir_all(data.join("conf.d")).unwrap();
+        prepare_mount(&volumes, &rootfs, &spec).unwrap();
+        assert_eq!(fs::read_dir(&data).unwrap().count(), 0);
+
+        // Mount points missing from the image are created.
+        let fresh = prepare_mount(&volumes, &rootfs, &"cache:/var/cache/app".parse().unwrap()).unwrap();
+        assert!(fresh.target.is_dir());
+    }
+}